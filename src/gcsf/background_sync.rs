@@ -0,0 +1,105 @@
+use super::FileManager;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+/// A worker thread which periodically asks Drive for remote changes and applies them to a shared
+/// `FileManager`. Changes are retrieved while only the manager's `DriveBackend` is locked, so FUSE
+/// callbacks which do not need the network are not held up while waiting for Drive to respond.
+pub struct BackgroundSync {
+    /// Set to `true` when the worker should exit. The condition variable wakes it up early.
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BackgroundSync {
+    /// Starts a worker which syncs `manager` every `interval`.
+    pub fn spawn(manager: Arc<Mutex<FileManager>>, interval: Duration) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let worker_stop = Arc::clone(&stop);
+
+        let handle = thread::Builder::new()
+            .name("gcsf-sync".to_string())
+            .spawn(move || {
+                let (ref lock, ref cvar) = *worker_stop;
+                loop {
+                    {
+                        let stopped = lock.lock().unwrap();
+                        if *stopped {
+                            break;
+                        }
+                        let (stopped, _) = cvar.wait_timeout(stopped, interval).unwrap();
+                        if *stopped {
+                            break;
+                        }
+                    }
+
+                    BackgroundSync::sync_once(&manager);
+                }
+                debug!("Background sync stopped");
+            })
+            .expect("Could not spawn the background sync thread");
+
+        BackgroundSync {
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    /// Retrieves the remote changes and applies them locally. The metadata cache is written after
+    /// the manager has been unlocked.
+    fn sync_once(manager: &Arc<Mutex<FileManager>>) {
+        let (df, request) = {
+            let manager = manager.lock().unwrap();
+            (Arc::clone(&manager.df), manager.sync_request())
+        };
+
+        let remote = match request.fetch(&mut *df.lock().unwrap()) {
+            Ok(remote) => remote,
+            Err(e) => {
+                debug!("Could not retrieve changes: {}", e);
+                return;
+            }
+        };
+
+        let snapshot = {
+            let mut manager = manager.lock().unwrap();
+            manager.last_sync = SystemTime::now();
+            if let Err(e) = manager.apply_remote_changes(remote) {
+                error!("Could not apply changes: {}", e);
+            }
+            manager.outdated_metadata()
+        };
+
+        let saved = match snapshot {
+            Ok(Some(snapshot)) => snapshot.save(),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = saved {
+            warn!("Could not save metadata cache: {}", e);
+        }
+    }
+
+    /// Asks the worker to exit and waits until it does. A sync which is in progress is allowed to
+    /// finish first.
+    pub fn stop(&mut self) {
+        {
+            let (ref lock, ref cvar) = *self.stop;
+            *lock.lock().unwrap() = true;
+            cvar.notify_all();
+        }
+
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("Background sync thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for BackgroundSync {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
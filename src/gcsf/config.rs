@@ -1,4 +1,9 @@
+use super::AttrDefaults;
+use libc;
+use std::env;
+use std::path::PathBuf;
 use std::time::Duration;
+use xdg;
 
 /// Provides a few properties of the file system that can be configured. Includes sensible
 /// defaults for the absent values.
@@ -6,12 +11,36 @@ use std::time::Duration;
 pub struct Config {
     debug: Option<bool>,
     cache_max_seconds: Option<u64>,
-    cache_max_items: Option<u64>,
     cache_statfs_seconds: Option<u64>,
     sync_interval: Option<u64>,
     mount_options: Option<Vec<String>>,
     pub token_path: Option<String>,
     authorize_using_code: Option<bool>,
+    pub api_root: Option<String>,
+    pub upload_root: Option<String>,
+    token_uri: Option<String>,
+    cache_metadata: Option<bool>,
+    lazy_population: Option<bool>,
+    starred_dir: Option<bool>,
+    recent_dir: Option<bool>,
+    recent_dir_limit: Option<usize>,
+    worker_threads: Option<usize>,
+    retry_max_attempts: Option<u32>,
+    retry_base_delay_ms: Option<u64>,
+    retry_max_wait_seconds: Option<u64>,
+    shutdown_timeout_seconds: Option<u64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    umask: Option<String>,
+    file_mode: Option<String>,
+    dir_mode: Option<String>,
+    content_cache_max_bytes: Option<u64>,
+    content_cache_max_file_bytes: Option<u64>,
+    pub content_cache_path: Option<String>,
+    pub spool_path: Option<String>,
+    pub journal_path: Option<String>,
+    pub recovery_path: Option<String>,
+    pub metadata_cache_path: Option<String>,
 }
 
 impl Config {
@@ -20,16 +49,12 @@ impl Config {
         self.debug.unwrap_or(false)
     }
 
-    /// How long to cache the contents of a file after it has been accessed.
+    /// How long a cached copy of a file is used before checking with Drive whether it is still
+    /// up to date.
     pub fn cache_max_seconds(&self) -> Duration {
         Duration::from_secs(self.cache_max_seconds.unwrap_or(10))
     }
 
-    /// How how many files to cache.
-    pub fn cache_max_items(&self) -> u64 {
-        self.cache_max_items.unwrap_or(10)
-    }
-
     /// How long to cache the size and capacity of the filesystem. These are the values reported by `df`.
     pub fn cache_statfs_seconds(&self) -> Duration {
         Duration::from_secs(self.cache_statfs_seconds.unwrap_or(100))
@@ -61,4 +86,198 @@ impl Config {
     pub fn authorize_using_code(&self) -> bool {
         self.authorize_using_code.unwrap_or(true)
     }
+
+    /// The base URL of the Drive v3 API. Requests for metadata, downloads and changes are sent
+    /// here.
+    pub fn api_root(&self) -> String {
+        self.api_root
+            .clone()
+            .unwrap_or(String::from("https://www.googleapis.com/drive/v3/"))
+    }
+
+    /// The root URL used for uploads. Drive expects `upload/drive/v3/` to follow it.
+    pub fn upload_root(&self) -> String {
+        self.upload_root
+            .clone()
+            .unwrap_or(String::from("https://www.googleapis.com/"))
+    }
+
+    /// The OAuth2 token endpoint. If absent, the one provided by the client secret is used.
+    pub fn token_uri(&self) -> Option<String> {
+        self.token_uri.clone()
+    }
+
+    /// Whether to save the file tree between sessions, so that mounting only needs to retrieve
+    /// the changes which happened in the meantime instead of the whole tree.
+    pub fn cache_metadata(&self) -> bool {
+        self.cache_metadata.unwrap_or(true)
+    }
+
+    /// The path to the file in which the file tree is saved between sessions. Absent if
+    /// `cache_metadata` is disabled.
+    pub fn metadata_cache_path(&self) -> Option<PathBuf> {
+        if !self.cache_metadata() {
+            return None;
+        }
+
+        self.metadata_cache_path.as_ref().map(PathBuf::from)
+    }
+
+    /// If set to true, the contents of a directory are only retrieved the first time it is
+    /// accessed, instead of crawling the whole Drive before mounting.
+    pub fn lazy_population(&self) -> bool {
+        self.lazy_population.unwrap_or(false)
+    }
+
+    /// Whether to add a "Starred" directory which lists the starred files.
+    pub fn starred_dir(&self) -> bool {
+        self.starred_dir.unwrap_or(false)
+    }
+
+    /// Whether to add a "Recent" directory which lists the files that have been viewed or
+    /// modified most recently.
+    pub fn recent_dir(&self) -> bool {
+        self.recent_dir.unwrap_or(false)
+    }
+
+    /// How many files the "Recent" directory lists at most.
+    pub fn recent_dir_limit(&self) -> usize {
+        self.recent_dir_limit.unwrap_or(50)
+    }
+
+    /// How many threads serve the file system operations which need to wait for Drive (e.g.
+    /// reading, flushing, creating or deleting files).
+    pub fn worker_threads(&self) -> usize {
+        self.worker_threads.unwrap_or(4)
+    }
+
+    /// How many times a request to Drive is attempted before giving up, if it keeps failing
+    /// because of rate limits, server errors or network problems.
+    pub fn retry_max_attempts(&self) -> u32 {
+        self.retry_max_attempts.unwrap_or(5)
+    }
+
+    /// How long to wait before retrying a failed request for the first time. The wait doubles
+    /// with every further attempt.
+    pub fn retry_base_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_base_delay_ms.unwrap_or(500))
+    }
+
+    /// The longest wait before retrying which Drive may ask for. Requests for which Drive asks to
+    /// wait longer fail right away as rate limited.
+    pub fn retry_max_wait(&self) -> Duration {
+        Duration::from_secs(self.retry_max_wait_seconds.unwrap_or(300))
+    }
+
+    /// How long to wait for the pending writes to be uploaded when GCSF is asked to quit. Writes
+    /// which have not been uploaded by then are kept and uploaded on the next mount.
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_seconds.unwrap_or(60))
+    }
+
+    /// The owner of the files which do not record their own. Defaults to the user who mounts
+    /// GCSF.
+    pub fn uid(&self) -> u32 {
+        self.uid.unwrap_or_else(|| unsafe { libc::getuid() })
+    }
+
+    /// The group of the files which do not record their own. Defaults to the primary group of the
+    /// user who mounts GCSF.
+    pub fn gid(&self) -> u32 {
+        self.gid.unwrap_or_else(|| unsafe { libc::getgid() })
+    }
+
+    /// The permission bits (in octal) which are cleared from the default modes and from the mode
+    /// of newly created files.
+    pub fn umask(&self) -> u16 {
+        octal_mode("umask", &self.umask, 0o022)
+    }
+
+    /// The mode of the files which do not record their own. It is configured in octal and the
+    /// umask is cleared from it.
+    pub fn file_mode(&self) -> u16 {
+        octal_mode("file_mode", &self.file_mode, 0o666) & !self.umask()
+    }
+
+    /// The mode of the directories which do not record their own. It is configured in octal and
+    /// the umask is cleared from it.
+    pub fn dir_mode(&self) -> u16 {
+        octal_mode("dir_mode", &self.dir_mode, 0o777) & !self.umask()
+    }
+
+    /// The ownership and permissions of the files which do not record their own.
+    pub fn attr_defaults(&self) -> AttrDefaults {
+        AttrDefaults {
+            uid: self.uid(),
+            gid: self.gid(),
+            file_mode: self.file_mode(),
+            dir_mode: self.dir_mode(),
+            umask: self.umask(),
+        }
+    }
+
+    /// How many bytes the cached file contents may use on disk in total.
+    pub fn content_cache_max_bytes(&self) -> u64 {
+        self.content_cache_max_bytes.unwrap_or(1024 * 1024 * 1024)
+    }
+
+    /// How many bytes the cached content of a single file may use, counting all of its chunks.
+    pub fn content_cache_max_file_bytes(&self) -> u64 {
+        self.content_cache_max_file_bytes.unwrap_or(256 * 1024 * 1024)
+    }
+
+    /// The directory in which file contents are cached. Absent if the content cache is disabled by
+    /// setting `content_cache_max_bytes` to 0.
+    pub fn content_cache_path(&self) -> Option<PathBuf> {
+        if self.content_cache_max_bytes() == 0 {
+            return None;
+        }
+
+        self.content_cache_path.as_ref().map(PathBuf::from)
+    }
+
+    /// The directory in which the pending writes of files are kept until they are uploaded.
+    pub fn spool_path(&self) -> PathBuf {
+        self.spool_path
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| data_path("spool"))
+    }
+
+    /// The directory into which pending writes left behind by a crash are moved if they cannot be
+    /// uploaded anymore.
+    pub fn recovery_path(&self) -> PathBuf {
+        self.recovery_path
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| data_path("recovered"))
+    }
+
+    /// The directory in which mutations are queued while Drive is unreachable.
+    pub fn journal_path(&self) -> PathBuf {
+        self.journal_path
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| data_path("journal"))
+    }
+}
+
+/// The default location of something which has to survive a reboot, e.g. pending writes. It is
+/// placed in the data directory of GCSF, or in the temporary directory if there is none.
+fn data_path(name: &str) -> PathBuf {
+    xdg::BaseDirectories::with_prefix("gcsf")
+        .map(|dirs| dirs.get_data_home().join(name))
+        .unwrap_or_else(|_| env::temp_dir().join(format!("gcsf-{}", name)))
+}
+
+/// Parses a mode given in octal (e.g. "755"). Falls back to `default` if it is absent or invalid.
+fn octal_mode(name: &str, value: &Option<String>, default: u16) -> u16 {
+    match value.as_ref().map(|value| u16::from_str_radix(value, 8)) {
+        Some(Ok(mode)) => mode & 0o7777,
+        Some(Err(e)) => {
+            warn!("Invalid {} {:?} ({}), using {:o} instead", name, value, e, default);
+            default
+        }
+        None => default,
+    }
 }
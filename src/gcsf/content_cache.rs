@@ -0,0 +1,371 @@
+use drive3;
+use failure::Error;
+use serde_json;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type DriveId = String;
+
+/// The name of the file which describes the cached entries. It is stored next to them.
+const INDEX_FILE: &str = "index.json";
+
+/// Identifies a revision of the content of a Drive file.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ContentVersion {
+    #[serde(default)]
+    pub head_revision_id: Option<String>,
+    pub md5_checksum: Option<String>,
+    pub modified_time: Option<String>,
+}
+
+impl ContentVersion {
+    pub fn of(file: &drive3::File) -> Self {
+        ContentVersion {
+            head_revision_id: file.head_revision_id.clone(),
+            md5_checksum: file.md5_checksum.clone(),
+            modified_time: file.modified_time.clone(),
+        }
+    }
+
+    /// Whether two versions describe the same content. The revisions are compared if both of them
+    /// are known, then the checksums. Drive does not provide either for some files (e.g. Google
+    /// Docs), in which case the modification times are compared instead.
+    pub fn matches(&self, other: &ContentVersion) -> bool {
+        if let (Some(a), Some(b)) = (&self.head_revision_id, &other.head_revision_id) {
+            return a == b;
+        }
+        match (&self.md5_checksum, &other.md5_checksum) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.modified_time.is_some() && self.modified_time == other.modified_time,
+        }
+    }
+
+    /// Whether nothing is known about the version, in which case it cannot match any other one.
+    pub fn is_unknown(&self) -> bool {
+        self.head_revision_id.is_none() && self.md5_checksum.is_none()
+            && self.modified_time.is_none()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CacheEntry {
+    size: u64,
+    version: ContentVersion,
+
+    /// When the entry was last known to match the file on Drive, in seconds since the epoch.
+    validated: u64,
+
+    /// Increases every time the entry is used. The entry with the lowest value is evicted first.
+    last_used: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct CacheIndex {
+    entries: HashMap<DriveId, CacheEntry>,
+    clock: u64,
+}
+
+/// Keeps copies of file contents on disk, within a limited number of bytes. When the limit is
+/// reached, the least recently used files are evicted first. No file takes up more than a given
+/// size, however many of its chunks are cached.
+///
+/// A copy is trusted for `ttl` after it has been validated. After that, it can still be used if
+/// its version matches the one reported by Drive, which avoids downloading it again.
+///
+/// The index is written once per operation, however many entries the operation stores or evicts.
+pub struct ContentCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_file_bytes: u64,
+    ttl: Duration,
+    index: CacheIndex,
+    used_bytes: u64,
+}
+
+impl ContentCache {
+    /// Opens the cache stored in `dir`, creating it if necessary. Entries left behind by a
+    /// previous session are kept as long as they are intact and fit within `max_bytes`.
+    pub fn new(
+        dir: PathBuf,
+        max_bytes: u64,
+        max_file_bytes: u64,
+        ttl: Duration,
+    ) -> Result<Self, Error> {
+        fs::create_dir_all(&dir)?;
+
+        let index: CacheIndex = fs::File::open(dir.join(INDEX_FILE))
+            .map_err(Error::from)
+            .and_then(|f| serde_json::from_reader(BufReader::new(f)).map_err(Error::from))
+            .unwrap_or_default();
+
+        let mut cache = ContentCache {
+            dir,
+            max_bytes,
+            max_file_bytes,
+            ttl,
+            index,
+            used_bytes: 0,
+        };
+        cache.discard_broken_entries()?;
+        cache.evict(0);
+        cache.save_index()?;
+
+        Ok(cache)
+    }
+
+    /// The key under which a chunk of a file is stored. The separator cannot occur in Drive IDs.
+    pub fn chunk_key(id: &str, index: u64) -> String {
+        format!("{}@{}", id, index)
+    }
+
+    /// Whether a copy of the file is stored, regardless of whether it is still valid.
+    pub fn contains(&self, id: &str) -> bool {
+        self.index.entries.contains_key(id)
+    }
+
+    /// The number of bytes used by the cached copies.
+    pub fn used_bytes(&self) -> u64 {
+        self.used_bytes
+    }
+
+    /// Returns the cached content of a file, unless it has not been validated for more than `ttl`.
+    pub fn get(&mut self, id: &str) -> Option<Vec<u8>> {
+        let expired = {
+            let entry = self.index.entries.get(id)?;
+            now().saturating_sub(entry.validated) >= self.ttl.as_secs()
+        };
+
+        if expired {
+            return None;
+        }
+        self.read_entry(id)
+    }
+
+    /// Returns the cached content of a file however long ago it was validated. Only meant for when
+    /// Drive cannot be reached, since the copy may be outdated.
+    pub fn get_stale(&mut self, id: &str) -> Option<Vec<u8>> {
+        if !self.contains(id) {
+            return None;
+        }
+        self.read_entry(id)
+    }
+
+    /// Compares the cached copy of a file with the `current` version reported by Drive. If they
+    /// match, the copy is considered valid for another `ttl` and its content is returned.
+    /// Otherwise, it is discarded.
+    pub fn revalidate(&mut self, id: &str, current: &ContentVersion) -> Option<Vec<u8>> {
+        let valid = self.index.entries.get(id)?.version.matches(current);
+        if !valid {
+            debug!("Cached copy of {} is outdated", id);
+            self.remove(id);
+            return None;
+        }
+
+        self.index.entries.get_mut(id)?.validated = now();
+        let data = self.read_entry(id);
+        self.save_index_or_warn();
+        data
+    }
+
+    /// Stores a copy of a file or of a chunk of it. Nothing is stored if the file would take up
+    /// more than the per-file limit (counting all of its cached chunks) or the total limit. Other
+    /// copies are evicted as needed in order to make room for it.
+    pub fn insert(&mut self, id: &str, version: ContentVersion, data: &[u8]) -> Result<(), Error> {
+        let stored = self.store(id, version, data);
+        self.save_index()?;
+        stored
+    }
+
+    /// Stores several copies of the same version, e.g. the chunks of a file which have been
+    /// downloaded at once, like `insert()` does. Stops at the first copy which cannot be stored.
+    pub fn insert_all<'a, I>(&mut self, version: &ContentVersion, entries: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (String, &'a [u8])>,
+    {
+        let stored = entries
+            .into_iter()
+            .try_for_each(|(id, data)| self.store(&id, version.clone(), data));
+        self.save_index()?;
+        stored
+    }
+
+    /// Discards the cached copy of a file, if there is one.
+    pub fn remove(&mut self, id: &str) {
+        if self.forget(id) {
+            self.save_index_or_warn();
+        }
+    }
+
+    /// Discards the cached copy of a file along with all of its cached chunks.
+    pub fn remove_file(&mut self, id: &str) {
+        let prefix = format!("{}@", id);
+        let keys: Vec<String> = self.index
+            .entries
+            .keys()
+            .filter(|key| key.as_str() == id || key.starts_with(&prefix))
+            .cloned()
+            .collect();
+
+        for key in &keys {
+            self.forget(key);
+        }
+        if !keys.is_empty() {
+            self.save_index_or_warn();
+        }
+    }
+
+    /// Stores a copy without writing the index.
+    fn store(&mut self, id: &str, version: ContentVersion, data: &[u8]) -> Result<(), Error> {
+        self.forget(id);
+
+        let size = data.len() as u64;
+        let file_size = size + self.file_bytes(id);
+        if file_size > self.max_file_bytes || size > self.max_bytes {
+            debug!("Not caching {}: {} bytes is too large", id, file_size);
+            return Ok(());
+        }
+        self.evict(size);
+
+        let path = self.path_of(id);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, &path)?;
+
+        self.index.clock += 1;
+        let entry = CacheEntry {
+            size,
+            version,
+            validated: now(),
+            last_used: self.index.clock,
+        };
+        self.index.entries.insert(id.to_string(), entry);
+        self.used_bytes += size;
+        Ok(())
+    }
+
+    /// Discards the cached copy of a file without writing the index. Returns whether there was one.
+    fn forget(&mut self, id: &str) -> bool {
+        let entry = match self.index.entries.remove(id) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        self.used_bytes -= entry.size;
+        if let Err(e) = fs::remove_file(self.path_of(id)) {
+            warn!("Could not remove cached copy of {}: {}", id, e);
+        }
+        true
+    }
+
+    /// The number of bytes used by the cached copy of the file which `key` belongs to, i.e. by
+    /// the copy of the whole file and by all of its cached chunks.
+    fn file_bytes(&self, key: &str) -> u64 {
+        let id = key.split('@').next().unwrap_or(key);
+        let prefix = format!("{}@", id);
+        self.index
+            .entries
+            .iter()
+            .filter(|&(key, _)| key.as_str() == id || key.starts_with(&prefix))
+            .map(|(_, entry)| entry.size)
+            .sum()
+    }
+
+    /// Evicts the least recently used copies until `incoming` more bytes fit within the limit.
+    /// Does not write the index.
+    fn evict(&mut self, incoming: u64) {
+        while self.used_bytes + incoming > self.max_bytes {
+            let victim = self.index
+                .entries
+                .iter()
+                .min_by_key(|&(_, entry)| entry.last_used)
+                .map(|(id, _)| id.clone());
+
+            match victim {
+                Some(id) => {
+                    debug!("Evicting cached copy of {}", &id);
+                    self.forget(&id);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn read_entry(&mut self, id: &str) -> Option<Vec<u8>> {
+        match fs::read(self.path_of(id)) {
+            Ok(data) => {
+                self.index.clock += 1;
+                let clock = self.index.clock;
+                if let Some(entry) = self.index.entries.get_mut(id) {
+                    entry.last_used = clock;
+                }
+                Some(data)
+            }
+            Err(e) => {
+                warn!("Could not read cached copy of {}: {}", id, e);
+                self.forget(id);
+                None
+            }
+        }
+    }
+
+    /// Forgets the entries whose files are missing or have the wrong size and removes the files
+    /// which do not belong to any entry.
+    fn discard_broken_entries(&mut self) -> Result<(), Error> {
+        let dir = self.dir.clone();
+        self.index.entries.retain(|id, entry| {
+            fs::metadata(dir.join(id))
+                .map(|metadata| metadata.len() == entry.size)
+                .unwrap_or(false)
+        });
+
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let dir_entry = dir_entry?;
+            let name = dir_entry.file_name().to_string_lossy().into_owned();
+            if name != INDEX_FILE && !self.index.entries.contains_key(&name) {
+                let _ = fs::remove_file(dir_entry.path());
+            }
+        }
+
+        self.used_bytes = self.index.entries.values().map(|entry| entry.size).sum();
+        Ok(())
+    }
+
+    fn path_of(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    fn save_index(&self) -> Result<(), Error> {
+        let path = self.dir.join(INDEX_FILE);
+        let tmp_path = path.with_extension("tmp");
+        {
+            let writer = BufWriter::new(fs::File::create(&tmp_path)?);
+            serde_json::to_writer(writer, &self.index)?;
+        }
+        fs::rename(&tmp_path, &path)?;
+
+        Ok(())
+    }
+
+    fn save_index_or_warn(&self) {
+        if let Err(e) = self.save_index() {
+            warn!("Could not save content cache index: {}", e);
+        }
+    }
+}
+
+impl Drop for ContentCache {
+    /// Saves the order in which the copies have been used.
+    fn drop(&mut self) {
+        self.save_index_or_warn();
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
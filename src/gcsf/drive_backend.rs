@@ -0,0 +1,135 @@
+use drive3;
+use failure::Error;
+use std::collections::HashMap;
+
+pub type DriveId = String;
+
+/// The set of Drive operations that `FileManager` and `GCSF` rely on. `DriveFacade` implements it
+/// by talking to the Google Drive API, while `MemoryDrive` keeps everything in memory, which makes
+/// it possible to exercise the file system logic without a Google account.
+pub trait DriveBackend: Send {
+    /// Returns the Drive ID of the root "My Drive" directory.
+    fn root_id(&mut self) -> Result<&String, Error>;
+
+    /// Returns the page token from which `get_all_changes()` will continue. If no token is known
+    /// yet, a fresh one is requested, which only covers changes performed from now on.
+    fn changes_token(&mut self) -> Result<&String, Error>;
+
+    /// Overrides the page token from which `get_all_changes()` will continue (e.g. with one that
+    /// was saved during a previous session). `None` discards the current token.
+    fn set_changes_token(&mut self, token: Option<String>);
+
+    /// Returns a list of all changes which are more recent than the changes token indicates.
+    fn get_all_changes(&mut self) -> Result<Vec<drive3::Change>, Error>;
+
+    /// Returns the temporary Drive IDs which have been replaced by real ones, along with their
+    /// replacements. Only backends which hand out temporary IDs (i.e. `OfflineDrive`) have any.
+    fn take_replaced_ids(&mut self) -> Vec<(DriveId, DriveId)> {
+        Vec::new()
+    }
+
+    /// Returns a list of all files. If the `parents` list is provided, only files which are
+    /// children of any one of the list's elements are returned. If `trashed` is provided, only
+    /// files which are trashed/not trashed are returned.
+    fn get_all_files(
+        &mut self,
+        parents: Option<Vec<DriveId>>,
+        trashed: Option<bool>,
+    ) -> Result<Vec<drive3::File>, Error>;
+
+    /// Returns the files which have been shared with the account directly and are not trashed.
+    /// Their parents, if they have any, usually belong to someone else.
+    fn get_shared_files(&mut self) -> Result<Vec<drive3::File>, Error>;
+
+    /// Returns the files which have been starred and are not trashed.
+    fn get_starred_files(&mut self) -> Result<Vec<drive3::File>, Error>;
+
+    /// Returns at most `limit` files (but no directories) which are not trashed. The ones which
+    /// have been viewed or modified by the user most recently come first.
+    fn get_recent_files(&mut self, limit: usize) -> Result<Vec<drive3::File>, Error>;
+
+    /// Returns the shared drives (formerly known as Team Drives) which the account is a member of.
+    fn shared_drives(&mut self) -> Result<Vec<drive3::TeamDrive>, Error>;
+
+    /// Returns the files of the shared drive `drive_id` which are children of any one of the
+    /// `parents` and which are not trashed.
+    fn get_drive_files(
+        &mut self,
+        drive_id: &DriveId,
+        parents: Vec<DriveId>,
+    ) -> Result<Vec<drive3::File>, Error>;
+
+    /// Like `changes_token()`, for the changes of the shared drive `drive_id`. Each shared drive
+    /// has a token of its own.
+    fn drive_changes_token(&mut self, drive_id: &DriveId) -> Result<&String, Error>;
+
+    /// Like `set_changes_token()`, for the changes of the shared drive `drive_id`.
+    fn set_drive_changes_token(&mut self, drive_id: &DriveId, token: Option<String>);
+
+    /// Like `get_all_changes()`, for the changes of the shared drive `drive_id`.
+    fn get_drive_changes(&mut self, drive_id: &DriveId) -> Result<Vec<drive3::Change>, Error>;
+
+    /// Reads at most `size` bytes of a file, starting from `offset`.
+    fn read(
+        &mut self,
+        drive_id: &str,
+        mime_type: Option<String>,
+        offset: usize,
+        size: usize,
+    ) -> Result<&[u8], Error>;
+
+    /// Retrieves the metadata of a file, including the fields which are exposed as extended
+    /// attributes.
+    fn get_metadata(&mut self, id: &DriveId) -> Result<drive3::File, Error>;
+
+    /// Creates an empty file and returns its Drive ID.
+    fn create(&mut self, drive_file: &drive3::File) -> Result<DriveId, Error>;
+
+    /// Records a write operation. It is not applied until the file is flushed.
+    fn write(&mut self, id: DriveId, offset: usize, data: &[u8]) -> Result<(), Error>;
+
+    /// Records that a file is cut (or extended with zeros) to `size` bytes. Like writes, it is
+    /// not applied until the file is flushed.
+    fn truncate(&mut self, id: &DriveId, size: u64) -> Result<(), Error>;
+
+    /// Applies all pending writes of a file.
+    fn flush(&mut self, id: &DriveId) -> Result<(), Error>;
+
+    /// Returns the Drive IDs of the files which have pending writes, i.e. which need a flush.
+    fn dirty_files(&self) -> Vec<DriveId> {
+        Vec::new()
+    }
+
+    /// Moves a file under a new parent and renames it.
+    fn move_to(
+        &mut self,
+        id: &DriveId,
+        parent: &DriveId,
+        new_name: &str,
+    ) -> Result<drive3::File, Error>;
+
+    /// Changes the metadata of a file. Only the fields which are set in `patch` are changed. Its
+    /// `app_properties` are added to the existing ones instead of replacing them.
+    fn update_metadata(
+        &mut self,
+        id: &DriveId,
+        patch: &drive3::File,
+    ) -> Result<drive3::File, Error>;
+
+    /// Sets the custom `properties` of a file which are given a value and removes the ones which
+    /// are `None`. The other properties are left alone.
+    fn update_properties(
+        &mut self,
+        id: &DriveId,
+        properties: &HashMap<String, Option<String>>,
+    ) -> Result<(), Error>;
+
+    /// Marks a file as trashed.
+    fn move_to_trash(&mut self, id: DriveId) -> Result<(), Error>;
+
+    /// Deletes a file without moving it to the trash first.
+    fn delete_permanently(&mut self, id: &DriveId) -> Result<bool, Error>;
+
+    /// Returns the size and capacity of the account. In some cases, the limit can be absent.
+    fn size_and_capacity(&mut self) -> Result<(u64, Option<u64>), Error>;
+}
@@ -1,19 +1,44 @@
-use super::Config;
+use super::error::FsError;
+use super::retry::{error_of_response, RetryAfterConnector, RetryPolicy};
+use super::spool::{SpoolFile, SpoolTarget};
+use super::xattr;
+use super::{Config, ContentCache, ContentVersion, DriveBackend};
+use chrono::Local;
 use drive3;
-use failure::{err_msg, Error};
+use failure::Error;
 use hyper;
 use hyper::client::Response;
+use hyper::header::{Authorization, Bearer, ByteRangeSpec, ContentType, Range};
+use hyper::method::Method;
+use hyper::status::StatusCode;
 use hyper_rustls;
-use lru_time_cache::LruCache;
+use libc;
 use mime_sniffer::MimeTypeSniffer;
 use oauth2;
+use oauth2::GetToken;
 use serde_json;
 use std::cmp;
 use std::collections::HashMap;
+use std::error;
+use std::fs;
 use std::io;
 use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 const PAGE_SIZE: i32 = 1000;
+
+/// Files are downloaded and cached in aligned chunks of this many bytes.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// How many chunks are downloaded at once when a file is being read sequentially.
+const READ_AHEAD_CHUNKS: u64 = 4;
+
+const DRIVE_SCOPE: &str = "https://www.googleapis.com/auth/drive";
+
+/// The fields of each file which are retrieved when listing files or changes.
+const FILE_FIELDS: &str = "name,id,size,mimeType,owners,parents,trashed,modifiedTime,createdTime,viewedByMeTime,sharedWithMeTime,appProperties,teamDriveId,capabilities(canAddChildren,canDelete,canEdit,canRename,canTrash)";
 const CLIENT_SECRET: &str = "{\"installed\":{\"client_id\":\"726003905312-e2mq9mesjc5llclmvc04ef1k7qopv9tu.apps.googleusercontent.com\",\"project_id\":\"weighty-triode-199418\",\"auth_uri\":\"https://accounts.google.com/o/oauth2/auth\",\"token_uri\":\"https://accounts.google.com/o/oauth2/token\",\"auth_provider_x509_cert_url\":\"https://www.googleapis.com/oauth2/v1/certs\",\"client_secret\":\"hp83n1Rzz8UpxgCnqvX15qC2\",\"redirect_uris\":[\"urn:ietf:wg:oauth:2.0:oob\",\"http://localhost\"]}}";
 
 type DriveId = String;
@@ -24,38 +49,80 @@ type GCAuthenticator = oauth2::Authenticator<
     oauth2::DiskTokenStorage,
     hyper::Client,
 >;
-type GCDrive = drive3::Drive<GCClient, GCAuthenticator>;
+type GCDrive = drive3::Drive<GCClient, SharedAuthenticator>;
+
+/// Lets the drive hub and the requests which `drive3` cannot express (e.g. ranged downloads) use
+/// the same authenticator, so that the token is refreshed in a single place.
+#[derive(Clone)]
+pub struct SharedAuthenticator(Arc<Mutex<GCAuthenticator>>);
+
+impl GetToken for SharedAuthenticator {
+    fn token<'b, I, T>(&mut self, scopes: I) -> Result<oauth2::Token, Box<dyn error::Error>>
+    where
+        T: AsRef<str> + Ord + 'b,
+        I: IntoIterator<Item = &'b T>,
+    {
+        self.0.lock().unwrap().token(scopes)
+    }
+
+    fn api_key(&mut self) -> Option<String> {
+        self.0.lock().unwrap().api_key()
+    }
+}
 
 /// Provides a simple high-level interface for interacting with the Google Drive API.
 pub struct DriveFacade {
     /// The `drive3::Drive` hub used for interacting with the API.
     pub hub: GCDrive,
 
+    /// Performs the requests which the hub cannot express, authorized by `auth`.
+    client: hyper::Client,
+    auth: SharedAuthenticator,
+    api_root: String,
+
     /// A buffer used for temporarily caching read blocks. Storing this inside the struct makes it possible to return a reference to the data without the danger of the data outliving the struct.
     buff: Vec<u8>,
 
-    /// Maps Drive IDs to a list of pending write operations that must be applied on them.
-    pending_writes: HashMap<DriveId, Vec<PendingWrite>>,
+    /// Maps Drive IDs to the spool files which hold their pending writes.
+    spools: HashMap<DriveId, SpoolFile>,
+
+    /// The directory in which spool files are created.
+    spool_dir: PathBuf,
+
+    /// The directory into which the pending writes of a previous session are moved if they cannot
+    /// be uploaded.
+    recovery_dir: PathBuf,
+
+    /// Stores copies of the file contents on disk. Absent if caching is disabled.
+    cache: Option<ContentCache>,
+
+    /// The content versions retrieved recently, along with the time when they were retrieved.
+    /// They are reused for `version_ttl`, so that downloading or validating the chunks of a file
+    /// does not require asking Drive for its version every time.
+    versions: HashMap<DriveId, (ContentVersion, SystemTime)>,
+    version_ttl: Duration,
 
-    /// The LRU cache used for storing the file contents for any given Drive ID.
-    cache: LruCache<DriveId, Vec<u8>>,
+    /// The version of each file's content which its pending writes are based on, i.e. the one which
+    /// was current when the file was first read or written to. Used for detecting conflicts.
+    base_versions: HashMap<DriveId, ContentVersion>,
+
+    /// Maps files to the offset right after their last read. A read which starts there is
+    /// considered sequential and triggers read-ahead.
+    next_offsets: HashMap<DriveId, u64>,
+
+    /// Decides how failed requests are retried.
+    retry: RetryPolicy,
 
     /// Keeps track of the page token used for receiving changes from the `changes.list` API endpoint.
     changes_token: Option<String>,
 
+    /// The same, for the changes of each shared drive.
+    drive_changes_tokens: HashMap<DriveId, String>,
+
     /// The root id is only stored once, effectively caching the root id.
     root_id: Option<String>,
 }
 
-/// Represents a write operation that has been performed from the user's point of view but has not
-/// yet been applied to the local or remote file.
-#[derive(Debug)]
-struct PendingWrite {
-    id: DriveId,
-    offset: usize,
-    data: Vec<u8>,
-}
-
 lazy_static! {
     static ref MIME_TYPES: HashMap<&'static str, &'static str> = hashmap!{
         "application/vnd.google-apps.document" => "application/vnd.oasis.opendocument.text",
@@ -68,31 +135,75 @@ impl DriveFacade {
     pub fn new(config: &Config) -> Self {
         debug!("DriveFacade::new()");
 
-        let ttl = config.cache_max_seconds();
-        let max_count = config.cache_max_items() as usize;
+        let cache = config.content_cache_path().and_then(|dir| {
+            match ContentCache::new(
+                dir,
+                config.content_cache_max_bytes(),
+                config.content_cache_max_file_bytes(),
+                config.cache_max_seconds(),
+            ) {
+                Ok(cache) => Some(cache),
+                Err(e) => {
+                    error!("Could not open content cache: {}", e);
+                    None
+                }
+            }
+        });
+
+        // Spool files left behind by a previous session are kept: they are uploaded below.
+        let spool_dir = config.spool_path();
+        if let Err(e) = fs::create_dir_all(&spool_dir) {
+            error!("Could not create spool directory {:?}: {}", &spool_dir, e);
+        }
 
-        DriveFacade {
-            hub: DriveFacade::create_drive(&config).unwrap(),
+        let auth = SharedAuthenticator(Arc::new(Mutex::new(
+            DriveFacade::create_drive_auth(config).unwrap(),
+        )));
+
+        let mut facade = DriveFacade {
+            hub: DriveFacade::create_drive(auth.clone(), config),
+            client: DriveFacade::create_client(),
+            auth,
+            api_root: config.api_root(),
             buff: Vec::new(),
-            pending_writes: HashMap::new(),
-            cache: LruCache::<String, Vec<u8>>::with_expiry_duration_and_capacity(ttl, max_count),
+            spools: HashMap::new(),
+            spool_dir,
+            recovery_dir: config.recovery_path(),
+            cache,
+            versions: HashMap::new(),
+            version_ttl: config.cache_max_seconds(),
+            base_versions: HashMap::new(),
+            next_offsets: HashMap::new(),
+            retry: RetryPolicy::new(
+                config.retry_max_attempts(),
+                config.retry_base_delay(),
+                config.retry_max_wait(),
+            ),
             root_id: None,
             changes_token: None,
-        }
+            drive_changes_tokens: HashMap::new(),
+        };
+
+        facade.recover_spools();
+        facade
     }
 
     fn create_drive_auth(config: &Config) -> Result<GCAuthenticator, Error> {
         let secret: oauth2::ConsoleApplicationSecret = serde_json::from_str(CLIENT_SECRET)?;
-        let secret = secret
+        let mut secret = secret
             .installed
-            .ok_or(err_msg("ConsoleApplicationSecret.installed is None"))?;
+            .ok_or_else(|| {
+                FsError::Other("ConsoleApplicationSecret.installed is None".to_string())
+            })?;
+
+        if let Some(token_uri) = config.token_uri() {
+            secret.token_uri = token_uri;
+        }
 
         let auth = oauth2::Authenticator::new(
             &secret,
             oauth2::DefaultAuthenticatorDelegate,
-            hyper::Client::with_connector(hyper::net::HttpsConnector::new(
-                hyper_rustls::TlsClient::new(),
-            )),
+            DriveFacade::create_client(),
             oauth2::DiskTokenStorage::new(&config.token_path().to_string()).unwrap(),
             Some(if config.authorize_using_code() {
                 oauth2::FlowType::InstalledInteractive
@@ -104,29 +215,19 @@ impl DriveFacade {
         Ok(auth)
     }
 
-    /// Creates a drive hub.
-    fn create_drive(config: &Config) -> Result<GCDrive, Error> {
-        let auth = Self::create_drive_auth(config)?;
-        Ok(drive3::Drive::new(
-            hyper::Client::with_connector(hyper::net::HttpsConnector::new(
-                hyper_rustls::TlsClient::new(),
-            )),
-            auth,
-        ))
-    }
+    /// Creates a drive hub which sends its requests to the endpoints specified in the config.
+    fn create_drive(auth: SharedAuthenticator, config: &Config) -> GCDrive {
+        let mut hub = drive3::Drive::new(DriveFacade::create_client(), auth);
 
-    /// Will still detect a file even if it is in Trash.
-    fn contains(&self, id: &DriveId) -> Result<bool, Error> {
-        let response = self.hub
-            .files()
-            .get(&id)
-            .add_scope(drive3::Scope::Full)
-            .doit();
+        hub.base_url(config.api_root());
+        hub.root_url(config.upload_root());
+        hub
+    }
 
-        match response {
-            Ok((_, file)) => Ok(file.id == Some(id.to_string())),
-            Err(e) => Err(err_msg(format!("{:#?}", e))),
-        }
+    fn create_client() -> hyper::Client {
+        hyper::Client::with_connector(RetryAfterConnector(hyper::net::HttpsConnector::new(
+            hyper_rustls::TlsClient::new(),
+        )))
     }
 
     #[allow(dead_code)]
@@ -134,15 +235,466 @@ impl DriveFacade {
         self.get_file_content(drive_id, mime_type).unwrap().len() as u64
     }
 
+    /// Retrieves the size of a Drive file's content. Files which have no content of their own
+    /// (e.g. Google Docs) are considered empty. Fails if the file does not exist.
+    fn get_remote_size(&self, id: &str) -> Result<u64, Error> {
+        let (_response, file) = self.retry.run("files.get", || {
+            self.hub
+                .files()
+                .get(id)
+                .param("fields", "size")
+                .supports_team_drives(true)
+                .add_scope(drive3::Scope::Full)
+                .doit()
+        })?;
+
+        Ok(file.size.and_then(|size| size.parse().ok()).unwrap_or(0))
+    }
+
+    /// Retrieves the name, parents and content version of a Drive file.
     fn get_file_metadata(&self, id: &str) -> Result<drive3::File, Error> {
-        self.hub
-            .files()
-            .get(id)
-            .param("fields", "id,name,parents,mimeType")
-            .add_scope(drive3::Scope::Full)
-            .doit()
-            .map(|(_response, file)| file)
-            .map_err(|e| err_msg(format!("{:#?}", e)))
+        let (_response, file) = self.retry.run("files.get", || {
+            self.hub
+                .files()
+                .get(id)
+                .param(
+                    "fields",
+                    "id,name,parents,mimeType,headRevisionId,md5Checksum,modifiedTime",
+                )
+                .supports_team_drives(true)
+                .add_scope(drive3::Scope::Full)
+                .doit()
+        })?;
+
+        Ok(file)
+    }
+
+    /// Retrieves the head revision, checksum and modification time of a Drive file, which identify
+    /// the current version of its content.
+    fn get_content_version(&self, id: &str, retry: &RetryPolicy) -> Result<ContentVersion, Error> {
+        retry
+            .run("files.get", || {
+                self.hub
+                    .files()
+                    .get(id)
+                    .param("fields", "headRevisionId,md5Checksum,modifiedTime")
+                    .supports_team_drives(true)
+                    .add_scope(drive3::Scope::Full)
+                    .doit()
+            })
+            .map(|(_response, file)| ContentVersion::of(&file))
+    }
+
+    /// Returns the current version of a file's content, retrieving it according to `retry`.
+    /// Versions which have been retrieved less than `version_ttl` ago are reused.
+    fn content_version(
+        &mut self,
+        drive_id: &str,
+        retry: &RetryPolicy,
+    ) -> Result<ContentVersion, Error> {
+        if let Some(&(ref version, retrieved)) = self.versions.get(drive_id) {
+            let age = SystemTime::now()
+                .duration_since(retrieved)
+                .unwrap_or(Duration::from_secs(0));
+            if age < self.version_ttl {
+                return Ok(version.clone());
+            }
+        }
+
+        let version = self.get_content_version(drive_id, retry)?;
+        self.versions
+            .insert(drive_id.to_string(), (version.clone(), SystemTime::now()));
+        Ok(version)
+    }
+
+    /// Returns the cached entry stored under `key` (either the whole content of the file or one of
+    /// its chunks), as long as it still matches the file on Drive.
+    fn cached_content(&mut self, key: &str, drive_id: &str) -> Option<Vec<u8>> {
+        match self.cache {
+            Some(ref mut cache) => {
+                if let Some(data) = cache.get(key) {
+                    return Some(data);
+                }
+                if !cache.contains(key) {
+                    return None;
+                }
+            }
+            None => return None,
+        };
+
+        // A possibly outdated copy is better than none while Drive is unreachable, so there is no
+        // point in waiting for it to come back.
+        let retry = self.retry.without_retrying_unreachable();
+        let version = match self.content_version(drive_id, &retry) {
+            Ok(version) => version,
+            Err(ref e) if FsError::is_offline(e) => {
+                warn!(
+                    "Drive is unreachable, serving possibly outdated copy of {}",
+                    drive_id
+                );
+                return self.cache.as_mut().and_then(|cache| cache.get_stale(key));
+            }
+            Err(e) => {
+                warn!("Could not validate cached copy of {}: {}", drive_id, e);
+                return None;
+            }
+        };
+
+        self.cache
+            .as_mut()
+            .and_then(|cache| cache.revalidate(key, &version))
+    }
+
+    /// Returns the version which cached copies of a file should be stored with. Absent if caching
+    /// is disabled. It is retrieved before downloading: if the file changes during the download,
+    /// the copy will look outdated and will be downloaded again next time, rather than the other
+    /// way around.
+    fn version_for_caching(&mut self, drive_id: &str) -> Result<Option<ContentVersion>, Error> {
+        let retry = self.retry.clone();
+        match self.cache {
+            Some(_) => self.content_version(drive_id, &retry).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Downloads the whole content of a file and stores a copy of it in the content cache. Only
+    /// used for files which cannot be downloaded in ranges, i.e. exported Google Docs.
+    fn download(&mut self, drive_id: &str, mime_type: Option<String>) -> Result<Vec<u8>, Error> {
+        let version = self.version_for_caching(drive_id)?;
+
+        let data = self.get_file_content(drive_id, mime_type)?;
+        if let (Some(cache), Some(version)) = (self.cache.as_mut(), version) {
+            if let Err(e) = cache.insert(drive_id, version, &data) {
+                warn!("Could not cache {}: {}", drive_id, e);
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Reads at most `size` bytes of a file which can be downloaded in ranges, starting from
+    /// `offset`. The file is downloaded and cached in aligned chunks, so only the chunks which
+    /// overlap the requested range need to be retrieved.
+    fn read_chunked(&mut self, drive_id: &str, offset: u64, size: u64) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::with_capacity(size as usize);
+        if size == 0 {
+            return Ok(data);
+        }
+
+        let sequential = self.next_offsets.get(drive_id) == Some(&offset);
+        let count = if sequential { READ_AHEAD_CHUNKS } else { 1 };
+
+        let first = offset / CHUNK_SIZE;
+        let last = (offset + size - 1) / CHUNK_SIZE;
+        for index in first..last + 1 {
+            let chunk = self.chunk(drive_id, index, count)?;
+            let chunk_offset = index * CHUNK_SIZE;
+
+            let start = cmp::min(chunk.len(), offset.saturating_sub(chunk_offset) as usize);
+            let end = cmp::min(chunk.len(), (offset + size - chunk_offset) as usize);
+            data.extend_from_slice(&chunk[start..end]);
+
+            // A partial chunk is the last one of the file.
+            if (chunk.len() as u64) < CHUNK_SIZE {
+                break;
+            }
+        }
+
+        self.next_offsets
+            .insert(drive_id.to_string(), offset + data.len() as u64);
+        Ok(data)
+    }
+
+    /// Reads from a file which has pending writes, i.e. from its spool file. The parts which have
+    /// not been written are filled in from Drive first.
+    fn read_spooled(&mut self, id: &str, offset: u64, size: u64) -> Result<Vec<u8>, Error> {
+        let mut spool = match self.spools.remove(id) {
+            Some(spool) => spool,
+            None => return Ok(Vec::new()),
+        };
+        let result = self.read_from_spool(id, &mut spool, offset, size);
+        self.spools.insert(id.to_string(), spool);
+        result
+    }
+
+    fn read_from_spool(
+        &mut self,
+        id: &str,
+        spool: &mut SpoolFile,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, Error> {
+        let remote_len = if spool.needs_remote() {
+            self.get_remote_size(id)?
+        } else {
+            0
+        };
+        let end = cmp::min(offset + size, spool.final_len(remote_len));
+        if end <= offset {
+            return Ok(Vec::new());
+        }
+
+        for (start, stop) in spool.gaps(remote_len) {
+            let (start, stop) = (cmp::max(start, offset), cmp::min(stop, end));
+            if start < stop {
+                let data = self.read_chunked(id, start, stop - start)?;
+                spool.fill(start, &data)?;
+            }
+        }
+        spool.read(offset, end - offset)
+    }
+
+    /// Returns a chunk of a file. If it is not cached, it is downloaded along with up to `count - 1`
+    /// following chunks, which are cached for the reads to come.
+    fn chunk(&mut self, drive_id: &str, index: u64, count: u64) -> Result<Vec<u8>, Error> {
+        let key = ContentCache::chunk_key(drive_id, index);
+        if let Some(data) = self.cached_content(&key, drive_id) {
+            return Ok(data);
+        }
+
+        let version = self.version_for_caching(drive_id)?;
+        // Read-ahead is pointless if there is nowhere to keep the extra chunks.
+        let count = if version.is_some() { count } else { 1 };
+        let mut data = self.get_file_range(drive_id, index * CHUNK_SIZE, count * CHUNK_SIZE)?;
+
+        if let (Some(cache), Some(version)) = (self.cache.as_mut(), version) {
+            let chunks = data.chunks(CHUNK_SIZE as usize).enumerate().map(|(i, chunk)| {
+                (ContentCache::chunk_key(drive_id, index + i as u64), chunk)
+            });
+            if let Err(e) = cache.insert_all(&version, chunks) {
+                warn!("Could not cache chunks of {}: {}", drive_id, e);
+            }
+        }
+
+        data.truncate(CHUNK_SIZE as usize);
+        Ok(data)
+    }
+
+    /// Downloads at most `len` bytes of a file, starting from `offset`. Fewer bytes are returned
+    /// if the file ends earlier. `drive3` cannot send a Range header, so the request is performed
+    /// directly.
+    fn get_file_range(&self, drive_id: &str, offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+        let url = format!(
+            "{}files/{}?alt=media&supportsTeamDrives=true",
+            self.api_root, drive_id
+        );
+
+        // Failures are reported the way `drive3` would report them, so that they are retried in
+        // the same way.
+        let mut response = self.retry.run("files.get (range)", || {
+            let token = self.auth
+                .clone()
+                .token(&[DRIVE_SCOPE])
+                .map_err(drive3::Error::MissingToken)?;
+
+            let response = self.client
+                .get(&url)
+                .header(Authorization(Bearer {
+                    token: token.access_token,
+                }))
+                .header(Range::Bytes(vec![
+                    ByteRangeSpec::FromTo(offset, offset + len - 1),
+                ]))
+                .send()
+                .map_err(drive3::Error::HttpError)?;
+
+            match response.status {
+                StatusCode::PartialContent | StatusCode::Ok | StatusCode::RangeNotSatisfiable => {
+                    Ok(response)
+                }
+                _ => Err(error_of_response(response)),
+            }
+        })?;
+
+        let mut data = Vec::new();
+        match response.status {
+            StatusCode::PartialContent => {
+                response.read_to_end(&mut data)?;
+            }
+            StatusCode::Ok => {
+                // The whole file was sent, as if no range had been requested.
+                response.read_to_end(&mut data)?;
+                let start = cmp::min(data.len(), offset as usize);
+                let end = cmp::min(data.len(), (offset + len) as usize);
+                data = data[start..end].to_vec();
+            }
+            _ => {
+                // The range starts after the end of the file.
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Sets or removes custom properties of a file. Properties are removed by setting them to null,
+    /// which `drive3` cannot express, so the request is performed directly.
+    fn patch_properties(
+        &self,
+        id: &str,
+        properties: &HashMap<String, Option<String>>,
+    ) -> Result<(), Error> {
+        let url = format!("{}files/{}?supportsTeamDrives=true", self.api_root, id);
+        let mut body = HashMap::new();
+        body.insert("properties", properties);
+        let body = serde_json::to_string(&body)?;
+
+        self.retry.run("files.update (properties)", || {
+            let token = self.auth
+                .clone()
+                .token(&[DRIVE_SCOPE])
+                .map_err(drive3::Error::MissingToken)?;
+
+            let response = self.client
+                .request(Method::Patch, &url)
+                .header(Authorization(Bearer {
+                    token: token.access_token,
+                }))
+                .header(ContentType::json())
+                .body(body.as_str())
+                .send()
+                .map_err(drive3::Error::HttpError)?;
+
+            match response.status {
+                StatusCode::Ok => Ok(()),
+                _ => Err(error_of_response(response)),
+            }
+        })
+    }
+
+    /// Returns all files which match `query`. Only the files of the shared drive `drive_id` are
+    /// listed, or the ones of "My Drive" if it is `None`.
+    fn list_files(
+        &self,
+        query: &str,
+        drive_id: Option<&DriveId>,
+    ) -> Result<Vec<drive3::File>, Error> {
+        self.list_files_by(query, drive_id, None, None)
+    }
+
+    /// Like `list_files()`, but the files are sorted according to `order_by` (e.g.
+    /// "modifiedTime desc") and no more than `limit` of them are retrieved.
+    fn list_files_by(
+        &self,
+        query: &str,
+        drive_id: Option<&DriveId>,
+        order_by: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<drive3::File>, Error> {
+        let fields = format!("nextPageToken,files({})", FILE_FIELDS);
+        let page_size = limit.map_or(PAGE_SIZE, |limit| cmp::min(limit as i32, PAGE_SIZE));
+        let mut all_files = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let (_, filelist) = self.retry.run("files.list", || {
+                let mut request = self.hub.files()
+                    .list()
+                    .param("fields", fields.as_str())
+                    .spaces("drive") // TODO: maybe add photos as well
+                    .page_size(page_size)
+                    .add_scope(drive3::Scope::Full);
+
+                request = match drive_id {
+                    Some(id) => request
+                        .corpora("teamDrive")
+                        .team_drive_id(id)
+                        .include_team_drive_items(true)
+                        .supports_team_drives(true),
+                    None => request.corpora("user"),
+                };
+                if let Some(ref token) = page_token {
+                    request = request.page_token(token);
+                };
+                if let Some(order_by) = order_by {
+                    request = request.order_by(order_by);
+                }
+
+                request.q(query).doit()
+            })?;
+
+            match filelist.files {
+                Some(files) => all_files.extend(files),
+                _ => warn!("Filelist does not contain any files!"),
+            };
+
+            if let Some(limit) = limit {
+                if all_files.len() >= limit {
+                    all_files.truncate(limit);
+                    break;
+                }
+            }
+
+            page_token = filelist.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+        Ok(all_files)
+    }
+
+    /// Retrieves all changes which are more recent than `token`. Returns them along with the token
+    /// from which the next changes can be retrieved. Only the changes of the shared drive
+    /// `drive_id` are listed, or the ones of "My Drive" if it is `None`.
+    fn list_changes(
+        &self,
+        mut token: String,
+        drive_id: Option<&DriveId>,
+    ) -> Result<(Vec<drive3::Change>, String), Error> {
+        let fields = format!(
+            "kind,newStartPageToken,nextPageToken,changes(kind,type,time,removed,fileId,teamDriveId,file({}))",
+            FILE_FIELDS
+        );
+        let mut all_changes = Vec::new();
+
+        loop {
+            let result = self.retry.run("changes.list", || {
+                let mut request = self.hub
+                    .changes()
+                    .list(&token)
+                    .param("fields", fields.as_str())
+                    .spaces("drive")
+                    // Whether to include changes indicating that items have been removed from the list of changes, for example by deletion or loss of access. (Default: true)
+                    .include_removed(false) // ^wtf?
+                    .supports_team_drives(true)
+                    .page_size(PAGE_SIZE)
+                    .add_scope(drive3::Scope::Full);
+
+                request = match drive_id {
+                    Some(id) => request.team_drive_id(id).include_team_drive_items(true),
+                    // Not restricted to My Drive, so that "Shared with me" is kept up to date.
+                    None => request
+                        .restrict_to_my_drive(false)
+                        .include_team_drive_items(false),
+                };
+                request.doit()
+            });
+            // Drive answers 404 (or 410) once a page token has expired.
+            let (_response, changelist) = match result {
+                Err(e) => match e.downcast::<FsError>() {
+                    Ok(FsError::NotFound(description)) => {
+                        return Err(FsError::ExpiredToken(description).into())
+                    }
+                    Ok(e) => return Err(e.into()),
+                    Err(e) => return Err(e),
+                },
+                Ok(result) => result,
+            };
+
+            match changelist.changes {
+                Some(changes) => all_changes.extend(changes),
+                _ => warn!("Changelist does not contain any changes!"),
+            };
+
+            token = match (changelist.next_page_token, changelist.new_start_page_token) {
+                (Some(next), _) => next,
+                (None, Some(new_start)) => return Ok((all_changes, new_start)),
+                (None, None) => {
+                    return Err(FsError::Remote(
+                        "Changelist has neither a next page token nor a new start page token"
+                            .to_string(),
+                    ).into())
+                }
+            };
+        }
     }
 
     /// Retrieves the content of a Drive file. If `mime_type` is specified, this method will
@@ -154,30 +706,32 @@ impl DriveFacade {
         mime_type: Option<String>,
     ) -> Result<Vec<u8>, Error> {
         let export_type: Option<&'static str> = mime_type
-            .and_then(|ref t| MIME_TYPES.get::<str>(&t))
+            .and_then(|ref t| MIME_TYPES.get::<str>(t))
             .cloned();
 
         let mut response = match export_type {
             Some(t) => {
-                let mut response = self.hub
-                    .files()
-                    .export(drive_id, &t)
-                    .add_scope(drive3::Scope::Full)
-                    .doit()
-                    .map_err(|e| err_msg(format!("{:#?}", e)))?;
+                let response = self.retry.run("files.export", || {
+                    self.hub
+                        .files()
+                        .export(drive_id, t)
+                        .add_scope(drive3::Scope::Full)
+                        .doit()
+                })?;
 
                 debug!("response: {:?}", &response);
                 response
             }
             None => {
-                let (mut response, _empty_file) = self.hub
-                    .files()
-                    .get(&drive_id)
-                    .supports_team_drives(false)
-                    .param("alt", "media")
-                    .add_scope(drive3::Scope::Full)
-                    .doit()
-                    .map_err(|e| err_msg(format!("{:#?}", e)))?;
+                let (response, _empty_file) = self.retry.run("files.get (media)", || {
+                    self.hub
+                        .files()
+                        .get(drive_id)
+                        .supports_team_drives(true)
+                        .param("alt", "media")
+                        .add_scope(drive3::Scope::Full)
+                        .doit()
+                })?;
                 response
             }
         };
@@ -188,349 +742,682 @@ impl DriveFacade {
         Ok(content)
     }
 
-    /// Applies all pending writes accumulated so far on a data buffer. The pending writes are then
-    /// cleared.
-    fn apply_pending_writes_on_data(&mut self, id: DriveId, data: &mut Vec<u8>) {
-        self.pending_writes
-            .entry(id.clone())
-            .or_insert(Vec::new())
-            .iter()
-            .filter(|write| write.id == id)
-            .for_each(|pending_write| {
-                debug!(
-                    "Applying pending write with offset {} on {}",
-                    &pending_write.offset, &pending_write.id
+    /// Returns the spool file of a Drive file, creating it if there are no pending writes yet.
+    fn spool(&mut self, id: &DriveId) -> Result<&mut SpoolFile, Error> {
+        if !self.spools.contains_key(id) {
+            let target = self.spool_target(id);
+            let spool = SpoolFile::create(self.spool_dir.join(id), target)?;
+            self.spools.insert(id.clone(), spool);
+        }
+
+        Ok(self.spools.get_mut(id).unwrap())
+    }
+
+    /// Completes a spool file with the parts of the remote content which are still needed and
+    /// uploads the result. The remote content is not downloaded at all if the pending writes
+    /// replace it completely.
+    ///
+    /// If the file has been changed on Drive since it was first read or written to, the original
+    /// is left alone and the result is uploaded as a conflicted copy next to it instead. Note that
+    /// the parts of the copy which were not written to are taken from the changed original.
+    fn upload_spool(&mut self, id: &DriveId, spool: &mut SpoolFile) -> Result<(), Error> {
+        let conflict = self.find_conflict(id)?;
+        let remote_len = if spool.needs_remote() {
+            self.get_remote_size(id)?
+        } else {
+            0
+        };
+
+        for (start, end) in spool.gaps(remote_len) {
+            let mut offset = start;
+            while offset < end {
+                let data = self.get_file_range(id, offset, cmp::min(CHUNK_SIZE, end - offset))?;
+                if data.is_empty() {
+                    break;
+                }
+                spool.fill(offset, &data)?;
+                offset += data.len() as u64;
+            }
+        }
+
+        let contents = spool.contents(spool.final_len(remote_len))?;
+        match conflict {
+            None => {
+                let (_response, file) = self.update_file_content(id.clone(), contents)?;
+                self.base_versions.remove(id);
+                let version = ContentVersion::of(&file);
+                if !version.is_unknown() {
+                    self.base_versions.insert(id.clone(), version);
+                }
+            }
+            Some(remote) => {
+                let name = conflicted_copy_name(
+                    remote.name.as_deref().unwrap_or(id),
+                );
+                let copy_id = self.upload_new_file(&name, remote.parents, contents)?;
+                warn!(
+                    "{} was changed on Drive in the meantime, uploaded as {:?} ({}) instead",
+                    id, name, copy_id
                 );
-                let required_size = pending_write.offset + pending_write.data.len();
+                self.base_versions.remove(id);
+            }
+        }
+        Ok(())
+    }
 
-                data.resize(required_size, 0);
-                data[pending_write.offset..].copy_from_slice(&pending_write.data[..]);
-            });
+    /// Creates a file and uploads its content. Returns its Drive ID.
+    fn upload_new_file<R: Read + Seek>(
+        &mut self,
+        name: &str,
+        parents: Option<Vec<DriveId>>,
+        content: R,
+    ) -> Result<DriveId, Error> {
+        let file = drive3::File {
+            name: Some(name.to_string()),
+            parents,
+            ..Default::default()
+        };
+        let id = self.create(&file)?;
+        self.update_file_content(id.clone(), content)?;
+        Ok(id)
+    }
+
+    /// Describes where the pending writes of a file are meant to go, so that they can still be
+    /// uploaded after a crash. Unless it is already known, the version of the content which they
+    /// are based on is recorded as well.
+    fn spool_target(&mut self, id: &DriveId) -> SpoolTarget {
+        let mut target = SpoolTarget {
+            id: id.clone(),
+            ..Default::default()
+        };
 
-        self.pending_writes.remove(&id);
+        match self.get_file_metadata(id) {
+            Ok(file) => {
+                let version = ContentVersion::of(&file);
+                if !self.base_versions.contains_key(id) && !version.is_unknown() {
+                    self.base_versions.insert(id.clone(), version);
+                }
+                target.name = file.name;
+                target.parents = file.parents;
+            }
+            Err(e) => warn!("Could not retrieve the metadata of {}: {}", id, e),
+        }
+
+        target.base_version = self.base_versions.get(id).cloned();
+        target
+    }
+
+    /// Uploads the pending writes which a previous session left behind, e.g. because it crashed
+    /// before they were flushed. The ones which cannot be uploaded are moved to the recovery
+    /// directory, so that they are not lost.
+    fn recover_spools(&mut self) {
+        let paths = match SpoolFile::find(&self.spool_dir) {
+            Ok(paths) => paths,
+            Err(e) => {
+                error!("Could not look for pending writes in {:?}: {}", &self.spool_dir, e);
+                return;
+            }
+        };
+
+        for path in paths {
+            let mut spool = match SpoolFile::open(path.clone()) {
+                Ok(spool) => spool,
+                Err(e) => {
+                    error!("Could not open spool file {:?}: {}", &path, e);
+                    continue;
+                }
+            };
+
+            let target = spool.target().clone();
+            info!(
+                "Uploading the pending writes of {} ({:?}) left by a previous session",
+                &target.id, &target.name
+            );
+            match self.recover_spool(&target, &mut spool) {
+                Ok(()) => spool.discard(),
+                Err(ref e) if FsError::is_offline(e) => {
+                    // Kept like any other pending writes, so that later writes add to them and
+                    // they are uploaded on the next flush.
+                    warn!("Drive is unreachable, will try again on the next flush: {}", e);
+                    self.spools.insert(target.id.clone(), spool);
+                }
+                Err(e) => {
+                    let name = format!(
+                        "{} {}",
+                        &target.id,
+                        target.name.as_deref().unwrap_or("")
+                    ).replace('/', "_");
+                    match spool.keep_in(&self.recovery_dir, name.trim()) {
+                        Ok(kept) => error!(
+                            "Could not upload pending writes of {}, moved them to {:?}: {}",
+                            &target.id, kept, e
+                        ),
+                        Err(keep_error) => error!(
+                            "Could not upload pending writes of {} ({}) nor move them: {}",
+                            &target.id, e, keep_error
+                        ),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Uploads a spool file left behind by a previous session. If the file which it was meant for
+    /// no longer exists, its content is uploaded as a new file under the same name and parents,
+    /// provided that it does not depend on the remote content.
+    fn recover_spool(&mut self, target: &SpoolTarget, spool: &mut SpoolFile) -> Result<(), Error> {
+        if let Some(ref version) = target.base_version {
+            self.base_versions.insert(target.id.clone(), version.clone());
+        }
+
+        let e = match self.upload_spool(&target.id, spool) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        let gone = matches!(e.downcast_ref::<FsError>(), Some(&FsError::NotFound(_)));
+        let name = match target.name {
+            Some(ref name) if gone && !spool.needs_remote() => name.clone(),
+            _ => return Err(e),
+        };
+
+        let contents = spool.contents(spool.final_len(0))?;
+        let id = self.upload_new_file(&name, target.parents.clone(), contents)?;
+        info!("{} no longer exists, uploaded {:?} as {}", &target.id, &name, &id);
+        Ok(())
+    }
+
+    /// Records the version of a file's content which is being read as the one which its next
+    /// writes will be based on. The version retrieved for validating cached content is reused if
+    /// there is one, so that reading does not cost another request every time. Otherwise, the
+    /// version is only retrieved when the file is first read.
+    fn remember_base_version(&mut self, id: &str) {
+        // Pending writes keep the version which the first of them was based on.
+        if self.spools.contains_key(id) {
+            return;
+        }
+
+        let known = self.versions.get(id).map(|(version, _)| version.clone());
+        let version = match known {
+            Some(version) => version,
+            None if self.base_versions.contains_key(id) => return,
+            None => {
+                let retry = self.retry.clone();
+                match self.content_version(id, &retry) {
+                    Ok(version) => version,
+                    Err(e) => {
+                        warn!("Could not retrieve the version of {}: {}", id, e);
+                        return;
+                    }
+                }
+            }
+        };
+
+        if !version.is_unknown() {
+            self.base_versions.insert(id.to_string(), version);
+        }
+    }
+
+    /// Returns the current metadata of a file if its content has changed on Drive since the
+    /// version which its pending writes are based on, i.e. if uploading them would overwrite
+    /// someone else's changes.
+    fn find_conflict(&self, id: &str) -> Result<Option<drive3::File>, Error> {
+        let base = match self.base_versions.get(id) {
+            Some(base) => base,
+            None => return Ok(None),
+        };
+
+        let file = self.get_file_metadata(id)?;
+        if base.matches(&ContentVersion::of(&file)) {
+            Ok(None)
+        } else {
+            Ok(Some(file))
+        }
+    }
+
+    /// Returns the start page token for the `changes.list` API endpoint. It covers the changes of
+    /// the shared drive `drive_id`, or the ones of "My Drive" if it is `None`.
+    fn get_start_page_token(&mut self, drive_id: Option<&DriveId>) -> Result<String, Error> {
+        self.retry
+            .run("changes.getStartPageToken", || {
+                let mut request = self.hub
+                    .changes()
+                    .get_start_page_token()
+                    .supports_team_drives(true)
+                    .add_scope(drive3::Scope::Full);
+
+                if let Some(id) = drive_id {
+                    request = request.team_drive_id(id);
+                }
+                request.doit()
+            })
+            .and_then(|result| {
+                result.1.start_page_token.ok_or_else(|| {
+                    FsError::Remote(
+                        "Received OK response from drive but there is no startPageToken included."
+                            .to_string(),
+                    ).into()
+                })
+            })
+    }
+
+    /// Updates the content of a file on Drive by streaming it from `content`. The MIME type is
+    /// guessed appropriately based on the beginning of the content.
+    fn update_file_content<R: Read + Seek>(
+        &mut self,
+        id: DriveId,
+        mut content: R,
+    ) -> Result<(Response, drive3::File), Error> {
+        let mut head = Vec::new();
+        (&mut content).take(4096).read_to_end(&mut head)?;
+        content.seek(SeekFrom::Start(0))?;
+
+        let head: &[u8] = &head;
+        let mime_guess = head.sniff_mime_type().unwrap_or("application/octet-stream");
+        debug!(
+            "Updating file content for {}. Mime type guess based on content: {}",
+            &id, &mime_guess
+        );
+
+        let file = drive3::File {
+            mime_type: Some(mime_guess.to_string()),
+            ..Default::default()
+        };
+
+        // The upload starts over from the beginning of the content on every attempt.
+        self.retry.run("files.update (upload)", || {
+            self.hub
+                .files()
+                .update(file.clone(), &id)
+                .param("fields", "id,headRevisionId,md5Checksum,modifiedTime")
+                .supports_team_drives(true)
+                .add_scope(drive3::Scope::Full)
+                .upload_resumable(&mut content, mime_guess.parse().unwrap())
+        })
     }
+}
 
+impl DriveBackend for DriveFacade {
     /// Returns the Drive ID of the root "My Drive" directory
-    pub fn root_id(&mut self) -> Result<&String, Error> {
-        if self.root_id.is_some() {
-            return Ok(self.root_id.as_ref().unwrap());
-        }
-
-        let parent = self.hub
-            .files()
-            .list()
-            .param("fields", "files(parents)")
-            .spaces("drive")
-            .corpora("user")
-            .page_size(1)
-            .q("'root' in parents")
-            .add_scope(drive3::Scope::Full)
-            .doit()
-            .map_err(|e| err_msg(format!("{:#?}", e)))?
+    fn root_id(&mut self) -> Result<&String, Error> {
+        if let Some(ref root_id) = self.root_id {
+            return Ok(root_id);
+        }
+
+        let no_files = || {
+            FsError::Other("No files on drive. Can't deduce drive id for 'My Drive'".to_string())
+        };
+        let parent = self.retry
+            .run("files.list", || {
+                self.hub
+                    .files()
+                    .list()
+                    .param("fields", "files(parents)")
+                    .spaces("drive")
+                    .corpora("user")
+                    .page_size(1)
+                    .q("'root' in parents")
+                    .add_scope(drive3::Scope::Full)
+                    .doit()
+            })?
             .1
             .files
-            .ok_or(err_msg("No files received"))?
+            .ok_or_else(|| FsError::Remote("No files received".to_string()))?
             .into_iter()
             .take(1)
             .next()
-            .ok_or(err_msg(
-                "No files on drive. Can't deduce drive id for 'My Drive'",
-            ))?
+            .ok_or_else(no_files)?
             .parents
-            .ok_or(err_msg(
-                "Probed file has no parents. Can't deduce drive id for 'My Drive'",
-            ))?
+            .ok_or_else(|| {
+                FsError::Other(
+                    "Probed file has no parents. Can't deduce drive id for 'My Drive'".to_string(),
+                )
+            })?
             .into_iter()
             .take(1)
             .next()
-            .ok_or(err_msg(
-                "No files on drive. Can't deduce drive id for 'My Drive'",
-            ))?;
+            .ok_or_else(no_files)?;
 
         self.root_id = Some(parent);
         Ok(self.root_id.as_ref().unwrap())
     }
 
-    /// Returns the start page token for the `changes.list` API endpoint.
-    fn get_start_page_token(&mut self) -> Result<String, Error> {
-        self.hub
-            .changes()
-            .get_start_page_token()
-            .add_scope(drive3::Scope::Full)
-            .doit()
-            .map_err(|e| err_msg(format!("{:#?}", e)))
-            .map(|result| {
-                result.1.start_page_token.unwrap_or(
-                    err_msg(
-                        "Received OK response from drive but there is no startPageToken included.",
-                    ).to_string(),
-                )
-            })
-    }
-
     /// Returns the current token for the `changes.list` API endpoint, or the start page token if
     /// absent.
     fn changes_token(&mut self) -> Result<&String, Error> {
         if self.changes_token.is_none() {
-            self.changes_token = Some(self.get_start_page_token()?);
+            self.changes_token = Some(self.get_start_page_token(None)?);
         }
 
         Ok(self.changes_token.as_ref().unwrap())
     }
 
+    fn set_changes_token(&mut self, token: Option<String>) {
+        self.changes_token = token;
+    }
+
     /// Returns a list of all changes reported by Drive which are more recent than the changes
     /// token indicates.
-    pub fn get_all_changes(&mut self) -> Result<Vec<drive3::Change>, Error> {
-        let mut all_changes = Vec::new();
+    fn get_all_changes(&mut self) -> Result<Vec<drive3::Change>, Error> {
+        let token = self.changes_token()?.clone();
+        let (changes, token) = self.list_changes(token, None)?;
+        self.changes_token = Some(token);
+        Ok(changes)
+    }
 
-        loop {
-            let token = self.changes_token()?.clone();
-            let (_response, changelist) = self.hub
-                .changes()
-                .list(&token)
-                .param("fields", "kind,newStartPageToken,changes(kind,type,time,removed,fileId,file(name,id,size,mimeType,owners,parents,trashed,modifiedTime,createdTime,viewedByMeTime))")
-                .spaces("drive")
-                .restrict_to_my_drive(true)
-                // Whether to include changes indicating that items have been removed from the list of changes, for example by deletion or loss of access. (Default: true)
-                .include_removed(false) // ^wtf?
-                .supports_team_drives(false)
-                .include_team_drive_items(false)
-                .page_size(PAGE_SIZE)
-                .add_scope(drive3::Scope::Full)
-                .doit()
-                .map_err(|e| err_msg(format!("{:#?}", e)))?;
+    fn get_shared_files(&mut self) -> Result<Vec<drive3::File>, Error> {
+        self.list_files("sharedWithMe = true and trashed = false", None)
+    }
 
-            match changelist.changes {
-                Some(changes) => all_changes.extend(changes),
-                _ => warn!("Changelist does not contain any changes!"),
-            };
+    fn get_starred_files(&mut self) -> Result<Vec<drive3::File>, Error> {
+        self.list_files("starred = true and trashed = false", None)
+    }
+
+    fn get_recent_files(&mut self, limit: usize) -> Result<Vec<drive3::File>, Error> {
+        self.list_files_by(
+            "mimeType != 'application/vnd.google-apps.folder' and trashed = false",
+            None,
+            Some("viewedByMeTime desc,modifiedByMeTime desc"),
+            Some(limit),
+        )
+    }
+
+    fn shared_drives(&mut self) -> Result<Vec<drive3::TeamDrive>, Error> {
+        let mut all_drives = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let (_, drivelist) = self.retry.run("teamdrives.list", || {
+                let mut request = self.hub
+                    .teamdrives()
+                    .list()
+                    .param("fields", "nextPageToken,teamDrives(id,name,capabilities)")
+                    .page_size(100)
+                    .add_scope(drive3::Scope::Full);
+
+                if let Some(ref token) = page_token {
+                    request = request.page_token(token);
+                };
+                request.doit()
+            })?;
+
+            if let Some(drives) = drivelist.team_drives {
+                all_drives.extend(drives);
+            }
 
-            self.changes_token = changelist.next_page_token;
-            if self.changes_token.is_none() {
-                self.changes_token = changelist.new_start_page_token;
+            page_token = drivelist.next_page_token;
+            if page_token.is_none() {
                 break;
             }
         }
-
-        Ok(all_changes)
+        Ok(all_drives)
     }
 
-    /// Returns a list of all files from Drive. If the `parents` list is provided, only files which are children of any one of the list's elements are returned. If `trashed` is provided, only files which are trashed/not trashed are returned. The two filters can be used together.
-    pub fn get_all_files(
+    fn get_drive_files(
         &mut self,
-        parents: Option<Vec<DriveId>>,
-        trashed: Option<bool>,
+        drive_id: &DriveId,
+        parents: Vec<DriveId>,
     ) -> Result<Vec<drive3::File>, Error> {
-        let mut all_files = Vec::new();
-        let mut page_token: Option<String> = None;
-        loop {
-            let mut request = self.hub.files()
-                .list()
-                .param("fields", "nextPageToken,files(name,id,size,mimeType,owners,parents,trashed,modifiedTime,createdTime,viewedByMeTime)")
-                .spaces("drive") // TODO: maybe add photos as well
-                .corpora("user")
-                .page_size(PAGE_SIZE)
-                .add_scope(drive3::Scope::Full);
-
-            if let Some(token) = page_token {
-                request = request.page_token(&token);
-            };
+        let query = format!("({}) and trashed = false", parents_query(&parents));
+        self.list_files(&query, Some(drive_id))
+    }
 
-            let mut query_chain: Vec<String> = Vec::new();
-            if let Some(ref p) = parents {
-                let q = p.into_iter()
-                    .map(|id| format!("'{}' in parents", id))
-                    .collect::<Vec<_>>()
-                    .join(" or ");
+    fn drive_changes_token(&mut self, drive_id: &DriveId) -> Result<&String, Error> {
+        if !self.drive_changes_tokens.contains_key(drive_id) {
+            let token = self.get_start_page_token(Some(drive_id))?;
+            self.drive_changes_tokens.insert(drive_id.clone(), token);
+        }
 
-                query_chain.push(format!("({})", q));
-            }
-            if let Some(trash) = trashed {
-                query_chain.push(format!("trashed = {}", trash));
-            }
+        Ok(&self.drive_changes_tokens[drive_id])
+    }
 
-            let query = query_chain.join(" and ");
-            let (_, filelist) = request
-                .q(&query)
-                .doit()
-                .map_err(|e| err_msg(format!("{:#?}", e)))?;
+    fn set_drive_changes_token(&mut self, drive_id: &DriveId, token: Option<String>) {
+        match token {
+            Some(token) => self.drive_changes_tokens.insert(drive_id.clone(), token),
+            None => self.drive_changes_tokens.remove(drive_id),
+        };
+    }
 
-            match filelist.files {
-                Some(files) => all_files.extend(files),
-                _ => warn!("Filelist does not contain any files!"),
-            };
+    fn get_drive_changes(&mut self, drive_id: &DriveId) -> Result<Vec<drive3::Change>, Error> {
+        let token = self.drive_changes_token(drive_id)?.clone();
+        let (changes, token) = self.list_changes(token, Some(drive_id))?;
+        self.drive_changes_tokens.insert(drive_id.clone(), token);
+        Ok(changes)
+    }
 
-            page_token = filelist.next_page_token;
-            if page_token.is_none() {
-                break;
-            }
+    /// Returns a list of all files from Drive. If the `parents` list is provided, only files which are children of any one of the list's elements are returned. If `trashed` is provided, only files which are trashed/not trashed are returned. The two filters can be used together.
+    fn get_all_files(
+        &mut self,
+        parents: Option<Vec<DriveId>>,
+        trashed: Option<bool>,
+    ) -> Result<Vec<drive3::File>, Error> {
+        let mut query_chain: Vec<String> = Vec::new();
+        if let Some(ref p) = parents {
+            query_chain.push(format!("({})", parents_query(p)));
+        }
+        if let Some(trash) = trashed {
+            query_chain.push(format!("trashed = {}", trash));
         }
-        return Ok(all_files);
+
+        self.list_files(&query_chain.join(" and "), None)
     }
 
-    pub fn read(
+    fn read(
         &mut self,
         drive_id: &str,
         mime_type: Option<String>,
         offset: usize,
         size: usize,
-    ) -> Option<&[u8]> {
-        if self.cache.contains_key(drive_id) {
-            let data = self.cache.get(drive_id).unwrap();
-            self.buff =
-                data[cmp::min(data.len(), offset)..cmp::min(data.len(), offset + size)].to_vec();
-            return Some(&self.buff);
-        }
-
-        match self.get_file_content(&drive_id, mime_type) {
-            Ok(data) => {
-                self.buff = data[cmp::min(data.len(), offset)..cmp::min(data.len(), offset + size)]
-                    .to_vec();
-                self.cache.insert(drive_id.to_string(), data.to_vec());
-                Some(&self.buff)
-            }
-            Err(e) => {
-                error!("Got error: {:?}", e);
-                None
-            }
+    ) -> Result<&[u8], Error> {
+        let exported = mime_type
+            .as_ref()
+            .map(|t| MIME_TYPES.contains_key(t.as_str()))
+            .unwrap_or(false);
+
+        // Pending writes are read back before they have been uploaded, e.g. right after the file
+        // has been closed.
+        if self.spools.contains_key(drive_id) {
+            self.buff = self.read_spooled(drive_id, offset as u64, size as u64)?;
+            return Ok(&self.buff);
+        }
+
+        // Exported files are generated on the fly by Drive, so they cannot be read in ranges.
+        if !exported {
+            let data = self.read_chunked(drive_id, offset as u64, size as u64)?;
+            self.remember_base_version(drive_id);
+            self.buff = data;
+            return Ok(&self.buff);
         }
+
+        let data = match self.cached_content(drive_id, drive_id) {
+            Some(data) => data,
+            None => self.download(drive_id, mime_type)?,
+        };
+        self.remember_base_version(drive_id);
+
+        self.buff =
+            data[cmp::min(data.len(), offset)..cmp::min(data.len(), offset + size)].to_vec();
+        Ok(&self.buff)
     }
 
-    pub fn create(&mut self, drive_file: &drive3::File) -> Result<DriveId, Error> {
-        let dummy_file = DummyFile::new(&[]);
-        self.hub
-            .files()
-            .create(drive_file.clone())
-            .use_content_as_indexable_text(true)
-            .supports_team_drives(false)
-            .ignore_default_visibility(true)
-            .upload(dummy_file, "application/octet-stream".parse().unwrap())
-            .map_err(|e| err_msg(format!("{:#?}", e)))
-            .map(|(_, file)| {
-                file.id.unwrap_or(
-                    err_msg("Received file from drive but it has no drive id.").to_string(),
-                )
+    fn get_metadata(&mut self, id: &DriveId) -> Result<drive3::File, Error> {
+        let fields = format!("{},properties", xattr::FIELDS.join(","));
+        self.retry
+            .run("files.get (metadata)", || {
+                self.hub
+                    .files()
+                    .get(id)
+                    .param("fields", fields.as_str())
+                    .supports_team_drives(true)
+                    .add_scope(drive3::Scope::Full)
+                    .doit()
             })
+            .map(|(_response, file)| file)
     }
 
-    pub fn write(&mut self, id: DriveId, offset: usize, data: &[u8]) {
-        let pending_write = PendingWrite {
-            id: id.clone(),
-            offset,
-            data: data.to_vec(),
-        };
+    fn create(&mut self, drive_file: &drive3::File) -> Result<DriveId, Error> {
+        self.retry
+            .run("files.create", || {
+                self.hub
+                    .files()
+                    .create(drive_file.clone())
+                    .use_content_as_indexable_text(true)
+                    .supports_team_drives(true)
+                    .ignore_default_visibility(true)
+                    .upload(DummyFile::new(&[]), "application/octet-stream".parse().unwrap())
+            })
+            .and_then(|(_, file)| {
+                file.id.ok_or_else(|| {
+                    FsError::Remote("Received file from drive but it has no drive id.".to_string())
+                        .into()
+                })
+            })
+    }
+
+    fn write(&mut self, id: DriveId, offset: usize, data: &[u8]) -> Result<(), Error> {
+        self.spool(&id)?.write(offset as u64, data)
+    }
+
+    fn truncate(&mut self, id: &DriveId, size: u64) -> Result<(), Error> {
+        self.spool(id)?.truncate(size)
+    }
 
-        self.pending_writes
-            .entry(id.clone())
-            .or_insert(Vec::with_capacity(3000))
-            .push(pending_write);
+    fn dirty_files(&self) -> Vec<DriveId> {
+        self.spools.keys().cloned().collect()
     }
 
-    pub fn delete_permanently(&mut self, id: &DriveId) -> Result<bool, Error> {
-        self.hub
-            .files()
-            .delete(&id)
-            .supports_team_drives(false)
-            .add_scope(drive3::Scope::Full)
-            .doit()
+    fn delete_permanently(&mut self, id: &DriveId) -> Result<bool, Error> {
+        self.retry
+            .run("files.delete", || {
+                self.hub
+                    .files()
+                    .delete(id)
+                    .supports_team_drives(true)
+                    .add_scope(drive3::Scope::Full)
+                    .doit()
+            })
             .map(|response| response.status.is_success())
-            .map_err(|e| err_msg(format!("{:#?}", e)))
     }
 
-    pub fn move_to(
+    fn move_to(
         &mut self,
         id: &DriveId,
         parent: &DriveId,
         new_name: &str,
-    ) -> Result<(Response, drive3::File), Error> {
+    ) -> Result<drive3::File, Error> {
         let current_parents = self.get_file_metadata(id)?
             .parents
             .unwrap_or(vec![String::from("root")])
             .join(",");
 
-        let mut file = drive3::File::default();
-        file.name = Some(new_name.to_string());
-        self.hub
-            .files()
-            .update(file, id)
-            .remove_parents(&current_parents)
-            .add_parents(parent)
-            .add_scope(drive3::Scope::Full)
-            .doit_without_upload()
-            .map_err(|e| err_msg(format!("DriveFacade::move_to() {}", e)))
-    }
-
-    pub fn move_to_trash(&mut self, id: DriveId) -> Result<(), Error> {
-        let mut f = drive3::File::default();
-        f.trashed = Some(true);
-
-        self.hub
-            .files()
-            .update(f, &id)
-            .add_scope(drive3::Scope::Full)
-            .doit_without_upload()
-            .map(|_| ())
-            .map_err(|e| err_msg(format!("DriveFacade::move_to() {}", e)))
+        let file = drive3::File {
+            name: Some(new_name.to_string()),
+            ..Default::default()
+        };
+        self.retry
+            .run("files.update (move)", || {
+                self.hub
+                    .files()
+                    .update(file.clone(), id)
+                    .remove_parents(&current_parents)
+                    .add_parents(parent)
+                    .supports_team_drives(true)
+                    .add_scope(drive3::Scope::Full)
+                    .doit_without_upload()
+            })
+            .map(|(_response, file)| file)
     }
 
-    pub fn flush(&mut self, id: &DriveId) -> Result<(), Error> {
-        if !self.pending_writes.contains_key(id) {
-            debug!("flush({}): no pending writes", id);
-            return Ok(());
-        }
-        self.cache.remove(id);
+    fn update_metadata(
+        &mut self,
+        id: &DriveId,
+        patch: &drive3::File,
+    ) -> Result<drive3::File, Error> {
+        self.retry
+            .run("files.update (metadata)", || {
+                self.hub
+                    .files()
+                    .update(patch.clone(), id)
+                    .supports_team_drives(true)
+                    .add_scope(drive3::Scope::Full)
+                    .doit_without_upload()
+            })
+            .map(|(_response, file)| file)
+    }
 
-        if let Ok(false) = self.contains(id) {
-            return Err(err_msg(format!(
-                "flush({}): file doesn't exist on drive!",
-                id
-            )));
-        }
+    fn update_properties(
+        &mut self,
+        id: &DriveId,
+        properties: &HashMap<String, Option<String>>,
+    ) -> Result<(), Error> {
+        self.patch_properties(id, properties)
+    }
 
-        let mut file_data = self.get_file_content(&id, None).unwrap_or_default();
-        self.apply_pending_writes_on_data(id.clone(), &mut file_data);
-        self.update_file_content(id.clone(), &file_data)?;
+    fn move_to_trash(&mut self, id: DriveId) -> Result<(), Error> {
+        let f = drive3::File {
+            trashed: Some(true),
+            ..Default::default()
+        };
 
-        Ok(())
+        self.retry
+            .run("files.update (trash)", || {
+                self.hub
+                    .files()
+                    .update(f.clone(), &id)
+                    .supports_team_drives(true)
+                    .add_scope(drive3::Scope::Full)
+                    .doit_without_upload()
+            })
+            .map(|_| ())
     }
 
-    /// Updates the content of a file on Drive. The MIME type is guessed appropriately based on the
-    /// content.
-    fn update_file_content(
-        &mut self,
-        id: DriveId,
-        data: &[u8],
-    ) -> Result<(Response, drive3::File), Error> {
-        let mime_guess = data.sniff_mime_type().unwrap_or("application/octet-stream");
-        debug!(
-            "Updating file content for {}. Mime type guess based on content: {}",
-            &id, &mime_guess
-        );
-
-        let mut file = drive3::File::default();
-        file.mime_type = Some(mime_guess.to_string());
+    fn flush(&mut self, id: &DriveId) -> Result<(), Error> {
+        let mut spool = match self.spools.remove(id) {
+            Some(spool) => spool,
+            None => {
+                debug!("flush({}): no pending writes", id);
+                return Ok(());
+            }
+        };
+        self.versions.remove(id);
+        self.next_offsets.remove(id);
+        if let Some(ref mut cache) = self.cache {
+            cache.remove_file(id);
+        }
 
-        self.hub
-            .files()
-            .update(file, &id)
-            .add_scope(drive3::Scope::Full)
-            .upload_resumable(DummyFile::new(data), mime_guess.parse().unwrap())
-            .map_err(|e| err_msg(format!("{:#?}", e)))
+        match self.upload_spool(id, &mut spool) {
+            Ok(()) => {
+                spool.discard();
+                Ok(())
+            }
+            Err(e) => {
+                // Keep the pending writes, so that flushing can be attempted again, even after a
+                // crash.
+                if let Err(e) = spool.persist() {
+                    error!("flush({}): could not sync pending writes: {}", id, e);
+                }
+                self.spools.insert(id.clone(), spool);
+                error!("flush({}): {}", id, e);
+                Err(e)
+            }
+        }
     }
 
     /// Returns the size and capacity of the Drive account. In some cases, the limit can be absent.
-    pub fn size_and_capacity(&mut self) -> Result<(u64, Option<u64>), Error> {
-        let (_response, about) = self.hub
-            .about()
-            .get()
-            .param("fields", "storageQuota")
-            .add_scope(drive3::Scope::Full)
-            .doit()
-            .map_err(|e| err_msg(format!("{:#?}", e)))?;
+    fn size_and_capacity(&mut self) -> Result<(u64, Option<u64>), Error> {
+        let (_response, about) = self.retry.run("about.get", || {
+            self.hub
+                .about()
+                .get()
+                .param("fields", "storageQuota")
+                .add_scope(drive3::Scope::Full)
+                .doit()
+        })?;
 
         let storage_quota = about
             .storage_quota
-            .ok_or(err_msg("size_and_capacity(): no storage quota in response"))?;
+            .ok_or_else(|| {
+                FsError::Remote("size_and_capacity(): no storage quota in response".to_string())
+            })?;
 
         let usage = storage_quota.usage.unwrap().parse::<u64>().unwrap();
         let limit = storage_quota.limit.map(|s| s.parse::<u64>().unwrap());
@@ -587,3 +1474,34 @@ impl Read for DummyFile {
         Ok(copied)
     }
 }
+
+/// A query which matches the children of any one of `parents`.
+fn parents_query(parents: &[DriveId]) -> String {
+    parents
+        .iter()
+        .map(|id| format!("'{}' in parents", id))
+        .collect::<Vec<_>>()
+        .join(" or ")
+}
+
+/// The name under which the local version of a file is uploaded when the original has been changed
+/// on Drive in the meantime, e.g. "notes.txt (conflicted copy laptop 2018-05-20 14-03-51)".
+fn conflicted_copy_name(name: &str) -> String {
+    format!(
+        "{} (conflicted copy {} {})",
+        name,
+        hostname(),
+        Local::now().format("%Y-%m-%d %H-%M-%S")
+    )
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if result != 0 {
+        return "unknown".to_string();
+    }
+
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
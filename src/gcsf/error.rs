@@ -0,0 +1,177 @@
+use drive3;
+use failure::Error;
+use libc::{
+    c_int, EACCES, EAGAIN, EEXIST, EINVAL, EIO, EISDIR, ENETDOWN, ENOENT, ENOSPC, ENOTEMPTY,
+    ENOTSUP, EREMOTE,
+};
+use serde::Serialize;
+use serde_json;
+use std::error;
+use std::fmt;
+use std::io;
+
+/// The reasons Drive gives when a request is refused because too many were sent. These come with a
+/// 403 status, which otherwise means that the request is not allowed.
+pub const RATE_LIMIT_REASONS: &[&str] = &["userRateLimitExceeded", "rateLimitExceeded"];
+
+/// The reasons Drive gives when the account has run out of storage.
+const QUOTA_REASONS: &[&str] = &["storageQuotaExceeded", "quotaExceeded"];
+
+/// The ways in which a file system operation can fail. `FileManager` and `DriveFacade` report
+/// their failures as one of these (wrapped in a `failure::Error`), so that `GCSF` can reply with
+/// a matching errno. Each variant holds a description of what went wrong.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FsError {
+    /// The file does not exist, either locally or on Drive.
+    NotFound(String),
+    /// There already is a file with the same name in the same directory.
+    AlreadyExists(String),
+    /// A directory which still has children cannot be removed.
+    NotEmpty(String),
+    /// The operation only makes sense for files, not directories.
+    IsDirectory(String),
+    /// Drive does not allow the operation on this file, or the account is not authorized.
+    PermissionDenied(String),
+    /// The Drive account has run out of storage.
+    QuotaExceeded(String),
+    /// Drive keeps refusing requests because too many of them have been sent.
+    RateLimited(String),
+    /// Drive cannot be reached.
+    Offline(String),
+    /// Drive no longer accepts the page token from which changes are retrieved, so the files
+    /// have to be listed from scratch.
+    ExpiredToken(String),
+    /// The name cannot be given to a file.
+    InvalidName(String),
+    /// The value cannot be given to a file attribute.
+    InvalidValue(String),
+    /// The operation is not supported, e.g. writing an attribute which is read-only on Drive.
+    Unsupported(String),
+    /// Drive failed in some other way.
+    Remote(String),
+    /// Anything else, e.g. an inconsistency in the local file tree.
+    Other(String),
+}
+
+impl FsError {
+    /// The errno which is reported to the process which requested the operation.
+    pub fn errno(&self) -> c_int {
+        match *self {
+            FsError::NotFound(_) => ENOENT,
+            FsError::AlreadyExists(_) => EEXIST,
+            FsError::NotEmpty(_) => ENOTEMPTY,
+            FsError::IsDirectory(_) => EISDIR,
+            FsError::PermissionDenied(_) => EACCES,
+            FsError::QuotaExceeded(_) => ENOSPC,
+            FsError::RateLimited(_) => EAGAIN,
+            FsError::Offline(_) => ENETDOWN,
+            FsError::ExpiredToken(_) => EIO,
+            FsError::InvalidName(_) => EINVAL,
+            FsError::InvalidValue(_) => EINVAL,
+            FsError::Unsupported(_) => ENOTSUP,
+            FsError::Remote(_) => EREMOTE,
+            FsError::Other(_) => EIO,
+        }
+    }
+
+    /// Classifies an error returned by a `drive3` call.
+    pub fn from_drive(error: &drive3::Error) -> Self {
+        let description = format!("{:#?}", error);
+        match *error {
+            drive3::Error::HttpError(_) => FsError::Offline(description),
+            drive3::Error::MissingToken(_) | drive3::Error::MissingAPIKey => {
+                FsError::PermissionDenied(description)
+            }
+            drive3::Error::BadRequest(ref response) => {
+                let (code, reasons) = bad_request_details(response);
+                let reasons: Vec<&str> = reasons.iter().map(String::as_str).collect();
+                FsError::from_status(code, &reasons, description)
+            }
+            drive3::Error::Failure(ref response) => {
+                FsError::from_status(response.status.to_u16(), &[], description)
+            }
+            _ => FsError::Remote(description),
+        }
+    }
+
+    fn from_status(code: u16, reasons: &[&str], description: String) -> Self {
+        let any_of = |known: &[&str]| reasons.iter().any(|reason| known.contains(reason));
+
+        match code {
+            429 => FsError::RateLimited(description),
+            403 if any_of(RATE_LIMIT_REASONS) => FsError::RateLimited(description),
+            403 if any_of(QUOTA_REASONS) => FsError::QuotaExceeded(description),
+            401 | 403 => FsError::PermissionDenied(description),
+            404 | 410 => FsError::NotFound(description),
+            409 => FsError::AlreadyExists(description),
+            _ => FsError::Remote(description),
+        }
+    }
+
+    /// Whether an error was reported because Drive could not be reached.
+    pub fn is_offline(error: &Error) -> bool {
+        matches!(error.downcast_ref::<FsError>(), Some(&FsError::Offline(_)))
+    }
+
+    /// Whether an error was reported because a changes token is no longer accepted.
+    pub fn is_expired_token(error: &Error) -> bool {
+        matches!(error.downcast_ref::<FsError>(), Some(&FsError::ExpiredToken(_)))
+    }
+
+    /// The errno which matches any error reported by `FileManager` or a `DriveBackend`. Errors
+    /// which did not originate in GCSF (e.g. I/O errors of the spool files) are mapped as well.
+    pub fn errno_of(error: &Error) -> c_int {
+        if let Some(e) = error.downcast_ref::<FsError>() {
+            return e.errno();
+        }
+        if let Some(e) = error.downcast_ref::<io::Error>() {
+            return e.raw_os_error().unwrap_or(EIO);
+        }
+        EIO
+    }
+}
+
+/// The status code and the reasons of an error response which `drive3` has parsed into a
+/// `BadRequest`. Its fields are private, so they are read back from its serialized form.
+pub fn bad_request_details<T: Serialize>(response: &T) -> (u16, Vec<String>) {
+    let value = serde_json::to_value(response).unwrap_or_default();
+    let error = &value["error"];
+    let code = error["code"].as_u64().unwrap_or(0) as u16;
+    let reasons = error["errors"]
+        .as_array()
+        .map(|errors| {
+            errors
+                .iter()
+                .filter_map(|message| message["reason"].as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default();
+    (code, reasons)
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FsError::NotFound(ref s) => write!(f, "Not found: {}", s),
+            FsError::AlreadyExists(ref s) => write!(f, "Already exists: {}", s),
+            FsError::NotEmpty(ref s) => write!(f, "Directory not empty: {}", s),
+            FsError::IsDirectory(ref s) => write!(f, "Is a directory: {}", s),
+            FsError::PermissionDenied(ref s) => write!(f, "Permission denied: {}", s),
+            FsError::QuotaExceeded(ref s) => write!(f, "Storage quota exceeded: {}", s),
+            FsError::RateLimited(ref s) => write!(f, "Rate limited: {}", s),
+            FsError::Offline(ref s) => write!(f, "Drive is unreachable: {}", s),
+            FsError::ExpiredToken(ref s) => write!(f, "Changes token expired: {}", s),
+            FsError::InvalidName(ref s) => write!(f, "Invalid name: {}", s),
+            FsError::InvalidValue(ref s) => write!(f, "Invalid value: {}", s),
+            FsError::Unsupported(ref s) => write!(f, "Not supported: {}", s),
+            FsError::Remote(ref s) => write!(f, "Drive error: {}", s),
+            FsError::Other(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl error::Error for FsError {
+    fn description(&self) -> &str {
+        "gcsf error"
+    }
+}
@@ -2,6 +2,7 @@ use chrono::DateTime;
 use drive3;
 use fuse::{FileAttr, FileType};
 use id_tree::NodeId;
+use libc;
 use std::collections::HashMap;
 use time::Timespec;
 
@@ -40,6 +41,48 @@ pub enum FileId {
     ParentAndName { parent: Inode, name: String },
 }
 
+/// The keys of the Drive `appProperties` in which the POSIX attributes of a file are kept. The mode
+/// is stored in octal, the owner and the group in decimal.
+pub const MODE_PROPERTY: &str = "posix_mode";
+pub const UID_PROPERTY: &str = "posix_uid";
+pub const GID_PROPERTY: &str = "posix_gid";
+
+/// The key of the Drive `appProperties` which marks a file as a symbolic link. It holds the target
+/// of the link.
+pub const LINK_TARGET_PROPERTY: &str = "symlink_target";
+
+/// The MIME type of the Drive files which represent symbolic links. They have no content.
+pub const LINK_MIME_TYPE: &str = "inode/symlink";
+
+/// Drive limits each property to 124 bytes, including the key.
+pub const MAX_LINK_TARGET_LEN: usize = 124 - LINK_TARGET_PROPERTY.len();
+
+/// The attributes of files whose Drive file does not record its own, e.g. because the file has
+/// been created outside of GCSF. See `Config::attr_defaults()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AttrDefaults {
+    pub uid: u32,
+    pub gid: u32,
+    pub file_mode: u16,
+    pub dir_mode: u16,
+
+    /// The permission bits which are cleared from the mode of newly created files.
+    pub umask: u16,
+}
+
+impl Default for AttrDefaults {
+    /// Files are owned by the user running GCSF.
+    fn default() -> Self {
+        AttrDefaults {
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            file_mode: 0o644,
+            dir_mode: 0o755,
+            umask: 0o022,
+        }
+    }
+}
+
 lazy_static! {
     static ref EXTENSIONS: HashMap<&'static str, &'static str> = hashmap!{
             "application/vnd.google-apps.document" => "#.odt",
@@ -49,15 +92,16 @@ lazy_static! {
 }
 
 impl File {
-    /// Creates a new file using a Drive file as a template.
-    pub fn from_drive_file(inode: Inode, drive_file: drive3::File) -> Self {
+    /// Creates a new file using a Drive file as a template. The mode, owner and group are read
+    /// from its `appProperties`, falling back to `defaults`.
+    pub fn from_drive_file(inode: Inode, drive_file: drive3::File, defaults: AttrDefaults) -> Self {
         let size = drive_file
             .size
             .clone()
             .map(|size| size.parse::<u64>().unwrap_or_default())
             .unwrap_or(10 * 1024 * 1024);
 
-        let times: Vec<_> = vec![
+        let times: Vec<_> = [
             &drive_file.created_time,
             &drive_file.modified_time,
             &drive_file.viewed_by_me_time,
@@ -82,17 +126,17 @@ impl File {
             atime,
             mtime,
             ctime: mtime,   // Time of last change
-            crtime: crtime, // Time of creation (macOS only)
+            crtime,         // Time of creation (macOS only)
             kind: if drive_file.mime_type == Some("application/vnd.google-apps.folder".to_string())
             {
                 FileType::Directory
             } else {
                 FileType::RegularFile
             },
-            perm: 0o755,
+            perm: defaults.file_mode,
             nlink: 2,
-            uid: 0,
-            gid: 0,
+            uid: defaults.uid,
+            gid: defaults.gid,
             rdev: 0,
             flags: 0,
         };
@@ -101,6 +145,11 @@ impl File {
             attr.size = 4096;
         }
 
+        if let Some(target) = File::link_target_of(&drive_file) {
+            attr.kind = FileType::Symlink;
+            attr.size = target.len() as u64;
+        }
+
         let mut filename = drive_file.name.clone().unwrap();
         // let owners: Vec<String> = drive_file
         //     .owners
@@ -114,20 +163,87 @@ impl File {
             .mime_type
             .clone()
             .and_then(|t| EXTENSIONS.get::<str>(&t));
-        if ext.is_some() {
-            filename = format!("{}{}", filename, ext.unwrap());
+        if let Some(ext) = ext {
+            filename = format!("{}{}", filename, ext);
         }
 
-        File {
+        let mut file = File {
             // name: format!("{} ({})", filename, owners.join(", ")),
             name: filename
                 .chars()
-                .filter(|c| File::is_posix(c))
+                .filter(File::is_posix)
                 .collect::<String>(),
             attr,
             identical_name_id: None,
             drive_file: Some(drive_file),
+        };
+        file.load_posix_attrs(defaults);
+        file
+    }
+
+    /// Sets the mode, owner and group from the `appProperties` of the Drive file, falling back to
+    /// `defaults` for the ones which it does not record.
+    pub fn load_posix_attrs(&mut self, defaults: AttrDefaults) {
+        self.attr.perm = match self.attr.kind {
+            FileType::Directory => defaults.dir_mode,
+            // The permissions of a symbolic link are never checked.
+            FileType::Symlink => 0o777,
+            _ => defaults.file_mode,
+        };
+        self.attr.uid = defaults.uid;
+        self.attr.gid = defaults.gid;
+
+        let properties = match self.drive_file
+            .as_ref()
+            .and_then(|f| f.app_properties.as_ref())
+        {
+            Some(properties) => properties,
+            None => return,
+        };
+        let property = |key: &str, radix: u32| {
+            properties
+                .get(key)
+                .and_then(|value| u32::from_str_radix(value, radix).ok())
+        };
+
+        if let Some(mode) = property(MODE_PROPERTY, 8) {
+            if self.attr.kind != FileType::Symlink {
+                self.attr.perm = (mode & 0o7777) as u16;
+            }
         }
+        self.attr.uid = property(UID_PROPERTY, 10).unwrap_or(self.attr.uid);
+        self.attr.gid = property(GID_PROPERTY, 10).unwrap_or(self.attr.gid);
+    }
+
+    /// The `appProperties` which record the given attributes. Absent attributes are left out.
+    pub fn attr_properties(
+        perm: Option<u16>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> HashMap<String, String> {
+        let mut properties = HashMap::new();
+        if let Some(perm) = perm {
+            properties.insert(MODE_PROPERTY.to_string(), format!("{:o}", perm));
+        }
+        if let Some(uid) = uid {
+            properties.insert(UID_PROPERTY.to_string(), uid.to_string());
+        }
+        if let Some(gid) = gid {
+            properties.insert(GID_PROPERTY.to_string(), gid.to_string());
+        }
+        properties
+    }
+
+    /// The target of a symbolic link. Absent if the file is not a symbolic link.
+    pub fn link_target(&self) -> Option<&String> {
+        self.drive_file.as_ref().and_then(File::link_target_of)
+    }
+
+    fn link_target_of(drive_file: &drive3::File) -> Option<&String> {
+        drive_file
+            .app_properties
+            .as_ref()
+            .and_then(|properties| properties.get(LINK_TARGET_PROPERTY))
     }
 
     /// Whether a character can be used in a valid POSIX file name.
@@ -161,23 +277,19 @@ impl File {
     }
 
     pub fn drive_parent(&self) -> Option<String> {
-        if self.drive_file.is_none() {
-            return None;
-        }
+        self.drive_file.as_ref().and_then(File::drive_parent_of)
+    }
 
-        self.drive_file
-            .clone()
-            .unwrap()
+    /// Returns the first parent of a Drive file.
+    pub fn drive_parent_of(drive_file: &drive3::File) -> Option<String> {
+        drive_file
             .parents
+            .as_ref()
             .and_then(|parents| parents.iter().take(1).next().cloned())
     }
 
     pub fn drive_id(&self) -> Option<String> {
-        if self.drive_file.is_none() {
-            return None;
-        }
-
-        self.drive_file.as_ref().unwrap().id.clone()
+        self.drive_file.as_ref()?.id.clone()
     }
 
     pub fn set_drive_id(&mut self, id: DriveId) {
@@ -190,10 +302,6 @@ impl File {
 
     #[allow(dead_code)]
     pub fn mime_type(&self) -> Option<String> {
-        if self.drive_file.is_none() {
-            return None;
-        }
-
-        self.drive_file.as_ref().unwrap().mime_type.clone()
+        self.drive_file.as_ref()?.mime_type.clone()
     }
 }
@@ -0,0 +1,75 @@
+use libc::{O_ACCMODE, O_APPEND, O_RDONLY, O_TRUNC};
+use std::collections::HashMap;
+
+type Inode = u64;
+
+/// What a file was opened for. Handed out by `open()` and `create()` and referred to by the file
+/// handle (`fh`) of every subsequent request on the same open file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileHandle {
+    pub inode: Inode,
+
+    /// Whether the file was opened for writing, i.e. not with `O_RDONLY`.
+    pub writable: bool,
+
+    /// `O_APPEND`: every write goes to the end of the file.
+    pub append: bool,
+
+    /// `O_TRUNC`: the file was truncated when it was opened.
+    pub truncate: bool,
+}
+
+impl FileHandle {
+    /// Describes a file which is opened with the given `open(2)` flags.
+    pub fn from_flags(inode: Inode, flags: u32) -> Self {
+        let flags = flags as i32;
+        FileHandle {
+            inode,
+            writable: flags & O_ACCMODE != O_RDONLY,
+            append: flags & O_APPEND != 0,
+            truncate: flags & O_TRUNC != 0,
+        }
+    }
+}
+
+/// The files which are currently open. Keeping track of them makes it possible to upload the
+/// pending writes of a file once, when its last writer closes it, instead of on every `close()`.
+#[derive(Debug)]
+pub struct FileHandles {
+    next_fh: u64,
+    handles: HashMap<u64, FileHandle>,
+}
+
+impl FileHandles {
+    pub fn new() -> Self {
+        FileHandles {
+            next_fh: 1,
+            handles: HashMap::new(),
+        }
+    }
+
+    /// Registers an open file and returns its handle id.
+    pub fn open(&mut self, handle: FileHandle) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(fh, handle);
+        fh
+    }
+
+    pub fn get(&self, fh: u64) -> Option<&FileHandle> {
+        self.handles.get(&fh)
+    }
+
+    /// Forgets an open file. Returns what it was opened for, if the handle was known.
+    pub fn release(&mut self, fh: u64) -> Option<FileHandle> {
+        self.handles.remove(&fh)
+    }
+
+    /// How many handles which allow writing are open for `inode`.
+    pub fn writers(&self, inode: Inode) -> usize {
+        self.handles
+            .values()
+            .filter(|handle| handle.inode == inode && handle.writable)
+            .count()
+    }
+}
@@ -1,23 +1,44 @@
-use super::{File, FileId};
+use super::error::FsError;
+use super::file::MAX_LINK_TARGET_LEN;
+use super::snapshot::{Snapshot, SnapshotEntry};
+use super::xattr::Change;
+use super::{AttrDefaults, Config, DriveBackend, File, FileId};
 use drive3;
-use failure::{err_msg, Error};
+use failure::Error;
 use fuse::{FileAttr, FileType};
 use id_tree::InsertBehavior::*;
 use id_tree::MoveBehavior::*;
 use id_tree::RemoveBehavior::*;
 use id_tree::{Node, NodeId, Tree, TreeBuilder};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::collections::LinkedList;
 use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 use time::Timespec;
-use DriveFacade;
 
 pub type Inode = u64;
 pub type DriveId = String;
 
 const ROOT_INODE: Inode = 1;
 const TRASH_INODE: Inode = 2;
+const SHARED_DRIVES_INODE: Inode = 3;
+const SHARED_WITH_ME_INODE: Inode = 4;
+const STARRED_INODE: Inode = 5;
+const RECENT_INODE: Inode = 6;
+
+/// The name of the directory which holds a directory for each shared drive.
+const SHARED_DRIVES_DIR_NAME: &str = "Shared drives";
+
+/// The name of the directory which holds the files that others have shared with the account.
+const SHARED_WITH_ME_DIR_NAME: &str = "Shared with me";
+
+/// The names of the directories which list the starred and the most recent files.
+const STARRED_DIR_NAME: &str = "Starred";
+const RECENT_DIR_NAME: &str = "Recent";
+
+const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
 
 macro_rules! unwrap_or_continue {
     ($res:expr) => {
@@ -31,7 +52,7 @@ macro_rules! unwrap_or_continue {
     };
 }
 
-/// Manages files locally and uses a DriveFacade in order to communicate with Google Drive and to ensure consistency between the local and remote state.
+/// Manages files locally and uses a DriveBackend in order to communicate with Google Drive and to ensure consistency between the local and remote state.
 pub struct FileManager {
     /// A representation of the file tree. Each tree node stores the inode of the corresponding file.
     tree: Tree<Inode>,
@@ -45,8 +66,9 @@ pub struct FileManager {
     /// Maps Google Drive ids (i.e strings) to corresponding inodes.
     pub drive_ids: HashMap<DriveId, Inode>,
 
-    /// A `DriveFacade` is used in order to communicate with the Google Drive API.
-    pub df: DriveFacade,
+    /// A `DriveBackend` (usually a `DriveFacade`) is used in order to communicate with Google Drive.
+    /// It is shared so that network requests can be performed without locking the whole manager.
+    pub df: Arc<Mutex<dyn DriveBackend>>,
 
     /// The last timestamp when the file manager asked Google Drive for remote changes.
     pub last_sync: SystemTime,
@@ -54,14 +76,112 @@ pub struct FileManager {
     /// Specifies how much time is needed to pass since `last_sync` for a new sync to be performed.
     pub sync_interval: Duration,
 
+    /// Where the file tree is saved between sessions. If absent, the tree is not saved at all.
+    metadata_cache: Option<PathBuf>,
+
+    /// If set, the children of a directory are only retrieved the first time they are needed,
+    /// instead of crawling the whole Drive at startup.
+    lazy: bool,
+
+    /// The directories whose children have been retrieved. Changes which affect the contents of
+    /// other directories are ignored, since they will be seen once those directories are loaded.
+    loaded_dirs: HashSet<Inode>,
+
+    /// The files listed by the "Starred" and "Recent" directories, which are not their parents.
+    listings: HashMap<Inode, Vec<Inode>>,
+
+    /// The files which Drive lists in "Starred" and "Recent", including the ones which are not in
+    /// the local file tree yet because their directories have not been loaded.
+    listed_files: HashMap<Inode, Vec<drive3::File>>,
+
+    /// Whether to add the "Starred" directory.
+    starred_dir: bool,
+
+    /// How many files the "Recent" directory lists. If absent, there is no such directory.
+    recent_limit: Option<usize>,
+
+    /// The mode, owner and group of files which do not record their own on Drive.
+    pub attr_defaults: AttrDefaults,
+
+    /// The tokens from which the next sync continues. They only move forward once the changes
+    /// retrieved with them have been applied.
+    tokens: ChangesTokens,
+
+    /// Whether remote changes have been applied since the metadata cache was last written.
+    metadata_outdated: bool,
+
+    /// How many snapshots of the file tree have been taken for the metadata cache.
+    snapshots_taken: u64,
+
+    /// The number of the last snapshot which has been written to the metadata cache. Snapshots are
+    /// written without the manager being locked, so this keeps an older one from replacing a newer.
+    snapshot_written: Arc<Mutex<u64>>,
+
     last_inode: Inode,
 }
 
 impl FileManager {
-    /// Creates a new FileManager with a specific `sync_interval` and an injected `DriveFacade`.
+    /// Creates a new FileManager with a specific `sync_interval` and an injected `DriveBackend`.
     /// Also populates the manager's file tree with files contained in "My Drive" and "Trash".
-    pub fn with_drive_facade(sync_interval: Duration, df: DriveFacade) -> Self {
-        let mut manager = FileManager {
+    pub fn with_drive_backend<D: DriveBackend + 'static>(sync_interval: Duration, df: D) -> Self {
+        let mut manager = FileManager::empty(sync_interval, Arc::new(Mutex::new(df)));
+        manager.populate_all();
+        manager
+    }
+
+    /// Creates a new FileManager which is set up according to `config`. If a metadata cache is
+    /// configured, the file tree is restored from the snapshot saved there and only the changes
+    /// which happened since the snapshot was taken are retrieved from Drive. Falls back to
+    /// populating the tree if the snapshot is missing or its changes token is no longer accepted.
+    /// If the changes cannot be retrieved for any other reason (e.g. while offline), the snapshot
+    /// is used as it is.
+    pub fn with_config<D: DriveBackend + 'static>(config: &Config, df: D) -> Self {
+        let mut manager = FileManager::empty(config.sync_interval(), Arc::new(Mutex::new(df)));
+        manager.lazy = config.lazy_population();
+        manager.starred_dir = config.starred_dir();
+        if config.recent_dir() {
+            manager.recent_limit = Some(config.recent_dir_limit());
+        }
+        manager.attr_defaults = config.attr_defaults();
+        manager.metadata_cache = config.metadata_cache_path();
+
+        let cache_path = match manager.metadata_cache.clone() {
+            Some(path) => path,
+            None => {
+                manager.populate_all();
+                return manager;
+            }
+        };
+
+        let restored = Snapshot::load(&cache_path)
+            .and_then(|snapshot| manager.restore(snapshot))
+            .and_then(|_| match manager.apply_all_changes() {
+                // The changes since the snapshot was saved cannot be retrieved right now (e.g.
+                // while offline). A later sync retrieves them.
+                Err(ref e) if !FsError::is_expired_token(e) => {
+                    warn!("Could not update the restored file tree: {}", e);
+                    Ok(())
+                }
+                result => result,
+            });
+        match restored {
+            Ok(()) => info!("Restored file tree from {:?}", &cache_path),
+            Err(e) => {
+                info!("Could not restore file tree ({}). Will populate it instead.", e);
+                manager.clear();
+                manager.populate_all();
+            }
+        }
+
+        if let Err(e) = manager.save_metadata() {
+            warn!("Could not save metadata cache: {}", e);
+        }
+        manager
+    }
+
+    /// Creates a FileManager with an empty file tree.
+    fn empty(sync_interval: Duration, df: Arc<Mutex<dyn DriveBackend>>) -> Self {
+        FileManager {
             tree: TreeBuilder::new().with_node_capacity(500).build(),
             files: HashMap::new(),
             node_ids: HashMap::new(),
@@ -69,36 +189,175 @@ impl FileManager {
             last_sync: SystemTime::now(),
             sync_interval,
             df,
-            last_inode: 2,
-        };
+            metadata_cache: None,
+            lazy: false,
+            loaded_dirs: HashSet::new(),
+            listings: HashMap::new(),
+            listed_files: HashMap::new(),
+            starred_dir: false,
+            recent_limit: None,
+            attr_defaults: AttrDefaults::default(),
+            tokens: ChangesTokens::default(),
+            metadata_outdated: false,
+            snapshots_taken: 0,
+            snapshot_written: Arc::new(Mutex::new(0)),
+            last_inode: RECENT_INODE,
+        }
+    }
+
+    /// Removes all files from the local file tree and discards the changes tokens.
+    fn clear(&mut self) {
+        self.tree = TreeBuilder::new().with_node_capacity(500).build();
+        self.files.clear();
+        self.node_ids.clear();
+        self.drive_ids.clear();
+        self.loaded_dirs.clear();
+        self.listings.clear();
+        self.listed_files.clear();
+        self.last_inode = RECENT_INODE;
+        self.tokens = ChangesTokens::default();
+    }
+
+    /// Populates the file tree with files contained in "My Drive", "Trash", "Shared with me" and
+    /// the shared drives.
+    /// The changes tokens are obtained beforehand, so that changes made while populating are not
+    /// missed.
+    fn populate_all(&mut self) {
+        {
+            let mut df = self.df.lock().unwrap();
+            df.set_changes_token(None);
+            match df.changes_token() {
+                Ok(token) => self.tokens.changes = Some(token.clone()),
+                Err(e) => warn!("Could not get changes token: {}", e),
+            }
+        }
 
-        if let Err(e) = manager.populate() {
+        if let Err(e) = self.populate() {
             error!("Could not populate filesystem: {}", e);
         }
 
-        if let Err(e) = manager.populate_trash() {
+        if let Err(e) = self.populate_trash() {
             error!("Could not populate trash dir: {}", e);
         }
 
-        manager
+        if let Err(e) = self.populate_shared_with_me() {
+            error!("Could not populate shared with me dir: {}", e);
+        }
+
+        if let Err(e) = self.populate_shared_drives() {
+            error!("Could not populate shared drives: {}", e);
+        }
+
+        if let Err(e) = self.refresh_listings() {
+            error!("Could not populate starred and recent dirs: {}", e);
+        }
     }
 
-    /// Tries to retrieve recent changes from the `DriveFacade` and apply them locally in order to
+    /// Tries to retrieve recent changes from the `DriveBackend` and apply them locally in order to
     /// maintain data consistency. Fails early if not enough time has passed since the last sync.
     pub fn sync(&mut self) -> Result<(), Error> {
         if SystemTime::now().duration_since(self.last_sync).unwrap() < self.sync_interval {
-            return Err(err_msg(
-                "Not enough time has passed since last sync. Will do nothing.",
-            ));
+            return Err(FsError::Other(
+                "Not enough time has passed since last sync. Will do nothing.".to_string(),
+            ).into());
         }
 
         info!("Checking for changes and possibly applying them.");
         self.last_sync = SystemTime::now();
+        self.apply_all_changes()
+    }
+
+    /// Retrieves all changes from the `DriveBackend` and applies them locally. The changes of each
+    /// shared drive are retrieved separately, after bringing the list of shared drives up to date.
+    /// "Starred" and "Recent" are listed again if anything changed.
+    fn apply_all_changes(&mut self) -> Result<(), Error> {
+        let request = self.sync_request();
+        let remote = request.fetch(&mut *self.df.lock().unwrap())?;
+        self.apply_remote_changes(remote)?;
+        if let Err(e) = self.save_outdated_metadata() {
+            warn!("Could not save metadata cache: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Describes what a sync has to retrieve from Drive. The request can be fetched while the
+    /// manager is not locked, and the result is then applied by `apply_remote_changes()`.
+    pub fn sync_request(&self) -> SyncRequest {
+        let shared_drives = if self.contains(&FileId::Inode(SHARED_DRIVES_INODE)) {
+            Some(self.shared_drive_ids())
+        } else {
+            None
+        };
 
-        for change in self.df
-            .get_all_changes()?
+        let shared_with_me = if self.loaded_dirs.contains(&SHARED_WITH_ME_INODE) {
+            Some(self.shared_with_me_ids())
+        } else {
+            None
+        };
+
+        SyncRequest {
+            tokens: self.tokens.clone(),
+            shared_drives,
+            shared_with_me,
+            crawl: !self.lazy,
+            starred_dir: self.starred_dir,
+            recent_limit: self.recent_limit,
+            listings_missing: self.listings.is_empty(),
+        }
+    }
+
+    /// Applies what has been retrieved by a `SyncRequest`: "Shared with me" and the list of shared
+    /// drives are brought up to date before the changes are applied, "Starred" and "Recent"
+    /// afterwards. The changes tokens only move forward if all of this succeeds. If anything
+    /// changed, the metadata cache is marked as outdated, but it is not written here: see
+    /// `outdated_metadata()`. Does not communicate with Drive.
+    pub fn apply_remote_changes(&mut self, remote: RemoteChanges) -> Result<(), Error> {
+        let changed = !remote.changes.is_empty() || !remote.replaced_ids.is_empty();
+
+        for (old_id, new_id) in remote.replaced_ids {
+            self.replace_drive_id(&old_id, new_id);
+        }
+        let mut trees = remote.trees;
+        if let Some(drives) = remote.shared_drives {
+            self.refresh_shared_drives(drives, &mut trees)?;
+        }
+        self.apply_changes_locally(remote.changes)?;
+        // What is left are the contents of the newly shared folders, which have just been added.
+        for (drive_id, tree) in trees {
+            if self.contains(&FileId::DriveId(drive_id)) {
+                self.add_tree(tree)?;
+            }
+        }
+        if let Some(listings) = remote.listings {
+            self.set_listings(listings)?;
+        }
+
+        self.tokens = remote.tokens;
+        self.metadata_outdated |= changed;
+        Ok(())
+    }
+
+    /// Makes a file which is known by a temporary Drive ID refer to its real Drive ID. Does not
+    /// communicate with Drive.
+    fn replace_drive_id(&mut self, old_id: &str, new_id: DriveId) {
+        let inode = match self.drive_ids.remove(old_id) {
+            Some(inode) => inode,
+            None => return,
+        };
+
+        debug!("{} is now known as {}", old_id, &new_id);
+        if let Some(file) = self.files.get_mut(&inode) {
+            file.set_drive_id(new_id.clone());
+        }
+        self.drive_ids.insert(new_id, inode);
+    }
+
+    /// Applies a list of changes reported by Drive on the local file tree. Does not communicate
+    /// with Drive.
+    fn apply_changes_locally(&mut self, changes: Vec<drive3::Change>) -> Result<(), Error> {
+        for change in changes
             .into_iter()
-            .filter(|change| (&change).file.is_some())
+            .filter(|change| change.file.is_some())
         {
             debug!("Processing a change from {:?}", &change.time);
             let id = FileId::DriveId(change.file_id.unwrap());
@@ -106,13 +365,29 @@ impl FileManager {
 
             // New file. Create it locally
             if !self.contains(&id) {
+                let parent = match self.local_parent_of(&drive_f) {
+                    Some(ref parent) if !self.lazy || self.is_loaded(parent) => parent.clone(),
+                    _ => {
+                        debug!("New file in a directory which is not known or loaded. Skip it.");
+                        continue;
+                    }
+                };
+
                 debug!("New file. Create it locally");
-                let f = File::from_drive_file(self.next_available_inode(), drive_f.clone());
+                let f = File::from_drive_file(
+                    self.next_available_inode(),
+                    drive_f.clone(),
+                    self.attr_defaults,
+                );
                 debug!("newly created file: {:#?}", &f);
-
-                let parent = f.drive_parent().unwrap();
                 debug!("drive parent: {:#?}", &parent);
-                self.add_file_locally(f, Some(FileId::DriveId(parent)))?;
+
+                let is_dir = f.kind() == FileType::Directory;
+                let inode = f.inode();
+                self.add_file_locally(f, Some(parent))?;
+                if is_dir && !self.lazy {
+                    self.loaded_dirs.insert(inode);
+                }
                 debug!("self.add_file_locally() finished");
             }
 
@@ -138,11 +413,25 @@ impl FileManager {
 
             // Anything else: reconstruct the file locally and move it under its parent.
             debug!("Anything else: reconstruct the file locally and move it under its parent.");
-            let new_parent = {
-                let mut f = unwrap_or_continue!(self.get_mut_file(&id));
-                *f = File::from_drive_file(f.inode(), drive_f.clone());
-                FileId::DriveId(f.drive_parent().unwrap())
+            // Files listed by "Starred" or "Recent" are kept even if their directory is not loaded.
+            let keep = !self.lazy || self.get_inode(&id).is_some_and(|inode| self.is_listed(inode));
+            let new_parent = match self.local_parent_of(&drive_f) {
+                Some(ref parent) if keep || self.is_loaded(parent) => parent.clone(),
+                _ => {
+                    debug!("File moved to a directory which is not known or loaded. Forget it.");
+                    let result = self.delete_locally(&id);
+                    if result.is_err() {
+                        error!("Could not delete locally: {:?}", result)
+                    }
+                    continue;
+                }
             };
+
+            {
+                let defaults = self.attr_defaults;
+                let f = unwrap_or_continue!(self.get_mut_file(&id));
+                *f = File::from_drive_file(f.inode(), drive_f.clone(), defaults);
+            }
             let result = self.move_locally(&id, &new_parent);
             if result.is_err() {
                 error!("Could not move locally: {:?}", result)
@@ -152,40 +441,166 @@ impl FileManager {
         Ok(())
     }
 
+    /// Saves the file tree and the current changes token to the metadata cache, if there is one.
+    pub fn save_metadata(&mut self) -> Result<(), Error> {
+        match self.metadata_snapshot()? {
+            Some(snapshot) => snapshot.save(),
+            None => Ok(()),
+        }
+    }
+
+    /// Saves the metadata cache if remote changes have been applied since it was last written.
+    fn save_outdated_metadata(&mut self) -> Result<(), Error> {
+        match self.outdated_metadata()? {
+            Some(snapshot) => snapshot.save(),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes a snapshot of the file tree if remote changes have been applied since the metadata
+    /// cache was last written. Writing a large tree takes a while, so the snapshot is meant to be
+    /// saved once the manager is no longer locked.
+    pub fn outdated_metadata(&mut self) -> Result<Option<MetadataSnapshot>, Error> {
+        if !self.metadata_outdated {
+            return Ok(None);
+        }
+        self.metadata_snapshot()
+    }
+
+    /// Takes a snapshot of the file tree and the current changes tokens for the metadata cache, if
+    /// there is one.
+    fn metadata_snapshot(&mut self) -> Result<Option<MetadataSnapshot>, Error> {
+        let path = match self.metadata_cache {
+            Some(ref path) => path.clone(),
+            None => return Ok(None),
+        };
+
+        let changes_token = self.tokens
+            .changes
+            .clone()
+            .ok_or_else(|| FsError::Other("No changes token has been obtained yet".to_string()))?;
+        let mut snapshot = Snapshot::new(changes_token, self.last_inode);
+        snapshot.loaded_dirs = self.loaded_dirs.iter().cloned().collect();
+        snapshot.drive_changes_tokens = self.tokens.drives.clone();
+
+        if let Some(root) = self.tree.root_node_id() {
+            for node in self.tree.traverse_pre_order(root)? {
+                let parent = node.parent()
+                    .and_then(|parent| self.tree.get(parent).ok())
+                    .map(|parent| *parent.data());
+                let file = unwrap_or_continue!(self.files.get(node.data()));
+                snapshot.entries.push(SnapshotEntry::new(file, parent));
+            }
+        }
+
+        self.metadata_outdated = false;
+        self.snapshots_taken += 1;
+        Ok(Some(MetadataSnapshot {
+            path,
+            snapshot,
+            number: self.snapshots_taken,
+            written: Arc::clone(&self.snapshot_written),
+        }))
+    }
+
+    /// Rebuilds the file tree from a snapshot and continues from its changes token.
+    fn restore(&mut self, snapshot: Snapshot) -> Result<(), Error> {
+        for entry in snapshot.entries {
+            let parent = entry.parent;
+            let mut file = entry.into_file()?;
+            // The defaults might have been configured differently since the snapshot was taken.
+            file.load_posix_attrs(self.attr_defaults);
+
+            let node_id = match parent {
+                Some(parent) => {
+                    let parent_id = self.get_node_id(&FileId::Inode(parent)).ok_or_else(|| {
+                        FsError::Other(format!(
+                            "Snapshot lists inode {} before its parent",
+                            file.inode()
+                        ))
+                    })?;
+                    self.tree
+                        .insert(Node::new(file.inode()), UnderNode(&parent_id))?
+                }
+                None => self.tree.insert(Node::new(file.inode()), AsRoot)?,
+            };
+
+            self.node_ids.insert(file.inode(), node_id);
+            file.drive_id()
+                .and_then(|drive_id| self.drive_ids.insert(drive_id, file.inode()));
+            self.files.insert(file.inode(), file);
+        }
+
+        self.last_inode = snapshot.last_inode;
+        self.loaded_dirs = snapshot.loaded_dirs.into_iter().collect();
+        self.tokens = ChangesTokens {
+            changes: Some(snapshot.changes_token),
+            drives: snapshot.drive_changes_tokens,
+        };
+        Ok(())
+    }
+
     /// Retrieves all files and directories shown in "My Drive" and adds them locally.
     fn populate(&mut self) -> Result<(), Error> {
         let root = self.new_root_file()?;
         self.add_file_locally(root, None)?;
 
-        let mut queue: LinkedList<DriveId> = LinkedList::new();
-        queue.push_back(self.df.root_id().unwrap_or(&"root".to_string()).to_string());
+        if self.lazy {
+            return Ok(());
+        }
+        self.loaded_dirs.insert(ROOT_INODE);
 
-        while !queue.is_empty() {
-            let mut parents = Vec::new();
-            while !queue.is_empty() {
-                parents.push(queue.pop_front().unwrap());
+        let root_id = self.df
+            .lock()
+            .unwrap()
+            .root_id()
+            .map(|id| id.to_string())
+            .unwrap_or(String::from("root"));
+        self.crawl(root_id, None)
+    }
+
+    /// Retrieves all files and directories under the directory `parent` and adds them locally.
+    /// `team_drive` is the shared drive which the directory belongs to, if any.
+    fn crawl(&mut self, parent: DriveId, team_drive: Option<DriveId>) -> Result<(), Error> {
+        let drive_files = fetch_tree(&mut *self.df.lock().unwrap(), parent, team_drive.as_ref())?;
+        self.add_tree(drive_files)
+    }
+
+    /// Adds the files retrieved by `fetch_tree()` locally. Their directories are marked as loaded.
+    fn add_tree(&mut self, drive_files: Vec<drive3::File>) -> Result<(), Error> {
+        for drive_file in drive_files {
+            // A file which has been shared on its own might also be found among the children of a
+            // shared folder.
+            let known = drive_file
+                .id
+                .as_ref()
+                .map(|id| self.drive_ids.contains_key(id));
+            if known == Some(true) {
+                continue;
             }
 
-            for drive_file in self.df.get_all_files(Some(parents), Some(false))? {
-                let mut file = File::from_drive_file(self.next_available_inode(), drive_file);
+            let file = File::from_drive_file(
+                self.next_available_inode(),
+                drive_file,
+                self.attr_defaults,
+            );
 
-                if file.kind() == FileType::Directory {
-                    queue.push_back(file.drive_id().unwrap());
-                }
+            if file.kind() == FileType::Directory {
+                self.loaded_dirs.insert(file.inode());
+            }
 
-                // TODO: this makes everything slow; find a better solution
-                // if file.is_drive_document() {
-                //     let size = drive_facade
-                //         .get_file_size(file.drive_id().as_ref().unwrap(), file.mime_type());
-                //     file.attr.size = size;
-                // }
-
-                let file_parent = file.drive_parent().unwrap();
-                if self.contains(&FileId::DriveId(file_parent.clone())) {
-                    self.add_file_locally(file, Some(FileId::DriveId(file_parent.clone())))?;
-                } else {
-                    self.add_file_locally(file, None)?;
-                }
+            // TODO: this makes everything slow; find a better solution
+            // if file.is_drive_document() {
+            //     let size = drive_facade
+            //         .get_file_size(file.drive_id().as_ref().unwrap(), file.mime_type());
+            //     file.attr.size = size;
+            // }
+
+            let file_parent = file.drive_parent().unwrap();
+            if self.contains(&FileId::DriveId(file_parent.clone())) {
+                self.add_file_locally(file, Some(FileId::DriveId(file_parent.clone())))?;
+            } else {
+                self.add_file_locally(file, None)?;
             }
         }
 
@@ -194,15 +609,400 @@ impl FileManager {
 
     /// Retrieves all trashed files and directories and adds them locally in a special directory.
     fn populate_trash(&mut self) -> Result<(), Error> {
-        let root_id = self.df.root_id()?.to_string();
+        let root_id = self.df.lock().unwrap().root_id()?.to_string();
         let trash = self.new_special_dir("Trash", Some(TRASH_INODE));
         self.add_file_locally(trash.clone(), Some(FileId::DriveId(root_id.to_string())))?;
 
-        for drive_file in self.df.get_all_files(None, Some(true))? {
-            let mut file = File::from_drive_file(self.next_available_inode(), drive_file);
+        if self.lazy {
+            return Ok(());
+        }
+
+        let drive_files = self.df.lock().unwrap().get_all_files(None, Some(true))?;
+        for drive_file in drive_files {
+            let file = File::from_drive_file(
+                self.next_available_inode(),
+                drive_file,
+                self.attr_defaults,
+            );
             self.add_file_locally(file, Some(FileId::Inode(trash.inode())))?;
         }
+        self.loaded_dirs.insert(TRASH_INODE);
+
+        Ok(())
+    }
+
+    /// Adds the special directory which holds the files that others have shared with the account.
+    /// Unless population is lazy, the shared files are added as well, along with the contents of
+    /// the shared folders.
+    fn populate_shared_with_me(&mut self) -> Result<(), Error> {
+        let root_id = self.df.lock().unwrap().root_id()?.to_string();
+        let dir = self.new_special_dir(SHARED_WITH_ME_DIR_NAME, Some(SHARED_WITH_ME_INODE));
+        self.add_file_locally(dir, Some(FileId::DriveId(root_id)))?;
+
+        if self.lazy {
+            return Ok(());
+        }
+
+        let mut trees = HashMap::new();
+        let drive_files = fetch_shared_with_me(&mut *self.df.lock().unwrap(), &mut trees)?;
+        self.add_shared_with_me(drive_files, &mut trees)
+    }
+
+    /// Adds `drive_files`, the files which are shared with the account, to "Shared with me", along
+    /// with the files of the shared folders from `trees`. Afterwards, files which are shared or
+    /// unshared are found among the changes. Does not communicate with Drive.
+    fn add_shared_with_me(
+        &mut self,
+        drive_files: Vec<drive3::File>,
+        trees: &mut HashMap<DriveId, Vec<drive3::File>>,
+    ) -> Result<(), Error> {
+        for drive_file in drive_files {
+            let drive_id = unwrap_or_continue!(drive_file.id.clone());
+            if self.drive_ids.contains_key(&drive_id) {
+                continue;
+            }
+
+            let file = File::from_drive_file(
+                self.next_available_inode(),
+                drive_file,
+                self.attr_defaults,
+            );
+            let tree = trees.remove(&drive_id);
+            if tree.is_some() {
+                self.loaded_dirs.insert(file.inode());
+            }
+            self.add_file_locally(file, Some(FileId::Inode(SHARED_WITH_ME_INODE)))?;
+
+            if let Some(tree) = tree {
+                self.add_tree(tree)?;
+            }
+        }
+
+        self.loaded_dirs.insert(SHARED_WITH_ME_INODE);
+        Ok(())
+    }
+
+    /// The directory in which a Drive file belongs in the local file tree: its parent if that is
+    /// known, otherwise "Shared with me" if the file has been shared with the account. `None` if
+    /// the file has no place in the file tree.
+    fn local_parent_of(&self, drive_file: &drive3::File) -> Option<FileId> {
+        if let Some(parent) = File::drive_parent_of(drive_file) {
+            let parent = FileId::DriveId(parent);
+            if self.contains(&parent) {
+                return Some(parent);
+            }
+        }
+
+        let shared_dir = FileId::Inode(SHARED_WITH_ME_INODE);
+        if drive_file.shared_with_me_time.is_some() && self.contains(&shared_dir) {
+            return Some(shared_dir);
+        }
+        None
+    }
+
+    /// Brings the "Starred" and "Recent" directories up to date. They are added or removed if they
+    /// have been enabled or disabled since the file tree was saved.
+    fn refresh_listings(&mut self) -> Result<(), Error> {
+        let listings = {
+            let mut df = self.df.lock().unwrap();
+            fetch_listings(&mut *df, self.starred_dir, self.recent_limit)?
+        };
+        self.set_listings(listings)
+    }
+
+    /// Makes "Starred" and "Recent" list the files retrieved by `fetch_listings()`. Does not
+    /// communicate with Drive.
+    fn set_listings(&mut self, listings: Listings) -> Result<(), Error> {
+        self.set_listing(STARRED_INODE, STARRED_DIR_NAME, listings.starred)?;
+        self.set_listing(RECENT_INODE, RECENT_DIR_NAME, listings.recent)
+    }
+
+    /// Makes the special directory `inode` list `drive_files` in order, or removes it if they are
+    /// absent. The listed files keep their inodes, so only the ones which are in the local file
+    /// tree can be listed; the others are loaded by a `ChildrenRequest` when the directory is read.
+    fn set_listing(
+        &mut self,
+        inode: Inode,
+        name: &str,
+        drive_files: Option<Vec<drive3::File>>,
+    ) -> Result<(), Error> {
+        let dir = FileId::Inode(inode);
+        let drive_files = match drive_files {
+            Some(drive_files) => drive_files,
+            None => {
+                self.listings.remove(&inode);
+                self.listed_files.remove(&inode);
+                if self.contains(&dir) {
+                    self.delete_locally(&dir)?;
+                }
+                return Ok(());
+            }
+        };
+
+        if !self.contains(&dir) {
+            let file = self.new_special_dir(name, Some(inode));
+            self.add_file_locally(file, Some(FileId::Inode(ROOT_INODE)))?;
+            self.loaded_dirs.insert(inode);
+        }
+
+        self.listed_files.insert(inode, drive_files);
+        self.resolve_listing(inode);
+        Ok(())
+    }
+
+    /// Lists the files of `listed_files` which are in the local file tree under the special
+    /// directory `inode`. Of several files with the same name, only the first one is listed.
+    fn resolve_listing(&mut self, inode: Inode) {
+        let drive_files = match self.listed_files.get(&inode) {
+            Some(drive_files) => drive_files,
+            None => return,
+        };
+
+        let mut names = HashSet::new();
+        let listed: Vec<Inode> = drive_files
+            .iter()
+            .filter_map(|drive_file| drive_file.id.as_ref())
+            .filter_map(|drive_id| self.drive_ids.get(drive_id))
+            .filter(|inode| match self.files.get(*inode) {
+                Some(file) => names.insert(file.name()),
+                None => false,
+            })
+            .cloned()
+            .collect();
+        self.listings.insert(inode, listed);
+    }
+
+    /// Whether "Starred" or "Recent" lists the file `inode`.
+    fn is_listed(&self, inode: Inode) -> bool {
+        self.listings.values().any(|listed| listed.contains(&inode))
+    }
+
+    /// Adds the special directory which holds the shared drives, then adds a directory for each of
+    /// them.
+    fn populate_shared_drives(&mut self) -> Result<(), Error> {
+        let root_id = self.df.lock().unwrap().root_id()?.to_string();
+        let dir = self.new_special_dir(SHARED_DRIVES_DIR_NAME, Some(SHARED_DRIVES_INODE));
+        self.add_file_locally(dir, Some(FileId::DriveId(root_id)))?;
+        self.loaded_dirs.insert(SHARED_DRIVES_INODE);
+
+        let mut trees = HashMap::new();
+        let drives = fetch_shared_drives(
+            &mut *self.df.lock().unwrap(),
+            &[],
+            !self.lazy,
+            &mut trees,
+            &mut self.tokens.drives,
+        )?;
+        self.refresh_shared_drives(drives, &mut trees)
+    }
+
+    /// Makes the directories in "Shared drives" match `drives`, the shared drives which the
+    /// account is a member of, as retrieved by `fetch_shared_drives()`. New drives are populated
+    /// with their files from `trees` (unless population is lazy), renamed ones are renamed and the
+    /// ones which are no longer available are removed locally. Does not communicate with Drive.
+    fn refresh_shared_drives(
+        &mut self,
+        drives: Vec<drive3::TeamDrive>,
+        trees: &mut HashMap<DriveId, Vec<drive3::File>>,
+    ) -> Result<(), Error> {
+        let known = self.shared_drive_ids();
+
+        for drive_id in &known {
+            if !drives.iter().any(|drive| drive.id.as_ref() == Some(drive_id)) {
+                info!("Shared drive {} is no longer available", drive_id);
+                self.tokens.drives.remove(drive_id);
+                self.delete_locally(&FileId::DriveId(drive_id.clone()))?;
+            }
+        }
+
+        for drive in drives {
+            let drive_id = unwrap_or_continue!(drive.id.clone());
+            let drive_file = shared_drive_file(drive);
+            if known.contains(&drive_id) {
+                let file = unwrap_or_continue!(self.get_mut_file(&FileId::DriveId(drive_id)));
+                file.name = drive_file.name.clone().unwrap_or(file.name.clone());
+                file.drive_file = Some(drive_file);
+                continue;
+            }
+
+            let file = File::from_drive_file(
+                self.next_available_inode(),
+                drive_file,
+                self.attr_defaults,
+            );
+            let tree = trees.remove(&drive_id);
+            if tree.is_some() {
+                self.loaded_dirs.insert(file.inode());
+            }
+            self.add_file_locally(file, Some(FileId::Inode(SHARED_DRIVES_INODE)))?;
+
+            if let Some(tree) = tree {
+                self.add_tree(tree)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The IDs of the files which are listed in "Shared with me".
+    fn shared_with_me_ids(&self) -> Vec<DriveId> {
+        self.get_children(&FileId::Inode(SHARED_WITH_ME_INODE))
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|file| file.drive_id())
+            .collect()
+    }
+
+    /// The IDs of the shared drives which have a directory in "Shared drives".
+    fn shared_drive_ids(&self) -> Vec<DriveId> {
+        self.get_children(&FileId::Inode(SHARED_DRIVES_INODE))
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|dir| dir.drive_id())
+            .collect()
+    }
+
+    /// Whether the children of a directory have been retrieved.
+    pub fn is_loaded(&self, id: &FileId) -> bool {
+        self.get_inode(id)
+            .map(|inode| self.loaded_dirs.contains(&inode))
+            .unwrap_or(false)
+    }
 
+    /// Retrieves the children of a directory and adds them locally, unless this has been done
+    /// before. Children which are already known (e.g. because they have been created locally) are
+    /// not added twice.
+    pub fn load_children(&mut self, id: &FileId) -> Result<(), Error> {
+        if let Some(request) = self.children_request(id)? {
+            let drive_files = request.fetch(&mut *self.df.lock().unwrap())?;
+            self.add_children(request.inode, drive_files)?;
+        }
+        Ok(())
+    }
+
+    /// Describes how the children of a directory can be retrieved from Drive. Returns `None` if
+    /// `id` is not a directory or if its children have already been loaded.
+    pub fn children_request(&self, id: &FileId) -> Result<Option<ChildrenRequest>, Error> {
+        let file = self.get_file(id)
+            .ok_or_else(|| not_found(id))?;
+
+        if let Some(drive_files) = self.listed_files.get(&file.inode()) {
+            return Ok(self.listing_request(file.inode(), drive_files));
+        }
+
+        if file.kind() != FileType::Directory || self.loaded_dirs.contains(&file.inode()) {
+            return Ok(None);
+        }
+
+        Ok(Some(ChildrenRequest {
+            inode: file.inode(),
+            drive_id: file.drive_id(),
+            team_drive_id: file.drive_file.as_ref().and_then(|f| f.team_drive_id.clone()),
+            missing: Vec::new(),
+            known_dirs: HashSet::new(),
+        }))
+    }
+
+    /// Describes how the files listed by "Starred" or "Recent" which are not in the local file
+    /// tree can be retrieved, along with the directories which lead to them. `None` if there are
+    /// no such files.
+    fn listing_request(
+        &self,
+        inode: Inode,
+        drive_files: &[drive3::File],
+    ) -> Option<ChildrenRequest> {
+        let missing: Vec<drive3::File> = drive_files
+            .iter()
+            .filter(|f| f.id.as_ref().is_some_and(|id| !self.drive_ids.contains_key(id)))
+            .cloned()
+            .collect();
+        if missing.is_empty() {
+            return None;
+        }
+
+        let known_dirs = self.files
+            .values()
+            .filter(|file| file.kind() == FileType::Directory)
+            .filter_map(|file| file.drive_id())
+            .collect();
+        Some(ChildrenRequest {
+            inode,
+            drive_id: None,
+            team_drive_id: None,
+            missing,
+            known_dirs,
+        })
+    }
+
+    /// Adds the children of a directory, as retrieved by a `ChildrenRequest`, to the local file
+    /// tree and marks the directory as loaded. Does not communicate with Drive.
+    pub fn add_children(
+        &mut self,
+        inode: Inode,
+        drive_files: Vec<drive3::File>,
+    ) -> Result<(), Error> {
+        if self.listed_files.contains_key(&inode) {
+            return self.add_listed_files(inode, drive_files);
+        }
+        if self.loaded_dirs.contains(&inode) {
+            return Ok(());
+        }
+
+        debug!("Loaded {} children of inode {}", drive_files.len(), inode);
+        for drive_file in drive_files {
+            let known = drive_file
+                .id
+                .as_ref()
+                .map(|id| self.drive_ids.contains_key(id));
+            if known == Some(true) {
+                continue;
+            }
+
+            let file = File::from_drive_file(
+                self.next_available_inode(),
+                drive_file,
+                self.attr_defaults,
+            );
+            self.add_file_locally(file, Some(FileId::Inode(inode)))?;
+        }
+
+        self.loaded_dirs.insert(inode);
+        Ok(())
+    }
+
+    /// Adds the files listed by "Starred" or "Recent" and the directories which lead to them, as
+    /// retrieved by a `ChildrenRequest`, under their parents in the local file tree. The
+    /// directories are not marked as loaded, since only some of their children are known. Files
+    /// which still have no place in the tree are no longer listed until the next sync.
+    fn add_listed_files(
+        &mut self,
+        inode: Inode,
+        drive_files: Vec<drive3::File>,
+    ) -> Result<(), Error> {
+        debug!("Loaded {} files listed by inode {}", drive_files.len(), inode);
+        for drive_file in drive_files {
+            let known = drive_file
+                .id
+                .as_ref()
+                .map(|id| self.drive_ids.contains_key(id));
+            if known != Some(false) {
+                continue;
+            }
+
+            let parent = unwrap_or_continue!(self.local_parent_of(&drive_file));
+            let file = File::from_drive_file(
+                self.next_available_inode(),
+                drive_file,
+                self.attr_defaults,
+            );
+            self.add_file_locally(file, Some(parent))?;
+        }
+
+        let drive_ids = &self.drive_ids;
+        if let Some(drive_files) = self.listed_files.get_mut(&inode) {
+            drive_files.retain(|f| f.id.as_ref().is_some_and(|id| drive_ids.contains_key(id)));
+        }
+        self.resolve_listing(inode);
         Ok(())
     }
 
@@ -211,9 +1011,13 @@ impl FileManager {
     fn new_root_file(&mut self) -> Result<File, Error> {
         let mut drive_file = drive3::File::default();
 
-        let fallback_id = String::from("root");
-        let root_id = self.df.root_id().unwrap_or(&fallback_id);
-        drive_file.id = Some(root_id.to_string());
+        let root_id = self.df
+            .lock()
+            .unwrap()
+            .root_id()
+            .map(|id| id.to_string())
+            .unwrap_or(String::from("root"));
+        drive_file.id = Some(root_id);
 
         Ok(File {
             name: String::from("."),
@@ -226,10 +1030,10 @@ impl FileManager {
                 ctime: Timespec { sec: 0, nsec: 0 },
                 crtime: Timespec { sec: 0, nsec: 0 },
                 kind: FileType::Directory,
-                perm: 0o755,
+                perm: self.attr_defaults.dir_mode,
                 nlink: 2,
-                uid: 0,
-                gid: 0,
+                uid: self.attr_defaults.uid,
+                gid: self.attr_defaults.gid,
                 rdev: 0,
                 flags: 0,
             },
@@ -252,10 +1056,10 @@ impl FileManager {
                 ctime: Timespec { sec: 0, nsec: 0 },
                 crtime: Timespec { sec: 0, nsec: 0 },
                 kind: FileType::Directory,
-                perm: 0o755,
+                perm: self.attr_defaults.dir_mode,
                 nlink: 2,
-                uid: 0,
-                gid: 0,
+                uid: self.attr_defaults.uid,
+                gid: self.attr_defaults.gid,
                 rdev: 0,
                 flags: 0,
             },
@@ -272,22 +1076,22 @@ impl FileManager {
 
     pub fn contains(&self, file_id: &FileId) -> bool {
         match file_id {
-            FileId::Inode(inode) => self.node_ids.contains_key(&inode),
+            FileId::Inode(inode) => self.node_ids.contains_key(inode),
             FileId::DriveId(drive_id) => self.drive_ids.contains_key(drive_id),
-            FileId::NodeId(node_id) => self.tree.get(&node_id).is_ok(),
-            pn @ FileId::ParentAndName { .. } => self.get_file(&pn).is_some(),
+            FileId::NodeId(node_id) => self.tree.get(node_id).is_ok(),
+            pn @ FileId::ParentAndName { .. } => self.get_file(pn).is_some(),
         }
     }
 
     pub fn get_node_id(&self, file_id: &FileId) -> Option<NodeId> {
         match file_id {
-            FileId::Inode(inode) => self.node_ids.get(&inode).cloned(),
+            FileId::Inode(inode) => self.node_ids.get(inode).cloned(),
             FileId::DriveId(drive_id) => self.get_node_id(&FileId::Inode(self.get_inode(
                 &FileId::DriveId(drive_id.to_string()),
             ).unwrap())),
             FileId::NodeId(node_id) => Some(node_id.clone()),
-            ref pn @ FileId::ParentAndName { .. } => {
-                let inode = self.get_inode(&pn)?;
+            pn => {
+                let inode = self.get_inode(pn)?;
                 self.get_node_id(&FileId::Inode(inode))
             }
         }
@@ -302,7 +1106,7 @@ impl FileManager {
             FileId::Inode(inode) => Some(*inode),
             FileId::DriveId(drive_id) => self.drive_ids.get(drive_id).cloned(),
             FileId::NodeId(node_id) => self.tree
-                .get(&node_id)
+                .get(node_id)
                 .map(|node| node.data())
                 .ok()
                 .cloned(),
@@ -317,13 +1121,15 @@ impl FileManager {
     }
 
     pub fn get_children(&self, id: &FileId) -> Option<Vec<&File>> {
-        let node_id = self.get_node_id(&id)?;
+        let node_id = self.get_node_id(id)?;
+        if let Some(listed) = self.get_inode(id).and_then(|inode| self.listings.get(&inode)) {
+            return Some(listed.iter().filter_map(|inode| self.files.get(inode)).collect());
+        }
+
         let children: Vec<&File> = self.tree
             .children(&node_id)
             .unwrap()
-            .map(|child| self.get_file(&FileId::Inode(*child.data())))
-            .filter(Option::is_some)
-            .map(Option::unwrap)
+            .filter_map(|child| self.get_file(&FileId::Inode(*child.data())))
             .collect();
 
         Some(children)
@@ -335,38 +1141,83 @@ impl FileManager {
     }
 
     pub fn get_mut_file(&mut self, id: &FileId) -> Option<&mut File> {
-        let inode = self.get_inode(&id)?;
+        let inode = self.get_inode(id)?;
         self.files.get_mut(&inode)
     }
 
     /// Creates a file on Drive and adds it to the local file tree.
     pub fn create_file(&mut self, mut file: File, parent: Option<FileId>) -> Result<(), Error> {
-        let drive_id = self.df.create(file.drive_file.as_ref().unwrap())?;
+        let drive_id = self.df
+            .lock()
+            .unwrap()
+            .create(file.drive_file.as_ref().unwrap())?;
         file.set_drive_id(drive_id);
+
+        self.add_created_file(file, parent)
+    }
+
+    /// Adds a file which has just been created on Drive to the local file tree. Does not
+    /// communicate with Drive.
+    pub fn add_created_file(&mut self, file: File, parent: Option<FileId>) -> Result<(), Error> {
+        // A new directory has no children which could be retrieved later.
+        if file.kind() == FileType::Directory {
+            self.loaded_dirs.insert(file.inode());
+        }
         self.add_file_locally(file, parent)?;
 
         Ok(())
     }
 
-    /// Passes along the FLUSH system call to the `DriveFacade`.
+    /// Passes along the FLUSH system call to the `DriveBackend`.
     pub fn flush(&mut self, id: &FileId) -> Result<(), Error> {
-        let file = self.get_drive_id(&id)
-            .ok_or(err_msg(format!("Cannot find drive id of {:?}", &id)))?;
-        self.df.flush(&file)
+        let file = self.get_drive_id(id)
+            .ok_or_else(|| not_found(id))?;
+        self.df.lock().unwrap().flush(&file)
+    }
+
+    /// Flushes every file which has pending writes, logging the progress. Returns how many of them
+    /// could not be flushed.
+    pub fn flush_all(&mut self) -> usize {
+        self.flush_all_tracked(&Mutex::new(Vec::new()))
+    }
+
+    /// Like `flush_all()`, but keeps `remaining` up to date with the Drive IDs of the files which
+    /// have not been flushed, so that they can be told from another thread while flushing is still
+    /// in progress. The ones which could not be flushed are left in it.
+    pub fn flush_all_tracked(&mut self, remaining: &Mutex<Vec<DriveId>>) -> usize {
+        let dirty = self.df.lock().unwrap().dirty_files();
+        *remaining.lock().unwrap() = dirty.clone();
+        if dirty.is_empty() {
+            return 0;
+        }
+
+        info!("Flushing {} files with pending writes...", dirty.len());
+        let mut failed = 0;
+        for (i, id) in dirty.iter().enumerate() {
+            let result = self.df.lock().unwrap().flush(id);
+            match result {
+                Ok(()) => {
+                    remaining.lock().unwrap().retain(|other| other != id);
+                    info!("Flushed {} ({}/{})", id, i + 1, dirty.len());
+                }
+                Err(e) => {
+                    error!("Could not flush {}: {}", id, e);
+                    failed += 1;
+                }
+            }
+        }
+
+        failed
     }
 
     /// Adds a file to the local file tree. Does not communicate with Drive.
     fn add_file_locally(&mut self, mut file: File, parent: Option<FileId>) -> Result<(), Error> {
         let node_id = match parent {
             Some(id) => {
-                let parent_id = self.get_node_id(&id).ok_or(err_msg(
-                    "FileManager::add_file_locally() could not find parent by FileId",
-                ))?;
+                let parent_id = self.get_node_id(&id).ok_or_else(|| not_found(&id))?;
 
                 let identical_filename_count = self.get_children(&id)
-                    .ok_or(err_msg(
-                        "FileManager::add_file_locally() could not get file siblings",
-                    ))?
+                    .ok_or_else(|| not_found(&id))?
                     .iter()
                     .filter(|child| child.name == file.name)
                     .count();
@@ -391,60 +1242,59 @@ impl FileManager {
 
     /// Moves a file somewhere else in the local file tree. Does not communicate with Drive.
     fn move_locally(&mut self, id: &FileId, new_parent: &FileId) -> Result<(), Error> {
-        let current_node = self.get_node_id(&id)
-            .ok_or(err_msg(format!("Cannot find node_id of {:?}", &id)))?;
-        let target_node = self.get_node_id(&new_parent)
-            .ok_or(err_msg("Target node doesn't exist"))?;
+        let current_node = self.get_node_id(id)
+            .ok_or_else(|| not_found(id))?;
+        let target_node = self.get_node_id(new_parent)
+            .ok_or_else(|| not_found(new_parent))?;
 
         self.tree.move_node(&current_node, ToParent(&target_node))?;
         Ok(())
     }
 
     /// Deletes a file and its children from the local file tree. Does not communicate with Drive.
-    fn delete_locally(&mut self, id: &FileId) -> Result<(), Error> {
+    pub fn delete_locally(&mut self, id: &FileId) -> Result<(), Error> {
         let node_id = self.get_node_id(id)
-            .ok_or(err_msg(format!("Cannot find node_id of {:?}", &id)))?;
+            .ok_or_else(|| not_found(id))?;
         let inode = self.get_inode(id)
-            .ok_or(err_msg(format!("Cannot find inode of {:?}", &id)))?;
-        let drive_id = self.get_drive_id(id)
-            .ok_or(err_msg(format!("Cannot find drive id of {:?}", &id)))?;
+            .ok_or_else(|| not_found(id))?;
+        // Special directories do not have a drive id.
+        if let Some(drive_id) = self.get_drive_id(id) {
+            self.drive_ids.remove(&drive_id);
+        }
 
         self.tree.remove_node(node_id, DropChildren)?;
         self.files.remove(&inode);
         self.node_ids.remove(&inode);
-        self.drive_ids.remove(&drive_id);
+        self.loaded_dirs.remove(&inode);
 
         Ok(())
     }
 
     /// Deletes a file locally *and* on Drive.
     pub fn delete(&mut self, id: &FileId) -> Result<(), Error> {
-        let drive_id = self.get_drive_id(id).ok_or(err_msg("No such file"))?;
+        let drive_id = self.get_drive_id(id).ok_or_else(|| not_found(id))?;
 
         self.delete_locally(id)?;
-        match self.df.delete_permanently(&drive_id) {
-            Ok(response) => {
-                debug!("{:?}", response);
-                Ok(())
-            }
-            Err(e) => Err(err_msg(format!("{}", e))),
-        }
+        let response = self.df.lock().unwrap().delete_permanently(&drive_id)?;
+        debug!("{:?}", response);
+        Ok(())
     }
 
     /// Moves a file to the Trash directory locally *and* on Drive.
     pub fn move_file_to_trash(&mut self, id: &FileId, also_on_drive: bool) -> Result<(), Error> {
         debug!("Moving {:?} to trash.", &id);
         let node_id = self.get_node_id(id)
-            .ok_or(err_msg(format!("Cannot find node_id of {:?}", &id)))?;
+            .ok_or_else(|| not_found(id))?;
         let drive_id = self.get_drive_id(id)
-            .ok_or(err_msg(format!("Cannot find drive_id of {:?}", &id)))?;
+            .ok_or_else(|| not_found(id))?;
         let trash_id = self.get_node_id(&FileId::Inode(TRASH_INODE))
-            .ok_or(err_msg("Cannot find node_id of Trash dir"))?;
+            .ok_or_else(|| not_found(&FileId::Inode(TRASH_INODE)))?;
+        self.check_capability(id, "trash", |c| c.can_trash)?;
 
         self.tree.move_node(&node_id, ToParent(&trash_id))?;
 
         if also_on_drive {
-            self.df.move_to_trash(drive_id)?;
+            self.df.lock().unwrap().move_to_trash(drive_id)?;
         }
 
         Ok(())
@@ -460,23 +1310,58 @@ impl FileManager {
         // Identify the file by its inode instead of (parent, name) because both the parent and
         // name will probably change in this method.
         let id = FileId::Inode(self.get_inode(id)
-            .ok_or(err_msg(format!("Cannot find node_id of {:?}", &id)))?);
+            .ok_or_else(|| not_found(id))?);
+
+        self.rename_locally(&id, new_parent, new_name.clone())?;
+
+        let drive_id = self.get_drive_id(&id)
+            .ok_or_else(|| not_found(&id))?;
+        let parent_id = self.get_drive_id(&FileId::Inode(new_parent))
+            .ok_or_else(|| not_found(&FileId::Inode(new_parent)))?;
+
+        debug!("parent_id: {}", &parent_id);
+        self.df
+            .lock()
+            .unwrap()
+            .move_to(&drive_id, &parent_id, &new_name)?;
+        Ok(())
+    }
+
+    /// Moves/renames a file in the local file tree. Does not communicate with Drive.
+    pub fn rename_locally(
+        &mut self,
+        id: &FileId,
+        new_parent: Inode,
+        new_name: String,
+    ) -> Result<(), Error> {
+        let id = FileId::Inode(self.get_inode(id)
+            .ok_or_else(|| not_found(id))?);
 
         let current_node = self.get_node_id(&id)
-            .ok_or(err_msg(format!("Cannot find node_id of {:?}", &id)))?;
+            .ok_or_else(|| not_found(&id))?;
         let target_node = self.get_node_id(&FileId::Inode(new_parent))
-            .ok_or(err_msg("Target node doesn't exist"))?;
+            .ok_or_else(|| not_found(&FileId::Inode(new_parent)))?;
+        check_name(&new_name)?;
+        // Files can still be renamed within a special directory.
+        let moves = self.tree
+            .get(&current_node)
+            .ok()
+            .and_then(|node| node.parent()) != Some(&target_node);
+        if moves {
+            check_not_special(self.get_file(&FileId::Inode(new_parent)).unwrap())?;
+        }
+        self.check_capability(&id, "rename", |c| c.can_rename)?;
 
         self.tree.move_node(&current_node, ToParent(&target_node))?;
 
         {
             let identical_filename_count = self.get_children(&FileId::Inode(new_parent))
-                .ok_or(err_msg("FileManager::rename() could not get file siblings"))?
+                .ok_or_else(|| not_found(&FileId::Inode(new_parent)))?
                 .iter()
                 .filter(|child| child.name == new_name)
                 .count();
 
-            let file = self.get_mut_file(&id).ok_or(err_msg("File doesn't exist"))?;
+            let file = self.get_mut_file(&id).ok_or_else(|| not_found(&id))?;
             file.name = new_name.clone();
 
             if identical_filename_count > 0 {
@@ -486,52 +1371,635 @@ impl FileManager {
             }
         }
 
+        Ok(())
+    }
+
+    /// Writes to a file locally *and* on Drive. Note: the pending write is not necessarily applied
+    /// instantly by the `DriveBackend`.
+    pub fn write(&mut self, id: FileId, offset: usize, data: &[u8]) -> Result<(), Error> {
         let drive_id = self.get_drive_id(&id)
-            .ok_or(err_msg(format!("Cannot find drive_id of {:?}", &id)))?;
-        let parent_id = self.get_drive_id(&FileId::Inode(new_parent))
-            .ok_or(err_msg(format!(
-                "Cannot find drive_id of {:?}",
-                &FileId::Inode(new_parent)
-            )))?;
+            .ok_or_else(|| not_found(&id))?;
+        self.check_capability(&id, "edit", |c| c.can_edit)?;
+        self.df.lock().unwrap().write(drive_id, offset, data)
+    }
 
-        debug!("parent_id: {}", &parent_id);
-        self.df.move_to(&drive_id, &parent_id, &new_name)?;
+    /// Changes the size of a file locally *and* on Drive. Like a write, the truncation is pending
+    /// until the file is flushed: shrinking the file cuts off its content, growing it fills the
+    /// new part with zeros.
+    pub fn truncate(&mut self, id: &FileId, size: u64) -> Result<(), Error> {
+        let drive_id = self.resize(id, size)?;
+        self.df.lock().unwrap().truncate(&drive_id, size)
+    }
+
+    /// Changes the size of a file in the local tree only and returns its Drive ID. The truncation
+    /// still has to be recorded with `DriveBackend::truncate()`, which can be done without holding
+    /// on to the `FileManager`.
+    pub fn resize(&mut self, id: &FileId, size: u64) -> Result<DriveId, Error> {
+        let drive_id = self.get_drive_id(id).ok_or_else(|| not_found(id))?;
+        if self.get_file(id).map(|f| f.attr.kind) == Some(FileType::Directory) {
+            return Err(FsError::IsDirectory(format!("{:?}", id)).into());
+        }
+        self.check_capability(id, "edit", |c| c.can_edit)?;
+
+        if let Some(file) = self.get_mut_file(id) {
+            file.attr.size = size;
+        }
+        Ok(drive_id)
+    }
+
+    /// Changes the mode, owner and/or group of a file locally *and* on Drive, where they are kept
+    /// in the `appProperties` of the file.
+    pub fn set_attributes(
+        &mut self,
+        id: &FileId,
+        perm: Option<u16>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> Result<(), Error> {
+        let (drive_id, patch) = self.set_local_attributes(id, perm, uid, gid)?;
+        self.df.lock().unwrap().update_metadata(&drive_id, &patch)?;
         Ok(())
     }
 
-    /// Writes to a file locally *and* on Drive. Note: the pending write is not necessarily applied
-    /// instantly by the `DriveFacade`.
-    pub fn write(&mut self, id: FileId, offset: usize, data: &[u8]) {
-        let drive_id = self.get_drive_id(&id).unwrap();
-        self.df.write(drive_id, offset, data);
+    /// Changes the mode, owner and/or group of a file in the local tree only. Returns its Drive ID
+    /// along with the patch which still has to be sent with `DriveBackend::update_metadata()`,
+    /// which can be done without holding on to the `FileManager`.
+    pub fn set_local_attributes(
+        &mut self,
+        id: &FileId,
+        perm: Option<u16>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> Result<(DriveId, drive3::File), Error> {
+        let drive_id = self.get_drive_id(id).ok_or_else(|| not_found(id))?;
+        self.check_capability(id, "edit", |c| c.can_edit)?;
+
+        let properties = File::attr_properties(perm, uid, gid);
+        let patch = drive3::File {
+            app_properties: Some(properties.clone()),
+            ..Default::default()
+        };
+
+        let file = self.get_mut_file(id).ok_or_else(|| not_found(id))?;
+        file.attr.perm = perm.unwrap_or(file.attr.perm);
+        file.attr.uid = uid.unwrap_or(file.attr.uid);
+        file.attr.gid = gid.unwrap_or(file.attr.gid);
+        if let Some(ref mut drive_file) = file.drive_file {
+            drive_file
+                .app_properties
+                .get_or_insert_with(HashMap::new)
+                .extend(properties);
+        }
+        Ok((drive_id, patch))
+    }
+
+    /// Applies a change of the Drive metadata which was requested through an extended attribute.
+    /// Starring a file only affects the current user, so it does not require edit access.
+    pub fn set_xattr(&mut self, id: &FileId, change: Change) -> Result<(), Error> {
+        let request = self.xattr_request(id, change)?;
+        request.send(&mut *self.df.lock().unwrap())?;
+        self.apply_xattr(id, request.change)
+    }
+
+    /// Checks whether a change of the Drive metadata which was requested through an extended
+    /// attribute is allowed and returns what is needed in order to send it to Drive.
+    pub fn xattr_request(&self, id: &FileId, change: Change) -> Result<XattrRequest, Error> {
+        let drive_id = self.get_drive_id(id).ok_or_else(|| not_found(id))?;
+        match change {
+            Change::Starred(_) => {}
+            _ => self.check_capability(id, "edit", |c| c.can_edit)?,
+        }
+        Ok(XattrRequest { drive_id, change })
+    }
+
+    /// Applies a change of the Drive metadata, which has already been sent to Drive, to the local
+    /// file tree.
+    pub fn apply_xattr(&mut self, id: &FileId, change: Change) -> Result<(), Error> {
+        let file = self.get_mut_file(id).ok_or_else(|| not_found(id))?;
+        if let Some(ref mut drive_file) = file.drive_file {
+            match change {
+                Change::Description(description) => drive_file.description = description,
+                Change::Starred(starred) => drive_file.starred = Some(starred),
+                Change::Property(key, value) => {
+                    let properties = drive_file.properties.get_or_insert_with(HashMap::new);
+                    match value {
+                        Some(value) => properties.insert(key, value),
+                        None => properties.remove(&key),
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks whether a file called `name` can be created in the directory `parent`.
+    pub fn check_new_file(&self, parent: Inode, name: &str) -> Result<(), Error> {
+        check_name(name)?;
+
+        let parent_id = FileId::Inode(parent);
+        if !self.contains(&parent_id) {
+            return Err(not_found(&parent_id));
+        }
+        check_not_special(self.get_file(&parent_id).unwrap())?;
+        self.check_capability(&parent_id, "add children", |c| c.can_add_children)?;
+
+        let id = FileId::ParentAndName {
+            parent,
+            name: name.to_string(),
+        };
+        if self.contains(&id) {
+            return Err(FsError::AlreadyExists(format!("{:?}", &id)).into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether a symbolic link called `name` which points to `target` can be created in
+    /// the directory `parent`. The target has to fit into a property of the Drive file.
+    pub fn check_new_symlink(&self, parent: Inode, name: &str, target: &str) -> Result<(), Error> {
+        if target.is_empty() || target.contains('\0') || target.len() > MAX_LINK_TARGET_LEN {
+            return Err(FsError::InvalidName(format!("link target {:?}", target)).into());
+        }
+
+        self.check_new_file(parent, name)
+    }
+
+    /// Removes a directory from the local file tree, provided that it is empty and that Drive
+    /// allows deleting it. Its children should have been loaded first, otherwise it looks empty.
+    /// Does not communicate with Drive.
+    pub fn remove_dir_locally(&mut self, id: &FileId) -> Result<(), Error> {
+        let empty = self.get_children(id)
+            .ok_or_else(|| not_found(id))?
+            .is_empty();
+        if !empty {
+            return Err(FsError::NotEmpty(format!("{:?}", id)).into());
+        }
+
+        self.check_capability(id, "delete", |c| c.can_delete)?;
+        self.delete_locally(id)
+    }
+
+    /// Fails with `PermissionDenied` if the capabilities which Drive reported for a file do not
+    /// allow an operation. If they are unknown, the operation is allowed and it is up to Drive
+    /// to refuse it.
+    pub fn check_capability<F>(&self, id: &FileId, operation: &str, allowed: F) -> Result<(), Error>
+    where
+        F: Fn(&drive3::FileCapabilities) -> Option<bool>,
+    {
+        let capabilities = self.get_file(id)
+            .ok_or_else(|| not_found(id))?
+            .drive_file
+            .as_ref()
+            .and_then(|f| f.capabilities.as_ref());
+
+        match capabilities.and_then(allowed) {
+            Some(false) => Err(FsError::PermissionDenied(format!(
+                "Drive does not allow to {} {:?}",
+                operation, id
+            )).into()),
+            _ => Ok(()),
+        }
     }
 }
 
+/// The information needed in order to retrieve the children of a directory from Drive. It can be
+/// used while the `FileManager` is not locked, so that the network request does not hold up other
+/// file system operations.
+pub struct ChildrenRequest {
+    pub inode: Inode,
+    drive_id: Option<DriveId>,
+    team_drive_id: Option<DriveId>,
+
+    /// For "Starred" and "Recent", the listed files which are not in the local file tree.
+    missing: Vec<drive3::File>,
+
+    /// The directories in the local file tree, at which the search for the parents of `missing`
+    /// stops.
+    known_dirs: HashSet<DriveId>,
+}
+
+impl ChildrenRequest {
+    /// Retrieves the children from Drive.
+    pub fn fetch(&self, df: &mut dyn DriveBackend) -> Result<Vec<drive3::File>, Error> {
+        match self.drive_id {
+            _ if !self.missing.is_empty() => self.fetch_missing(df),
+            _ if self.inode == TRASH_INODE => df.get_all_files(None, Some(true)),
+            _ if self.inode == SHARED_WITH_ME_INODE => df.get_shared_files().map(shared_top_level),
+            Some(ref drive_id) => match self.team_drive_id {
+                Some(ref team_drive) => df.get_drive_files(team_drive, vec![drive_id.clone()]),
+                None => df.get_all_files(Some(vec![drive_id.clone()]), Some(false)),
+            },
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Retrieves the directories which lead from the local file tree to the missing files. Each
+    /// file comes after its parent. A directory which cannot be retrieved (e.g. because it has not
+    /// been shared with the account) ends the search.
+    fn fetch_missing(&self, df: &mut dyn DriveBackend) -> Result<Vec<drive3::File>, Error> {
+        let mut fetched = Vec::new();
+        let mut seen = self.known_dirs.clone();
+        for drive_file in &self.missing {
+            let mut path = vec![drive_file.clone()];
+            let mut parent = File::drive_parent_of(drive_file);
+            while let Some(id) = parent {
+                if !seen.insert(id.clone()) {
+                    break;
+                }
+                let dir = match df.get_metadata(&id) {
+                    Ok(dir) => dir,
+                    Err(ref e) if !FsError::is_offline(e) => break,
+                    Err(e) => return Err(e),
+                };
+                parent = File::drive_parent_of(&dir);
+                path.push(dir);
+            }
+            fetched.extend(path.into_iter().rev());
+        }
+        Ok(fetched)
+    }
+}
+
+/// A change of the Drive metadata which was requested through an extended attribute. Like a
+/// `ChildrenRequest`, it can be sent while the `FileManager` is not locked.
+pub struct XattrRequest {
+    drive_id: DriveId,
+    pub change: Change,
+}
+
+impl XattrRequest {
+    /// Sends the change to Drive.
+    pub fn send(&self, df: &mut dyn DriveBackend) -> Result<(), Error> {
+        match self.change {
+            Change::Description(ref description) => {
+                let patch = drive3::File {
+                    description: Some(description.clone().unwrap_or_default()),
+                    ..Default::default()
+                };
+                df.update_metadata(&self.drive_id, &patch).map(|_| ())
+            }
+            Change::Starred(starred) => {
+                let patch = drive3::File {
+                    starred: Some(starred),
+                    ..Default::default()
+                };
+                df.update_metadata(&self.drive_id, &patch).map(|_| ())
+            }
+            Change::Property(ref key, ref value) => {
+                let properties = hashmap!{ key.clone() => value.clone() };
+                df.update_properties(&self.drive_id, &properties)
+            }
+        }
+    }
+}
+
+/// The page tokens from which the changes of "My Drive" and of each known shared drive are
+/// retrieved. The ones of the `DriveBackend` move forward as soon as changes are retrieved, so
+/// `FileManager` keeps the ones which it has caught up with and hands them back on every sync.
+#[derive(Clone, Debug, Default)]
+struct ChangesTokens {
+    changes: Option<String>,
+    drives: HashMap<DriveId, String>,
+}
+
+impl ChangesTokens {
+    /// Makes `df` continue from these tokens.
+    fn load_into(&self, df: &mut dyn DriveBackend) {
+        df.set_changes_token(self.changes.clone());
+        for (drive_id, token) in &self.drives {
+            df.set_drive_changes_token(drive_id, Some(token.clone()));
+        }
+    }
+}
+
+/// A snapshot of the file tree which has been taken for the metadata cache. It is saved while the
+/// `FileManager` is not locked.
+pub struct MetadataSnapshot {
+    path: PathBuf,
+    snapshot: Snapshot,
+    /// Increases with every snapshot taken by the same `FileManager`.
+    number: u64,
+    /// The number of the last snapshot which has been written.
+    written: Arc<Mutex<u64>>,
+}
+
+impl MetadataSnapshot {
+    /// Writes the snapshot to the metadata cache, unless a newer one has been written already.
+    pub fn save(self) -> Result<(), Error> {
+        let mut written = self.written.lock().unwrap();
+        if *written > self.number {
+            return Ok(());
+        }
+
+        self.snapshot.save(&self.path)?;
+        *written = self.number;
+        debug!("Saved {} files to {:?}", self.snapshot.entries.len(), &self.path);
+        Ok(())
+    }
+}
+
+/// What a sync has to retrieve from Drive besides the changes of "My Drive". Like a
+/// `ChildrenRequest`, it can be fetched while the `FileManager` is not locked.
+pub struct SyncRequest {
+    /// The tokens which the changes are retrieved from.
+    tokens: ChangesTokens,
+
+    /// The shared drives which have a directory in "Shared drives". Absent if there is no such
+    /// directory.
+    shared_drives: Option<Vec<DriveId>>,
+
+    /// The files which are listed in "Shared with me". Absent if it has not been loaded, in which
+    /// case newly shared folders are not populated either.
+    shared_with_me: Option<Vec<DriveId>>,
+
+    /// Whether newly found shared drives and shared folders are populated.
+    crawl: bool,
+
+    /// Which of "Starred" and "Recent" are wanted, and how many files the latter lists.
+    starred_dir: bool,
+    recent_limit: Option<usize>,
+
+    /// Whether "Starred" and "Recent" are listed even if nothing has changed.
+    listings_missing: bool,
+}
+
+/// Everything that a `SyncRequest` has retrieved from Drive.
+pub struct RemoteChanges {
+    /// The changes of "My Drive" and of the shared drives which were already known.
+    changes: Vec<drive3::Change>,
+
+    /// The tokens which the next sync continues from once these changes have been applied. They
+    /// cover the shared drives which are still available, including the new ones.
+    tokens: ChangesTokens,
+
+    /// The shared drives which the account is a member of, if they have been asked for.
+    shared_drives: Option<Vec<drive3::TeamDrive>>,
+
+    /// The files of the newly found shared drives and shared folders, by drive or folder.
+    trees: HashMap<DriveId, Vec<drive3::File>>,
+
+    /// The files to list in "Starred" and "Recent". Absent if they have not been listed again.
+    listings: Option<Listings>,
+
+    /// The temporary Drive IDs of files created while offline, along with their real ones.
+    replaced_ids: Vec<(DriveId, DriveId)>,
+}
+
+/// The files which "Starred" and "Recent" list, in order. Absent for a directory which is not
+/// wanted.
+struct Listings {
+    starred: Option<Vec<drive3::File>>,
+    recent: Option<Vec<drive3::File>>,
+}
+
+impl SyncRequest {
+    /// Retrieves the changes, along with the list of shared drives and the files which are shared
+    /// with the account. The changes of a shared drive are only retrieved if it was already known,
+    /// since a new one is populated from scratch. "Starred" and "Recent" are listed again if
+    /// anything changed.
+    pub fn fetch(&self, df: &mut dyn DriveBackend) -> Result<RemoteChanges, Error> {
+        self.tokens.load_into(df);
+        let changes = df.get_all_changes()?;
+        let mut remote = RemoteChanges {
+            changes,
+            tokens: ChangesTokens {
+                changes: Some(df.changes_token()?.clone()),
+                drives: HashMap::new(),
+            },
+            shared_drives: None,
+            trees: HashMap::new(),
+            listings: None,
+            replaced_ids: Vec::new(),
+        };
+
+        // Newly shared files show up among the changes like any other file, with the time they
+        // were shared. The contents of shared folders do not, so they are retrieved separately.
+        if let (Some(ref listed), true) = (&self.shared_with_me, self.crawl) {
+            let shared_folders: Vec<DriveId> = remote
+                .changes
+                .iter()
+                .filter_map(|change| change.file.as_ref())
+                .filter(|f| f.shared_with_me_time.is_some() && f.trashed != Some(true))
+                .filter(|f| f.mime_type.as_deref() == Some(FOLDER_MIME_TYPE))
+                .filter_map(|f| f.id.clone())
+                .filter(|drive_id| !listed.contains(drive_id))
+                .collect();
+            for drive_id in shared_folders {
+                let tree = fetch_tree(df, drive_id.clone(), None)?;
+                remote.trees.insert(drive_id, tree);
+            }
+        }
+
+        if let Some(ref known) = self.shared_drives {
+            let drives = fetch_shared_drives(
+                df,
+                known,
+                self.crawl,
+                &mut remote.trees,
+                &mut remote.tokens.drives,
+            )?;
+            for drive_id in known {
+                if drives.iter().any(|drive| drive.id.as_ref() == Some(drive_id)) {
+                    let drive_changes = df.get_drive_changes(drive_id)?;
+                    remote.changes.extend(drive_changes);
+                    let token = df.drive_changes_token(drive_id)?.clone();
+                    remote.tokens.drives.insert(drive_id.clone(), token);
+                }
+            }
+            remote.shared_drives = Some(drives);
+        }
+
+        if !remote.changes.is_empty() || self.listings_missing {
+            let listings = fetch_listings(df, self.starred_dir, self.recent_limit)?;
+            remote.listings = Some(listings);
+        }
+        // Taken last, so that they are not lost if anything above fails.
+        remote.replaced_ids = df.take_replaced_ids();
+        Ok(remote)
+    }
+}
+
+/// Retrieves all files and directories under the directory `parent`, each directory before its
+/// children. `team_drive` is the shared drive which the directory belongs to, if any.
+fn fetch_tree(
+    df: &mut dyn DriveBackend,
+    parent: DriveId,
+    team_drive: Option<&DriveId>,
+) -> Result<Vec<drive3::File>, Error> {
+    let mut tree = Vec::new();
+    let mut seen = HashSet::new();
+    let mut queue: LinkedList<DriveId> = LinkedList::new();
+    queue.push_back(parent);
+
+    while !queue.is_empty() {
+        let mut parents = Vec::new();
+        while !queue.is_empty() {
+            parents.push(queue.pop_front().unwrap());
+        }
+
+        let drive_files = match team_drive {
+            Some(drive_id) => df.get_drive_files(drive_id, parents)?,
+            None => df.get_all_files(Some(parents), Some(false))?,
+        };
+        for drive_file in drive_files {
+            let drive_id = unwrap_or_continue!(drive_file.id.clone());
+            if !seen.insert(drive_id.clone()) {
+                continue;
+            }
+            if drive_file.mime_type.as_deref() == Some(FOLDER_MIME_TYPE) {
+                queue.push_back(drive_id);
+            }
+            tree.push(drive_file);
+        }
+    }
+
+    Ok(tree)
+}
+
+/// Retrieves the files which "Starred" and "Recent" list, if they are wanted.
+fn fetch_listings(
+    df: &mut dyn DriveBackend,
+    starred_dir: bool,
+    recent_limit: Option<usize>,
+) -> Result<Listings, Error> {
+    let starred = if starred_dir {
+        Some(df.get_starred_files()?)
+    } else {
+        None
+    };
+    let recent = match recent_limit {
+        Some(limit) => Some(df.get_recent_files(limit)?),
+        None => None,
+    };
+    Ok(Listings { starred, recent })
+}
+
+/// Retrieves the files which are shared with the account, leaving out the ones which are found in
+/// shared folders. The files of each shared folder are added to `trees`.
+fn fetch_shared_with_me(
+    df: &mut dyn DriveBackend,
+    trees: &mut HashMap<DriveId, Vec<drive3::File>>,
+) -> Result<Vec<drive3::File>, Error> {
+    let drive_files = shared_top_level(df.get_shared_files()?);
+    for drive_file in &drive_files {
+        let drive_id = unwrap_or_continue!(drive_file.id.as_ref());
+        if drive_file.mime_type.as_deref() == Some(FOLDER_MIME_TYPE) {
+            let tree = fetch_tree(df, drive_id.clone(), None)?;
+            trees.insert(drive_id.clone(), tree);
+        }
+    }
+    Ok(drive_files)
+}
+
+/// Retrieves the shared drives which the account is a member of. For each one which is not among
+/// the `known` ones, a fresh changes token is added to `tokens` and, if `crawl` is set, its files
+/// are added to `trees`. The token is obtained first, so that changes made while populating are
+/// not missed.
+fn fetch_shared_drives(
+    df: &mut dyn DriveBackend,
+    known: &[DriveId],
+    crawl: bool,
+    trees: &mut HashMap<DriveId, Vec<drive3::File>>,
+    tokens: &mut HashMap<DriveId, String>,
+) -> Result<Vec<drive3::TeamDrive>, Error> {
+    let drives = df.shared_drives()?;
+    for drive in &drives {
+        let drive_id = unwrap_or_continue!(drive.id.as_ref());
+        if known.contains(drive_id) {
+            continue;
+        }
+
+        df.set_drive_changes_token(drive_id, None);
+        let token = df.drive_changes_token(drive_id)?.clone();
+        tokens.insert(drive_id.clone(), token);
+        if crawl {
+            let tree = fetch_tree(df, drive_id.clone(), Some(drive_id))?;
+            trees.insert(drive_id.clone(), tree);
+        }
+    }
+    Ok(drives)
+}
+
+/// The longest file name that is accepted, in bytes. It matches the `namelen` reported by `statfs`.
+const MAX_NAME_LEN: usize = 1024;
+
+fn not_found(id: &FileId) -> Error {
+    FsError::NotFound(format!("{:?}", id)).into()
+}
+
+/// Leaves out the shared files which can also be found among the children of a shared folder.
+fn shared_top_level(files: Vec<drive3::File>) -> Vec<drive3::File> {
+    let ids: HashSet<DriveId> = files.iter().filter_map(|f| f.id.clone()).collect();
+    files
+        .into_iter()
+        .filter(|f| File::drive_parent_of(f).is_none_or(|parent| !ids.contains(&parent)))
+        .collect()
+}
+
+/// Special directories (e.g. "Trash") do not exist on Drive, so files cannot be put into them.
+fn check_not_special(dir: &File) -> Result<(), Error> {
+    if dir.drive_id().is_none() {
+        let message = format!("{} is a special directory", dir.name);
+        return Err(FsError::PermissionDenied(message).into());
+    }
+
+    Ok(())
+}
+
+/// Describes the root folder of a shared drive, whose ID is the ID of the drive. Drive does not
+/// allow renaming, editing or removing it like a regular folder.
+fn shared_drive_file(drive: drive3::TeamDrive) -> drive3::File {
+    let can_add_children = drive.capabilities.and_then(|c| c.can_add_children);
+    drive3::File {
+        id: drive.id.clone(),
+        name: drive.name,
+        mime_type: Some("application/vnd.google-apps.folder".to_string()),
+        team_drive_id: drive.id,
+        capabilities: Some(drive3::FileCapabilities {
+            can_add_children,
+            can_edit: Some(false),
+            can_rename: Some(false),
+            can_trash: Some(false),
+            can_delete: Some(false),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Drive accepts almost any name, but some of them cannot be represented in the file system.
+fn check_name(name: &str) -> Result<(), Error> {
+    let reserved = name.is_empty() || name == "." || name == "..";
+    if reserved || name.contains('/') || name.contains('\0') || name.len() > MAX_NAME_LEN {
+        return Err(FsError::InvalidName(format!("{:?}", name)).into());
+    }
+
+    Ok(())
+}
+
 impl fmt::Debug for FileManager {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "FileManager(\n")?;
+        writeln!(f, "FileManager(")?;
 
         if self.tree.root_node_id().is_none() {
-            return write!(f, ")\n");
+            return writeln!(f, ")");
         }
 
         let mut stack: Vec<(u32, &NodeId)> = vec![(0, self.tree.root_node_id().unwrap())];
 
-        while !stack.is_empty() {
-            let (level, node_id) = stack.pop().unwrap();
-
+        while let Some((level, node_id)) = stack.pop() {
             for _ in 0..level {
                 write!(f, "\t")?;
             }
 
             let file = self.get_file(&FileId::NodeId(node_id.clone())).unwrap();
-            write!(f, "{:3} => {}\n", file.inode(), file.name)?;
+            writeln!(f, "{:3} => {}", file.inode(), file.name)?;
 
             self.tree.children_ids(node_id).unwrap().for_each(|id| {
                 stack.push((level + 1, id));
             });
         }
 
-        write!(f, ")\n")
+        writeln!(f, ")")
     }
 }
@@ -1,19 +1,31 @@
-use super::{Config, File, FileId, FileManager};
+use super::file::{LINK_MIME_TYPE, LINK_TARGET_PROPERTY};
+use super::worker_pool::WorkerPool;
+use super::xattr;
+use super::{
+    BackgroundSync, Config, DriveBackend, File, FileHandle, FileHandles, FileId, FileManager,
+    FsError, OfflineDrive,
+};
 use drive3;
+use failure::Error;
 use fuse::{
     FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
-    ReplyEntry, ReplyStatfs, ReplyWrite, Request,
+    ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request,
 };
-use libc::{ENOENT, ENOTDIR, EREMOTE};
+use libc::{c_int, EBADF, EINVAL, ENODATA, ENOENT, ENOTSUP, ERANGE, EROFS};
 use lru_time_cache::LruCache;
-use std;
 use std::clone::Clone;
 use std::cmp;
 use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use time::Timespec;
 use DriveFacade;
 
 pub type Inode = u64;
+pub type DriveId = String;
 
 /// An empty FUSE file system. It can be used in a mounting test aimed to determine whether or
 /// not the real file system can be mounted as well. If the test fails, the application can fail
@@ -22,48 +34,720 @@ pub struct NullFS;
 impl Filesystem for NullFS {}
 
 /// A FUSE file system which is linked to a Google Drive account.
+///
+/// Operations which can be answered from the local file tree are replied to right away. The ones
+/// which need to wait for Drive are handed over to a pool of workers, which send the replies once
+/// Drive has responded. This way, a slow download does not prevent other processes from using the
+/// file system in the meantime.
 pub struct GCSF {
-    manager: FileManager,
-    statfs_cache: LruCache<String, u64>,
+    /// Shared with the workers and the background sync worker.
+    manager: Arc<Mutex<FileManager>>,
+    statfs_cache: Arc<Mutex<LruCache<String, u64>>>,
+    workers: WorkerPool,
+
+    /// The open files. Shared with the workers, which release the handle of a file which could
+    /// not be created.
+    handles: Arc<Mutex<FileHandles>>,
+
+    /// Set once an orderly shutdown has begun. From then on, modifications are refused.
+    shutting_down: Arc<AtomicBool>,
+}
+
+/// Shuts the file system down in an orderly way from outside of the FUSE session, e.g. when the
+/// process receives a signal. Obtained from `GCSF::shutdown_handle()` before mounting.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    manager: Arc<Mutex<FileManager>>,
+    shutting_down: Arc<AtomicBool>,
+}
+
+/// What is left behind by `ShutdownHandle::shutdown()`.
+#[derive(Debug, PartialEq)]
+pub struct ShutdownReport {
+    /// Whether flushing was given up on because it took longer than the timeout.
+    pub timed_out: bool,
+
+    /// The Drive IDs of the files whose pending writes have not been uploaded.
+    pub dirty_files: Vec<DriveId>,
+}
+
+impl ShutdownReport {
+    /// Whether all pending writes have been uploaded.
+    pub fn is_clean(&self) -> bool {
+        !self.timed_out && self.dirty_files.is_empty()
+    }
+}
+
+/// The attributes which `setattr()` is asked to change. The absent ones are left as they are.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AttrChanges {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<Timespec>,
+    pub mtime: Option<Timespec>,
+    pub ctime: Option<Timespec>,
+    pub crtime: Option<Timespec>,
+    pub flags: Option<u32>,
 }
 
 const TTL: Timespec = Timespec { sec: 1, nsec: 0 }; // 1 second
 
 impl GCSF {
     pub fn with_config(config: Config) -> Self {
+        let df = OfflineDrive::new(
+            DriveFacade::new(&config),
+            &config.journal_path(),
+            &config.recovery_path(),
+        );
+        GCSF::with_drive_backend(&config, df)
+    }
+
+    /// Creates a file system which is set up according to `config`, but communicates with Drive
+    /// through the given `DriveBackend`.
+    pub fn with_drive_backend<D: DriveBackend + 'static>(config: &Config, df: D) -> Self {
         GCSF {
-            manager: FileManager::with_drive_facade(
-                config.sync_interval(),
-                DriveFacade::new(&config),
-            ),
-            statfs_cache: LruCache::<String, u64>::with_expiry_duration_and_capacity(
-                config.cache_statfs_seconds(),
-                2,
-            ),
+            manager: Arc::new(Mutex::new(FileManager::with_config(config, df))),
+            statfs_cache: Arc::new(Mutex::new(
+                LruCache::<String, u64>::with_expiry_duration_and_capacity(
+                    config.cache_statfs_seconds(),
+                    2,
+                ),
+            )),
+            workers: WorkerPool::new(config.worker_threads()),
+            handles: Arc::new(Mutex::new(FileHandles::new())),
+            shutting_down: Arc::new(AtomicBool::new(false)),
         }
     }
-}
 
-impl Filesystem for GCSF {
-    fn lookup(&mut self, _req: &Request, parent: Inode, name: &OsStr, reply: ReplyEntry) {
-        // self.manager.sync();
+    /// Returns a handle which can shut the file system down once it has been mounted.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            manager: Arc::clone(&self.manager),
+            shutting_down: Arc::clone(&self.shutting_down),
+        }
+    }
 
-        let name = name.to_str().unwrap().to_string();
-        let id = FileId::ParentAndName { parent, name };
+    fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Starts a worker which applies remote changes to the file system every `sync_interval`.
+    /// It keeps running until it is stopped or dropped.
+    pub fn spawn_background_sync(&self) -> BackgroundSync {
+        let interval = self.manager.lock().unwrap().sync_interval;
+        BackgroundSync::spawn(Arc::clone(&self.manager), interval)
+    }
+
+    /// Runs `f` once the children of `dir` are known. If they still need to be retrieved from
+    /// Drive, this happens on a worker and `f` runs there as well. Otherwise, `f` runs right away.
+    fn with_children_loaded<F>(&self, dir: Inode, f: F)
+    where
+        F: FnOnce(&mut FileManager) + Send + 'static,
+    {
+        let request = {
+            let mut manager = self.manager.lock().unwrap();
+            match manager.children_request(&FileId::Inode(dir)) {
+                Ok(Some(request)) => request,
+                _ => {
+                    f(&mut manager);
+                    return;
+                }
+            }
+        };
+
+        let manager = Arc::clone(&self.manager);
+        self.workers.execute(move || {
+            // Only the DriveBackend is locked while waiting for Drive.
+            let df = Arc::clone(&manager.lock().unwrap().df);
+            let drive_files = request.fetch(&mut *df.lock().unwrap());
 
-        match self.manager.get_file(&id) {
-            Some(ref file) => {
-                reply.entry(&TTL, &file.attr, 0);
+            let mut manager = manager.lock().unwrap();
+            if let Err(e) = drive_files.and_then(|files| manager.add_children(request.inode, files))
+            {
+                error!("Could not load children of inode={}: {}", request.inode, e);
             }
+            f(&mut manager);
+        });
+    }
+
+    /// Creates `file` on Drive from a worker. Once Drive has assigned it an id, the file is added
+    /// under `parent` in the local file tree and `done` receives its attributes.
+    fn create_on_worker<F>(&self, mut file: File, parent: Inode, done: F)
+    where
+        F: FnOnce(Result<FileAttr, Error>) + Send + 'static,
+    {
+        let manager = Arc::clone(&self.manager);
+        self.workers.execute(move || {
+            let df = Arc::clone(&manager.lock().unwrap().df);
+            let created = df.lock().unwrap().create(file.drive_file.as_ref().unwrap());
+
+            done(created.and_then(|drive_id| {
+                file.set_drive_id(drive_id);
+                let attr = file.attr;
+                manager
+                    .lock()
+                    .unwrap()
+                    .add_created_file(file, Some(FileId::Inode(parent)))
+                    .map(|_| attr)
+            }));
+        });
+    }
+
+    /// Sets or removes an extended attribute, i.e. changes the Drive metadata of a file, on a
+    /// worker. `value` is `None` when the attribute is removed. `done` is called once Drive has
+    /// been updated.
+    pub fn set_xattr_on_worker<F>(&self, ino: Inode, name: &OsStr, value: Option<&[u8]>, done: F)
+    where
+        F: FnOnce(Result<(), c_int>) + Send + 'static,
+    {
+        let name = match name.to_str() {
+            Some(name) if xattr::is_known(name) => name,
+            _ => {
+                done(Err(ENOTSUP));
+                return;
+            }
+        };
+
+        let change = match xattr::Change::parse(name, value) {
+            Ok(change) => change,
+            Err(e) => {
+                error!("setxattr: {}", e);
+                done(Err(e.errno()));
+                return;
+            }
+        };
+
+        let manager = Arc::clone(&self.manager);
+        self.workers.execute(move || {
+            let id = FileId::Inode(ino);
+            let (df, request) = {
+                let manager = manager.lock().unwrap();
+                (Arc::clone(&manager.df), manager.xattr_request(&id, change))
+            };
+
+            // Only the DriveBackend is locked while waiting for Drive.
+            let result = request.and_then(|request| {
+                request.send(&mut *df.lock().unwrap())?;
+                manager.lock().unwrap().apply_xattr(&id, request.change)
+            });
+            match result {
+                Ok(()) => done(Ok(())),
+                Err(e) => {
+                    error!("setxattr: {}", e);
+                    done(Err(FsError::errno_of(&e)));
+                }
+            }
+        });
+    }
+
+    /// Looks up the file `name` in the directory `parent`. `done` receives its attributes.
+    pub fn lookup_file<F>(&self, parent: Inode, name: String, done: F)
+    where
+        F: FnOnce(Result<FileAttr, c_int>) + Send + 'static,
+    {
+        self.with_children_loaded(parent, move |manager| {
+            let id = FileId::ParentAndName { parent, name };
+            match manager.get_file(&id) {
+                Some(file) => done(Ok(file.attr)),
+                None => done(Err(ENOENT)),
+            }
+        });
+    }
+
+    /// Opens the file `ino` with the flags given to `open()`. A file opened for writing with
+    /// `O_TRUNC` is emptied. `done` receives the new file handle.
+    pub fn open_file<F>(&self, ino: Inode, flags: u32, done: F)
+    where
+        F: FnOnce(Result<u64, c_int>) + Send + 'static,
+    {
+        let handle = FileHandle::from_flags(ino, flags);
+        if handle.writable && self.is_shutting_down() {
+            done(Err(EROFS));
+            return;
+        }
+
+        let mut manager = self.manager.lock().unwrap();
+        let id = FileId::Inode(ino);
+        if !manager.contains(&id) {
+            error!("open: could not find inode={} in the file tree", ino);
+            done(Err(ENOENT));
+            return;
+        }
+
+        let mut result = Ok(());
+        if handle.writable {
+            result = manager.check_capability(&id, "edit", |c| c.can_edit);
+        }
+        let mut truncation = None;
+        if result.is_ok() && handle.writable && handle.truncate {
+            match manager.resize(&id, 0) {
+                Ok(drive_id) => truncation = Some(drive_id),
+                Err(e) => result = Err(e),
+            }
+        }
+        if let Err(e) = result {
+            error!("open: {}", e);
+            done(Err(FsError::errno_of(&e)));
+            return;
+        }
+
+        let fh = self.handles.lock().unwrap().open(handle);
+        let drive_id = match truncation {
+            Some(drive_id) => drive_id,
             None => {
-                reply.error(ENOENT);
+                done(Ok(fh));
+                return;
+            }
+        };
+
+        // Recording the truncation may require asking Drive where the file is, so it happens on a
+        // worker. The file is already empty in the local tree.
+        let df = Arc::clone(&manager.df);
+        let handles = Arc::clone(&self.handles);
+        self.workers.execute(move || {
+            let result = df.lock().unwrap().truncate(&drive_id, 0);
+            match result {
+                Ok(()) => done(Ok(fh)),
+                Err(e) => {
+                    error!("open: {}", e);
+                    handles.lock().unwrap().release(fh);
+                    done(Err(FsError::errno_of(&e)));
+                }
+            }
+        });
+    }
+
+    /// Reads up to `size` bytes at `offset` from the file `ino` on a worker, including the writes
+    /// which have not been uploaded yet. `done` receives the data.
+    pub fn read_file<F>(&self, ino: Inode, offset: i64, size: u32, done: F)
+    where
+        F: FnOnce(Result<&[u8], c_int>) + Send + 'static,
+    {
+        let manager = self.manager.lock().unwrap();
+        let (mime, id) = match manager.get_file(&FileId::Inode(ino)) {
+            Some(file) => {
+                let mime = file.drive_file
+                    .as_ref()
+                    .and_then(|f| f.mime_type.as_ref())
+                    .cloned();
+                (mime, file.drive_id().unwrap())
+            }
+            None => {
+                done(Err(ENOENT));
+                return;
+            }
+        };
+
+        let df = Arc::clone(&manager.df);
+        self.workers.execute(move || {
+            match df.lock()
+                .unwrap()
+                .read(&id, mime, offset as usize, size as usize)
+            {
+                Ok(data) => done(Ok(data)),
+                Err(e) => {
+                    error!("read: {}", e);
+                    done(Err(FsError::errno_of(&e)));
+                }
+            }
+        });
+    }
+
+    /// Writes `data` at `offset` to the file `ino` through the handle `fh`, or at the end of the
+    /// file if it has been opened with `O_APPEND`. `done` receives the number of bytes written.
+    pub fn write_file<F>(&self, ino: Inode, fh: u64, offset: i64, data: &[u8], done: F)
+    where
+        F: FnOnce(Result<u32, c_int>) + Send + 'static,
+    {
+        if self.is_shutting_down() {
+            done(Err(EROFS));
+            return;
+        }
+
+        let append = match self.handles.lock().unwrap().get(fh) {
+            Some(handle) if !handle.writable => {
+                done(Err(EBADF));
+                return;
+            }
+            Some(handle) => handle.append,
+            None => false,
+        };
+
+        let mut manager = self.manager.lock().unwrap();
+        let mut offset: usize = cmp::max(offset, 0) as usize;
+
+        if let Err(e) = manager.check_capability(&FileId::Inode(ino), "edit", |c| c.can_edit) {
+            done(Err(FsError::errno_of(&e)));
+            return;
+        }
+
+        let drive_id = match manager.get_mut_file(&FileId::Inode(ino)) {
+            Some(ref mut file) => {
+                if append {
+                    offset = file.attr.size as usize;
+                }
+                file.attr.size = cmp::max(file.attr.size, offset as u64 + data.len() as u64);
+                file.drive_id().unwrap()
+            }
+            None => {
+                done(Err(ENOENT));
+                return;
+            }
+        };
+
+        // The DriveBackend might be busy talking to Drive, so the write is recorded on a worker.
+        let df = Arc::clone(&manager.df);
+        let data = data.to_vec();
+        self.workers.execute(move || {
+            match df.lock().unwrap().write(drive_id, offset, &data) {
+                Ok(()) => done(Ok(data.len() as u32)),
+                Err(e) => {
+                    error!("write: {}", e);
+                    done(Err(FsError::errno_of(&e)));
+                }
+            }
+        });
+    }
+
+    /// Releases the handle `fh` of the file `ino`. Once the last writer has released the file,
+    /// its pending writes are uploaded on a worker and `done` is only called afterwards.
+    pub fn release_file<F>(&self, ino: Inode, fh: u64, done: F)
+    where
+        F: FnOnce(Result<(), c_int>) + Send + 'static,
+    {
+        let last_writer = {
+            let mut handles = self.handles.lock().unwrap();
+            let released = handles.release(fh);
+            released.is_some_and(|handle| handle.writable) && handles.writers(ino) == 0
+        };
+        if !last_writer {
+            done(Ok(()));
+            return;
+        }
+
+        let manager = self.manager.lock().unwrap();
+        let drive_id = match manager.get_drive_id(&FileId::Inode(ino)) {
+            Some(drive_id) => drive_id,
+            None => {
+                // The file has been removed while it was open.
+                done(Ok(()));
+                return;
+            }
+        };
+
+        let df = Arc::clone(&manager.df);
+        self.workers.execute(move || {
+            match df.lock().unwrap().flush(&drive_id) {
+                Ok(()) => done(Ok(())),
+                Err(e) => {
+                    error!("release: {}", e);
+                    done(Err(FsError::errno_of(&e)));
+                }
+            }
+        });
+    }
+
+    /// Changes the attributes of the file `ino`. A new size is recorded like a write and is
+    /// uploaded right away unless the file is open for writing. `done` receives the new
+    /// attributes.
+    pub fn set_attributes<F>(&self, ino: Inode, changes: AttrChanges, done: F)
+    where
+        F: FnOnce(Result<FileAttr, c_int>) + Send + 'static,
+    {
+        let AttrChanges {
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+            ctime,
+            crtime,
+            flags,
+        } = changes;
+        let changes_drive = size.is_some() || mode.is_some() || uid.is_some() || gid.is_some();
+        if changes_drive && self.is_shutting_down() {
+            done(Err(EROFS));
+            return;
+        }
+
+        let mut manager = self.manager.lock().unwrap();
+        if !manager.contains(&FileId::Inode(ino)) {
+            error!("setattr: could not find inode={} in the file tree", ino);
+            done(Err(ENOENT));
+            return;
+        }
+
+        // The local tree is updated right away, while the truncation is recorded (and possibly
+        // uploaded) and the new attributes are sent to Drive by a worker.
+        let mut truncation = None;
+        if let Some(size) = size {
+            match manager.resize(&FileId::Inode(ino), size) {
+                Ok(drive_id) => {
+                    // Without a writer (e.g. truncate(2)), no release is going to upload the
+                    // change.
+                    let flush = self.handles.lock().unwrap().writers(ino) == 0;
+                    truncation = Some((drive_id, size, flush));
+                }
+                Err(e) => {
+                    error!("setattr: {}", e);
+                    done(Err(FsError::errno_of(&e)));
+                    return;
+                }
             }
+        }
+
+        let mut patch = None;
+        if mode.is_some() || uid.is_some() || gid.is_some() {
+            let perm = mode.map(mode_to_perm);
+            match manager.set_local_attributes(&FileId::Inode(ino), perm, uid, gid) {
+                Ok(change) => patch = Some(change),
+                Err(e) => {
+                    error!("setattr: {}", e);
+                    done(Err(FsError::errno_of(&e)));
+                    return;
+                }
+            }
+        }
+
+        let file = manager.get_mut_file(&FileId::Inode(ino)).unwrap();
+
+        let new_attr = FileAttr {
+            ino: file.attr.ino,
+            kind: file.attr.kind,
+            size: file.attr.size,
+            blocks: file.attr.blocks,
+            atime: atime.unwrap_or(file.attr.atime),
+            mtime: mtime.unwrap_or(file.attr.mtime),
+            ctime: ctime.unwrap_or(file.attr.ctime),
+            crtime: crtime.unwrap_or(file.attr.crtime),
+            perm: file.attr.perm,
+            nlink: file.attr.nlink,
+            uid: file.attr.uid,
+            gid: file.attr.gid,
+            rdev: file.attr.rdev,
+            flags: flags.unwrap_or(file.attr.flags),
         };
+
+        file.attr = new_attr;
+        let attr = file.attr;
+
+        if truncation.is_none() && patch.is_none() {
+            done(Ok(attr));
+            return;
+        }
+
+        let df = Arc::clone(&manager.df);
+        self.workers.execute(move || {
+            let result = {
+                let mut df = df.lock().unwrap();
+                let mut result = Ok(());
+                if let Some((drive_id, size, flush)) = truncation {
+                    result = df.truncate(&drive_id, size);
+                    if result.is_ok() && flush {
+                        result = df.flush(&drive_id);
+                    }
+                }
+                match patch {
+                    Some((drive_id, patch)) if result.is_ok() => {
+                        result = df.update_metadata(&drive_id, &patch).map(|_| ());
+                    }
+                    _ => {}
+                }
+                result
+            };
+            match result {
+                Ok(()) => done(Ok(attr)),
+                Err(e) => {
+                    error!("setattr: {}", e);
+                    done(Err(FsError::errno_of(&e)));
+                }
+            }
+        });
+    }
+
+    /// Creates the file `name` in the directory `parent` and opens it with the flags given to
+    /// `open()`. `done` receives its attributes and the new file handle once Drive has created it.
+    pub fn create_file<F>(&self, parent: Inode, name: String, mode: u32, flags: u32, done: F)
+    where
+        F: FnOnce(Result<(FileAttr, u64), c_int>) + Send + 'static,
+    {
+        if self.is_shutting_down() {
+            done(Err(EROFS));
+            return;
+        }
+
+        let mut manager = self.manager.lock().unwrap();
+        let filename = name;
+
+        if let Err(e) = manager.check_new_file(parent, &filename) {
+            error!("create: {}", e);
+            done(Err(FsError::errno_of(&e)));
+            return;
+        }
+
+        let defaults = manager.attr_defaults;
+        let perm = mode_to_perm(mode) & !defaults.umask;
+        let file = File {
+            name: filename.clone(),
+            attr: FileAttr {
+                ino: manager.next_available_inode(),
+                kind: FileType::RegularFile,
+                size: 0,
+                blocks: 123,
+                atime: Timespec::new(1, 0),
+                mtime: Timespec::new(1, 0),
+                ctime: Timespec::new(1, 0),
+                crtime: Timespec::new(1, 0),
+                perm,
+                nlink: 0,
+                uid: defaults.uid,
+                gid: defaults.gid,
+                rdev: 0,
+                flags: 0,
+            },
+            identical_name_id: None,
+            drive_file: Some(drive3::File {
+                name: Some(filename.clone()),
+                mime_type: None,
+                parents: Some(vec![
+                    manager.get_drive_id(&FileId::Inode(parent)).unwrap(),
+                ]),
+                app_properties: Some(File::attr_properties(
+                    Some(perm),
+                    Some(defaults.uid),
+                    Some(defaults.gid),
+                )),
+                ..Default::default()
+            }),
+        };
+
+        let fh = self.handles
+            .lock()
+            .unwrap()
+            .open(FileHandle::from_flags(file.attr.ino, flags));
+        let handles = Arc::clone(&self.handles);
+        self.create_on_worker(file, parent, move |result| match result {
+            Ok(attr) => done(Ok((attr, fh))),
+            Err(e) => {
+                error!("create: {}", e);
+                handles.lock().unwrap().release(fh);
+                done(Err(FsError::errno_of(&e)));
+            }
+        });
+    }
+
+    /// Performs `op` on a worker and replies once it has finished.
+    fn reply_from_worker<F>(&self, df: Arc<Mutex<dyn DriveBackend>>, reply: ReplyEmpty, op: F)
+    where
+        F: FnOnce(&mut dyn DriveBackend) -> Result<(), Error> + Send + 'static,
+    {
+        self.workers.execute(move || {
+            let result = op(&mut *df.lock().unwrap());
+            match result {
+                Ok(()) => reply.ok(),
+                Err(e) => {
+                    error!("{}", e);
+                    reply.error(FsError::errno_of(&e));
+                }
+            }
+        });
+    }
+}
+
+/// Replies with an extended attribute (or a list of them), or only with its length if `size` is 0.
+fn reply_xattr(reply: ReplyXattr, size: u32, value: Option<&[u8]>) {
+    match value {
+        None => reply.error(ENODATA),
+        Some(value) if size == 0 => reply.size(value.len() as u32),
+        Some(value) if value.len() > size as usize => reply.error(ERANGE),
+        Some(value) => reply.data(value),
+    }
+}
+
+/// The permission bits of a mode given by the kernel, which also contains the file type.
+fn mode_to_perm(mode: u32) -> u16 {
+    (mode & 0o7777) as u16
+}
+
+fn reply_statfs(reply: ReplyStatfs, size: u64, capacity: u64, files: u64) {
+    reply.statfs(
+        /* blocks:*/ capacity,
+        /* bfree: */ capacity - size,
+        /* bavail: */ capacity - size,
+        /* files: */ u64::MAX,
+        /* ffree: */ u64::MAX - files,
+        /* bsize: */ 1,
+        /* namelen: */ 1024,
+        /* frsize: */ 1,
+    );
+}
+
+impl ShutdownHandle {
+    /// Makes the file system refuse further modifications, then uploads the pending writes of
+    /// all files and saves the metadata cache. Gives up waiting for the uploads after `timeout`;
+    /// whatever is left stays in the spool and is uploaded on the next mount. Reports which files
+    /// still have pending writes.
+    pub fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        self.shutting_down.store(true, Ordering::SeqCst);
+
+        let (sender, receiver) = mpsc::channel();
+        let manager = Arc::clone(&self.manager);
+        let remaining = Arc::new(Mutex::new(Vec::new()));
+        let flushing = Arc::clone(&remaining);
+        thread::spawn(move || {
+            let mut manager = manager.lock().unwrap();
+            let failed = manager.flush_all_tracked(&flushing);
+            if let Err(e) = manager.save_metadata() {
+                error!("Could not save metadata cache: {}", e);
+            }
+            let _ = sender.send(failed);
+        });
+
+        let timed_out = match receiver.recv_timeout(timeout) {
+            Ok(0) => false,
+            Ok(failed) => {
+                error!("{} files could not be flushed", failed);
+                false
+            }
+            Err(_) => {
+                error!("Gave up flushing files after {:?}", timeout);
+                true
+            }
+        };
+
+        let dirty_files = remaining.lock().unwrap().clone();
+        for id in &dirty_files {
+            error!("{} still has pending writes", id);
+        }
+        ShutdownReport {
+            timed_out,
+            dirty_files,
+        }
+    }
+}
+
+impl Filesystem for GCSF {
+    fn destroy(&mut self, _req: &Request) {
+        let mut manager = self.manager.lock().unwrap();
+        if let Err(e) = manager.save_metadata() {
+            error!("Could not save metadata cache: {}", e);
+        }
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: Inode, name: &OsStr, reply: ReplyEntry) {
+        let name = name.to_str().unwrap().to_string();
+        self.lookup_file(parent, name, move |result| match result {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(errno) => reply.error(errno),
+        });
     }
 
     fn getattr(&mut self, _req: &Request, ino: Inode, reply: ReplyAttr) {
-        // self.manager.sync();
-        match self.manager.get_file(&FileId::Inode(ino)) {
+        let manager = self.manager.lock().unwrap();
+        match manager.get_file(&FileId::Inode(ino)) {
             Some(file) => {
                 reply.attr(&TTL, &file.attr);
             }
@@ -82,54 +766,26 @@ impl Filesystem for GCSF {
         size: u32,
         reply: ReplyData,
     ) {
-        if !self.manager.contains(&FileId::Inode(ino)) {
-            reply.error(ENOENT);
-            return;
-        }
-
-        let (mime, id) = self.manager
-            .get_file(&FileId::Inode(ino))
-            .map(|f| {
-                let mime = f.drive_file
-                    .as_ref()
-                    .and_then(|f| f.mime_type.as_ref())
-                    .cloned();
-                let id = f.drive_id().unwrap();
-
-                (mime, id)
-            })
-            .unwrap();
-
-        reply.data(
-            self.manager
-                .df
-                .read(&id, mime, offset as usize, size as usize)
-                .unwrap_or(&[]),
-        );
+        self.read_file(ino, offset, size, move |result| match result {
+            Ok(data) => reply.data(data),
+            Err(errno) => reply.error(errno),
+        });
     }
 
     fn write(
         &mut self,
         _req: &Request,
         ino: Inode,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         data: &[u8],
         _flags: u32,
         reply: ReplyWrite,
     ) {
-        let offset: usize = cmp::max(offset, 0) as usize;
-        self.manager.write(FileId::Inode(ino), offset, data);
-
-        match self.manager.get_mut_file(&FileId::Inode(ino)) {
-            Some(ref mut file) => {
-                file.attr.size = offset as u64 + data.len() as u64;
-                reply.written(data.len() as u32);
-            }
-            None => {
-                reply.error(ENOENT);
-            }
-        };
+        self.write_file(ino, fh, offset, data, move |result| match result {
+            Ok(written) => reply.written(written),
+            Err(errno) => reply.error(errno),
+        });
     }
 
     fn readdir(
@@ -140,23 +796,21 @@ impl Filesystem for GCSF {
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        if let Err(e) = self.manager.sync() {
-            debug!("Could not perform sync: {}", e);
-        }
-
-        let mut curr_offs = offset + 1;
-        match self.manager.get_children(&FileId::Inode(ino)) {
-            Some(children) => {
-                for child in children.iter().skip(offset as usize) {
-                    reply.add(child.inode(), curr_offs, child.kind(), &child.name());
-                    curr_offs += 1;
+        self.with_children_loaded(ino, move |manager| {
+            let mut curr_offs = offset + 1;
+            match manager.get_children(&FileId::Inode(ino)) {
+                Some(children) => {
+                    for child in children.iter().skip(offset as usize) {
+                        reply.add(child.inode(), curr_offs, child.kind(), child.name());
+                        curr_offs += 1;
+                    }
+                    reply.ok();
                 }
-                reply.ok();
-            }
-            None => {
-                reply.error(ENOENT);
-            }
-        };
+                None => {
+                    reply.error(ENOENT);
+                }
+            };
+        });
     }
 
     fn rename(
@@ -168,29 +822,53 @@ impl Filesystem for GCSF {
         new_name: &OsStr,
         reply: ReplyEmpty,
     ) {
+        if self.is_shutting_down() {
+            reply.error(EROFS);
+            return;
+        }
+
+        let mut manager = self.manager.lock().unwrap();
         let name = name.to_str().unwrap().to_string();
         let new_name = new_name.to_str().unwrap().to_string();
 
-        match self.manager.rename(
-            &FileId::ParentAndName { parent, name },
-            new_parent,
-            new_name,
-        ) {
-            Ok(_) => {
-                reply.ok();
+        let id = match manager.get_inode(&FileId::ParentAndName { parent, name }) {
+            Some(inode) => FileId::Inode(inode),
+            None => {
+                reply.error(ENOENT);
+                return;
             }
-            Err(e) => {
-                error!("{}", e);
+        };
+
+        if let Err(e) = manager.rename_locally(&id, new_parent, new_name.clone()) {
+            error!("rename: {}", e);
+            reply.error(FsError::errno_of(&e));
+            return;
+        }
+
+        // A file which is renamed within a special directory stays where it is on Drive.
+        let drive_id = manager.get_drive_id(&id);
+        let parent_id = manager
+            .get_drive_id(&FileId::Inode(new_parent))
+            .or_else(|| manager.get_file(&id).and_then(File::drive_parent));
+        let (drive_id, parent_id) = match (drive_id, parent_id) {
+            (Some(drive_id), Some(parent_id)) => (drive_id, parent_id),
+            _ => {
+                error!("rename: could not find the drive ids of {:?}", &id);
                 reply.error(ENOENT);
+                return;
             }
         };
+
+        self.reply_from_worker(Arc::clone(&manager.df), reply, move |df| {
+            df.move_to(&drive_id, &parent_id, &new_name).map(|_| ())
+        });
     }
 
     fn setattr(
         &mut self,
         _req: &Request,
         ino: Inode,
-        _mode: Option<u32>,
+        mode: Option<u32>,
         uid: Option<u32>,
         gid: Option<u32>,
         size: Option<u64>,
@@ -203,256 +881,495 @@ impl Filesystem for GCSF {
         flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        if !self.manager.contains(&FileId::Inode(ino)) {
-            error!("setattr: could not find inode={} in the file tree", ino);
-            reply.error(ENOENT);
+        let changes = AttrChanges {
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+            ctime: chgtime,
+            crtime,
+            flags,
+        };
+        self.set_attributes(ino, changes, move |result| match result {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(errno) => reply.error(errno),
+        });
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+        reply: ReplyCreate,
+    ) {
+        let name = name.to_str().unwrap().to_string();
+        self.create_file(parent, name, mode, flags, move |result| match result {
+            Ok((attr, fh)) => reply.created(&TTL, &attr, 0, fh, 0),
+            Err(errno) => reply.error(errno),
+        });
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: Inode, name: &OsStr, reply: ReplyEmpty) {
+        if self.is_shutting_down() {
+            reply.error(EROFS);
             return;
         }
 
-        let file = self.manager.get_mut_file(&FileId::Inode(ino)).unwrap();
+        let mut manager = self.manager.lock().unwrap();
+        let id = FileId::ParentAndName {
+            parent,
+            name: name.to_str().unwrap().to_string(),
+        };
 
-        let new_attr = FileAttr {
-            ino: file.attr.ino,
-            kind: file.attr.kind,
-            size: size.unwrap_or(file.attr.size),
-            blocks: file.attr.blocks,
-            atime: atime.unwrap_or(file.attr.atime),
-            mtime: mtime.unwrap_or(file.attr.mtime),
-            ctime: chgtime.unwrap_or(file.attr.ctime),
-            crtime: crtime.unwrap_or(file.attr.crtime),
-            perm: file.attr.perm,
-            nlink: file.attr.nlink,
-            uid: uid.unwrap_or(file.attr.uid),
-            gid: gid.unwrap_or(file.attr.gid),
-            rdev: file.attr.rdev,
-            flags: flags.unwrap_or(file.attr.flags),
+        let drive_id = match manager.get_drive_id(&id) {
+            Some(drive_id) => drive_id,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
         };
 
-        file.attr = new_attr;
-        reply.attr(&TTL, &file.attr);
+        if let Err(e) = manager.move_file_to_trash(&id, false) {
+            error!("unlink: {}", e);
+            reply.error(FsError::errno_of(&e));
+            return;
+        }
+
+        self.reply_from_worker(Arc::clone(&manager.df), reply, move |df| {
+            df.move_to_trash(drive_id)
+        });
     }
 
-    fn create(
+    fn forget(&mut self, _req: &Request, _ino: u64, _nlookup: u64) {}
+
+    fn mkdir(
         &mut self,
-        req: &Request,
+        _req: &Request,
         parent: Inode,
         name: &OsStr,
-        _mode: u32,
-        _flags: u32,
-        reply: ReplyCreate,
+        mode: u32,
+        reply: ReplyEntry,
     ) {
-        let filename = name.to_str().unwrap().to_string();
-
-        // TODO: these two checks might not be necessary
-        if !self.manager.contains(&FileId::Inode(parent)) {
-            error!(
-                "create: could not find parent inode={} in the file tree",
-                parent
-            );
-            reply.error(ENOTDIR);
+        if self.is_shutting_down() {
+            reply.error(EROFS);
             return;
         }
-        if self.manager.contains(&FileId::ParentAndName {
-            parent,
-            name: filename.clone(),
-        }) {
-            error!(
-                "create: file {:?} of parent(inode={}) already exists",
-                name, parent
-            );
-            reply.error(ENOTDIR);
+
+        let mut manager = self.manager.lock().unwrap();
+        let dirname = name.to_str().unwrap().to_string();
+
+        if let Err(e) = manager.check_new_file(parent, &dirname) {
+            error!("mkdir: {}", e);
+            reply.error(FsError::errno_of(&e));
             return;
         }
 
-        let file = File {
-            name: filename.clone(),
+        let defaults = manager.attr_defaults;
+        let perm = mode_to_perm(mode) & !defaults.umask;
+        let dir = File {
+            name: dirname.clone(),
             attr: FileAttr {
-                ino: self.manager.next_available_inode(),
-                kind: FileType::RegularFile,
+                ino: manager.next_available_inode(),
+                kind: FileType::Directory,
                 size: 0,
                 blocks: 123,
                 atime: Timespec::new(1, 0),
                 mtime: Timespec::new(1, 0),
                 ctime: Timespec::new(1, 0),
                 crtime: Timespec::new(1, 0),
-                perm: 0o744,
+                perm,
                 nlink: 0,
-                uid: req.uid(),
-                gid: req.gid(),
+                uid: defaults.uid,
+                gid: defaults.gid,
                 rdev: 0,
                 flags: 0,
             },
             identical_name_id: None,
             drive_file: Some(drive3::File {
-                name: Some(filename.clone()),
-                mime_type: None,
+                name: Some(dirname.clone()),
+                mime_type: Some("application/vnd.google-apps.folder".to_string()),
                 parents: Some(vec![
-                    self.manager.get_drive_id(&FileId::Inode(parent)).unwrap(),
+                    manager.get_drive_id(&FileId::Inode(parent)).unwrap(),
                 ]),
+                app_properties: Some(File::attr_properties(
+                    Some(perm),
+                    Some(defaults.uid),
+                    Some(defaults.gid),
+                )),
                 ..Default::default()
             }),
         };
 
-        let attr = file.attr.clone();
-        match self.manager.create_file(file, Some(FileId::Inode(parent))) {
-            Ok(()) => {
-                reply.created(&TTL, &attr, 0, 0, 0);
-            }
-            Err(e) => {
-                error!("create: {}", e);
-                reply.error(EREMOTE);
-            }
-        }
-    }
-
-    fn unlink(&mut self, _req: &Request, parent: Inode, name: &OsStr, reply: ReplyEmpty) {
-        let id = FileId::ParentAndName {
-            parent,
-            name: name.to_str().unwrap().to_string(),
-        };
-
-        if !self.manager.contains(&id) {
-            reply.error(ENOENT);
-            return;
-        }
-
-        match self.manager.move_file_to_trash(&id, true) {
-            Ok(response) => {
-                debug!("{:?}", response);
-                reply.ok();
+        self.create_on_worker(dir, parent, move |result| match result {
+            Ok(attr) => {
+                reply.entry(&TTL, &attr, 0);
             }
             Err(e) => {
-                error!("{:?}", e);
-                reply.error(EREMOTE);
+                error!("mkdir: {}", e);
+                reply.error(FsError::errno_of(&e));
             }
-        };
+        });
     }
 
-    fn forget(&mut self, _req: &Request, _ino: u64, _nlookup: u64) {}
-
-    fn mkdir(
+    fn symlink(
         &mut self,
         _req: &Request,
         parent: Inode,
         name: &OsStr,
-        _mode: u32,
+        link: &Path,
         reply: ReplyEntry,
     ) {
-        let dirname = name.to_str().unwrap().to_string();
-
-        // TODO: these two checks might not be necessary
-        if !self.manager.contains(&FileId::Inode(parent)) {
-            error!(
-                "mkdir: could not find parent inode={} in the file tree",
-                parent
-            );
-            reply.error(ENOTDIR);
+        if self.is_shutting_down() {
+            reply.error(EROFS);
             return;
         }
-        if self.manager.contains(&FileId::ParentAndName {
-            parent,
-            name: dirname.clone(),
-        }) {
-            error!(
-                "mkdir: file {:?} of parent(inode={}) already exists",
-                name, parent
-            );
-            reply.error(ENOTDIR);
+
+        let mut manager = self.manager.lock().unwrap();
+        let linkname = name.to_str().unwrap().to_string();
+        let target = match link.to_str() {
+            Some(target) => target.to_string(),
+            None => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+
+        if let Err(e) = manager.check_new_symlink(parent, &linkname, &target) {
+            error!("symlink: {}", e);
+            reply.error(FsError::errno_of(&e));
             return;
         }
 
-        let dir = File {
-            name: dirname.clone(),
+        let defaults = manager.attr_defaults;
+        let mut app_properties =
+            File::attr_properties(None, Some(defaults.uid), Some(defaults.gid));
+        app_properties.insert(LINK_TARGET_PROPERTY.to_string(), target.clone());
+
+        let link = File {
+            name: linkname.clone(),
             attr: FileAttr {
-                ino: self.manager.next_available_inode(),
-                kind: FileType::Directory,
-                size: 0,
-                blocks: 123,
+                ino: manager.next_available_inode(),
+                kind: FileType::Symlink,
+                size: target.len() as u64,
+                blocks: 1,
                 atime: Timespec::new(1, 0),
                 mtime: Timespec::new(1, 0),
                 ctime: Timespec::new(1, 0),
                 crtime: Timespec::new(1, 0),
-                perm: 0o644,
+                perm: 0o777,
                 nlink: 0,
-                uid: 0,
-                gid: 0,
+                uid: defaults.uid,
+                gid: defaults.gid,
                 rdev: 0,
                 flags: 0,
             },
             identical_name_id: None,
             drive_file: Some(drive3::File {
-                name: Some(dirname.clone()),
-                mime_type: Some("application/vnd.google-apps.folder".to_string()),
+                name: Some(linkname.clone()),
+                mime_type: Some(LINK_MIME_TYPE.to_string()),
                 parents: Some(vec![
-                    self.manager.get_drive_id(&FileId::Inode(parent)).unwrap(),
+                    manager.get_drive_id(&FileId::Inode(parent)).unwrap(),
                 ]),
+                app_properties: Some(app_properties),
                 ..Default::default()
             }),
         };
 
-        let attr = dir.attr.clone();
-        match self.manager.create_file(dir, Some(FileId::Inode(parent))) {
-            Ok(()) => {
+        self.create_on_worker(link, parent, move |result| match result {
+            Ok(attr) => {
                 reply.entry(&TTL, &attr, 0);
             }
             Err(e) => {
-                error!("mkdir: {}", e);
-                reply.error(EREMOTE);
+                error!("symlink: {}", e);
+                reply.error(FsError::errno_of(&e));
             }
+        });
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: Inode, reply: ReplyData) {
+        let manager = self.manager.lock().unwrap();
+        match manager.get_file(&FileId::Inode(ino)) {
+            Some(file) => match file.link_target() {
+                Some(target) => reply.data(target.as_bytes()),
+                None => reply.error(EINVAL),
+            },
+            None => reply.error(ENOENT),
         }
     }
 
     fn rmdir(&mut self, _req: &Request, parent: Inode, name: &OsStr, reply: ReplyEmpty) {
-        match self.manager.delete(&FileId::ParentAndName {
+        if self.is_shutting_down() {
+            reply.error(EROFS);
+            return;
+        }
+
+        let mut manager = self.manager.lock().unwrap();
+        let id = FileId::ParentAndName {
             parent,
             name: name.to_str().unwrap().to_string(),
-        }) {
-            Ok(response) => {
-                debug!("{:?}", response);
-                reply.ok();
+        };
+
+        let (drive_id, inode) = match (manager.get_drive_id(&id), manager.get_inode(&id)) {
+            (Some(drive_id), Some(inode)) => (drive_id, inode),
+            _ => {
+                reply.error(ENOENT);
+                return;
             }
+        };
+        let id = FileId::Inode(inode);
+
+        let request = match manager.children_request(&id) {
+            Ok(request) => request,
             Err(e) => {
-                error!("{:?}", e);
-                reply.error(EREMOTE);
+                reply.error(FsError::errno_of(&e));
+                return;
             }
         };
+
+        // Whether the directory is empty can only be told once its children are known. If they
+        // still need to be retrieved, everything happens on a worker.
+        if let Some(request) = request {
+            let manager = Arc::clone(&self.manager);
+            self.workers.execute(move || {
+                let df = Arc::clone(&manager.lock().unwrap().df);
+                let drive_files = request.fetch(&mut *df.lock().unwrap());
+                let result = drive_files
+                    .and_then(|files| {
+                        let mut manager = manager.lock().unwrap();
+                        manager.add_children(inode, files)?;
+                        manager.remove_dir_locally(&id)
+                    })
+                    .and_then(|_| df.lock().unwrap().delete_permanently(&drive_id));
+
+                match result {
+                    Ok(_) => reply.ok(),
+                    Err(e) => {
+                        error!("rmdir: {}", e);
+                        reply.error(FsError::errno_of(&e));
+                    }
+                }
+            });
+            return;
+        }
+
+        if let Err(e) = manager.remove_dir_locally(&id) {
+            error!("rmdir: {}", e);
+            reply.error(FsError::errno_of(&e));
+            return;
+        }
+
+        self.reply_from_worker(Arc::clone(&manager.df), reply, move |df| {
+            df.delete_permanently(&drive_id).map(|response| {
+                debug!("{:?}", response);
+            })
+        });
     }
 
+    fn open(&mut self, _req: &Request, ino: Inode, flags: u32, reply: ReplyOpen) {
+        self.open_file(ino, flags, move |result| match result {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(errno) => reply.error(errno),
+        });
+    }
+
+    /// Called on every `close()`, which happens a lot more often than actual changes. Pending
+    /// writes are uploaded when the last writer releases the file instead.
     fn flush(&mut self, _req: &Request, ino: Inode, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        match self.manager.flush(&FileId::Inode(ino)) {
+        if self.manager.lock().unwrap().contains(&FileId::Inode(ino)) {
+            reply.ok();
+        } else {
+            reply.error(ENOENT);
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: Inode,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        // Nobody is going to see an error at this point, since close() has already returned.
+        self.release_file(ino, fh, move |result| match result {
             Ok(()) => reply.ok(),
-            Err(e) => {
-                error!("{:?}", e);
-                reply.error(EREMOTE);
+            Err(errno) => reply.error(errno),
+        });
+    }
+
+    /// Uploads the pending writes right away and only replies once they have reached Drive.
+    fn fsync(&mut self, _req: &Request, ino: Inode, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let manager = self.manager.lock().unwrap();
+        let drive_id = match manager.get_drive_id(&FileId::Inode(ino)) {
+            Some(drive_id) => drive_id,
+            None => {
+                error!("fsync: could not find the drive id of inode={}", ino);
+                reply.error(ENOENT);
+                return;
             }
+        };
+
+        self.reply_from_worker(Arc::clone(&manager.df), reply, move |df| {
+            df.flush(&drive_id)
+        });
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: Inode, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let name = match name.to_str() {
+            Some(name) if xattr::is_known(name) => name.to_string(),
+            _ => {
+                reply.error(ENODATA);
+                return;
+            }
+        };
+
+        let manager = self.manager.lock().unwrap();
+        let local = match manager.get_file(&FileId::Inode(ino)) {
+            Some(file) => file.drive_file.clone().unwrap_or_default(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        // The file listings already provide some of the fields.
+        let drive_id = match local.id.clone() {
+            Some(ref id) if xattr::is_on_demand(&name) => id.clone(),
+            _ => {
+                let value = xattr::get(&local, &name);
+                reply_xattr(reply, size, value.as_ref().map(|v| v.as_bytes()));
+                return;
+            }
+        };
+
+        let df = Arc::clone(&manager.df);
+        self.workers.execute(move || {
+            let result = df.lock().unwrap().get_metadata(&drive_id);
+            let file = match result {
+                Ok(file) => file,
+                Err(ref e) if FsError::is_offline(e) => local,
+                Err(e) => {
+                    error!("getxattr: {}", e);
+                    reply.error(FsError::errno_of(&e));
+                    return;
+                }
+            };
+
+            let value = xattr::get(&file, &name);
+            reply_xattr(reply, size, value.as_ref().map(|v| v.as_bytes()));
+        });
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: Inode, size: u32, reply: ReplyXattr) {
+        let manager = self.manager.lock().unwrap();
+        let local = match manager.get_file(&FileId::Inode(ino)) {
+            Some(file) => file.drive_file.clone().unwrap_or_default(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let drive_id = match local.id.clone() {
+            Some(id) => id,
+            None => {
+                reply_xattr(reply, size, Some(&[]));
+                return;
+            }
+        };
+
+        // Only the attributes which have a value are listed, so all of them need to be known.
+        let df = Arc::clone(&manager.df);
+        self.workers.execute(move || {
+            let result = df.lock().unwrap().get_metadata(&drive_id);
+            let file = match result {
+                Ok(file) => file,
+                Err(ref e) if FsError::is_offline(e) => local,
+                Err(e) => {
+                    error!("listxattr: {}", e);
+                    reply.error(FsError::errno_of(&e));
+                    return;
+                }
+            };
+
+            reply_xattr(reply, size, Some(&xattr::list(&file)));
+        });
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request,
+        ino: Inode,
+        name: &OsStr,
+        value: &[u8],
+        _flags: u32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        if self.is_shutting_down() {
+            reply.error(EROFS);
+            return;
         }
+
+        self.set_xattr_on_worker(ino, name, Some(value), move |result| match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        });
+    }
+
+    fn removexattr(&mut self, _req: &Request, ino: Inode, name: &OsStr, reply: ReplyEmpty) {
+        if self.is_shutting_down() {
+            reply.error(EROFS);
+            return;
+        }
+
+        self.set_xattr_on_worker(ino, name, None, move |result| match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        });
     }
 
     fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
-        let (size, capacity) = if !self.statfs_cache.contains_key("size")
-            || !self.statfs_cache.contains_key("capacity")
-        {
-            let (size, capacity) = self.manager.df.size_and_capacity().unwrap_or((0, Some(0)));
-            let capacity = capacity.unwrap_or(std::i64::MAX as u64);
-            self.statfs_cache.insert("size".to_string(), size);
-            self.statfs_cache.insert("capacity".to_string(), capacity);
-
-            (size, capacity)
-        } else {
-            let size = self.statfs_cache.get("size").unwrap().to_owned();
-            let capacity = self.statfs_cache.get("capacity").unwrap().to_owned();
-            (size, capacity)
-        };
-
-        reply.statfs(
-            /* blocks:*/ capacity,
-            /* bfree: */ capacity - size,
-            /* bavail: */ capacity - size,
-            /* files: */ std::u64::MAX,
-            /* ffree: */ std::u64::MAX - self.manager.files.len() as u64,
-            /* bsize: */ 1,
-            /* namelen: */ 1024,
-            /* frsize: */ 1,
-        );
+        let (df, files) = {
+            let manager = self.manager.lock().unwrap();
+            (Arc::clone(&manager.df), manager.files.len() as u64)
+        };
+
+        let cached = {
+            let mut cache = self.statfs_cache.lock().unwrap();
+            match (cache.get("size").cloned(), cache.get("capacity").cloned()) {
+                (Some(size), Some(capacity)) => Some((size, capacity)),
+                _ => None,
+            }
+        };
+
+        if let Some((size, capacity)) = cached {
+            reply_statfs(reply, size, capacity, files);
+            return;
+        }
+
+        let cache = Arc::clone(&self.statfs_cache);
+        self.workers.execute(move || {
+            let (size, capacity) = df.lock()
+                .unwrap()
+                .size_and_capacity()
+                .unwrap_or((0, Some(0)));
+            let capacity = capacity.unwrap_or(i64::MAX as u64);
+
+            {
+                let mut cache = cache.lock().unwrap();
+                cache.insert("size".to_string(), size);
+                cache.insert("capacity".to_string(), capacity);
+            }
+
+            reply_statfs(reply, size, capacity, files);
+        });
     }
 }
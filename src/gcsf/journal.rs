@@ -0,0 +1,354 @@
+use drive3;
+use failure::Error;
+use serde_json;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+type DriveId = String;
+
+/// The name of the file which holds the queued mutations. The data of writes is stored next to
+/// it, one file per `Mutation::Write`.
+const JOURNAL_FILE: &str = "journal.json";
+
+/// A change to Drive which could not be performed because Drive was unreachable.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Mutation {
+    /// `id` is the temporary ID handed out in place of the real one, which is only known once the
+    /// file has actually been created.
+    Create { id: DriveId, file: drive3::File },
+    /// Consecutive writes to the same file. The written bytes are stored separately, at their
+    /// offsets within the file, and `ranges` holds the `[start, end)` ranges which were written.
+    Write { id: DriveId, ranges: Vec<(u64, u64)> },
+    Truncate { id: DriveId, size: u64 },
+    Flush { id: DriveId },
+    MoveTo {
+        id: DriveId,
+        parent: DriveId,
+        name: String,
+    },
+    UpdateMetadata { id: DriveId, patch: drive3::File },
+    UpdateProperties {
+        id: DriveId,
+        properties: HashMap<String, Option<String>>,
+    },
+    MoveToTrash { id: DriveId },
+    Delete { id: DriveId },
+}
+
+impl Mutation {
+    /// The file which is changed.
+    fn id(&self) -> &str {
+        match *self {
+            Mutation::Create { ref id, .. }
+            | Mutation::Write { ref id, .. }
+            | Mutation::Truncate { ref id, .. }
+            | Mutation::Flush { ref id }
+            | Mutation::MoveTo { ref id, .. }
+            | Mutation::UpdateMetadata { ref id, .. }
+            | Mutation::UpdateProperties { ref id, .. }
+            | Mutation::MoveToTrash { ref id }
+            | Mutation::Delete { ref id } => id,
+        }
+    }
+}
+
+/// A queued mutation and its position in the queue.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub seq: u64,
+    pub mutation: Mutation,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct JournalState {
+    /// The sequence number of the next entry. It keeps growing across sessions.
+    next_seq: u64,
+
+    /// The queued mutations, oldest first.
+    entries: VecDeque<Entry>,
+
+    /// Maps the temporary IDs of files which have since been created on Drive to their real IDs.
+    id_map: HashMap<DriveId, DriveId>,
+}
+
+/// A persistent, ordered queue of mutations which still have to be sent to Drive. Every change is
+/// written to disk before returning, so that the queue survives a restart.
+pub struct Journal {
+    dir: PathBuf,
+    state: JournalState,
+}
+
+impl Journal {
+    /// Opens the journal stored in `dir`, or starts an empty one if there is none. A journal which
+    /// cannot be read is left in place and an empty one is used instead.
+    pub fn open(dir: &Path) -> Self {
+        if let Err(e) = fs::create_dir_all(dir) {
+            error!("Could not create journal directory {:?}: {}", dir, e);
+        }
+
+        let mut journal = Journal {
+            dir: dir.to_path_buf(),
+            state: JournalState::default(),
+        };
+
+        let path = journal.path();
+        if path.exists() {
+            match Journal::load(&path) {
+                Ok(state) => {
+                    if !state.entries.is_empty() {
+                        info!(
+                            "Found {} queued mutations in {:?}",
+                            state.entries.len(),
+                            &path
+                        );
+                    }
+                    journal.state = state;
+                }
+                Err(e) => error!("Could not read journal {:?}: {}", &path, e),
+            }
+        }
+
+        journal
+    }
+
+    fn load(path: &Path) -> Result<JournalState, Error> {
+        let reader = BufReader::new(fs::File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(JOURNAL_FILE)
+    }
+
+    fn data_path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{}.data", seq))
+    }
+
+    /// Writes the journal to disk. The previous version is only replaced once the new one has been
+    /// written completely and has reached the disk.
+    fn save(&self) -> Result<(), Error> {
+        let path = self.path();
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(fs::File::create(&tmp_path)?);
+            serde_json::to_writer(&mut writer, &self.state)?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.entries.len()
+    }
+
+    /// The sequence number which the next queued mutation will get.
+    pub fn next_seq(&self) -> u64 {
+        self.state.next_seq
+    }
+
+    /// Appends a mutation to the queue. Writes are queued with `push_write()` instead.
+    pub fn push(&mut self, mutation: Mutation) -> Result<(), Error> {
+        let seq = self.state.next_seq;
+        self.state.next_seq += 1;
+        self.state.entries.push_back(Entry { seq, mutation });
+        self.save()
+    }
+
+    /// Queues `data`, written to the file `id` at `offset`. Unless another mutation of the file
+    /// has been queued since, the data joins the file's last `Mutation::Write` and its data file,
+    /// so that copying a large file does not grow the queue with every write.
+    pub fn push_write(&mut self, id: DriveId, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let last = self.state
+            .entries
+            .iter()
+            .rposition(|entry| entry.mutation.id() == id);
+        let index = match last {
+            Some(index) => match self.state.entries[index].mutation {
+                Mutation::Write { .. } => index,
+                _ => self.push_empty_write(id),
+            },
+            None => self.push_empty_write(id),
+        };
+
+        let seq = self.state.entries[index].seq;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.data_path(seq))?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        file.sync_data()?;
+
+        if let Mutation::Write { ref mut ranges, .. } = self.state.entries[index].mutation {
+            add_range(ranges, offset, offset + data.len() as u64);
+        }
+        self.save()
+    }
+
+    /// Appends a `Mutation::Write` which does not contain any data yet. Returns its index.
+    fn push_empty_write(&mut self, id: DriveId) -> usize {
+        let seq = self.state.next_seq;
+        self.state.next_seq += 1;
+        self.state.entries.push_back(Entry {
+            seq,
+            mutation: Mutation::Write {
+                id,
+                ranges: Vec::new(),
+            },
+        });
+        self.state.entries.len() - 1
+    }
+
+    /// The oldest queued mutation.
+    pub fn front(&self) -> Option<&Entry> {
+        self.state.entries.front()
+    }
+
+    /// Removes the oldest queued mutation, along with its data.
+    pub fn pop_front(&mut self) -> Result<(), Error> {
+        if let Some(entry) = self.state.entries.pop_front() {
+            let data_path = self.data_path(entry.seq);
+            if data_path.exists() {
+                fs::remove_file(data_path)?;
+            }
+        }
+        self.save()
+    }
+
+    /// The bytes written by a `Mutation::Write` in the range `[start, end)`.
+    pub fn data(&self, entry: &Entry, start: u64, end: u64) -> Result<Vec<u8>, Error> {
+        let mut file = fs::File::open(self.data_path(entry.seq))?;
+        file.seek(SeekFrom::Start(start))?;
+
+        let mut data = Vec::new();
+        file.take(end - start).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Removes the mutations of the file `id` which were queued after the entry `seq`, along with
+    /// their data.
+    pub fn remove_after(&mut self, seq: u64, id: &str) -> Result<(), Error> {
+        let (removed, kept) = self.state
+            .entries
+            .drain(..)
+            .partition(|entry| entry.seq > seq && entry.mutation.id() == id);
+        self.state.entries = kept;
+
+        for entry in removed.iter() {
+            let data_path = self.data_path(entry.seq);
+            if data_path.exists() {
+                fs::remove_file(data_path)?;
+            }
+        }
+        self.save()
+    }
+
+    /// The size of a file which only exists in the journal so far.
+    pub fn content_len(&self, id: &str) -> u64 {
+        let mut len = 0;
+        for entry in self.state.entries.iter().filter(|e| e.mutation.id() == id) {
+            match entry.mutation {
+                Mutation::Write { ref ranges, .. } => for &(_, stop) in ranges {
+                    len = cmp::max(len, stop);
+                },
+                Mutation::Truncate { size, .. } => len = size,
+                _ => {}
+            }
+        }
+        len
+    }
+
+    /// Reads up to `size` bytes at `offset` from a file which only exists in the journal so far,
+    /// i.e. whose content is made up of its queued writes and truncations.
+    pub fn read(&self, id: &str, offset: u64, size: usize) -> Result<Vec<u8>, Error> {
+        let end = offset + size as u64;
+        let mut window = vec![0; size];
+        let len = self.content_len(id);
+
+        for entry in self.state.entries.iter().filter(|e| e.mutation.id() == id) {
+            match entry.mutation {
+                Mutation::Write { ref ranges, .. } => for &(start, stop) in ranges {
+                    let (start, stop) = (cmp::max(start, offset), cmp::min(stop, end));
+                    if start < stop {
+                        let data = self.data(entry, start, stop)?;
+                        let at = (start - offset) as usize;
+                        window[at..at + data.len()].copy_from_slice(&data);
+                    }
+                },
+                Mutation::Truncate { size, .. } => {
+                    for byte in window.iter_mut().skip(size.saturating_sub(offset) as usize) {
+                        *byte = 0;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        window.truncate(cmp::min(size as u64, len.saturating_sub(offset)) as usize);
+        Ok(window)
+    }
+
+    /// Returns the real ID of a file if `id` is a temporary ID which has since been replaced.
+    pub fn resolve(&self, id: &str) -> DriveId {
+        self.state
+            .id_map
+            .get(id)
+            .cloned()
+            .unwrap_or_else(|| id.to_string())
+    }
+
+    /// Records that the file known by the temporary ID `id` has been created as `real_id`.
+    pub fn replace_id(&mut self, id: &str, real_id: DriveId) -> Result<(), Error> {
+        self.state.id_map.insert(id.to_string(), real_id);
+        self.save()
+    }
+
+    /// All temporary IDs which have been replaced, along with their replacements.
+    pub fn replaced_ids(&self) -> Vec<(DriveId, DriveId)> {
+        self.state
+            .id_map
+            .iter()
+            .map(|(id, real_id)| (id.clone(), real_id.clone()))
+            .collect()
+    }
+
+    /// Forgets the replaced IDs once nobody refers to them anymore, i.e. once the queue is empty
+    /// and the local file tree has been updated.
+    pub fn forget_replaced_ids(&mut self) -> Result<(), Error> {
+        if !self.is_empty() || self.state.id_map.is_empty() {
+            return Ok(());
+        }
+
+        self.state.id_map.clear();
+        self.save()
+    }
+}
+
+/// Adds `[start, end)` to `ranges`, merging it with the ones it overlaps or touches.
+fn add_range(ranges: &mut Vec<(u64, u64)>, start: u64, end: u64) {
+    let mut merged = (start, end);
+    let mut kept = Vec::with_capacity(ranges.len() + 1);
+
+    for &(s, e) in ranges.iter() {
+        if e < merged.0 || s > merged.1 {
+            kept.push((s, e));
+        } else {
+            merged = (cmp::min(s, merged.0), cmp::max(e, merged.1));
+        }
+    }
+
+    kept.push(merged);
+    kept.sort();
+    *ranges = kept;
+}
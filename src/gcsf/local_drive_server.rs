@@ -0,0 +1,719 @@
+use super::memory_drive::{merge, merge_value};
+use super::{Config, DriveBackend, MemoryDrive};
+use drive3;
+use failure::{err_msg, Error};
+use hyper::header::{ContentType, Headers, Host, Location};
+use hyper::method::Method;
+use hyper::server::{Handler, Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use hyper::Url;
+use oauth2;
+use oauth2::TokenStorage;
+use serde::Serialize;
+use serde_json;
+use std::collections::hash_map::DefaultHasher;
+use std::cmp;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+type DriveId = String;
+
+/// A small HTTP server which stands in for Google Drive. It implements the parts of the Drive v3
+/// `files`, `changes`, `teamdrives` and `about` endpoints (plus an OAuth2 token endpoint) that
+/// `DriveFacade` uses, and serves them from a `MemoryDrive`. Useful for running end-to-end tests
+/// without touching a real Drive account.
+pub struct LocalDriveServer {
+    listening: Listening,
+
+    /// The number of file contents served so far, shared with the handler.
+    downloads: Arc<AtomicUsize>,
+
+    /// The number of requests which are still to be refused, shared with the handler.
+    failures: Arc<AtomicUsize>,
+
+    /// How many seconds the refused requests ask to wait, shared with the handler.
+    retry_after: Arc<AtomicUsize>,
+}
+
+/// The state shared by all threads serving requests.
+struct LocalDriveHandler {
+    drive: MemoryDrive,
+
+    /// Resumable uploads that have been started but not yet completed.
+    uploads: Mutex<HashMap<String, Upload>>,
+
+    /// Used for generating upload session ids.
+    last_upload: AtomicUsize,
+
+    /// Counts the requests for file contents, including partial ones.
+    downloads: Arc<AtomicUsize>,
+
+    /// How many of the next API requests are refused because of a (simulated) rate limit.
+    failures: Arc<AtomicUsize>,
+
+    /// The Retry-After header of the refused requests, in seconds. Not sent if 0.
+    retry_after: Arc<AtomicUsize>,
+}
+
+/// A resumable upload in progress. `id` is absent if the upload creates a new file.
+struct Upload {
+    id: Option<DriveId>,
+    metadata: drive3::File,
+    data: Vec<u8>,
+}
+
+/// A response which has been computed but not yet sent.
+struct Reply {
+    status: StatusCode,
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+impl LocalDriveServer {
+    /// Starts serving `drive` on a random local port.
+    pub fn start(drive: MemoryDrive) -> Result<Self, Error> {
+        let downloads = Arc::new(AtomicUsize::new(0));
+        let failures = Arc::new(AtomicUsize::new(0));
+        let retry_after = Arc::new(AtomicUsize::new(0));
+        let handler = LocalDriveHandler {
+            drive,
+            uploads: Mutex::new(HashMap::new()),
+            last_upload: AtomicUsize::new(0),
+            downloads: Arc::clone(&downloads),
+            failures: Arc::clone(&failures),
+            retry_after: Arc::clone(&retry_after),
+        };
+
+        let listening = Server::http("127.0.0.1:0")
+            .and_then(|server| server.handle(handler))
+            .map_err(|e| err_msg(format!("Could not start local drive server: {}", e)))?;
+
+        info!("Local drive server listening on {}", &listening.socket);
+        Ok(LocalDriveServer {
+            listening,
+            downloads,
+            failures,
+            retry_after,
+        })
+    }
+
+    /// Makes the next `count` requests fail the way Drive refuses requests when its rate limit is
+    /// exceeded. Token requests are not affected.
+    pub fn fail_next_requests(&self, count: usize) {
+        self.fail_next_requests_asking_to_wait(count, 0);
+    }
+
+    /// Like `fail_next_requests()`, but the refusals ask to wait `seconds` before trying again.
+    pub fn fail_next_requests_asking_to_wait(&self, count: usize, seconds: usize) {
+        self.retry_after.store(seconds, Ordering::SeqCst);
+        self.failures.store(count, Ordering::SeqCst);
+    }
+
+    /// The number of times file contents have been downloaded, either fully or partially.
+    pub fn downloads(&self) -> usize {
+        self.downloads.load(Ordering::SeqCst)
+    }
+
+    /// The value of the `api_root` config key which points GCSF to this server.
+    pub fn api_root(&self) -> String {
+        format!("http://{}/drive/v3/", self.listening.socket)
+    }
+
+    /// The value of the `upload_root` config key which points GCSF to this server.
+    pub fn upload_root(&self) -> String {
+        format!("http://{}/", self.listening.socket)
+    }
+
+    /// The value of the `token_uri` config key which points GCSF to this server.
+    pub fn token_uri(&self) -> String {
+        format!("http://{}/token", self.listening.socket)
+    }
+
+    /// Stores a valid access token in `token_path` and returns a config which makes GCSF use this
+    /// server without going through the OAuth2 flow. Failed requests are retried almost
+    /// immediately.
+    pub fn authorized_config(&self, token_path: &str) -> Result<Config, Error> {
+        let mut token = oauth2::Token {
+            access_token: String::from("local-access-token"),
+            refresh_token: String::from("local-refresh-token"),
+            token_type: String::from("Bearer"),
+            expires_in: Some(3600),
+            expires_in_timestamp: None,
+        };
+        token.set_expiry_absolute();
+
+        // The authenticator looks tokens up by the hash of their sorted scopes.
+        let scopes = vec!["https://www.googleapis.com/auth/drive"];
+        let mut hasher = DefaultHasher::new();
+        scopes.hash(&mut hasher);
+
+        let mut storage = oauth2::DiskTokenStorage::new(&token_path.to_string())?;
+        storage
+            .set(hasher.finish(), &scopes, Some(token))
+            .map_err(|e| err_msg(format!("Could not store token: {}", e)))?;
+
+        let config = format!(
+            "{{\"token_path\": {:?}, \"api_root\": {:?}, \"upload_root\": {:?}, \"token_uri\": {:?}, \"authorize_using_code\": true, \"spool_path\": {:?}, \"recovery_path\": {:?}, \"retry_base_delay_ms\": 1}}",
+            token_path,
+            self.api_root(),
+            self.upload_root(),
+            self.token_uri(),
+            format!("{}.spool", token_path),
+            format!("{}.recovered", token_path)
+        );
+
+        Ok(serde_json::from_str(&config)?)
+    }
+}
+
+impl Drop for LocalDriveServer {
+    fn drop(&mut self) {
+        if let Err(e) = self.listening.close() {
+            warn!("Could not stop local drive server: {}", e);
+        }
+    }
+}
+
+impl Reply {
+    fn json<T: Serialize>(value: &T) -> Self {
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+
+        Reply {
+            status: StatusCode::Ok,
+            headers,
+            body: serde_json::to_vec(value).unwrap_or_default(),
+        }
+    }
+
+    fn bytes(body: Vec<u8>) -> Self {
+        Reply {
+            status: StatusCode::Ok,
+            headers: Headers::new(),
+            body,
+        }
+    }
+
+    fn empty(status: StatusCode) -> Self {
+        Reply {
+            status,
+            headers: Headers::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// An error in the format used by the Drive API, so that `drive3` can parse it.
+    fn error(status: StatusCode, reason: &str, message: &str) -> Self {
+        let code = status.to_u16();
+        let body = format!(
+            "{{\"error\": {{\"errors\": [{{\"domain\": \"global\", \"reason\": {:?}, \"message\": {:?}}}], \"code\": {}, \"message\": {:?}}}}}",
+            reason, message, code, message
+        );
+
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+
+        Reply {
+            status,
+            headers,
+            body: body.into_bytes(),
+        }
+    }
+
+    fn not_found(id: &str) -> Self {
+        Reply::error(
+            StatusCode::NotFound,
+            "notFound",
+            &format!("File not found: {}.", id),
+        )
+    }
+}
+
+impl Handler for LocalDriveHandler {
+    fn handle<'a, 'k>(&'a self, mut req: Request<'a, 'k>, mut res: Response<'a>) {
+        let method = req.method.clone();
+        let headers = req.headers.clone();
+        let url = match req.uri {
+            RequestUri::AbsolutePath(ref path) => Url::parse(&format!("http://localhost{}", path)),
+            ref uri => Url::parse(&uri.to_string()),
+        };
+
+        let mut body = Vec::new();
+        let _ = req.read_to_end(&mut body);
+
+        let reply = match url {
+            Ok(url) => self.route(&method, &url, &headers, body),
+            Err(_) => Reply::empty(StatusCode::BadRequest),
+        };
+
+        debug!("{} {:?} -> {}", &method, &req.uri, &reply.status);
+        *res.status_mut() = reply.status;
+        *res.headers_mut() = reply.headers;
+        if let Err(e) = res.send(&reply.body) {
+            warn!("Could not send response: {}", e);
+        }
+    }
+}
+
+impl LocalDriveHandler {
+    fn route(&self, method: &Method, url: &Url, headers: &Headers, body: Vec<u8>) -> Reply {
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        let segments: Vec<&str> = url.path().trim_matches('/').split('/').collect();
+
+        if segments != ["token"] && self.take_failure() {
+            let mut reply = Reply::error(
+                StatusCode::Forbidden,
+                "userRateLimitExceeded",
+                "User Rate Limit Exceeded.",
+            );
+            let retry_after = self.retry_after.load(Ordering::SeqCst);
+            if retry_after > 0 {
+                reply
+                    .headers
+                    .set_raw("Retry-After", vec![retry_after.to_string().into_bytes()]);
+            }
+            return reply;
+        }
+
+        match (method, &segments[..]) {
+            (&Method::Get, &["drive", "v3", "files"]) => self.list_files(&params),
+            (&Method::Get, &["drive", "v3", "files", id]) => self.get_file(id, &params, headers),
+            (&Method::Get, &["drive", "v3", "files", id, "export"]) => self.download(id),
+            (&Method::Patch, &["drive", "v3", "files", id]) => {
+                match serde_json::from_slice(&body) {
+                    Ok(patch) => self.update_file(id, patch, &params),
+                    Err(_) => Reply::empty(StatusCode::BadRequest),
+                }
+            }
+            (&Method::Delete, &["drive", "v3", "files", id]) => self.delete_file(id),
+            (&Method::Get, &["drive", "v3", "changes", "startPageToken"]) => {
+                Reply::json(&drive3::StartPageToken {
+                    kind: Some("drive#startPageToken".to_string()),
+                    start_page_token: Some(self.drive.start_page_token().to_string()),
+                })
+            }
+            (&Method::Get, &["drive", "v3", "changes"]) => self.list_changes(&params),
+            (&Method::Get, &["drive", "v3", "about"]) => self.about(),
+            (&Method::Get, &["drive", "v3", "teamdrives"]) => self.list_shared_drives(),
+            (&Method::Post, &["upload", "drive", "v3", "files"]) => {
+                self.create_multipart(headers, &body)
+            }
+            (&Method::Post, &["resumable", "upload", "drive", "v3", "files"]) => {
+                self.start_upload(None, headers, &body)
+            }
+            (&Method::Patch, &["resumable", "upload", "drive", "v3", "files", id]) => {
+                self.start_upload(Some(id.to_string()), headers, &body)
+            }
+            (&Method::Put, &["upload-session", session])
+            | (&Method::Post, &["upload-session", session]) => {
+                self.continue_upload(session, headers, body)
+            }
+            (&Method::Post, &["token"]) => {
+                let mut reply = Reply::bytes(
+                    b"{\"access_token\": \"local-access-token\", \"token_type\": \"Bearer\", \"expires_in\": 3600}"
+                        .to_vec(),
+                );
+                reply.headers.set(ContentType::json());
+                reply
+            }
+            _ => Reply::error(StatusCode::NotFound, "notFound", "Unknown endpoint."),
+        }
+    }
+
+    /// Whether the current request should be refused. Uses up one of the pending failures.
+    fn take_failure(&self) -> bool {
+        let mut pending = self.failures.load(Ordering::SeqCst);
+        while pending > 0 {
+            match self.failures.compare_exchange(
+                pending,
+                pending - 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(current) => pending = current,
+            }
+        }
+
+        false
+    }
+
+    /// Translates the "root" alias into the actual Drive ID of "My Drive".
+    fn resolve(&self, id: &str) -> DriveId {
+        if id == "root" {
+            self.drive.clone().root_id().unwrap().to_string()
+        } else {
+            id.to_string()
+        }
+    }
+
+    /// Only understands the queries built by `DriveFacade`: a disjunction of `'id' in parents`
+    /// clauses, optionally combined with `trashed = true/false`, or `sharedWithMe = true` or
+    /// `starred = true`. Lists the files of a shared drive if `teamDriveId` is given, and the
+    /// `pageSize` most recent files if they are ordered by `viewedByMeTime`.
+    fn list_files(&self, params: &HashMap<String, String>) -> Reply {
+        let q = params.get("q").cloned().unwrap_or_default();
+
+        // Odd pieces are quoted strings. They denote parents if they precede "in parents".
+        let pieces: Vec<&str> = q.split('\'').collect();
+        let parents: Vec<DriveId> = (1..pieces.len())
+            .step_by(2)
+            .filter(|&i| i + 1 < pieces.len() && pieces[i + 1].trim().starts_with("in parents"))
+            .map(|i| self.resolve(pieces[i]))
+            .collect();
+
+        let trashed = if q.contains("trashed = true") {
+            Some(true)
+        } else if q.contains("trashed = false") {
+            Some(false)
+        } else {
+            None
+        };
+
+        let parents = if parents.is_empty() {
+            None
+        } else {
+            Some(parents)
+        };
+
+        let page_size = params.get("pageSize").and_then(|size| size.parse::<usize>().ok());
+        let recent = params.get("orderBy").map(|order| order.starts_with("viewedByMeTime"));
+
+        let files = match params.get("teamDriveId") {
+            _ if q.contains("sharedWithMe = true") => self.drive.clone().get_shared_files(),
+            _ if q.contains("starred = true") => self.drive.clone().get_starred_files(),
+            _ if recent == Some(true) => self.drive
+                .clone()
+                .get_recent_files(page_size.unwrap_or(100)),
+            Some(drive_id) => self.drive
+                .clone()
+                .get_drive_files(drive_id, parents.unwrap_or_default()),
+            None => self.drive.clone().get_all_files(parents, trashed),
+        };
+        match files {
+            Ok(files) => Reply::json(&drive3::FileList {
+                kind: Some("drive#fileList".to_string()),
+                files: Some(files),
+                ..Default::default()
+            }),
+            Err(e) => Reply::error(StatusCode::InternalServerError, "backendError", &e.to_string()),
+        }
+    }
+
+    fn get_file(&self, id: &str, params: &HashMap<String, String>, headers: &Headers) -> Reply {
+        if params.get("alt").map(String::as_ref) == Some("media") {
+            return self.download_range(id, headers);
+        }
+
+        match self.drive.file(&self.resolve(id)) {
+            Some(file) => Reply::json(&file),
+            None => Reply::not_found(id),
+        }
+    }
+
+    fn download(&self, id: &str) -> Reply {
+        self.downloads.fetch_add(1, Ordering::SeqCst);
+        match self.drive.content(&self.resolve(id)) {
+            Some(content) => Reply::bytes(content),
+            None => Reply::not_found(id),
+        }
+    }
+
+    /// Serves a download, honouring a "Range: bytes=first-last" header the way Drive does.
+    fn download_range(&self, id: &str, headers: &Headers) -> Reply {
+        self.downloads.fetch_add(1, Ordering::SeqCst);
+        let content = match self.drive.content(&self.resolve(id)) {
+            Some(content) => content,
+            None => return Reply::not_found(id),
+        };
+
+        let (first, last) = match raw_header(headers, "Range").and_then(|r| parse_byte_range(&r)) {
+            Some(range) => range,
+            None => return Reply::bytes(content),
+        };
+
+        let len = content.len() as u64;
+        if first >= len {
+            let mut reply = Reply::empty(StatusCode::RangeNotSatisfiable);
+            reply
+                .headers
+                .set_raw("Content-Range", vec![format!("bytes */{}", len).into_bytes()]);
+            return reply;
+        }
+
+        let last = cmp::min(last, len - 1);
+        let mut reply = Reply::bytes(content[first as usize..last as usize + 1].to_vec());
+        reply.status = StatusCode::PartialContent;
+        reply.headers.set_raw(
+            "Content-Range",
+            vec![format!("bytes {}-{}/{}", first, last, len).into_bytes()],
+        );
+        reply
+    }
+
+    fn update_file(
+        &self,
+        id: &str,
+        patch: serde_json::Value,
+        params: &HashMap<String, String>,
+    ) -> Reply {
+        let id = self.resolve(id);
+        let file = match self.drive.file(&id) {
+            Some(file) => file,
+            None => return Reply::not_found(&id),
+        };
+
+        let mut file = merge_value(file, &patch);
+        let split = |key: &str| -> Vec<DriveId> {
+            params
+                .get(key)
+                .map(|ids| ids.split(',').map(|id| self.resolve(id)).collect())
+                .unwrap_or_default()
+        };
+
+        let (added, removed) = (split("addParents"), split("removeParents"));
+        if !added.is_empty() || !removed.is_empty() {
+            let mut parents: Vec<DriveId> = file.parents
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|p| !removed.contains(p))
+                .collect();
+            parents.extend(added);
+            file.parents = Some(parents);
+        }
+
+        match self.drive.update_file(file.clone()) {
+            Ok(()) => Reply::json(&self.drive.file(&id).unwrap_or(file)),
+            Err(_) => Reply::not_found(&id),
+        }
+    }
+
+    fn delete_file(&self, id: &str) -> Reply {
+        let id = self.resolve(id);
+        if self.drive.file(&id).is_none() {
+            return Reply::not_found(&id);
+        }
+
+        self.drive.remove_file(&id);
+        Reply::empty(StatusCode::NoContent)
+    }
+
+    fn list_changes(&self, params: &HashMap<String, String>) -> Reply {
+        let token = match params.get("pageToken").and_then(|t| t.parse::<usize>().ok()) {
+            Some(token) => token,
+            None => {
+                return Reply::error(StatusCode::BadRequest, "invalid", "Invalid page token.");
+            }
+        };
+
+        let drive_id = params.get("teamDriveId").map(String::as_str);
+        let (changes, next_token) = self.drive.changes_since(token, drive_id);
+        Reply::json(&drive3::ChangeList {
+            kind: Some("drive#changeList".to_string()),
+            changes: Some(changes),
+            new_start_page_token: Some(next_token.to_string()),
+            next_page_token: None,
+        })
+    }
+
+    fn list_shared_drives(&self) -> Reply {
+        match self.drive.clone().shared_drives() {
+            Ok(drives) => Reply::json(&drive3::TeamDriveList {
+                kind: Some("drive#teamDriveList".to_string()),
+                team_drives: Some(drives),
+                next_page_token: None,
+            }),
+            Err(e) => Reply::error(StatusCode::InternalServerError, "backendError", &e.to_string()),
+        }
+    }
+
+    fn about(&self) -> Reply {
+        match self.drive.clone().size_and_capacity() {
+            Ok((usage, limit)) => Reply::json(&drive3::About {
+                kind: Some("drive#about".to_string()),
+                storage_quota: Some(drive3::AboutStorageQuota {
+                    usage: Some(usage.to_string()),
+                    limit: limit.map(|l| l.to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            Err(e) => Reply::error(StatusCode::InternalServerError, "backendError", &e.to_string()),
+        }
+    }
+
+    /// Handles `uploadType=multipart`: the first part holds the metadata, the second the content.
+    fn create_multipart(&self, headers: &Headers, body: &[u8]) -> Reply {
+        let content_type = raw_header(headers, "Content-Type").unwrap_or_default();
+        let boundary = match content_type.split("boundary=").nth(1) {
+            Some(boundary) => format!("--{}", boundary.trim_matches('"')),
+            None => return Reply::empty(StatusCode::BadRequest),
+        };
+
+        let parts: Vec<&[u8]> = split_bytes(body, boundary.as_bytes())
+            .into_iter()
+            .filter_map(|part| {
+                let start = find_bytes(part, b"\r\n\r\n")? + 4;
+                let end = if part.ends_with(b"\r\n") {
+                    part.len() - 2
+                } else {
+                    part.len()
+                };
+                Some(&part[start..end])
+            })
+            .collect();
+
+        let metadata: drive3::File = match parts.first().map(|m| serde_json::from_slice(m)) {
+            Some(Ok(metadata)) => metadata,
+            _ => return Reply::empty(StatusCode::BadRequest),
+        };
+
+        let content = parts.get(1).map(|c| c.to_vec()).unwrap_or_default();
+        Reply::json(&self.create_file(metadata, &content))
+    }
+
+    fn create_file(&self, mut metadata: drive3::File, content: &[u8]) -> drive3::File {
+        metadata.id = None;
+        metadata.parents = metadata
+            .parents
+            .map(|parents| parents.iter().map(|p| self.resolve(p)).collect());
+
+        let id = self.drive.add_file(metadata, content);
+        self.drive.file(&id).unwrap()
+    }
+
+    /// Handles `uploadType=resumable`: hands out a session URL which the content is PUT to.
+    fn start_upload(&self, id: Option<DriveId>, headers: &Headers, body: &[u8]) -> Reply {
+        let id = id.map(|id| self.resolve(&id));
+        if let Some(ref id) = id {
+            if self.drive.file(id).is_none() {
+                return Reply::not_found(id);
+            }
+        }
+
+        let metadata = serde_json::from_slice(body).unwrap_or_default();
+        let session = (self.last_upload.fetch_add(1, Ordering::SeqCst) + 1).to_string();
+        self.uploads.lock().unwrap().insert(
+            session.clone(),
+            Upload {
+                id,
+                metadata,
+                data: Vec::new(),
+            },
+        );
+
+        let host = headers
+            .get::<Host>()
+            .map(|host| match host.port {
+                Some(port) => format!("{}:{}", host.hostname, port),
+                None => host.hostname.clone(),
+            })
+            .unwrap_or(String::from("localhost"));
+
+        let mut reply = Reply::empty(StatusCode::Ok);
+        reply
+            .headers
+            .set(Location(format!("http://{}/upload-session/{}", host, session)));
+        reply
+    }
+
+    /// Receives a chunk of a resumable upload. The upload is applied once the last byte arrives.
+    fn continue_upload(&self, session: &str, headers: &Headers, body: Vec<u8>) -> Reply {
+        let mut uploads = self.uploads.lock().unwrap();
+        let complete = {
+            let upload = match uploads.get_mut(session) {
+                Some(upload) => upload,
+                None => return Reply::not_found(session),
+            };
+            upload.data.extend(body);
+
+            // Content-Range is either "bytes first-last/total" or "bytes */total".
+            let total = raw_header(headers, "Content-Range")
+                .and_then(|range| range.rsplit('/').next().map(str::to_string))
+                .and_then(|total| total.trim().parse::<usize>().ok());
+
+            match total {
+                Some(total) => upload.data.len() >= total,
+                None => true,
+            }
+        };
+
+        if !complete {
+            let received = uploads.get(session).map(|u| u.data.len()).unwrap_or(0);
+            let mut reply = Reply::empty(StatusCode::PermanentRedirect);
+            if received > 0 {
+                reply
+                    .headers
+                    .set_raw("Range", vec![format!("bytes=0-{}", received - 1).into_bytes()]);
+            }
+            return reply;
+        }
+
+        let upload = uploads.remove(session).unwrap();
+        let file = match upload.id {
+            Some(id) => {
+                let file = match self.drive.file(&id) {
+                    Some(file) => merge(file, upload.metadata),
+                    None => return Reply::not_found(&id),
+                };
+                let _ = self.drive.update_file(file);
+                let _ = self.drive.update_content(&id, &upload.data);
+                self.drive.file(&id).unwrap()
+            }
+            None => self.create_file(upload.metadata, &upload.data),
+        };
+
+        Reply::json(&file)
+    }
+}
+
+/// Parses "bytes=first-last". An open-ended range ("bytes=first-") extends to the end of the file.
+fn parse_byte_range(range: &str) -> Option<(u64, u64)> {
+    let range = range.trim();
+    if !range.starts_with("bytes=") {
+        return None;
+    }
+
+    let mut bounds = range["bytes=".len()..].splitn(2, '-');
+    let first = bounds.next()?.trim().parse().ok()?;
+    let last = match bounds.next()?.trim() {
+        "" => u64::MAX,
+        last => last.parse().ok()?,
+    };
+
+    Some((first, last))
+}
+
+fn raw_header(headers: &Headers, name: &str) -> Option<String> {
+    headers
+        .get_raw(name)
+        .and_then(|values| values.iter().next())
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Splits `data` around every occurrence of `separator`, dropping the pieces before the first
+/// and after the last occurrence.
+fn split_bytes<'a>(data: &'a [u8], separator: &[u8]) -> Vec<&'a [u8]> {
+    let mut pieces = Vec::new();
+    let mut rest = match find_bytes(data, separator) {
+        Some(pos) => &data[pos + separator.len()..],
+        None => return pieces,
+    };
+
+    while let Some(pos) = find_bytes(rest, separator) {
+        pieces.push(&rest[..pos]);
+        rest = &rest[pos + separator.len()..];
+    }
+
+    pieces
+}
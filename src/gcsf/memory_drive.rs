@@ -0,0 +1,707 @@
+use super::error::FsError;
+use super::DriveBackend;
+use chrono::Utc;
+use drive3;
+use failure::{err_msg, Error};
+use serde_json;
+use serde_json::{Map, Value};
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+type DriveId = String;
+
+const ROOT_ID: &str = "memory-root";
+const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+
+/// The fields of a Drive file which hold key-value properties. Updating them only replaces the
+/// given keys.
+const PROPERTY_FIELDS: &[&str] = &["appProperties", "properties"];
+
+/// A fake Google Drive which keeps all of its files, contents and changes in memory. It behaves
+/// like `DriveFacade` as far as `FileManager` can tell, so it can be used for deterministic tests
+/// of the file system layer.
+///
+/// Clones share the same underlying Drive, which makes it possible to keep a handle around and
+/// simulate remote modifications after the original has been handed over to a `FileManager`.
+#[derive(Clone)]
+pub struct MemoryDrive {
+    /// The state of the fake Drive, shared between all clones.
+    state: Arc<Mutex<MemoryDriveState>>,
+
+    /// A buffer used for returning read blocks by reference.
+    buff: Vec<u8>,
+
+    /// Maps Drive IDs to a list of writes that have not been flushed yet.
+    pending_writes: HashMap<DriveId, Vec<PendingWrite>>,
+
+    /// The position in the change log up to which changes have already been reported.
+    changes_token: Option<String>,
+
+    /// The same, for the changes of each shared drive.
+    drive_changes_tokens: HashMap<DriveId, String>,
+
+    /// The Drive ID of the root "My Drive" directory.
+    root_id: DriveId,
+}
+
+/// An operation recorded by `write` or `truncate`, which is applied when the file is flushed.
+#[derive(Clone)]
+enum PendingWrite {
+    Data(usize, Vec<u8>),
+    Truncate(usize),
+}
+
+#[derive(Default)]
+struct MemoryDriveState {
+    /// All files known to the fake Drive, including the trashed ones.
+    files: HashMap<DriveId, drive3::File>,
+
+    /// The shared drives which the account is a member of.
+    shared_drives: Vec<drive3::TeamDrive>,
+
+    /// The content of each file.
+    contents: HashMap<DriveId, Vec<u8>>,
+
+    /// Every change ever performed on the fake Drive. Page tokens are positions in this list.
+    changes: Vec<drive3::Change>,
+
+    /// Used for generating new Drive IDs.
+    last_id: u64,
+
+    /// The reported capacity of the fake Drive.
+    capacity: Option<u64>,
+
+    /// How long each flush takes before it is applied.
+    flush_delay: Option<Duration>,
+
+    /// Whether requests fail as if the fake Drive could not be reached.
+    offline: bool,
+
+    /// How many more requests are answered before the fake Drive goes offline, if it is about to.
+    requests_until_offline: Option<usize>,
+
+    /// Page tokens which are older than this are no longer accepted.
+    oldest_token: usize,
+}
+
+impl MemoryDriveState {
+    fn check_online(&mut self) -> Result<(), Error> {
+        match self.requests_until_offline {
+            Some(0) => {
+                self.offline = true;
+                self.requests_until_offline = None;
+            }
+            Some(requests) => self.requests_until_offline = Some(requests - 1),
+            None => {}
+        }
+        if self.offline {
+            return Err(FsError::Offline("MemoryDrive is offline".to_string()).into());
+        }
+        Ok(())
+    }
+
+    fn next_id(&mut self) -> DriveId {
+        self.last_id += 1;
+        format!("memory-{:08}", self.last_id)
+    }
+
+    /// Appends a change to the change log. A `None` file indicates a removal.
+    fn record_change(&mut self, id: &str, file: Option<drive3::File>) {
+        let team_drive_id = file.as_ref()
+            .or_else(|| self.files.get(id))
+            .and_then(|f| f.team_drive_id.clone());
+        self.changes.push(drive3::Change {
+            kind: Some("drive#change".to_string()),
+            type_: Some("file".to_string()),
+            time: Some(Utc::now().to_rfc3339()),
+            removed: Some(file.is_none()),
+            file_id: Some(id.to_string()),
+            file,
+            team_drive_id,
+            ..Default::default()
+        });
+    }
+
+    /// Stores a file (and optionally its content) and records the corresponding change.
+    fn put_file(&mut self, mut file: drive3::File, content: Option<Vec<u8>>) -> DriveId {
+        let id = match file.id.clone() {
+            Some(id) => id,
+            None => self.next_id(),
+        };
+
+        if let Some(content) = content {
+            file.head_revision_id = Some(format!("revision-{}", self.changes.len()));
+            file.size = Some(content.len().to_string());
+            self.contents.insert(id.clone(), content);
+        }
+
+        file.id = Some(id.clone());
+        file.trashed = Some(file.trashed.unwrap_or(false));
+        file.parents = Some(file.parents.unwrap_or(vec![ROOT_ID.to_string()]));
+        file.modified_time = Some(Utc::now().to_rfc3339());
+        if file.created_time.is_none() {
+            file.created_time = file.modified_time.clone();
+        }
+
+        self.files.insert(id.clone(), file.clone());
+        self.record_change(&id, Some(file));
+        id
+    }
+
+    /// Removes a file and all of its descendants, recording a change for each of them.
+    fn remove_file(&mut self, id: &str) {
+        let children: Vec<DriveId> = self.files
+            .values()
+            .filter(|f| f.parents.as_ref().map(|p| p.contains(&id.to_string())) == Some(true))
+            .filter_map(|f| f.id.clone())
+            .collect();
+
+        for child in children {
+            self.remove_file(&child);
+        }
+
+        self.record_change(id, None);
+        self.files.remove(id);
+        self.contents.remove(id);
+    }
+}
+
+impl Default for MemoryDrive {
+    fn default() -> Self {
+        MemoryDrive::new()
+    }
+}
+
+impl MemoryDrive {
+    /// Creates an empty fake Drive which only contains the "My Drive" root directory.
+    pub fn new() -> Self {
+        MemoryDrive {
+            state: Arc::new(Mutex::new(MemoryDriveState::default())),
+            buff: Vec::new(),
+            pending_writes: HashMap::new(),
+            changes_token: None,
+            drive_changes_tokens: HashMap::new(),
+            root_id: ROOT_ID.to_string(),
+        }
+    }
+
+    /// Sets the capacity reported by `size_and_capacity()`. Flushing fails once the contents
+    /// would no longer fit. By default, there is no limit.
+    pub fn set_capacity(&self, capacity: Option<u64>) {
+        self.state.lock().unwrap().capacity = capacity;
+    }
+
+    /// Makes every flush wait for `delay` before it is applied, as if the upload were slow.
+    pub fn set_flush_delay(&self, delay: Option<Duration>) {
+        self.state.lock().unwrap().flush_delay = delay;
+    }
+
+    /// Makes all requests fail as if the fake Drive could not be reached, until it is set back
+    /// online. Writes are still recorded, since they do not reach Drive before a flush.
+    pub fn set_offline(&self, offline: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.offline = offline;
+        state.requests_until_offline = None;
+    }
+
+    /// Answers `requests` more requests, then goes offline like `set_offline(true)`.
+    pub fn go_offline_after(&self, requests: usize) {
+        self.state.lock().unwrap().requests_until_offline = Some(requests);
+    }
+
+    /// Makes all page tokens which have been handed out so far expire, as Drive does with old ones.
+    pub fn expire_changes_tokens(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.oldest_token = state.changes.len();
+    }
+
+    /// Adds a file as if it was created remotely. If the file has no parents, it is placed in
+    /// "My Drive". Returns the Drive ID of the file.
+    pub fn add_file(&self, file: drive3::File, content: &[u8]) -> DriveId {
+        self.state
+            .lock()
+            .unwrap()
+            .put_file(file, Some(content.to_vec()))
+    }
+
+    /// Adds a directory as if it was created remotely. Returns its Drive ID.
+    pub fn add_dir(&self, name: &str, parent: Option<&str>) -> DriveId {
+        let dir = drive3::File {
+            name: Some(name.to_string()),
+            mime_type: Some(FOLDER_MIME_TYPE.to_string()),
+            parents: parent.map(|p| vec![p.to_string()]),
+            ..Default::default()
+        };
+
+        self.state.lock().unwrap().put_file(dir, None)
+    }
+
+    /// Adds a shared drive which the account is a member of. Files are added to it by giving them
+    /// its ID as `team_drive_id` and as parent. Returns its ID.
+    pub fn add_shared_drive(&self, name: &str) -> DriveId {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id();
+        state.shared_drives.push(drive3::TeamDrive {
+            id: Some(id.clone()),
+            name: Some(name.to_string()),
+            capabilities: Some(drive3::TeamDriveCapabilities {
+                can_add_children: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        id
+    }
+
+    /// Replaces the metadata of an existing file as if it was modified remotely.
+    pub fn update_file(&self, file: drive3::File) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        let id = file.id.clone().ok_or(err_msg("File has no drive id"))?;
+        if !state.files.contains_key(&id) {
+            return Err(not_found(&id));
+        }
+
+        state.put_file(file, None);
+        Ok(())
+    }
+
+    /// Replaces the content of an existing file as if it was modified remotely.
+    pub fn update_content(&self, id: &str, content: &[u8]) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        let file = state
+            .files
+            .get(id)
+            .cloned()
+            .ok_or_else(|| not_found(id))?;
+
+        state.put_file(file, Some(content.to_vec()));
+        Ok(())
+    }
+
+    /// Removes a file (and its descendants) as if it was deleted remotely.
+    pub fn remove_file(&self, id: &str) {
+        self.state.lock().unwrap().remove_file(id);
+    }
+
+    /// Returns the current metadata of a file, if it exists.
+    pub fn file(&self, id: &str) -> Option<drive3::File> {
+        self.state.lock().unwrap().files.get(id).cloned()
+    }
+
+    /// Returns the current content of a file, if it exists.
+    pub fn content(&self, id: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().contents.get(id).cloned()
+    }
+
+    /// Returns a page token which only covers changes performed from now on.
+    pub fn start_page_token(&self) -> usize {
+        self.state.lock().unwrap().changes.len()
+    }
+
+    /// Reads a page token handed out by `changes_token()` or `drive_changes_token()`.
+    fn parse_token(&self, token: &str) -> Result<usize, Error> {
+        match token.parse::<usize>() {
+            Ok(token) if token >= self.state.lock().unwrap().oldest_token => Ok(token),
+            _ => Err(FsError::ExpiredToken(format!("Invalid page token {}", token)).into()),
+        }
+    }
+
+    /// Returns all changes performed since `token` was issued, along with the next page token.
+    /// Only the changes of the shared drive `drive` are returned, or the ones of "My Drive" if it
+    /// is `None`.
+    pub fn changes_since(&self, token: usize, drive: Option<&str>) -> (Vec<drive3::Change>, usize) {
+        let state = self.state.lock().unwrap();
+        let start = cmp::min(token, state.changes.len());
+        let changes = state.changes[start..]
+            .iter()
+            .filter(|change| change.team_drive_id.as_deref() == drive)
+            .cloned()
+            .collect();
+        (changes, state.changes.len())
+    }
+
+    /// Returns the files of the shared drive `drive` (or of "My Drive" if it is `None`) which are
+    /// children of any of `parents`, if given.
+    fn files_in(
+        &self,
+        drive: Option<&str>,
+        parents: Option<Vec<DriveId>>,
+        trashed: Option<bool>,
+    ) -> Result<Vec<drive3::File>, Error> {
+        let mut state = self.state.lock().unwrap();
+        state.check_online()?;
+        let mut files: Vec<drive3::File> = state
+            .files
+            .values()
+            .filter(|f| f.team_drive_id.as_deref() == drive)
+            .filter(|f| match parents {
+                Some(ref parents) => f.parents
+                    .as_ref()
+                    .map(|p| p.iter().any(|id| parents.contains(id))) == Some(true),
+                None => true,
+            })
+            .filter(|f| match trashed {
+                Some(trashed) => f.trashed.unwrap_or(false) == trashed,
+                None => true,
+            })
+            .cloned()
+            .collect();
+
+        files.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(files)
+    }
+}
+
+impl DriveBackend for MemoryDrive {
+    fn root_id(&mut self) -> Result<&String, Error> {
+        Ok(&self.root_id)
+    }
+
+    fn changes_token(&mut self) -> Result<&String, Error> {
+        if self.changes_token.is_none() {
+            self.state.lock().unwrap().check_online()?;
+            self.changes_token = Some(self.start_page_token().to_string());
+        }
+
+        Ok(self.changes_token.as_ref().unwrap())
+    }
+
+    fn set_changes_token(&mut self, token: Option<String>) {
+        self.changes_token = token;
+    }
+
+    fn get_all_changes(&mut self) -> Result<Vec<drive3::Change>, Error> {
+        self.state.lock().unwrap().check_online()?;
+        let token = self.changes_token()?.clone();
+        let token = self.parse_token(&token)?;
+
+        let (changes, next_token) = self.changes_since(token, None);
+        self.changes_token = Some(next_token.to_string());
+        Ok(changes)
+    }
+
+    fn get_shared_files(&mut self) -> Result<Vec<drive3::File>, Error> {
+        let files = self.files_in(None, None, Some(false))?;
+        Ok(files
+            .into_iter()
+            .filter(|f| f.shared_with_me_time.is_some())
+            .collect())
+    }
+
+    fn get_starred_files(&mut self) -> Result<Vec<drive3::File>, Error> {
+        let files = self.files_in(None, None, Some(false))?;
+        Ok(files
+            .into_iter()
+            .filter(|f| f.starred == Some(true))
+            .collect())
+    }
+
+    fn get_recent_files(&mut self, limit: usize) -> Result<Vec<drive3::File>, Error> {
+        let mut files: Vec<drive3::File> = self.files_in(None, None, Some(false))?
+            .into_iter()
+            .filter(|f| f.mime_type.as_deref() != Some(FOLDER_MIME_TYPE))
+            .collect();
+
+        // Timestamps are in RFC 3339, so they can be compared as strings.
+        files.sort_by(|a, b| {
+            (&b.viewed_by_me_time, &b.modified_by_me_time)
+                .cmp(&(&a.viewed_by_me_time, &a.modified_by_me_time))
+        });
+        files.truncate(limit);
+        Ok(files)
+    }
+
+    fn shared_drives(&mut self) -> Result<Vec<drive3::TeamDrive>, Error> {
+        let mut state = self.state.lock().unwrap();
+        state.check_online()?;
+        Ok(state.shared_drives.clone())
+    }
+
+    fn drive_changes_token(&mut self, drive_id: &DriveId) -> Result<&String, Error> {
+        if !self.drive_changes_tokens.contains_key(drive_id) {
+            self.state.lock().unwrap().check_online()?;
+            let token = self.start_page_token().to_string();
+            self.drive_changes_tokens.insert(drive_id.clone(), token);
+        }
+
+        Ok(&self.drive_changes_tokens[drive_id])
+    }
+
+    fn set_drive_changes_token(&mut self, drive_id: &DriveId, token: Option<String>) {
+        match token {
+            Some(token) => self.drive_changes_tokens.insert(drive_id.clone(), token),
+            None => self.drive_changes_tokens.remove(drive_id),
+        };
+    }
+
+    fn get_drive_changes(&mut self, drive_id: &DriveId) -> Result<Vec<drive3::Change>, Error> {
+        self.state.lock().unwrap().check_online()?;
+        let token = self.drive_changes_token(drive_id)?.clone();
+        let token = self.parse_token(&token)?;
+
+        let (changes, next_token) = self.changes_since(token, Some(drive_id));
+        self.drive_changes_tokens
+            .insert(drive_id.clone(), next_token.to_string());
+        Ok(changes)
+    }
+
+    fn get_all_files(
+        &mut self,
+        parents: Option<Vec<DriveId>>,
+        trashed: Option<bool>,
+    ) -> Result<Vec<drive3::File>, Error> {
+        self.files_in(None, parents, trashed)
+    }
+
+    fn get_drive_files(
+        &mut self,
+        drive_id: &DriveId,
+        parents: Vec<DriveId>,
+    ) -> Result<Vec<drive3::File>, Error> {
+        self.files_in(Some(drive_id), Some(parents), Some(false))
+    }
+
+    fn read(
+        &mut self,
+        drive_id: &str,
+        _mime_type: Option<String>,
+        offset: usize,
+        size: usize,
+    ) -> Result<&[u8], Error> {
+        let data = {
+            let mut state = self.state.lock().unwrap();
+            state.check_online()?;
+            state
+                .contents
+                .get(drive_id)
+                .cloned()
+                .ok_or_else(|| not_found(drive_id))?
+        };
+        self.buff =
+            data[cmp::min(data.len(), offset)..cmp::min(data.len(), offset + size)].to_vec();
+        Ok(&self.buff)
+    }
+
+    fn get_metadata(&mut self, id: &DriveId) -> Result<drive3::File, Error> {
+        let mut state = self.state.lock().unwrap();
+        state.check_online()?;
+        state.files.get(id).cloned().ok_or_else(|| not_found(id))
+    }
+
+    fn create(&mut self, drive_file: &drive3::File) -> Result<DriveId, Error> {
+        let mut file = drive_file.clone();
+        file.id = None;
+
+        let mut state = self.state.lock().unwrap();
+        state.check_online()?;
+        for parent in file.parents.iter().flatten() {
+            let known = parent == ROOT_ID
+                || state.files.contains_key(parent)
+                || state.shared_drives.iter().any(|d| d.id.as_ref() == Some(parent));
+            if !known {
+                return Err(not_found(parent));
+            }
+        }
+        Ok(state.put_file(file, Some(Vec::new())))
+    }
+
+    fn write(&mut self, id: DriveId, offset: usize, data: &[u8]) -> Result<(), Error> {
+        self.pending_writes
+            .entry(id)
+            .or_default()
+            .push(PendingWrite::Data(offset, data.to_vec()));
+        Ok(())
+    }
+
+    fn truncate(&mut self, id: &DriveId, size: u64) -> Result<(), Error> {
+        self.pending_writes
+            .entry(id.clone())
+            .or_default()
+            .push(PendingWrite::Truncate(size as usize));
+        Ok(())
+    }
+
+    fn flush(&mut self, id: &DriveId) -> Result<(), Error> {
+        let delay = self.state.lock().unwrap().flush_delay;
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+        self.state.lock().unwrap().check_online()?;
+        let writes = match self.pending_writes.get(id) {
+            Some(writes) => writes,
+            None => return Ok(()),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let file = state
+            .files
+            .get(id)
+            .cloned()
+            .ok_or_else(|| not_found(id))?;
+
+        let mut content = state.contents.get(id).cloned().unwrap_or_default();
+        for write in writes {
+            match *write {
+                PendingWrite::Data(offset, ref data) => {
+                    let required_size = cmp::max(content.len(), offset + data.len());
+                    content.resize(required_size, 0);
+                    content[offset..offset + data.len()].copy_from_slice(data);
+                }
+                PendingWrite::Truncate(size) => content.resize(size, 0),
+            }
+        }
+
+        let usage: u64 = state
+            .contents
+            .iter()
+            .filter(|&(other, _)| other != id)
+            .map(|(_, c)| c.len() as u64)
+            .sum();
+        if let Some(capacity) = state.capacity {
+            if usage + content.len() as u64 > capacity {
+                return Err(FsError::QuotaExceeded(format!("flush({})", id)).into());
+            }
+        }
+
+        state.put_file(file, Some(content));
+        self.pending_writes.remove(id);
+        Ok(())
+    }
+
+    fn dirty_files(&self) -> Vec<DriveId> {
+        self.pending_writes.keys().cloned().collect()
+    }
+
+    fn move_to(
+        &mut self,
+        id: &DriveId,
+        parent: &DriveId,
+        new_name: &str,
+    ) -> Result<drive3::File, Error> {
+        let mut state = self.state.lock().unwrap();
+        state.check_online()?;
+        let mut file = state
+            .files
+            .get(id)
+            .cloned()
+            .ok_or_else(|| not_found(id))?;
+
+        file.name = Some(new_name.to_string());
+        file.parents = Some(vec![parent.to_string()]);
+        state.put_file(file.clone(), None);
+        Ok(file)
+    }
+
+    fn update_metadata(
+        &mut self,
+        id: &DriveId,
+        patch: &drive3::File,
+    ) -> Result<drive3::File, Error> {
+        let mut state = self.state.lock().unwrap();
+        state.check_online()?;
+        let file = state
+            .files
+            .get(id)
+            .cloned()
+            .ok_or_else(|| not_found(id))?;
+
+        let file = merge(file, patch.clone());
+        state.put_file(file.clone(), None);
+        Ok(file)
+    }
+
+    fn update_properties(
+        &mut self,
+        id: &DriveId,
+        properties: &HashMap<String, Option<String>>,
+    ) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        state.check_online()?;
+        let file = state
+            .files
+            .get(id)
+            .cloned()
+            .ok_or_else(|| not_found(id))?;
+
+        let mut patch = Map::new();
+        patch.insert("properties".to_string(), serde_json::to_value(properties)?);
+        state.put_file(merge_value(file, &Value::Object(patch)), None);
+        Ok(())
+    }
+
+    fn move_to_trash(&mut self, id: DriveId) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        state.check_online()?;
+        let mut file = state
+            .files
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| not_found(&id))?;
+
+        file.trashed = Some(true);
+        state.put_file(file, None);
+        Ok(())
+    }
+
+    fn delete_permanently(&mut self, id: &DriveId) -> Result<bool, Error> {
+        let mut state = self.state.lock().unwrap();
+        state.check_online()?;
+        if !state.files.contains_key(id) {
+            return Err(not_found(id));
+        }
+
+        state.remove_file(id);
+        Ok(true)
+    }
+
+    fn size_and_capacity(&mut self) -> Result<(u64, Option<u64>), Error> {
+        let mut state = self.state.lock().unwrap();
+        state.check_online()?;
+        let usage = state.contents.values().map(|c| c.len() as u64).sum();
+        Ok((usage, state.capacity))
+    }
+}
+
+/// Applies the fields which are set in `patch` to `file`, the way Drive handles a `files.update`
+/// request. Properties are merged key by key.
+pub fn merge(file: drive3::File, patch: drive3::File) -> drive3::File {
+    let patch = serde_json::to_value(&patch).unwrap_or(Value::Null);
+    merge_value(file, &patch)
+}
+
+/// Like `merge()`, but takes the patch as JSON, which makes it possible to remove a property by
+/// setting it to null.
+pub fn merge_value(file: drive3::File, patch: &Value) -> drive3::File {
+    let mut value = serde_json::to_value(&file).unwrap_or(Value::Null);
+    if let (Some(target), Some(patch)) = (value.as_object_mut(), patch.as_object()) {
+        for (key, val) in patch.iter().filter(|&(_, val)| !val.is_null()) {
+            let merged = match *val {
+                Value::Object(ref properties) if PROPERTY_FIELDS.contains(&key.as_str()) => {
+                    let mut merged = match target.get(key) {
+                        Some(Value::Object(existing)) => existing.clone(),
+                        _ => Map::new(),
+                    };
+                    for (name, property) in properties {
+                        if property.is_null() {
+                            merged.remove(name);
+                        } else {
+                            merged.insert(name.clone(), property.clone());
+                        }
+                    }
+                    Value::Object(merged)
+                }
+                _ => val.clone(),
+            };
+            target.insert(key.clone(), merged);
+        }
+    }
+
+    serde_json::from_value(value).unwrap_or(file)
+}
+
+fn not_found(id: &str) -> Error {
+    FsError::NotFound(format!("No such file: {}", id)).into()
+}
@@ -1,10 +1,32 @@
+pub use self::background_sync::BackgroundSync;
 pub use self::config::Config;
+pub use self::content_cache::{ContentCache, ContentVersion};
+pub use self::drive_backend::DriveBackend;
 pub use self::drive_facade::DriveFacade;
-pub use self::file::{File, FileId};
+pub use self::error::FsError;
+pub use self::file::{AttrDefaults, File, FileId};
+pub use self::file_handles::{FileHandle, FileHandles};
 pub use self::file_manager::FileManager;
+pub use self::local_drive_server::LocalDriveServer;
+pub use self::memory_drive::MemoryDrive;
+pub use self::offline_drive::OfflineDrive;
 
+mod background_sync;
 mod config;
+mod content_cache;
+mod drive_backend;
 mod drive_facade;
+mod error;
 mod file;
+mod file_handles;
 mod file_manager;
 pub mod filesystem;
+mod journal;
+mod local_drive_server;
+mod memory_drive;
+mod offline_drive;
+mod retry;
+mod snapshot;
+mod spool;
+mod worker_pool;
+pub mod xattr;
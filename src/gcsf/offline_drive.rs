@@ -0,0 +1,458 @@
+use super::error::FsError;
+use super::journal::{Entry, Journal, Mutation};
+use super::DriveBackend;
+use drive3;
+use failure::Error;
+use std::cmp;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+type DriveId = String;
+
+/// Temporary Drive IDs, which are handed out for files created while Drive is unreachable, start
+/// with this prefix.
+const LOCAL_ID_PREFIX: &str = "gcsf-local-";
+
+/// Queued writes are replayed in pieces of at most this many bytes.
+const REPLAY_PIECE_LEN: u64 = 4 * 1024 * 1024;
+
+/// Wraps another `DriveBackend` so that the file system keeps working while Drive is unreachable.
+///
+/// A mutation which fails because Drive cannot be reached is recorded in a persistent `Journal`
+/// and reported as successful, so that `FileManager` applies it to the local file tree as usual.
+/// From then on, all mutations are queued behind it in order to preserve their order. Reads are
+/// still sent to the wrapped backend, which serves them from its cache if it can.
+///
+/// The journal is replayed in order the next time the changes are retrieved, i.e. once Drive is
+/// reachable again. Files created in the meantime get a temporary ID, which `FileManager` swaps
+/// for the real one (see `DriveBackend::take_replaced_ids()`).
+pub struct OfflineDrive<D: DriveBackend> {
+    inner: D,
+    journal: Journal,
+
+    /// Where the data of writes which cannot be replayed is moved to, so that it is not lost.
+    recovery_dir: PathBuf,
+
+    /// Holds the data returned by the last read of a file which only exists in the journal.
+    buff: Vec<u8>,
+}
+
+impl<D: DriveBackend> OfflineDrive<D> {
+    /// Wraps `inner`, keeping the journal in `journal_dir`. Mutations queued during a previous
+    /// session are replayed along with the next ones. The data of writes which cannot be replayed
+    /// is moved to `recovery_dir`.
+    pub fn new(inner: D, journal_dir: &Path, recovery_dir: &Path) -> Self {
+        OfflineDrive {
+            inner,
+            journal: Journal::open(journal_dir),
+            recovery_dir: recovery_dir.to_path_buf(),
+            buff: Vec::new(),
+        }
+    }
+
+    /// The number of mutations queued in the journal stored in `journal_dir`, e.g. by a previous
+    /// session.
+    pub fn queued_mutations(journal_dir: &Path) -> usize {
+        Journal::open(journal_dir).len()
+    }
+
+    /// Whether some mutations are waiting for Drive to become reachable.
+    pub fn is_offline(&self) -> bool {
+        !self.journal.is_empty()
+    }
+
+    /// Queues a mutation, to be replayed once Drive is reachable again.
+    fn queue(&mut self, mutation: Mutation) -> Result<(), Error> {
+        debug!("Drive is unreachable, queueing {:?}", &mutation);
+        self.journal.push(mutation)
+    }
+
+    /// Replays the queued mutations in order. Stops at the first one which fails because Drive is
+    /// still unreachable. Mutations which fail for any other reason are logged and dropped, since
+    /// sending them again would not help, but the data they carry is kept (see `recover()`).
+    fn replay(&mut self) -> Result<(), Error> {
+        if self.journal.is_empty() {
+            return Ok(());
+        }
+
+        info!("Replaying {} queued mutations", self.journal.len());
+        while let Some(entry) = self.journal.front().cloned() {
+            match self.apply(&entry) {
+                Ok(()) => info!("Replayed {:?}", &entry.mutation),
+                Err(e) => {
+                    if FsError::is_offline(&e) {
+                        warn!(
+                            "Drive is still unreachable, {} mutations remain queued",
+                            self.journal.len()
+                        );
+                        return Err(e);
+                    }
+                    error!("Could not replay {:?}, dropping it: {}", &entry.mutation, e);
+                    match self.recover(&entry) {
+                        Ok(Some(path)) => error!("Moved its data to {:?}", path),
+                        Ok(None) => {}
+                        Err(e) => {
+                            error!("Could not move its data to {:?}: {}", &self.recovery_dir, e)
+                        }
+                    }
+                }
+            }
+            self.journal.pop_front()?;
+        }
+
+        info!("All queued mutations have been replayed");
+        Ok(())
+    }
+
+    /// Moves the data of a mutation which could not be replayed to the recovery directory. If a
+    /// file could not be created, its content is made up of the writes which were queued for it,
+    /// and these are dropped along with everything else that was queued for the file. Returns
+    /// where the data has been moved to, if the mutation had any.
+    fn recover(&mut self, entry: &Entry) -> Result<Option<PathBuf>, Error> {
+        let (id, name) = match entry.mutation {
+            Mutation::Create { ref id, ref file } => (id.clone(), file.name.clone()),
+            Mutation::Write { ref id, .. } => (self.journal.resolve(id), None),
+            _ => return Ok(None),
+        };
+
+        fs::create_dir_all(&self.recovery_dir)?;
+        let name = format!("{} {}", &id, name.as_deref().unwrap_or("")).replace('/', "_");
+        let path = self.recovery_dir.join(name.trim());
+        let mut file = fs::File::create(&path)?;
+
+        match entry.mutation {
+            Mutation::Create { ref id, .. } => {
+                let len = self.journal.content_len(id);
+                let mut offset = 0;
+                while offset < len {
+                    let size = cmp::min(len - offset, REPLAY_PIECE_LEN);
+                    file.write_all(&self.journal.read(id, offset, size as usize)?)?;
+                    offset += size;
+                }
+                self.journal.remove_after(entry.seq, id)?;
+            }
+            Mutation::Write { ref ranges, .. } => for &(start, end) in ranges {
+                let mut offset = start;
+                while offset < end {
+                    let piece_end = cmp::min(end, offset + REPLAY_PIECE_LEN);
+                    file.seek(SeekFrom::Start(offset))?;
+                    file.write_all(&self.journal.data(entry, offset, piece_end)?)?;
+                    offset = piece_end;
+                }
+            },
+            _ => {}
+        }
+
+        file.sync_all()?;
+        Ok(Some(path))
+    }
+
+    /// Sends a queued mutation to the wrapped backend.
+    fn apply(&mut self, entry: &Entry) -> Result<(), Error> {
+        match entry.mutation {
+            Mutation::Create { ref id, ref file } => {
+                let file = self.resolve_parents(file);
+                let real_id = self.inner.create(&file)?;
+                self.journal.replace_id(id, real_id)
+            }
+            Mutation::Write { ref id, ref ranges } => {
+                let id = self.journal.resolve(id);
+                for &(start, end) in ranges {
+                    // Large ranges are sent in pieces, so that they do not have to fit in memory.
+                    let mut offset = start;
+                    while offset < end {
+                        let piece_end = cmp::min(end, offset + REPLAY_PIECE_LEN);
+                        let data = self.journal.data(entry, offset, piece_end)?;
+                        self.inner.write(id.clone(), offset as usize, &data)?;
+                        offset = piece_end;
+                    }
+                }
+                Ok(())
+            }
+            Mutation::Truncate { ref id, size } => {
+                self.inner.truncate(&self.journal.resolve(id), size)
+            }
+            Mutation::Flush { ref id } => self.inner.flush(&self.journal.resolve(id)),
+            Mutation::MoveTo {
+                ref id,
+                ref parent,
+                ref name,
+            } => self.inner
+                .move_to(&self.journal.resolve(id), &self.journal.resolve(parent), name)
+                .map(|_| ()),
+            Mutation::UpdateMetadata { ref id, ref patch } => self.inner
+                .update_metadata(&self.journal.resolve(id), patch)
+                .map(|_| ()),
+            Mutation::UpdateProperties {
+                ref id,
+                ref properties,
+            } => self.inner
+                .update_properties(&self.journal.resolve(id), properties),
+            Mutation::MoveToTrash { ref id } => self.inner.move_to_trash(self.journal.resolve(id)),
+            Mutation::Delete { ref id } => self.inner
+                .delete_permanently(&self.journal.resolve(id))
+                .map(|_| ()),
+        }
+    }
+
+    /// Replaces the temporary IDs among the parents of a file.
+    fn resolve_parents(&self, file: &drive3::File) -> drive3::File {
+        let mut file = file.clone();
+        file.parents = file.parents
+            .map(|parents| parents.iter().map(|p| self.journal.resolve(p)).collect());
+        file
+    }
+}
+
+impl<D: DriveBackend> DriveBackend for OfflineDrive<D> {
+    fn root_id(&mut self) -> Result<&String, Error> {
+        self.inner.root_id()
+    }
+
+    fn changes_token(&mut self) -> Result<&String, Error> {
+        self.inner.changes_token()
+    }
+
+    fn set_changes_token(&mut self, token: Option<String>) {
+        self.inner.set_changes_token(token)
+    }
+
+    fn get_all_changes(&mut self) -> Result<Vec<drive3::Change>, Error> {
+        self.replay()?;
+        self.inner.get_all_changes()
+    }
+
+    fn take_replaced_ids(&mut self) -> Vec<(DriveId, DriveId)> {
+        let replaced = self.journal.replaced_ids();
+        if let Err(e) = self.journal.forget_replaced_ids() {
+            warn!("Could not update journal: {}", e);
+        }
+        replaced
+    }
+
+    fn get_all_files(
+        &mut self,
+        parents: Option<Vec<DriveId>>,
+        trashed: Option<bool>,
+    ) -> Result<Vec<drive3::File>, Error> {
+        let parents = parents.map(|parents| {
+            parents
+                .iter()
+                .map(|parent| self.journal.resolve(parent))
+                .collect()
+        });
+        self.inner.get_all_files(parents, trashed)
+    }
+
+    fn get_shared_files(&mut self) -> Result<Vec<drive3::File>, Error> {
+        self.inner.get_shared_files()
+    }
+
+    fn get_starred_files(&mut self) -> Result<Vec<drive3::File>, Error> {
+        self.inner.get_starred_files()
+    }
+
+    fn get_recent_files(&mut self, limit: usize) -> Result<Vec<drive3::File>, Error> {
+        self.inner.get_recent_files(limit)
+    }
+
+    fn shared_drives(&mut self) -> Result<Vec<drive3::TeamDrive>, Error> {
+        self.inner.shared_drives()
+    }
+
+    fn get_drive_files(
+        &mut self,
+        drive_id: &DriveId,
+        parents: Vec<DriveId>,
+    ) -> Result<Vec<drive3::File>, Error> {
+        let parents = parents
+            .iter()
+            .map(|parent| self.journal.resolve(parent))
+            .collect();
+        self.inner.get_drive_files(drive_id, parents)
+    }
+
+    fn drive_changes_token(&mut self, drive_id: &DriveId) -> Result<&String, Error> {
+        self.inner.drive_changes_token(drive_id)
+    }
+
+    fn set_drive_changes_token(&mut self, drive_id: &DriveId, token: Option<String>) {
+        self.inner.set_drive_changes_token(drive_id, token)
+    }
+
+    fn get_drive_changes(&mut self, drive_id: &DriveId) -> Result<Vec<drive3::Change>, Error> {
+        self.replay()?;
+        self.inner.get_drive_changes(drive_id)
+    }
+
+    fn read(
+        &mut self,
+        drive_id: &str,
+        mime_type: Option<String>,
+        offset: usize,
+        size: usize,
+    ) -> Result<&[u8], Error> {
+        let id = self.journal.resolve(drive_id);
+        if id.starts_with(LOCAL_ID_PREFIX) {
+            // The file does not exist on Drive yet. Its content is still in the journal.
+            self.buff = self.journal.read(&id, offset as u64, size)?;
+            return Ok(&self.buff);
+        }
+        self.inner.read(&id, mime_type, offset, size)
+    }
+
+    fn get_metadata(&mut self, id: &DriveId) -> Result<drive3::File, Error> {
+        self.inner.get_metadata(&self.journal.resolve(id))
+    }
+
+    fn create(&mut self, drive_file: &drive3::File) -> Result<DriveId, Error> {
+        let file = self.resolve_parents(drive_file);
+        if self.journal.is_empty() {
+            match self.inner.create(&file) {
+                Err(ref e) if FsError::is_offline(e) => warn!("Could not create file: {}", e),
+                result => return result,
+            }
+        }
+
+        let id = format!("{}{}", LOCAL_ID_PREFIX, self.journal.next_seq());
+        self.queue(Mutation::Create {
+            id: id.clone(),
+            file,
+        })?;
+        Ok(id)
+    }
+
+    fn write(&mut self, id: DriveId, offset: usize, data: &[u8]) -> Result<(), Error> {
+        let id = self.journal.resolve(&id);
+        if self.journal.is_empty() {
+            return self.inner.write(id, offset, data);
+        }
+        debug!(
+            "Drive is unreachable, queueing a write of {} bytes to {}",
+            data.len(),
+            &id
+        );
+        self.journal.push_write(id, offset as u64, data)
+    }
+
+    fn truncate(&mut self, id: &DriveId, size: u64) -> Result<(), Error> {
+        let id = self.journal.resolve(id);
+        if self.journal.is_empty() {
+            return self.inner.truncate(&id, size);
+        }
+        self.queue(Mutation::Truncate { id, size })
+    }
+
+    fn flush(&mut self, id: &DriveId) -> Result<(), Error> {
+        let id = self.journal.resolve(id);
+        if self.journal.is_empty() {
+            match self.inner.flush(&id) {
+                Err(ref e) if FsError::is_offline(e) => warn!("Could not flush {}: {}", &id, e),
+                result => return result,
+            }
+        }
+        self.queue(Mutation::Flush { id })
+    }
+
+    fn dirty_files(&self) -> Vec<DriveId> {
+        self.inner.dirty_files()
+    }
+
+    fn move_to(
+        &mut self,
+        id: &DriveId,
+        parent: &DriveId,
+        new_name: &str,
+    ) -> Result<drive3::File, Error> {
+        let id = self.journal.resolve(id);
+        let parent = self.journal.resolve(parent);
+        if self.journal.is_empty() {
+            match self.inner.move_to(&id, &parent, new_name) {
+                Err(ref e) if FsError::is_offline(e) => warn!("Could not move {}: {}", &id, e),
+                result => return result,
+            }
+        }
+
+        let moved = drive3::File {
+            id: Some(id.clone()),
+            name: Some(new_name.to_string()),
+            parents: Some(vec![parent.clone()]),
+            ..Default::default()
+        };
+        self.queue(Mutation::MoveTo {
+            id,
+            parent,
+            name: new_name.to_string(),
+        })?;
+        Ok(moved)
+    }
+
+    fn update_metadata(
+        &mut self,
+        id: &DriveId,
+        patch: &drive3::File,
+    ) -> Result<drive3::File, Error> {
+        let id = self.journal.resolve(id);
+        if self.journal.is_empty() {
+            match self.inner.update_metadata(&id, patch) {
+                Err(ref e) if FsError::is_offline(e) => warn!("Could not update {}: {}", &id, e),
+                result => return result,
+            }
+        }
+
+        let mut updated = patch.clone();
+        updated.id = Some(id.clone());
+        self.queue(Mutation::UpdateMetadata {
+            id,
+            patch: patch.clone(),
+        })?;
+        Ok(updated)
+    }
+
+    fn update_properties(
+        &mut self,
+        id: &DriveId,
+        properties: &HashMap<String, Option<String>>,
+    ) -> Result<(), Error> {
+        let id = self.journal.resolve(id);
+        if self.journal.is_empty() {
+            match self.inner.update_properties(&id, properties) {
+                Err(ref e) if FsError::is_offline(e) => warn!("Could not update {}: {}", &id, e),
+                result => return result,
+            }
+        }
+        self.queue(Mutation::UpdateProperties {
+            id,
+            properties: properties.clone(),
+        })
+    }
+
+    fn move_to_trash(&mut self, id: DriveId) -> Result<(), Error> {
+        let id = self.journal.resolve(&id);
+        if self.journal.is_empty() {
+            match self.inner.move_to_trash(id.clone()) {
+                Err(ref e) if FsError::is_offline(e) => warn!("Could not trash {}: {}", &id, e),
+                result => return result,
+            }
+        }
+        self.queue(Mutation::MoveToTrash { id })
+    }
+
+    fn delete_permanently(&mut self, id: &DriveId) -> Result<bool, Error> {
+        let id = self.journal.resolve(id);
+        if self.journal.is_empty() {
+            match self.inner.delete_permanently(&id) {
+                Err(ref e) if FsError::is_offline(e) => warn!("Could not delete {}: {}", &id, e),
+                result => return result,
+            }
+        }
+        self.queue(Mutation::Delete { id })?;
+        Ok(true)
+    }
+
+    fn size_and_capacity(&mut self) -> Result<(u64, Option<u64>), Error> {
+        self.inner.size_and_capacity()
+    }
+}
@@ -0,0 +1,298 @@
+use super::error::{bad_request_details, FsError, RATE_LIMIT_REASONS};
+use drive3;
+use failure::Error;
+use hyper;
+use hyper::client::Response;
+use hyper::net::{NetworkConnector, NetworkStream};
+use rand::{self, Rng};
+use serde_json;
+use std::cell::Cell;
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::thread;
+use std::time::Duration;
+
+/// No single wait between two attempts is longer than this many seconds, unless Drive asks for it.
+const MAX_DELAY_SECS: u64 = 64;
+
+/// Responses whose head is longer than this are not searched for a Retry-After header.
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+thread_local! {
+    /// The wait requested by the last response received on this thread, if any.
+    static RETRY_AFTER: Cell<Option<Duration>> = const { Cell::new(None) };
+}
+
+/// Whether a failed request is worth sending again.
+#[derive(Debug, PartialEq)]
+pub enum FailureKind {
+    /// The request may succeed later, e.g. after a rate limit or a server error. Drive can say
+    /// how long to wait first.
+    Transient { retry_after: Option<Duration> },
+    /// Repeating the request would fail the same way.
+    Permanent,
+}
+
+impl FailureKind {
+    /// Classifies an error returned by a `drive3` call.
+    pub fn of(error: &drive3::Error) -> Self {
+        match *error {
+            // The request did not get a response, e.g. because the connection was reset.
+            drive3::Error::HttpError(_) => FailureKind::Transient { retry_after: None },
+            drive3::Error::BadRequest(ref response) => {
+                let (code, reasons) = bad_request_details(response);
+                let rate_limited = reasons
+                    .iter()
+                    .any(|reason| RATE_LIMIT_REASONS.contains(&reason.as_str()));
+
+                if rate_limited || is_transient_status(code) {
+                    FailureKind::Transient { retry_after: None }
+                } else {
+                    FailureKind::Permanent
+                }
+            }
+            drive3::Error::Failure(ref response) => FailureKind::of_response(response),
+            _ => FailureKind::Permanent,
+        }
+    }
+
+    /// Classifies an unsuccessful response by its status, honouring its Retry-After header when it
+    /// is given in seconds.
+    pub fn of_response(response: &Response) -> Self {
+        if !is_transient_status(response.status.to_u16()) {
+            return FailureKind::Permanent;
+        }
+
+        let retry_after = response
+            .headers
+            .get_raw("Retry-After")
+            .and_then(|values| values.iter().next())
+            .and_then(|value| String::from_utf8_lossy(value).trim().parse().ok())
+            .map(Duration::from_secs);
+
+        FailureKind::Transient { retry_after }
+    }
+
+    /// Uses `wait` for a transient failure which does not say how long to wait by itself.
+    fn or_retry_after(self, wait: Option<Duration>) -> Self {
+        match self {
+            FailureKind::Transient { retry_after: None } => FailureKind::Transient {
+                retry_after: wait,
+            },
+            kind => kind,
+        }
+    }
+}
+
+/// Turns an unsuccessful response to a request sent without `drive3` into the error `drive3` would
+/// have returned for it, i.e. a parsed `BadRequest` if its body is a Drive error.
+pub fn error_of_response(mut response: Response) -> drive3::Error {
+    let mut body = String::new();
+    if response.read_to_string(&mut body).is_ok() {
+        if let Ok(error) = serde_json::from_str::<drive3::ErrorResponse>(&body) {
+            return drive3::Error::BadRequest(error);
+        }
+    }
+    drive3::Error::Failure(response)
+}
+
+fn is_transient_status(code: u16) -> bool {
+    code == 408 || code == 429 || (500..600).contains(&code)
+}
+
+/// Decides how many times a request to Drive is attempted and how long to wait in between. The
+/// waits grow exponentially and are randomized, so that many requests which fail together (e.g.
+/// while copying a directory) do not all come back at the same moment.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+
+    /// The longest wait which Drive may ask for. If it asks for more, the request is given up on.
+    max_wait: Duration,
+
+    /// Whether requests which got no response at all (e.g. because the connection was refused)
+    /// are attempted again.
+    retry_unreachable: bool,
+}
+
+impl RetryPolicy {
+    /// A policy which attempts each request at most `max_attempts` times (at least once) and
+    /// waits around `base_delay` after the first failure, but never longer than `max_wait`.
+    pub fn new(max_attempts: u32, base_delay: Duration, max_wait: Duration) -> Self {
+        RetryPolicy {
+            max_attempts: cmp::max(max_attempts, 1),
+            base_delay,
+            max_wait,
+            retry_unreachable: true,
+        }
+    }
+
+    /// The same policy, except that requests fail right away if Drive cannot be reached. Meant for
+    /// requests which have a reasonable fallback while offline.
+    pub fn without_retrying_unreachable(&self) -> Self {
+        RetryPolicy {
+            retry_unreachable: false,
+            ..self.clone()
+        }
+    }
+
+    /// Runs `request` until it succeeds, fails permanently or runs out of attempts. `what`
+    /// describes the request in the logs.
+    pub fn run<T, F>(&self, what: &str, mut request: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Result<T, drive3::Error>,
+    {
+        let mut attempt = 1;
+        loop {
+            RETRY_AFTER.with(|wait| wait.set(None));
+            let e = match request() {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+
+            // `drive3` drops the response of the errors it parses (e.g. rate limit 403s and 429s),
+            // so their Retry-After header is only known to `RetryAfterConnector`.
+            let kind = match e {
+                drive3::Error::HttpError(_) if !self.retry_unreachable => FailureKind::Permanent,
+                _ => FailureKind::of(&e).or_retry_after(RETRY_AFTER.with(Cell::get)),
+            };
+            let retry_after = match kind {
+                FailureKind::Transient { retry_after } if attempt < self.max_attempts => {
+                    retry_after
+                }
+                _ => return Err(FsError::from_drive(&e).into()),
+            };
+            if let Some(wait) = retry_after.filter(|wait| *wait > self.max_wait) {
+                return Err(FsError::RateLimited(format!(
+                    "{} failed and Drive asked to wait {:?}, longer than {:?}: {:?}",
+                    what, wait, self.max_wait, e
+                )).into());
+            }
+
+            let delay = self.delay(attempt, retry_after);
+            warn!(
+                "{} failed (attempt {} of {}), retrying in {:?}: {:?}",
+                what, attempt, self.max_attempts, delay, e
+            );
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    /// How long to wait after the given failed attempt. The backoff doubles with every attempt and
+    /// is then scaled by a random factor between 0.5 and 1. A wait requested by Drive is always
+    /// respected, since `run()` gives up on the ones which are too long.
+    fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let factor = 1u32.checked_shl(attempt - 1).unwrap_or(u32::MAX);
+        let max_delay = Duration::from_secs(MAX_DELAY_SECS);
+        let backoff = self.base_delay
+            .checked_mul(factor)
+            .map(|d| cmp::min(d, max_delay))
+            .unwrap_or(max_delay);
+
+        let millis = backoff.as_secs() * 1000 + u64::from(backoff.subsec_millis());
+        let jittered = Duration::from_millis(rand::thread_rng().gen_range(millis / 2, millis + 1));
+
+        match retry_after {
+            Some(wait) => cmp::max(wait, jittered),
+            None => jittered,
+        }
+    }
+}
+
+/// Wraps the connector of a hyper `Client` in order to note the Retry-After header of every
+/// response, which `RetryPolicy` honours even if the response itself is dropped by `drive3`.
+pub struct RetryAfterConnector<C>(pub C);
+
+impl<C> NetworkConnector for RetryAfterConnector<C>
+where
+    C: NetworkConnector,
+    C::Stream: NetworkStream + Send,
+{
+    type Stream = RetryAfterStream<C::Stream>;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<Self::Stream> {
+        self.0
+            .connect(host, port, scheme)
+            .map(|inner| RetryAfterStream { inner, head: None })
+    }
+}
+
+/// A connection which looks for the Retry-After header in the head of each response.
+pub struct RetryAfterStream<S> {
+    inner: S,
+
+    /// The part of the current response's head which has been read so far. Absent once the whole
+    /// head has been read.
+    head: Option<Vec<u8>>,
+}
+
+impl<S: Read> Read for RetryAfterStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let complete = match self.head {
+            Some(ref mut head) => {
+                head.extend_from_slice(&buf[..read]);
+                read == 0 || head.len() > MAX_HEAD_LEN || head.windows(4).any(|w| w == b"\r\n\r\n")
+            }
+            None => false,
+        };
+
+        if complete {
+            let wait = self.head.take().and_then(|head| parse_retry_after(&head));
+            RETRY_AFTER.with(|retry_after| retry_after.set(wait));
+        }
+        Ok(read)
+    }
+}
+
+impl<S: Write> Write for RetryAfterStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A request is being sent, so the next bytes read are the head of its response.
+        self.head = Some(Vec::new());
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: NetworkStream + Send> NetworkStream for RetryAfterStream<S> {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.inner.close(how)
+    }
+}
+
+/// Finds the Retry-After header in the head of a response, if it is given in seconds.
+fn parse_retry_after(head: &[u8]) -> Option<Duration> {
+    String::from_utf8_lossy(head)
+        .lines()
+        .skip(1)
+        .take_while(|line| !line.trim().is_empty())
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("retry-after") => {
+                    value.trim().parse().ok()
+                }
+                _ => None,
+            }
+        })
+        .next()
+        .map(Duration::from_secs)
+}
@@ -0,0 +1,190 @@
+use super::File;
+use drive3;
+use failure::{err_msg, Error};
+use fuse::{FileAttr, FileType};
+use serde_json;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use time::Timespec;
+
+type Inode = u64;
+type DriveId = String;
+
+/// Bumped whenever the format changes, so that snapshots written by older versions are ignored.
+const SNAPSHOT_VERSION: u32 = 5;
+
+/// A serializable copy of the local file tree, along with the changes token that was current when
+/// it was taken. Loading it on mount makes it unnecessary to crawl the whole Drive again: only the
+/// changes which happened in the meantime need to be applied.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    pub version: u32,
+
+    /// The token from which `changes.list` should continue.
+    pub changes_token: String,
+
+    /// The same, for each shared drive.
+    pub drive_changes_tokens: HashMap<DriveId, String>,
+
+    /// The last inode that was handed out.
+    pub last_inode: Inode,
+
+    /// All files, ordered so that each parent comes before its children.
+    pub entries: Vec<SnapshotEntry>,
+
+    /// The directories whose children have been retrieved.
+    pub loaded_dirs: Vec<Inode>,
+}
+
+/// A file and the inode of its parent in the file tree.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotEntry {
+    pub parent: Option<Inode>,
+    pub name: String,
+    pub identical_name_id: Option<usize>,
+    pub attr: SnapshotAttr,
+    pub drive_file: Option<drive3::File>,
+}
+
+/// Mirrors `fuse::FileAttr`, which is not serializable.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotAttr {
+    ino: Inode,
+    size: u64,
+    blocks: u64,
+    atime: (i64, i32),
+    mtime: (i64, i32),
+    ctime: (i64, i32),
+    crtime: (i64, i32),
+    kind: String,
+    perm: u16,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    flags: u32,
+}
+
+impl Snapshot {
+    pub fn new(changes_token: String, last_inode: Inode) -> Self {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            changes_token,
+            drive_changes_tokens: HashMap::new(),
+            last_inode,
+            entries: Vec::new(),
+            loaded_dirs: Vec::new(),
+        }
+    }
+
+    /// Reads a snapshot from disk. Fails if it was written by an incompatible version.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let reader = BufReader::new(fs::File::open(path)?);
+        let snapshot: Snapshot = serde_json::from_reader(reader)?;
+
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(err_msg(format!(
+                "Snapshot version {} is not supported",
+                snapshot.version
+            )));
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Writes the snapshot to disk. The previous snapshot is only replaced once the new one has
+    /// been written completely.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        {
+            let writer = BufWriter::new(fs::File::create(&tmp_path)?);
+            serde_json::to_writer(writer, self)?;
+        }
+        fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+}
+
+impl SnapshotEntry {
+    pub fn new(file: &File, parent: Option<Inode>) -> Self {
+        SnapshotEntry {
+            parent,
+            name: file.name.clone(),
+            identical_name_id: file.identical_name_id,
+            attr: SnapshotAttr::from(&file.attr),
+            drive_file: file.drive_file.clone(),
+        }
+    }
+
+    pub fn into_file(self) -> Result<File, Error> {
+        Ok(File {
+            name: self.name,
+            attr: self.attr.into_attr()?,
+            identical_name_id: self.identical_name_id,
+            drive_file: self.drive_file,
+        })
+    }
+}
+
+impl From<&FileAttr> for SnapshotAttr {
+    fn from(attr: &FileAttr) -> Self {
+        let time = |t: Timespec| (t.sec, t.nsec);
+
+        SnapshotAttr {
+            ino: attr.ino,
+            size: attr.size,
+            blocks: attr.blocks,
+            atime: time(attr.atime),
+            mtime: time(attr.mtime),
+            ctime: time(attr.ctime),
+            crtime: time(attr.crtime),
+            kind: format!("{:?}", attr.kind),
+            perm: attr.perm,
+            nlink: attr.nlink,
+            uid: attr.uid,
+            gid: attr.gid,
+            rdev: attr.rdev,
+            flags: attr.flags,
+        }
+    }
+}
+
+impl SnapshotAttr {
+    fn into_attr(self) -> Result<FileAttr, Error> {
+        let time = |(sec, nsec): (i64, i32)| Timespec { sec, nsec };
+        let kind = match self.kind.as_ref() {
+            "NamedPipe" => FileType::NamedPipe,
+            "CharDevice" => FileType::CharDevice,
+            "BlockDevice" => FileType::BlockDevice,
+            "Directory" => FileType::Directory,
+            "RegularFile" => FileType::RegularFile,
+            "Symlink" => FileType::Symlink,
+            "Socket" => FileType::Socket,
+            other => return Err(err_msg(format!("Unknown file type: {}", other))),
+        };
+
+        Ok(FileAttr {
+            ino: self.ino,
+            size: self.size,
+            blocks: self.blocks,
+            atime: time(self.atime),
+            mtime: time(self.mtime),
+            ctime: time(self.ctime),
+            crtime: time(self.crtime),
+            kind,
+            perm: self.perm,
+            nlink: self.nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: self.rdev,
+            flags: self.flags,
+        })
+    }
+}
@@ -0,0 +1,302 @@
+use super::ContentVersion;
+use failure::Error;
+use serde_json;
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+type DriveId = String;
+
+/// The extension of the files which describe the spool files. They are stored next to them.
+const STATE_EXTENSION: &str = "json";
+
+/// Where the content of a spool file is meant to go: it replaces the content of the Drive file
+/// `id`. If that file is gone by the time the content is uploaded, a new file with the same name
+/// can be created under the same parents instead.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SpoolTarget {
+    pub id: DriveId,
+    pub name: Option<String>,
+    pub parents: Option<Vec<DriveId>>,
+
+    /// The version of the remote content which the pending writes are based on, if it is known.
+    pub base_version: Option<ContentVersion>,
+}
+
+/// Everything about a spool file except its content. It is written next to the content whenever
+/// the pending writes change shape, so that they can be recovered after a crash.
+#[derive(Serialize, Deserialize, Debug)]
+struct SpoolState {
+    target: SpoolTarget,
+
+    /// Sorted, disjoint `[start, end)` ranges which hold pending writes.
+    dirty: Vec<(u64, u64)>,
+
+    /// How many bytes of the remote content can still be part of the result. Only lowered by
+    /// truncation.
+    retained: u64,
+
+    /// The length which the result has at least: the end of the furthest write, or the size
+    /// which the file was last truncated to.
+    min_len: u64,
+}
+
+/// Holds the pending content of a Drive file in a sparse local file, along with a record of the
+/// ranges which have been written. The rest of the content still has to be retrieved from Drive
+/// before uploading, unless it has been overwritten or cut off by a truncation.
+///
+/// Both are synced to disk before a truncation or a write which starts a new range of pending
+/// writes is acknowledged. Writes which only extend a range that has already been recorded (e.g.
+/// sequential ones) are not synced until `persist()` is called. Spool files are only removed by
+/// `discard()`, so the ones which are left behind by a crash can be found with `find()` and
+/// uploaded later.
+pub struct SpoolFile {
+    path: PathBuf,
+    file: File,
+    state: SpoolState,
+
+    /// Whether some writes have not been synced to disk yet.
+    unsynced: bool,
+}
+
+impl SpoolFile {
+    /// Creates an empty spool file at `path`, replacing any file which might be there.
+    pub fn create(path: PathBuf, target: SpoolTarget) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+
+        let spool = SpoolFile {
+            path,
+            file,
+            state: SpoolState {
+                target,
+                dirty: Vec::new(),
+                retained: u64::MAX,
+                min_len: 0,
+            },
+            unsynced: false,
+        };
+        spool.save_state()?;
+        Ok(spool)
+    }
+
+    /// Reopens a spool file which was left behind by a previous session.
+    pub fn open(path: PathBuf) -> Result<Self, Error> {
+        let state_file = File::open(state_path(&path))?;
+        let state: SpoolState = serde_json::from_reader(state_file)?;
+        let file = OpenOptions::new().read(true).write(true).open(&path)?;
+
+        Ok(SpoolFile {
+            path,
+            file,
+            state,
+            unsynced: false,
+        })
+    }
+
+    /// Returns the paths of all spool files in `dir`.
+    pub fn find(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some(STATE_EXTENSION) {
+                paths.push(path.with_extension(""));
+            }
+        }
+
+        paths.sort();
+        Ok(paths)
+    }
+
+    pub fn target(&self) -> &SpoolTarget {
+        &self.state.target
+    }
+
+    /// Records a write operation. It is only synced to disk right away if it changes the shape of
+    /// the pending writes, i.e. if it does not just extend a range which has already been recorded.
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        self.write_at(offset, data)?;
+        let end = offset + data.len() as u64;
+        let starts: Vec<u64> = self.state.dirty.iter().map(|&(start, _)| start).collect();
+        self.mark_dirty(offset, end);
+        self.state.min_len = cmp::max(self.state.min_len, end);
+
+        if self.state.dirty.iter().map(|&(start, _)| start).eq(starts) {
+            self.unsynced = true;
+            return Ok(());
+        }
+        self.sync()
+    }
+
+    /// Syncs the writes which have not been synced yet to disk. Called when the pending writes
+    /// have to outlive the session, e.g. when they cannot be uploaded.
+    pub fn persist(&mut self) -> Result<(), Error> {
+        if !self.unsynced {
+            return Ok(());
+        }
+        self.sync()
+    }
+
+    /// Records a truncation. Everything after `size` is discarded. If the file is extended, the
+    /// new part is filled with zeros.
+    pub fn truncate(&mut self, size: u64) -> Result<(), Error> {
+        self.file.set_len(size)?;
+        self.state.dirty = self.state
+            .dirty
+            .iter()
+            .filter(|&&(start, _)| start < size)
+            .map(|&(start, end)| (start, cmp::min(end, size)))
+            .collect();
+        self.state.retained = cmp::min(self.state.retained, size);
+        self.state.min_len = size;
+
+        self.sync()
+    }
+
+    /// Reads `len` bytes at `offset`. The parts which have neither been written nor filled in
+    /// with `fill()` read as zeros.
+    pub fn read(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::with_capacity(len as usize);
+        self.file.seek(SeekFrom::Start(offset))?;
+        (&mut self.file).take(len).read_to_end(&mut data)?;
+        data.resize(len as usize, 0);
+        Ok(data)
+    }
+
+    /// Whether the remote content could still be needed, regardless of its size. If not, the file
+    /// has been completely overwritten or truncated to a size which only covers pending writes.
+    pub fn needs_remote(&self) -> bool {
+        !self.gaps(u64::MAX).is_empty()
+    }
+
+    /// The ranges of the remote content which have to be filled in, given that the remote file
+    /// is `remote_len` bytes long.
+    pub fn gaps(&self, remote_len: u64) -> Vec<(u64, u64)> {
+        let limit = cmp::min(remote_len, self.state.retained);
+        let mut gaps = Vec::new();
+        let mut pos = 0;
+
+        for &(start, end) in &self.state.dirty {
+            if start >= limit {
+                break;
+            }
+            if start > pos {
+                gaps.push((pos, start));
+            }
+            pos = cmp::max(pos, end);
+        }
+        if pos < limit {
+            gaps.push((pos, limit));
+        }
+
+        gaps
+    }
+
+    /// Copies a piece of the remote content into the spool file. Unlike a write, it is not
+    /// considered a pending change.
+    pub fn fill(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        self.write_at(offset, data)
+    }
+
+    /// The length of the result, given that the remote file is `remote_len` bytes long.
+    pub fn final_len(&self, remote_len: u64) -> u64 {
+        cmp::max(
+            cmp::min(remote_len, self.state.retained),
+            self.state.min_len,
+        )
+    }
+
+    /// Returns a handle to the spool file, set to `len` bytes and positioned at the start, from
+    /// which the result can be uploaded.
+    pub fn contents(&mut self, len: u64) -> Result<File, Error> {
+        self.file.set_len(len)?;
+
+        let mut file = self.file.try_clone()?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(file)
+    }
+
+    /// Removes the spool file once its content has been uploaded.
+    pub fn discard(self) {
+        for path in &[self.path.clone(), state_path(&self.path)] {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Could not remove spool file {:?}: {}", path, e);
+            }
+        }
+    }
+
+    /// Moves the spool file into `dir` under the given name, along with its description. Used for
+    /// keeping pending writes which cannot be uploaded. Returns the new path of the content.
+    pub fn keep_in(mut self, dir: &Path, name: &str) -> Result<PathBuf, Error> {
+        self.persist()?;
+        fs::create_dir_all(dir)?;
+        let path = dir.join(name);
+        fs::rename(state_path(&self.path), state_path(&path))?;
+        fs::rename(&self.path, &path)?;
+        Ok(path)
+    }
+
+    /// Makes sure that the content and the description of the spool file are on disk.
+    fn sync(&mut self) -> Result<(), Error> {
+        self.file.sync_data()?;
+        self.save_state()?;
+        self.unsynced = false;
+        Ok(())
+    }
+
+    /// Writes the description of the spool file. The previous one is only replaced once the new
+    /// one has been written completely.
+    fn save_state(&self) -> Result<(), Error> {
+        let path = state_path(&self.path);
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            serde_json::to_writer(&mut file, &self.state)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        Ok(())
+    }
+
+    /// Adds `[start, end)` to the dirty ranges, merging it with the ones it overlaps or touches.
+    fn mark_dirty(&mut self, start: u64, end: u64) {
+        let mut merged = (start, end);
+        let mut ranges = Vec::with_capacity(self.state.dirty.len() + 1);
+
+        for &(s, e) in &self.state.dirty {
+            if e < merged.0 || s > merged.1 {
+                ranges.push((s, e));
+            } else {
+                merged = (cmp::min(s, merged.0), cmp::max(e, merged.1));
+            }
+        }
+
+        ranges.push(merged);
+        ranges.sort();
+        self.state.dirty = ranges;
+    }
+}
+
+/// The path of the file which describes the spool file at `path`.
+fn state_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(STATE_EXTENSION);
+    path.with_file_name(name)
+}
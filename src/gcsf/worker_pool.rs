@@ -0,0 +1,72 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of threads which run jobs in the order in which they are submitted. `GCSF` uses
+/// it for operations which need to wait for Drive, so that the FUSE session thread can go on
+/// serving requests which can be answered locally.
+pub struct WorkerPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Starts `size` worker threads. At least one thread is always started.
+    pub fn new(size: usize) -> Self {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size.max(1))
+            .map(|i| {
+                let receiver = Arc::clone(&receiver);
+                thread::Builder::new()
+                    .name(format!("gcsf-worker-{}", i))
+                    .spawn(move || WorkerPool::run(&receiver))
+                    .expect("Could not spawn a worker thread")
+            })
+            .collect();
+
+        WorkerPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// Queues a job. It is run by the first worker which becomes available.
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(ref sender) = self.sender {
+            if sender.send(Box::new(job)).is_err() {
+                error!("Could not queue job: all workers have stopped");
+            }
+        }
+    }
+
+    fn run(receiver: &Mutex<Receiver<Job>>) {
+        loop {
+            // The lock is released as soon as a job has been received, so that other workers can
+            // pick up jobs while this one is busy.
+            let job = match receiver.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => break,
+            };
+            job();
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    /// Waits for all queued jobs to finish.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!("A worker thread panicked");
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use super::error::FsError;
+use drive3;
+use std::str;
+
+/// The prefix of the extended attributes which expose the metadata of the Drive file.
+pub const PREFIX: &str = "user.gcsf.";
+
+/// The prefix of the extended attributes which expose the custom `properties` of the Drive file,
+/// e.g. `user.prop.status` for the property `status`.
+pub const PROPERTY_PREFIX: &str = "user.prop.";
+
+/// Drive limits the size of a property, key and value together, to this many bytes.
+pub const MAX_PROPERTY_LEN: usize = 124;
+
+/// The Drive fields which are exposed as extended attributes, e.g. `user.gcsf.webViewLink`.
+pub const FIELDS: &[&str] = &[
+    "id",
+    "mimeType",
+    "md5Checksum",
+    "webViewLink",
+    "owners",
+    "starred",
+    "description",
+    "version",
+    "headRevisionId",
+    "quotaBytesUsed",
+];
+
+/// The fields which the file listings do not retrieve. They are requested from Drive when one of
+/// them is read.
+pub const ON_DEMAND_FIELDS: &[&str] = &[
+    "md5Checksum",
+    "webViewLink",
+    "starred",
+    "description",
+    "version",
+    "headRevisionId",
+    "quotaBytesUsed",
+];
+
+/// The Drive field which an extended attribute exposes, if it is one of `FIELDS`.
+pub fn field(name: &str) -> Option<&'static str> {
+    if !name.starts_with(PREFIX) {
+        return None;
+    }
+
+    let field = &name[PREFIX.len()..];
+    FIELDS.iter().cloned().find(|&known| known == field)
+}
+
+/// The key of the custom property which an extended attribute exposes, if it has one.
+pub fn property(name: &str) -> Option<&str> {
+    if !name.starts_with(PROPERTY_PREFIX) || name.len() == PROPERTY_PREFIX.len() {
+        return None;
+    }
+
+    Some(&name[PROPERTY_PREFIX.len()..])
+}
+
+/// Whether an extended attribute exposes a field or a custom property of the Drive file.
+pub fn is_known(name: &str) -> bool {
+    field(name).is_some() || property(name).is_some()
+}
+
+/// Whether the value of an extended attribute needs to be requested from Drive, since the file
+/// listings do not retrieve it.
+pub fn is_on_demand(name: &str) -> bool {
+    match field(name) {
+        Some(field) => ON_DEMAND_FIELDS.contains(&field),
+        None => true,
+    }
+}
+
+/// The value of an extended attribute of a Drive file, either a field or a custom property.
+pub fn get(file: &drive3::File, name: &str) -> Option<String> {
+    if let Some(field) = field(name) {
+        return value(file, field);
+    }
+
+    let key = property(name)?;
+    file.properties
+        .as_ref()
+        .and_then(|properties| properties.get(key).cloned())
+}
+
+/// The value of a field of a Drive file, formatted as an extended attribute. Absent if the file
+/// does not have one. Owners are given by their email addresses, separated by commas.
+pub fn value(file: &drive3::File, field: &str) -> Option<String> {
+    match field {
+        "id" => file.id.clone(),
+        "mimeType" => file.mime_type.clone(),
+        "md5Checksum" => file.md5_checksum.clone(),
+        "webViewLink" => file.web_view_link.clone(),
+        "owners" => file.owners.as_ref().map(|owners| {
+            owners
+                .iter()
+                .filter_map(|owner| owner.email_address.clone())
+                .collect::<Vec<_>>()
+                .join(",")
+        }),
+        "starred" => file.starred.map(|starred| starred.to_string()),
+        "description" => file.description.clone(),
+        "version" => file.version.clone(),
+        "headRevisionId" => file.head_revision_id.clone(),
+        "quotaBytesUsed" => file.quota_bytes_used.clone(),
+        _ => None,
+    }
+}
+
+/// The names of the extended attributes which a Drive file has, in the format expected by
+/// `listxattr(2)`: each one is followed by a null byte.
+pub fn list(file: &drive3::File) -> Vec<u8> {
+    let mut names = Vec::new();
+    for field in FIELDS.iter().filter(|field| value(file, field).is_some()) {
+        names.extend_from_slice(PREFIX.as_bytes());
+        names.extend_from_slice(field.as_bytes());
+        names.push(0);
+    }
+
+    let mut keys: Vec<&String> = file.properties.iter().flat_map(|p| p.keys()).collect();
+    keys.sort();
+    for key in keys {
+        names.extend_from_slice(PROPERTY_PREFIX.as_bytes());
+        names.extend_from_slice(key.as_bytes());
+        names.push(0);
+    }
+    names
+}
+
+/// A modification of the Drive metadata, requested by setting or removing an extended attribute.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// Sets or clears the description of the file.
+    Description(Option<String>),
+    /// Stars or unstars the file. Removing the attribute unstars it.
+    Starred(bool),
+    /// Sets or removes a custom property.
+    Property(String, Option<String>),
+}
+
+impl Change {
+    /// Interprets setting the extended attribute `name` to `value`, or removing it if `value` is
+    /// `None`. Only the description, the starred flag and the custom properties can be written.
+    pub fn parse(name: &str, value: Option<&[u8]>) -> Result<Change, FsError> {
+        let value = match value.map(str::from_utf8) {
+            Some(Ok(value)) => Some(value.to_string()),
+            Some(Err(_)) => {
+                return Err(FsError::InvalidValue(format!("{} is not valid UTF-8", name)));
+            }
+            None => None,
+        };
+
+        if let Some(key) = property(name) {
+            let len = key.len() + value.as_ref().map_or(0, |v| v.len());
+            if len > MAX_PROPERTY_LEN {
+                return Err(FsError::InvalidValue(format!(
+                    "{} is longer than {} bytes",
+                    name, MAX_PROPERTY_LEN
+                )));
+            }
+            return Ok(Change::Property(key.to_string(), value));
+        }
+
+        match field(name) {
+            Some("description") => Ok(Change::Description(value)),
+            Some("starred") => match value.as_ref().map(|v| v.trim()) {
+                Some("true") | Some("1") => Ok(Change::Starred(true)),
+                Some("false") | Some("0") | None => Ok(Change::Starred(false)),
+                Some(other) => Err(FsError::InvalidValue(format!(
+                    "{} must be true or false, not {:?}",
+                    name, other
+                ))),
+            },
+            _ => Err(FsError::Unsupported(format!("{} cannot be written", name))),
+        }
+    }
+}
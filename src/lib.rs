@@ -1,3 +1,6 @@
+// Requests to Drive fail with `drive3::Error`, which is large but not ours to change.
+#![allow(clippy::result_large_err)]
+
 extern crate chrono;
 extern crate failure;
 extern crate fuse;
@@ -19,14 +22,18 @@ extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 extern crate time;
+extern crate xdg;
 extern crate yup_oauth2 as oauth2;
 #[macro_use]
 extern crate lazy_static;
 
 mod gcsf;
 
-pub use gcsf::filesystem::{NullFS, GCSF};
-pub use gcsf::{Config, DriveFacade, FileManager};
+pub use gcsf::filesystem::{NullFS, ShutdownHandle, GCSF};
+pub use gcsf::{
+    AttrDefaults, BackgroundSync, Config, ContentCache, ContentVersion, DriveBackend, DriveFacade,
+    FileManager, FsError, LocalDriveServer, MemoryDrive, OfflineDrive,
+};
 
 #[cfg(test)]
 mod tests;
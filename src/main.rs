@@ -20,12 +20,13 @@ use std::ffi::OsStr;
 use std::fs;
 use std::io::prelude::*;
 use std::iter;
+use std::process;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time;
 
-use gcsf::{Config, NullFS, GCSF};
+use gcsf::{Config, DriveFacade, NullFS, OfflineDrive, GCSF};
 
 const DEBUG_LOG: &str =
     "hyper::client=error,rustls::client_hs=error,hyper::http=error,hyper::net=error,debug";
@@ -41,11 +42,19 @@ const DEFAULT_CONFIG: &str = "\
 # Show additional logging info?
 debug = false
 
-# How long to cache the contents of a file after it has been accessed.
+# How many seconds a cached copy of a file is used before checking with Drive
+# whether it is still up to date. Copies which are still up to date are not
+# downloaded again.
 cache_max_seconds = 300
 
-# How how many files to cache.
-cache_max_items = 20
+# How many bytes the cached file contents may use in total. The contents are
+# cached in $XDG_CACHE_HOME/gcsf/content. When the limit is reached, the least
+# recently used files are evicted first. Set to 0 in order to disable caching.
+content_cache_max_bytes = 1073741824
+
+# No file takes up more than this many bytes in the cache. Of larger files, only
+# the parts which fit are cached.
+content_cache_max_file_bytes = 268435456
 
 # How long to cache the size and capacity of the filesystem. These are the
 # values reported by `df`.
@@ -55,6 +64,56 @@ cache_statfs_seconds = 10
 # locally.
 sync_interval = 10
 
+# Whether to save the file tree between sessions. If enabled, mounting only
+# needs to retrieve the changes which happened since the last session instead
+# of the whole tree. The tree is saved in $XDG_CACHE_HOME/gcsf/metadata.json
+cache_metadata = true
+
+# If set to true, the contents of a directory are only retrieved from Drive the
+# first time it is accessed. Mounting a large Drive becomes much faster, but
+# changes are only tracked for directories which have been accessed.
+lazy_population = false
+
+# If set to true, a \"Starred\" directory lists the starred files, and a \"Recent\"
+# directory lists the files which have been viewed or modified most recently.
+# Their entries are the files themselves, not copies. Both are updated whenever
+# remote changes are applied.
+starred_dir = false
+recent_dir = false
+
+# How many files the \"Recent\" directory lists at most.
+recent_dir_limit = 50
+
+# How many threads serve the file system operations which need to wait for
+# Drive, such as reading or uploading files. Operations which can be answered
+# locally (e.g. listing a directory which has already been loaded) do not wait
+# for them.
+worker_threads = 4
+
+# How many times a request to Drive is attempted before giving up. Requests are
+# retried if Drive reports a rate limit or a server error, or if the network
+# fails. The wait between attempts starts at retry_base_delay_ms and doubles
+# every time. Drive may ask for a longer wait, up to retry_max_wait_seconds;
+# requests for which it asks to wait longer fail right away.
+retry_max_attempts = 5
+retry_base_delay_ms = 500
+retry_max_wait_seconds = 300
+
+# How many seconds to wait for pending writes to be uploaded when GCSF is asked
+# to quit (Ctrl-C or SIGTERM). Writes which are still pending after that are
+# uploaded on the next mount.
+shutdown_timeout_seconds = 60
+
+# The owner, group and permissions of files which have not been given their own
+# with chmod or chown. The owner and group default to the user who mounts GCSF.
+# The modes are written in octal and the umask is cleared from them, as well as
+# from the mode of newly created files.
+# uid = 1000
+# gid = 1000
+umask = \"022\"
+file_mode = \"666\"
+dir_mode = \"777\"
+
 # Mount options
 mount_options = [
     \"fsname=GCSF\",
@@ -69,9 +128,17 @@ mount_options = [
 #
 # If set to false, Google Drive will attempt to communicate with GCSF directly.
 # This is usually faster and more convenient.
-authorize_using_code = false\n";
+authorize_using_code = false
 
-fn mount_gcsf(config: Config, mountpoint: &str) {
+# The endpoints used for talking to Google Drive. They only need to be changed
+# when going through a proxy or when testing against a local stand-in server.
+# api_root = \"https://www.googleapis.com/drive/v3/\"
+# upload_root = \"https://www.googleapis.com/\"
+# token_uri = \"https://accounts.google.com/o/oauth2/token\"\n";
+
+/// Mounts GCSF and serves it until Ctrl-C or SIGTERM is received. Returns whether it shut down
+/// cleanly, i.e. without losing track of any pending writes.
+fn mount_gcsf(config: Config, mountpoint: &str) -> bool {
     let vals = config.mount_options();
     let mut options = iter::repeat("-o")
         .interleave_shortest(vals.iter().map(String::as_ref))
@@ -87,35 +154,58 @@ fn mount_gcsf(config: Config, mountpoint: &str) {
             }
             Err(e) => {
                 error!("Could not mount to {}: {}", &mountpoint, e);
-                return;
+                return false;
             }
         };
     }
 
+    let shutdown_timeout = config.shutdown_timeout();
     info!("Creating and populating file system...");
     let fs: GCSF = GCSF::with_config(config);
     info!("File sytem created.");
 
+    let mut sync = fs.spawn_background_sync();
+    let shutdown = fs.shutdown_handle();
+
     unsafe {
         info!("Mounting to {}", &mountpoint);
         match fuse::spawn_mount(fs, &mountpoint, &options) {
-            Ok(_session) => {
+            Ok(session) => {
                 info!("Mounted to {}", &mountpoint);
 
                 let running = Arc::new(AtomicBool::new(true));
                 let r = running.clone();
 
+                // Handles SIGTERM as well as Ctrl-C.
                 ctrlc::set_handler(move || {
-                    info!("Ctrl-C detected");
+                    info!("Termination signal received");
                     r.store(false, Ordering::SeqCst);
                 }).expect("Error setting Ctrl-C handler");
 
                 while running.load(Ordering::SeqCst) {
                     thread::sleep(time::Duration::from_millis(50));
                 }
+
+                // Stop syncing before unmounting, so that no changes are applied to a file system
+                // which is being torn down.
+                info!("Stopping background sync...");
+                sync.stop();
+
+                info!("Uploading pending writes...");
+                let clean = shutdown.shutdown(shutdown_timeout).is_clean();
+                if !clean {
+                    error!("Some pending writes remain, they are uploaded on the next mount");
+                }
+
+                drop(session);
+                info!("Unmounted {}", &mountpoint);
+                clean
             }
-            Err(e) => error!("Could not mount to {}: {}", &mountpoint, e),
-        };
+            Err(e) => {
+                error!("Could not mount to {}: {}", &mountpoint, e);
+                false
+            }
+        }
     }
 }
 
@@ -142,8 +232,33 @@ fn load_conf() -> Result<Config, Error> {
         .merge(config::File::with_name(config_path.to_str().unwrap()))
         .expect("Invalid configuration file");
 
+    let metadata_cache_path = xdg_dirs
+        .place_cache_file("metadata.json")
+        .map_err(|_| err_msg("Cannot create cache directory"))?;
+
+    let content_cache_path = xdg_dirs
+        .create_cache_directory("content")
+        .map_err(|_| err_msg("Cannot create cache directory"))?;
+
+    let spool_path = xdg_dirs
+        .create_data_directory("spool")
+        .map_err(|_| err_msg("Cannot create data directory"))?;
+
+    let journal_path = xdg_dirs
+        .create_data_directory("journal")
+        .map_err(|_| err_msg("Cannot create data directory"))?;
+
+    let recovery_path = xdg_dirs
+        .create_data_directory("recovered")
+        .map_err(|_| err_msg("Cannot create data directory"))?;
+
     let mut config = settings.try_into::<Config>()?;
     config.token_path = Some(token_path.to_str().unwrap().to_string());
+    config.metadata_cache_path = Some(metadata_cache_path.to_str().unwrap().to_string());
+    config.content_cache_path = Some(content_cache_path.to_str().unwrap().to_string());
+    config.spool_path = Some(spool_path.to_str().unwrap().to_string());
+    config.journal_path = Some(journal_path.to_str().unwrap().to_string());
+    config.recovery_path = Some(recovery_path.to_str().unwrap().to_string());
 
     Ok(config)
 }
@@ -160,6 +275,21 @@ fn main() {
     let matches = App::from_yaml(yaml).get_matches();
 
     if let Some(_matches) = matches.subcommand_matches("logout") {
+        // Pending changes can only be uploaded with the credentials which are about to be deleted.
+        let spooled = fs::read_dir(config.spool_path())
+            .map(|entries| entries.count())
+            .unwrap_or(0);
+        let queued = OfflineDrive::<DriveFacade>::queued_mutations(&config.journal_path());
+        if spooled > 0 || queued > 0 {
+            println!(
+                "Not logging out, some changes have not been uploaded yet (see {:?} and {:?}). \
+                 Mount GCSF again in order to upload them.",
+                config.spool_path(),
+                config.journal_path()
+            );
+            process::exit(1);
+        }
+
         let filename = config.token_path.as_ref().unwrap();
         match fs::remove_file(filename) {
             Ok(_) => {
@@ -169,10 +299,22 @@ fn main() {
                 println!("Could not remove {}: {}", filename, e);
             }
         };
+
+        // The saved file tree and contents belong to the account that just logged out.
+        if let Some(filename) = config.metadata_cache_path.as_ref() {
+            let _ = fs::remove_file(filename);
+        }
+        if let Some(dirname) = config.content_cache_path.as_ref() {
+            let _ = fs::remove_dir_all(dirname);
+        }
+        let _ = fs::remove_dir_all(config.spool_path());
+        let _ = fs::remove_dir_all(config.journal_path());
     }
 
     if let Some(matches) = matches.subcommand_matches("mount") {
         let mountpoint = matches.value_of("mountpoint").unwrap();
-        mount_gcsf(config, mountpoint);
+        if !mount_gcsf(config, mountpoint) {
+            process::exit(1);
+        }
     }
 }
@@ -1,4 +1,1551 @@
+use drive3;
+use failure::Error;
+use fuse::FileType;
+use gcsf::{
+    AttrDefaults, BackgroundSync, Config, ContentCache, ContentVersion, DriveBackend, DriveFacade,
+    File, FileHandle, FileHandles, FileId, FileManager, FsError, LocalDriveServer, MemoryDrive,
+    OfflineDrive,
+};
+use gcsf::filesystem::{AttrChanges, ShutdownReport, GCSF};
+use gcsf::xattr;
+use libc::{
+    EACCES, EAGAIN, EEXIST, EINVAL, ENOENT, ENOSPC, ENOTEMPTY, ENOTSUP, EROFS, O_APPEND, O_RDONLY,
+    O_RDWR, O_TRUNC, O_WRONLY,
+};
+use serde_json;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
 #[test]
 fn some_test() {
     assert_eq!(2 + 2, 4);
 }
+
+fn text_file(name: &str, parent: Option<&str>) -> drive3::File {
+    drive3::File {
+        name: Some(name.to_string()),
+        mime_type: Some("text/plain".to_string()),
+        parents: parent.map(|p| vec![p.to_string()]),
+        ..Default::default()
+    }
+}
+
+fn child(parent: u64, name: &str) -> FileId {
+    FileId::ParentAndName {
+        parent,
+        name: name.to_string(),
+    }
+}
+
+/// Creates a file manager which syncs as often as it is asked to.
+fn manager_for(drive: &MemoryDrive) -> FileManager {
+    FileManager::with_drive_backend(Duration::from_secs(0), drive.clone())
+}
+
+/// Waits up to two seconds for `condition` to hold, e.g. for a background sync to catch up.
+fn eventually<F: Fn() -> bool>(condition: F) -> bool {
+    for _ in 0..200 {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+/// Runs an operation of `GCSF` and waits for the result which it hands to its callback.
+fn run<T, O>(op: O) -> T
+where
+    T: Send + 'static,
+    O: FnOnce(Box<dyn FnOnce(T) + Send>),
+{
+    let (sender, receiver) = mpsc::channel();
+    op(Box::new(move |result| sender.send(result).unwrap()));
+    receiver.recv_timeout(Duration::from_secs(30)).unwrap()
+}
+
+/// Creates a config which syncs as often as it is asked to. `extra` holds additional JSON keys.
+fn config_with(extra: &str) -> Config {
+    serde_json::from_str(&format!("{{\"sync_interval\": 0 {}}}", extra)).unwrap()
+}
+
+/// A `LocalDriveServer` which serves a `MemoryDrive`, along with a config that points GCSF at it.
+/// The token, the spool and the recovered writes are kept in a temporary directory of their own,
+/// which is removed when the fixture is dropped.
+struct LocalDrive {
+    server: LocalDriveServer,
+    config: Config,
+    dir: PathBuf,
+}
+
+impl LocalDrive {
+    fn start(drive: &MemoryDrive, name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("gcsf-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let server = LocalDriveServer::start(drive.clone()).unwrap();
+        let token_path = dir.join("token.json");
+        let config = server
+            .authorized_config(token_path.to_str().unwrap())
+            .unwrap();
+        LocalDrive {
+            server,
+            config,
+            dir,
+        }
+    }
+}
+
+impl Drop for LocalDrive {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn cached_config(cache_path: &Path) -> Config {
+    let mut config = config_with("");
+    config.metadata_cache_path = Some(cache_path.to_str().unwrap().to_string());
+    config
+}
+
+#[test]
+fn populate_mirrors_drive_tree() {
+    let drive = MemoryDrive::new();
+    let dir = drive.add_dir("dir", None);
+    drive.add_file(text_file("a.txt", None), b"a");
+    drive.add_file(text_file("b.txt", Some(&dir)), b"b");
+
+    let manager = manager_for(&drive);
+
+    let dir_inode = manager.get_inode(&child(1, "dir")).unwrap();
+    assert!(manager.contains(&child(1, "a.txt")));
+    assert!(manager.contains(&child(dir_inode, "b.txt")));
+    assert!(manager.contains(&child(1, "Trash")));
+}
+
+#[test]
+fn write_and_flush_reach_drive() {
+    let drive = MemoryDrive::new();
+    let id = drive.add_file(text_file("a.txt", None), b"hello world");
+    let mut manager = manager_for(&drive);
+
+    manager.write(FileId::DriveId(id.clone()), 6, b"drive").unwrap();
+    assert_eq!(drive.content(&id).unwrap(), b"hello world".to_vec());
+
+    manager.flush(&FileId::DriveId(id.clone())).unwrap();
+    assert_eq!(drive.content(&id).unwrap(), b"hello drive".to_vec());
+    assert_eq!(
+        manager.df.lock().unwrap().read(&id, None, 0, 5).map(|data| data.to_vec()).ok(),
+        Some(b"hello".to_vec())
+    );
+}
+
+#[test]
+fn truncate_changes_size_on_drive() {
+    let drive = MemoryDrive::new();
+    let id = drive.add_file(text_file("a.txt", None), b"hello world");
+    let dir = drive.add_dir("dir", None);
+    let mut manager = manager_for(&drive);
+    let file_id = FileId::DriveId(id.clone());
+
+    manager.truncate(&file_id, 0).unwrap();
+    manager.write(file_id.clone(), 0, b"hi").unwrap();
+    manager.flush(&file_id).unwrap();
+    assert_eq!(drive.content(&id), Some(b"hi".to_vec()));
+
+    manager.truncate(&file_id, 4).unwrap();
+    assert_eq!(manager.get_file(&file_id).unwrap().attr.size, 4);
+    manager.flush(&file_id).unwrap();
+    assert_eq!(drive.content(&id), Some(b"hi\0\0".to_vec()));
+
+    assert!(manager.truncate(&FileId::DriveId(dir), 0).is_err());
+}
+
+#[test]
+fn file_handles_track_open_modes_and_writers() {
+    let reader = FileHandle::from_flags(2, O_RDONLY as u32);
+    assert!(!reader.writable);
+
+    let appender = FileHandle::from_flags(2, (O_WRONLY | O_APPEND) as u32);
+    assert!(appender.writable && appender.append && !appender.truncate);
+
+    let truncator = FileHandle::from_flags(3, (O_RDWR | O_TRUNC) as u32);
+    assert!(truncator.writable && !truncator.append && truncator.truncate);
+
+    let mut handles = FileHandles::new();
+    let r = handles.open(reader);
+    let a = handles.open(appender.clone());
+    let b = handles.open(appender.clone());
+    assert!(r != a && a != b);
+    assert_eq!(handles.writers(2), 2);
+    assert_eq!(handles.writers(3), 0);
+
+    assert_eq!(handles.release(a), Some(appender));
+    assert_eq!(handles.release(a), None);
+    assert_eq!(handles.writers(2), 1);
+    assert_eq!(handles.get(r).map(|h| h.writable), Some(false));
+}
+
+#[test]
+fn mode_and_owner_survive_a_round_trip_through_drive() {
+    let drive = MemoryDrive::new();
+    let script = drive.add_file(text_file("build.sh", None), b"#!/bin/sh");
+    drive.add_dir("dir", None);
+    let mut manager = manager_for(&drive);
+
+    // Files which do not record their attributes get the defaults.
+    let defaults = AttrDefaults::default();
+    let attr = manager.get_file(&child(1, "build.sh")).unwrap().attr;
+    assert_eq!(
+        (attr.perm, attr.uid, attr.gid),
+        (defaults.file_mode, defaults.uid, defaults.gid)
+    );
+    let attr = manager.get_file(&child(1, "dir")).unwrap().attr;
+    assert_eq!(attr.perm, defaults.dir_mode);
+
+    manager
+        .set_attributes(&child(1, "build.sh"), Some(0o755), Some(1000), None)
+        .unwrap();
+    let properties = drive.file(&script).unwrap().app_properties.unwrap();
+    assert_eq!(properties.get("posix_mode"), Some(&"755".to_string()));
+    assert_eq!(properties.get("posix_uid"), Some(&"1000".to_string()));
+    assert_eq!(properties.get("posix_gid"), None);
+
+    manager
+        .set_attributes(&child(1, "build.sh"), None, None, Some(100))
+        .unwrap();
+    let attr = manager_for(&drive).get_file(&child(1, "build.sh")).unwrap().attr;
+    assert_eq!((attr.perm, attr.uid, attr.gid), (0o755, 1000, 100));
+}
+
+#[test]
+fn configured_owner_and_modes_apply_to_files_without_their_own() {
+    let drive = MemoryDrive::new();
+    drive.add_file(text_file("a.txt", None), b"a");
+    drive.add_dir("dir", None);
+
+    let config = config_with(
+        ", \"uid\": 1234, \"gid\": 5678, \"umask\": \"027\", \"file_mode\": \"664\"",
+    );
+    let defaults = config.attr_defaults();
+    assert_eq!((defaults.file_mode, defaults.dir_mode), (0o640, 0o750));
+
+    let manager = FileManager::with_config(&config, drive.clone());
+    let attr = |id: &FileId| {
+        let attr = manager.get_file(id).unwrap().attr;
+        (attr.perm, attr.uid, attr.gid)
+    };
+    assert_eq!(attr(&FileId::Inode(1)), (0o750, 1234, 5678));
+    assert_eq!(attr(&child(1, "a.txt")), (0o640, 1234, 5678));
+    assert_eq!(attr(&child(1, "dir")), (0o750, 1234, 5678));
+
+    // Invalid modes are ignored.
+    let config = config_with(", \"umask\": \"999\"");
+    assert_eq!(config.umask(), 0o022);
+}
+
+#[test]
+fn symlinks_are_kept_as_drive_properties() {
+    let drive = MemoryDrive::new();
+    let mut link = text_file("link", None);
+    link.mime_type = Some("inode/symlink".to_string());
+    link.app_properties = Some(hashmap!{
+        "symlink_target".to_string() => "../target".to_string(),
+    });
+    let id = drive.add_file(link, b"");
+    let mut manager = manager_for(&drive);
+    let errno = |result: Result<(), Error>| FsError::errno_of(&result.unwrap_err());
+
+    let file = manager.get_file(&child(1, "link")).unwrap().clone();
+    assert_eq!(file.kind(), FileType::Symlink);
+    assert_eq!(file.attr.size, 9);
+    assert_eq!(file.link_target(), Some(&"../target".to_string()));
+
+    // A link which is renamed remotely is still a link.
+    let mut renamed = drive.file(&id).unwrap();
+    renamed.name = Some("renamed".to_string());
+    drive.update_file(renamed).unwrap();
+    manager.sync().unwrap();
+    let file = manager.get_file(&child(1, "renamed")).unwrap();
+    assert_eq!(file.kind(), FileType::Symlink);
+    assert_eq!(file.link_target(), Some(&"../target".to_string()));
+
+    assert!(manager.check_new_symlink(1, "new", "target").is_ok());
+    assert_eq!(
+        errno(manager.check_new_symlink(1, "new", &"x".repeat(200))),
+        EINVAL
+    );
+    assert_eq!(errno(manager.check_new_symlink(1, "renamed", "target")), EEXIST);
+}
+
+#[test]
+fn drive_metadata_is_exposed_as_xattrs() {
+    let drive = MemoryDrive::new();
+    let id = drive.add_file(
+        drive3::File {
+            starred: Some(true),
+            owners: Some(vec![
+                drive3::User {
+                    email_address: Some("a@example.com".to_string()),
+                    ..Default::default()
+                },
+                drive3::User {
+                    email_address: Some("b@example.com".to_string()),
+                    ..Default::default()
+                },
+            ]),
+            ..text_file("a.txt", None)
+        },
+        b"a",
+    );
+    let file = drive.clone().get_metadata(&id).unwrap();
+
+    assert_eq!(xattr::field("user.gcsf.webViewLink"), Some("webViewLink"));
+    assert_eq!(xattr::field("user.gcsf.name"), None);
+    assert_eq!(xattr::field("user.other.id"), None);
+
+    assert_eq!(xattr::value(&file, "id"), Some(id.clone()));
+    assert_eq!(xattr::value(&file, "starred"), Some("true".to_string()));
+    assert_eq!(
+        xattr::value(&file, "owners"),
+        Some("a@example.com,b@example.com".to_string())
+    );
+    assert_eq!(xattr::value(&file, "description"), None);
+
+    let names = String::from_utf8(xattr::list(&file)).unwrap();
+    assert!(names.contains("user.gcsf.id\0"));
+    assert!(names.contains("user.gcsf.mimeType\0"));
+    assert!(!names.contains("user.gcsf.description\0"));
+    assert!(names.ends_with('\0'));
+}
+
+#[test]
+fn xattrs_write_description_starred_and_properties_to_drive() {
+    let drive = MemoryDrive::new();
+    let id = drive.add_file(text_file("a.txt", None), b"a");
+    let mut manager = manager_for(&drive);
+    let file = FileId::DriveId(id.clone());
+    let set = |name: &str, value: Option<&[u8]>| xattr::Change::parse(name, value).unwrap();
+
+    let changes = vec![
+        set("user.gcsf.description", Some(b"notes")),
+        set("user.gcsf.starred", Some(b"true")),
+        set("user.prop.status", Some(b"processed")),
+        set("user.prop.stage", Some(b"2")),
+    ];
+    for change in changes {
+        manager.set_xattr(&file, change).unwrap();
+    }
+
+    let remote = drive.file(&id).unwrap();
+    assert_eq!(remote.description, Some("notes".to_string()));
+    assert_eq!(remote.starred, Some(true));
+    assert_eq!(xattr::get(&remote, "user.prop.status"), Some("processed".to_string()));
+    let names = String::from_utf8(xattr::list(&remote)).unwrap();
+    assert!(names.ends_with("user.prop.stage\0user.prop.status\0"));
+
+    manager
+        .set_xattr(&file, set("user.prop.status", None))
+        .unwrap();
+    manager
+        .set_xattr(&file, set("user.gcsf.starred", None))
+        .unwrap();
+    let remote = drive.file(&id).unwrap();
+    assert_eq!(xattr::get(&remote, "user.prop.status"), None);
+    assert_eq!(xattr::get(&remote, "user.prop.stage"), Some("2".to_string()));
+    assert_eq!(remote.starred, Some(false));
+
+    let local = manager.get_file(&file).unwrap().drive_file.clone().unwrap();
+    assert_eq!(xattr::get(&local, "user.prop.status"), None);
+    assert_eq!(local.description, Some("notes".to_string()));
+
+    let errno = |name: &str, value: &[u8]| {
+        xattr::Change::parse(name, Some(value))
+            .unwrap_err()
+            .errno()
+    };
+    assert_eq!(errno("user.gcsf.id", b"x"), ENOTSUP);
+    assert_eq!(errno("user.gcsf.starred", b"maybe"), EINVAL);
+    assert_eq!(errno("user.prop.key", &[b'x'; 200]), EINVAL);
+}
+
+#[test]
+fn flush_all_uploads_every_dirty_file() {
+    let drive = MemoryDrive::new();
+    let a = drive.add_file(text_file("a.txt", None), b"a");
+    let b = drive.add_file(text_file("b.txt", None), b"b");
+    let mut manager = manager_for(&drive);
+
+    manager.write(FileId::DriveId(a.clone()), 1, b"a").unwrap();
+    manager.write(FileId::DriveId(b.clone()), 1, b"b").unwrap();
+    assert_eq!(manager.flush_all(), 0);
+    assert_eq!(drive.content(&a), Some(b"aa".to_vec()));
+    assert_eq!(drive.content(&b), Some(b"bb".to_vec()));
+
+    // Files which cannot be flushed are counted and keep their pending writes.
+    drive.set_capacity(Some(5));
+    manager
+        .write(FileId::DriveId(a.clone()), 2, b"too much")
+        .unwrap();
+    assert_eq!(manager.flush_all(), 1);
+    assert_eq!(
+        manager.df.lock().unwrap().dirty_files(),
+        vec![a.clone()]
+    );
+}
+
+#[test]
+fn rename_moves_file_on_drive() {
+    let drive = MemoryDrive::new();
+    let dir = drive.add_dir("dir", None);
+    let id = drive.add_file(text_file("a.txt", None), b"a");
+    let mut manager = manager_for(&drive);
+
+    let dir_inode = manager.get_inode(&child(1, "dir")).unwrap();
+    manager
+        .rename(&child(1, "a.txt"), dir_inode, "b.txt".to_string())
+        .unwrap();
+
+    assert!(!manager.contains(&child(1, "a.txt")));
+    assert!(manager.contains(&child(dir_inode, "b.txt")));
+
+    let remote = drive.file(&id).unwrap();
+    assert_eq!(remote.name, Some("b.txt".to_string()));
+    assert_eq!(remote.parents, Some(vec![dir]));
+}
+
+#[test]
+fn trash_and_delete_reach_drive() {
+    let drive = MemoryDrive::new();
+    let trashed = drive.add_file(text_file("a.txt", None), b"a");
+    let deleted = drive.add_dir("dir", None);
+    let mut manager = manager_for(&drive);
+
+    manager.move_file_to_trash(&child(1, "a.txt"), true).unwrap();
+    assert!(manager.contains(&child(2, "a.txt")));
+    assert_eq!(drive.file(&trashed).unwrap().trashed, Some(true));
+
+    manager.delete(&child(1, "dir")).unwrap();
+    assert!(!manager.contains(&child(1, "dir")));
+    assert!(drive.file(&deleted).is_none());
+}
+
+#[test]
+fn sync_applies_remote_changes() {
+    let drive = MemoryDrive::new();
+    let id = drive.add_file(text_file("a.txt", None), b"a");
+    let mut manager = manager_for(&drive);
+
+    drive.add_file(text_file("new.txt", None), b"new");
+    let mut renamed = drive.file(&id).unwrap();
+    renamed.name = Some("renamed.txt".to_string());
+    drive.update_file(renamed).unwrap();
+
+    manager.sync().unwrap();
+    assert!(manager.contains(&child(1, "new.txt")));
+    assert!(manager.contains(&child(1, "renamed.txt")));
+    assert!(!manager.contains(&child(1, "a.txt")));
+}
+
+#[test]
+fn shared_drives_are_mounted_under_a_virtual_dir() {
+    let drive = MemoryDrive::new();
+    let team = drive.add_shared_drive("Team");
+    let in_drive = |id: &str, file: drive3::File| drive3::File {
+        team_drive_id: Some(id.to_string()),
+        ..file
+    };
+    let docs = drive.add_file(
+        in_drive(
+            &team,
+            drive3::File {
+                mime_type: Some("application/vnd.google-apps.folder".to_string()),
+                ..text_file("docs", Some(&team))
+            },
+        ),
+        b"",
+    );
+    drive.add_file(in_drive(&team, text_file("plan.txt", Some(&docs))), b"plan");
+    let mut manager = manager_for(&drive);
+
+    let shared = manager.get_inode(&child(1, "Shared drives")).unwrap();
+    let team_dir = manager.get_inode(&child(shared, "Team")).unwrap();
+    let docs_dir = manager.get_inode(&child(team_dir, "docs")).unwrap();
+    assert!(manager.contains(&child(docs_dir, "plan.txt")));
+    assert!(!manager.contains(&child(1, "docs")));
+
+    // Each drive keeps track of its changes with a token of its own.
+    drive.add_file(in_drive(&team, text_file("new.txt", Some(&team))), b"new");
+    let other = drive.add_shared_drive("Other");
+    manager.sync().unwrap();
+    assert!(manager.contains(&child(team_dir, "new.txt")));
+    assert_eq!(
+        manager.df.lock().unwrap().get_drive_changes(&team).unwrap().len(),
+        0
+    );
+
+    drive.add_file(in_drive(&other, text_file("x.txt", Some(&other))), b"x");
+    manager.sync().unwrap();
+    let other_dir = manager.get_inode(&child(shared, "Other")).unwrap();
+    assert!(manager.contains(&child(other_dir, "x.txt")));
+
+    // The virtual directory itself does not exist on Drive.
+    let refused = manager.check_new_file(shared, "file.txt").unwrap_err();
+    assert_eq!(FsError::errno_of(&refused), EACCES);
+}
+
+#[test]
+fn changes_are_retrieved_again_if_a_sync_fails_halfway() {
+    let drive = MemoryDrive::new();
+    let team = drive.add_shared_drive("Team");
+    let mut manager = manager_for(&drive);
+    let shared = manager.get_inode(&child(1, "Shared drives")).unwrap();
+    let team_dir = manager.get_inode(&child(shared, "Team")).unwrap();
+
+    drive.add_file(text_file("a.txt", None), b"a");
+    let in_team = drive3::File {
+        team_drive_id: Some(team.clone()),
+        ..text_file("b.txt", Some(&team))
+    };
+    drive.add_file(in_team, b"b");
+
+    // The changes of "My Drive" are retrieved, then Drive goes away.
+    drive.go_offline_after(1);
+    assert!(manager.sync().is_err());
+    assert!(!manager.contains(&child(1, "a.txt")));
+
+    drive.set_offline(false);
+    manager.sync().unwrap();
+    assert!(manager.contains(&child(1, "a.txt")));
+    assert!(manager.contains(&child(team_dir, "b.txt")));
+}
+
+#[test]
+fn files_shared_with_me_are_listed_under_a_virtual_dir() {
+    let drive = MemoryDrive::new();
+    let shared = |file: drive3::File| drive3::File {
+        shared_with_me_time: Some("2018-05-01T10:00:00.000Z".to_string()),
+        ..file
+    };
+    // The parents of shared files belong to someone else.
+    let project = drive.add_file(
+        shared(drive3::File {
+            mime_type: Some("application/vnd.google-apps.folder".to_string()),
+            ..text_file("project", Some("someone-elses-dir"))
+        }),
+        b"",
+    );
+    drive.add_file(text_file("notes.txt", Some(&project)), b"notes");
+    let memo = drive.add_file(shared(text_file("memo.txt", Some("someone-elses-dir"))), b"memo");
+    let mut manager = manager_for(&drive);
+
+    let shared_dir = manager.get_inode(&child(1, "Shared with me")).unwrap();
+    let project_dir = manager.get_inode(&child(shared_dir, "project")).unwrap();
+    assert!(manager.contains(&child(project_dir, "notes.txt")));
+    assert!(manager.contains(&child(shared_dir, "memo.txt")));
+
+    drive.add_file(shared(text_file("new.txt", Some("someone-elses-dir"))), b"new");
+    drive.add_file(text_file("more.txt", Some(&project)), b"more");
+    manager.sync().unwrap();
+    assert!(manager.contains(&child(shared_dir, "new.txt")));
+    assert!(manager.contains(&child(project_dir, "more.txt")));
+
+    // The contents of a newly shared folder are retrieved along with it.
+    let drafts = drive.add_file(
+        drive3::File {
+            mime_type: Some("application/vnd.google-apps.folder".to_string()),
+            ..text_file("drafts", Some("someone-elses-dir"))
+        },
+        b"",
+    );
+    drive.add_file(text_file("draft.txt", Some(&drafts)), b"draft");
+    manager.sync().unwrap();
+    assert!(!manager.contains(&child(shared_dir, "drafts")));
+
+    drive.update_file(shared(drive.file(&drafts).unwrap())).unwrap();
+    manager.sync().unwrap();
+    let drafts_dir = manager.get_inode(&child(shared_dir, "drafts")).unwrap();
+    assert!(manager.contains(&child(drafts_dir, "draft.txt")));
+
+    // Files which are no longer shared disappear.
+    let mut unshared = drive.file(&memo).unwrap();
+    unshared.shared_with_me_time = None;
+    drive.update_file(unshared).unwrap();
+    manager.sync().unwrap();
+    assert!(!manager.contains(&child(shared_dir, "memo.txt")));
+}
+
+#[test]
+fn starred_and_recent_dirs_list_the_same_inodes() {
+    let drive = MemoryDrive::new();
+    let viewed = |time: &str, file: drive3::File| drive3::File {
+        viewed_by_me_time: Some(time.to_string()),
+        ..file
+    };
+    let a = drive.add_file(
+        drive3::File {
+            starred: Some(true),
+            ..text_file("a.txt", None)
+        },
+        b"a",
+    );
+    let b = drive.add_file(viewed("2018-05-01T10:00:00.000Z", text_file("b.txt", None)), b"b");
+    drive.add_file(viewed("2018-05-02T10:00:00.000Z", text_file("c.txt", None)), b"c");
+    drive.add_file(viewed("2018-04-01T10:00:00.000Z", text_file("d.txt", None)), b"d");
+
+    let config =
+        config_with(", \"starred_dir\": true, \"recent_dir\": true, \"recent_dir_limit\": 2");
+    let mut manager = FileManager::with_config(&config, drive.clone());
+    let starred = manager.get_inode(&child(1, "Starred")).unwrap();
+    let recent = manager.get_inode(&child(1, "Recent")).unwrap();
+
+    assert_eq!(
+        manager.get_inode(&child(starred, "a.txt")),
+        manager.get_inode(&FileId::DriveId(a))
+    );
+    let names: Vec<String> = manager
+        .get_children(&FileId::Inode(recent))
+        .unwrap()
+        .iter()
+        .map(|file| file.name())
+        .collect();
+    assert_eq!(names, vec!["c.txt".to_string(), "b.txt".to_string()]);
+
+    // Both are listed again once changes are applied.
+    let mut starred_b = drive.file(&b).unwrap();
+    starred_b.starred = Some(true);
+    drive.update_file(starred_b).unwrap();
+    manager.sync().unwrap();
+    assert_eq!(
+        manager.get_inode(&child(starred, "b.txt")),
+        manager.get_inode(&FileId::DriveId(b))
+    );
+}
+
+#[test]
+fn recent_dir_loads_the_files_it_lists_when_population_is_lazy() {
+    let drive = MemoryDrive::new();
+    let docs = drive.add_dir("docs", None);
+    let notes = drive.add_dir("notes", Some(&docs));
+    let todo = drive.add_file(
+        drive3::File {
+            viewed_by_me_time: Some("2018-05-01T10:00:00.000Z".to_string()),
+            ..text_file("todo.txt", Some(&notes))
+        },
+        b"todo",
+    );
+
+    let config = config_with(", \"lazy_population\": true, \"recent_dir\": true");
+    let mut manager = FileManager::with_config(&config, drive.clone());
+    let recent = manager.get_inode(&child(1, "Recent")).unwrap();
+    assert!(!manager.contains(&FileId::DriveId(todo.clone())));
+
+    manager.load_children(&FileId::Inode(recent)).unwrap();
+    let listed = manager.get_inode(&child(recent, "todo.txt"));
+    assert!(listed.is_some());
+
+    // The file keeps its inode once the directories which lead to it are loaded.
+    manager.load_children(&FileId::Inode(1)).unwrap();
+    let docs = manager.get_inode(&child(1, "docs")).unwrap();
+    manager.load_children(&FileId::Inode(docs)).unwrap();
+    let notes = manager.get_inode(&child(docs, "notes")).unwrap();
+    manager.load_children(&FileId::Inode(notes)).unwrap();
+    assert_eq!(manager.get_inode(&child(notes, "todo.txt")), listed);
+    assert_eq!(manager.get_children(&FileId::Inode(notes)).unwrap().len(), 1);
+}
+
+#[test]
+fn background_sync_applies_remote_changes() {
+    let drive = MemoryDrive::new();
+    let manager = Arc::new(Mutex::new(manager_for(&drive)));
+    let mut sync = BackgroundSync::spawn(Arc::clone(&manager), Duration::from_millis(10));
+
+    drive.add_file(text_file("new.txt", None), b"new");
+    assert!(eventually(|| manager.lock().unwrap().contains(&child(1, "new.txt"))));
+
+    // Once stopped, no more changes are applied.
+    sync.stop();
+    drive.add_file(text_file("late.txt", None), b"late");
+    thread::sleep(Duration::from_millis(50));
+    assert!(!manager.lock().unwrap().contains(&child(1, "late.txt")));
+}
+
+#[test]
+fn remote_changes_are_applied_while_a_slow_drive_request_is_in_flight() {
+    let drive = MemoryDrive::new();
+    let manager = Arc::new(Mutex::new(manager_for(&drive)));
+    let df = Arc::clone(&manager.lock().unwrap().df);
+
+    drive.add_file(text_file("new.txt", None), b"new");
+    let request = manager.lock().unwrap().sync_request();
+    let remote = request.fetch(&mut *df.lock().unwrap()).unwrap();
+
+    // Another operation keeps waiting for Drive while the changes are applied.
+    let in_flight = df.lock().unwrap();
+    let (done, applied) = mpsc::channel();
+    let sync_manager = Arc::clone(&manager);
+    thread::spawn(move || {
+        let result = sync_manager.lock().unwrap().apply_remote_changes(remote);
+        done.send(result.is_ok()).unwrap();
+    });
+
+    assert_eq!(applied.recv_timeout(Duration::from_secs(5)), Ok(true));
+    assert!(manager.lock().unwrap().get_inode(&child(1, "new.txt")).is_some());
+    drop(in_flight);
+}
+
+#[test]
+fn background_sync_follows_shared_drives() {
+    let drive = MemoryDrive::new();
+    let team = drive.add_shared_drive("Team");
+    let manager = Arc::new(Mutex::new(manager_for(&drive)));
+    let (shared, team_dir) = {
+        let manager = manager.lock().unwrap();
+        let shared = manager.get_inode(&child(1, "Shared drives")).unwrap();
+        (shared, manager.get_inode(&child(shared, "Team")).unwrap())
+    };
+    let _sync = BackgroundSync::spawn(Arc::clone(&manager), Duration::from_millis(10));
+
+    drive.add_file(
+        drive3::File {
+            team_drive_id: Some(team.clone()),
+            ..text_file("new.txt", Some(&team))
+        },
+        b"new",
+    );
+    assert!(eventually(|| manager.lock().unwrap().contains(&child(team_dir, "new.txt"))));
+
+    drive.add_shared_drive("Other");
+    assert!(eventually(|| manager.lock().unwrap().contains(&child(shared, "Other"))));
+}
+
+#[test]
+fn background_sync_refreshes_shared_with_me() {
+    let drive = MemoryDrive::new();
+    let shared = |file: drive3::File| drive3::File {
+        shared_with_me_time: Some("2018-05-01T10:00:00.000Z".to_string()),
+        ..file
+    };
+    let memo = drive.add_file(shared(text_file("memo.txt", Some("someone-elses-dir"))), b"memo");
+    let manager = Arc::new(Mutex::new(manager_for(&drive)));
+    let shared_dir = manager
+        .lock()
+        .unwrap()
+        .get_inode(&child(1, "Shared with me"))
+        .unwrap();
+    let _sync = BackgroundSync::spawn(Arc::clone(&manager), Duration::from_millis(10));
+
+    let project = drive.add_file(
+        shared(drive3::File {
+            mime_type: Some("application/vnd.google-apps.folder".to_string()),
+            ..text_file("project", Some("someone-elses-dir"))
+        }),
+        b"",
+    );
+    drive.add_file(text_file("notes.txt", Some(&project)), b"notes");
+    assert!(eventually(|| {
+        let manager = manager.lock().unwrap();
+        manager
+            .get_inode(&child(shared_dir, "project"))
+            .is_some_and(|dir| manager.contains(&child(dir, "notes.txt")))
+    }));
+
+    let mut unshared = drive.file(&memo).unwrap();
+    unshared.shared_with_me_time = None;
+    drive.update_file(unshared).unwrap();
+    assert!(eventually(|| !manager.lock().unwrap().contains(&child(shared_dir, "memo.txt"))));
+}
+
+#[test]
+fn background_sync_refreshes_starred_dir() {
+    let drive = MemoryDrive::new();
+    let a = drive.add_file(text_file("a.txt", None), b"a");
+    let config = config_with(", \"starred_dir\": true");
+    let manager = Arc::new(Mutex::new(FileManager::with_config(&config, drive.clone())));
+    let starred = manager
+        .lock()
+        .unwrap()
+        .get_inode(&child(1, "Starred"))
+        .unwrap();
+    let _sync = BackgroundSync::spawn(Arc::clone(&manager), Duration::from_millis(10));
+
+    let mut starred_a = drive.file(&a).unwrap();
+    starred_a.starred = Some(true);
+    drive.update_file(starred_a).unwrap();
+    assert!(eventually(|| manager.lock().unwrap().contains(&child(starred, "a.txt"))));
+}
+
+#[test]
+fn drive_facade_against_local_server() {
+    let drive = MemoryDrive::new();
+    let id = drive.add_file(text_file("a.txt", None), b"hello world");
+    let local = LocalDrive::start(&drive, "facade");
+
+    let mut manager =
+        FileManager::with_drive_backend(Duration::from_secs(0), DriveFacade::new(&local.config));
+    assert!(manager.contains(&child(1, "a.txt")));
+    assert_eq!(
+        manager.df.lock().unwrap().read(&id, None, 6, 5).map(|data| data.to_vec()).ok(),
+        Some(b"world".to_vec())
+    );
+
+    manager.write(FileId::DriveId(id.clone()), 0, b"HELLO").unwrap();
+    manager.flush(&FileId::DriveId(id.clone())).unwrap();
+    assert_eq!(drive.content(&id).unwrap(), b"HELLO world".to_vec());
+}
+
+#[test]
+fn metadata_cache_restores_tree_and_replays_changes() {
+    let drive = MemoryDrive::new();
+    drive.add_file(text_file("a.txt", None), b"a");
+
+    let cache_path = env::temp_dir().join("gcsf-test-metadata-restore.json");
+    let _ = fs::remove_file(&cache_path);
+
+    let inode = {
+        let manager = FileManager::with_config(&cached_config(&cache_path), drive.clone());
+        manager.get_inode(&child(1, "a.txt")).unwrap()
+    };
+    assert!(cache_path.exists());
+
+    drive.add_file(text_file("b.txt", None), b"b");
+    let manager = FileManager::with_config(&cached_config(&cache_path), drive.clone());
+    assert_eq!(manager.get_inode(&child(1, "a.txt")), Some(inode));
+    assert!(manager.contains(&child(1, "b.txt")));
+
+    let _ = fs::remove_file(&cache_path);
+}
+
+#[test]
+fn metadata_cache_is_used_while_offline_and_dropped_once_its_token_expires() {
+    let drive = MemoryDrive::new();
+    drive.add_file(text_file("a.txt", None), b"a");
+
+    let cache_path = env::temp_dir().join("gcsf-test-metadata-offline.json");
+    let _ = fs::remove_file(&cache_path);
+    FileManager::with_config(&cached_config(&cache_path), drive.clone());
+
+    drive.set_offline(true);
+    let manager = FileManager::with_config(&cached_config(&cache_path), drive.clone());
+    assert!(manager.contains(&child(1, "a.txt")));
+
+    drive.set_offline(false);
+    drive.add_file(text_file("b.txt", None), b"b");
+    drive.expire_changes_tokens();
+    let manager = FileManager::with_config(&cached_config(&cache_path), drive.clone());
+    assert!(manager.contains(&child(1, "a.txt")));
+    assert!(manager.contains(&child(1, "b.txt")));
+
+    let _ = fs::remove_file(&cache_path);
+}
+
+#[test]
+fn metadata_snapshots_are_saved_outside_of_the_manager_and_never_replace_newer_ones() {
+    let drive = MemoryDrive::new();
+    let cache_path = env::temp_dir().join("gcsf-test-metadata-snapshots.json");
+    let _ = fs::remove_file(&cache_path);
+
+    let mut manager = FileManager::with_config(&cached_config(&cache_path), drive.clone());
+    let df = Arc::clone(&manager.df);
+    assert!(manager.outdated_metadata().unwrap().is_none());
+
+    let mut snapshots = Vec::new();
+    for name in &["a.txt", "b.txt"] {
+        drive.add_file(text_file(name, None), b"x");
+        let remote = manager.sync_request().fetch(&mut *df.lock().unwrap()).unwrap();
+        manager.apply_remote_changes(remote).unwrap();
+        snapshots.push(manager.outdated_metadata().unwrap().unwrap());
+    }
+    drop(manager);
+
+    let older = snapshots.remove(0);
+    snapshots.remove(0).save().unwrap();
+    older.save().unwrap();
+
+    drive.set_offline(true);
+    let manager = FileManager::with_config(&cached_config(&cache_path), drive.clone());
+    assert!(manager.contains(&child(1, "a.txt")));
+    assert!(manager.contains(&child(1, "b.txt")));
+
+    let _ = fs::remove_file(&cache_path);
+}
+
+#[test]
+fn unusable_metadata_cache_falls_back_to_populate() {
+    let drive = MemoryDrive::new();
+    drive.add_file(text_file("a.txt", None), b"a");
+
+    let cache_path = env::temp_dir().join("gcsf-test-metadata-fallback.json");
+    fs::write(&cache_path, b"{ not a snapshot").unwrap();
+
+    let manager = FileManager::with_config(&cached_config(&cache_path), drive.clone());
+    assert!(manager.contains(&child(1, "a.txt")));
+    assert!(manager.contains(&child(1, "Trash")));
+
+    let _ = fs::remove_file(&cache_path);
+}
+
+#[test]
+fn lazy_population_loads_directories_on_demand() {
+    let drive = MemoryDrive::new();
+    let dir = drive.add_dir("dir", None);
+    drive.add_file(text_file("a.txt", Some(&dir)), b"a");
+
+    let config = config_with(", \"lazy_population\": true");
+    let mut manager = FileManager::with_config(&config, drive.clone());
+    assert!(!manager.contains(&child(1, "dir")));
+
+    manager.load_children(&FileId::Inode(1)).unwrap();
+    let dir_inode = manager.get_inode(&child(1, "dir")).unwrap();
+    assert!(!manager.is_loaded(&FileId::Inode(dir_inode)));
+    assert!(!manager.contains(&child(dir_inode, "a.txt")));
+
+    // Changes are only applied to directories which have been loaded.
+    drive.add_file(text_file("b.txt", Some(&dir)), b"b");
+    drive.add_file(text_file("c.txt", None), b"c");
+    manager.sync().unwrap();
+    assert!(!manager.contains(&child(dir_inode, "b.txt")));
+    assert!(manager.contains(&child(1, "c.txt")));
+
+    manager.load_children(&FileId::Inode(dir_inode)).unwrap();
+    assert!(manager.contains(&child(dir_inode, "a.txt")));
+    assert!(manager.contains(&child(dir_inode, "b.txt")));
+}
+
+#[test]
+fn children_are_fetched_separately_from_being_added() {
+    let drive = MemoryDrive::new();
+    let dir = drive.add_dir("dir", None);
+    drive.add_file(text_file("a.txt", Some(&dir)), b"a");
+
+    let config = config_with(", \"lazy_population\": true");
+    let mut manager = FileManager::with_config(&config, drive.clone());
+    manager.load_children(&FileId::Inode(1)).unwrap();
+    let dir_id = child(1, "dir");
+
+    let request = manager.children_request(&dir_id).unwrap().unwrap();
+    let drive_files = request.fetch(&mut *manager.df.lock().unwrap()).unwrap();
+    assert!(!manager.contains(&child(request.inode, "a.txt")));
+
+    manager.add_children(request.inode, drive_files).unwrap();
+    assert!(manager.contains(&child(request.inode, "a.txt")));
+    assert!(manager.children_request(&dir_id).unwrap().is_none());
+}
+
+fn version(md5: &str) -> ContentVersion {
+    ContentVersion {
+        head_revision_id: None,
+        md5_checksum: Some(md5.to_string()),
+        modified_time: None,
+    }
+}
+
+#[test]
+fn content_cache_evicts_least_recently_used_within_budget() {
+    let dir = env::temp_dir().join("gcsf-test-content-cache-lru");
+    let _ = fs::remove_dir_all(&dir);
+
+    let ttl = Duration::from_secs(3600);
+    let mut cache = ContentCache::new(dir.clone(), 10, 6, ttl).unwrap();
+    cache.insert("a", version("a"), b"aaaa").unwrap();
+    cache.insert("b", version("b"), b"bbbb").unwrap();
+    assert_eq!(cache.get("a"), Some(b"aaaa".to_vec()));
+
+    // "b" is the least recently used, so it makes room for "c".
+    cache.insert("c", version("c"), b"cccc").unwrap();
+    assert!(cache.contains("a"));
+    assert!(!cache.contains("b"));
+    assert_eq!(cache.used_bytes(), 8);
+
+    // Larger than the per-file limit.
+    cache.insert("d", version("d"), b"ddddddd").unwrap();
+    assert!(!cache.contains("d"));
+
+    // Entries survive reopening the cache.
+    drop(cache);
+    let mut cache = ContentCache::new(dir.clone(), 10, 6, ttl).unwrap();
+    assert_eq!(cache.get("c"), Some(b"cccc".to_vec()));
+
+    // The per-file limit covers all cached chunks of a file.
+    let chunk = |index| ContentCache::chunk_key("e", index);
+    cache.insert(&chunk(0), version("e"), b"eee").unwrap();
+    cache.insert(&chunk(1), version("e"), b"eee").unwrap();
+    cache.insert(&chunk(2), version("e"), b"e").unwrap();
+    assert!(cache.contains(&chunk(0)) && cache.contains(&chunk(1)));
+    assert!(!cache.contains(&chunk(2)));
+
+    // Chunks which are downloaded together are stored and removed together.
+    let chunk = |index| ContentCache::chunk_key("f", index);
+    let chunks = vec![(chunk(0), &b"ff"[..]), (chunk(1), &b"f"[..])];
+    cache.insert_all(&version("f"), chunks).unwrap();
+    drop(cache);
+    let mut cache = ContentCache::new(dir.clone(), 10, 6, ttl).unwrap();
+    assert_eq!(cache.get(&chunk(1)), Some(b"f".to_vec()));
+    cache.remove_file("f");
+    drop(cache);
+    let cache = ContentCache::new(dir.clone(), 10, 6, ttl).unwrap();
+    assert!(!cache.contains(&chunk(0)) && !cache.contains(&chunk(1)));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn content_cache_revalidates_expired_entries() {
+    let dir = env::temp_dir().join("gcsf-test-content-cache-revalidate");
+    let _ = fs::remove_dir_all(&dir);
+
+    let mut cache = ContentCache::new(dir.clone(), 100, 100, Duration::from_secs(0)).unwrap();
+    cache.insert("a", version("1"), b"old").unwrap();
+    assert_eq!(cache.get("a"), None);
+    assert!(cache.contains("a"));
+
+    assert_eq!(cache.revalidate("a", &version("1")), Some(b"old".to_vec()));
+    assert_eq!(cache.revalidate("a", &version("2")), None);
+    assert!(!cache.contains("a"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn drive_facade_reads_ranges_across_chunks() {
+    const MIB: usize = 1024 * 1024;
+    let content: Vec<u8> = (0..MIB * 5 / 2).map(|i| (i % 251) as u8).collect();
+
+    let drive = MemoryDrive::new();
+    let id = drive.add_file(text_file("big.bin", None), &content);
+    let mut local = LocalDrive::start(&drive, "ranges");
+    let cache_dir = local.dir.join("content");
+    local.config.content_cache_path = Some(cache_dir.to_str().unwrap().to_string());
+    let mut df = DriveFacade::new(&local.config);
+
+    let read = |df: &mut DriveFacade, offset: usize, size: usize| {
+        df.read(&id, None, offset, size).map(|data| data.to_vec()).ok()
+    };
+
+    assert_eq!(read(&mut df, 0, 4096), Some(content[..4096].to_vec()));
+    // Sequential read which spans two chunks.
+    assert_eq!(
+        read(&mut df, 4096, MIB),
+        Some(content[4096..MIB + 4096].to_vec())
+    );
+    // Partially beyond the end of the file, then completely beyond it.
+    assert_eq!(
+        read(&mut df, content.len() - 10, 100),
+        Some(content[content.len() - 10..].to_vec())
+    );
+    assert_eq!(read(&mut df, content.len() + 10, 100), Some(Vec::new()));
+}
+
+#[test]
+fn drive_facade_spools_writes_until_flush() {
+    let drive = MemoryDrive::new();
+    let id = drive.add_file(text_file("spooled.txt", None), b"hello world");
+    let local = LocalDrive::start(&drive, "spool");
+    let mut df = DriveFacade::new(&local.config);
+
+    // Overwriting the whole file does not need its current content.
+    df.write(id.clone(), 0, b"HELLO").unwrap();
+    df.write(id.clone(), 5, b" WORLD").unwrap();
+    assert_eq!(drive.content(&id), Some(b"hello world".to_vec()));
+    df.flush(&id).unwrap();
+    assert_eq!(drive.content(&id), Some(b"HELLO WORLD".to_vec()));
+    assert_eq!(local.server.downloads(), 0);
+
+    // Neither does truncating it to zero first.
+    df.truncate(&id, 0).unwrap();
+    df.write(id.clone(), 0, b"new").unwrap();
+    df.flush(&id).unwrap();
+    assert_eq!(drive.content(&id), Some(b"new".to_vec()));
+    assert_eq!(local.server.downloads(), 0);
+
+    // A partial write is merged with the rest of the content.
+    df.write(id.clone(), 1, b"o").unwrap();
+    df.write(id.clone(), 5, b"!").unwrap();
+    df.flush(&id).unwrap();
+    assert_eq!(drive.content(&id), Some(b"now\0\0!".to_vec()));
+    assert!(local.server.downloads() > 0);
+}
+
+#[test]
+fn shutdown_reports_the_files_which_are_still_dirty_when_it_times_out() {
+    let drive = MemoryDrive::new();
+    let a = drive.add_file(text_file("a.txt", None), b"a");
+    let fs = GCSF::with_drive_backend(&config_with(""), drive.clone());
+    let shutdown = fs.shutdown_handle();
+
+    let ino = run(|done| fs.lookup_file(1, "a.txt".to_string(), done)).unwrap().ino;
+    let fh = run(|done| fs.open_file(ino, O_WRONLY as u32, done)).unwrap();
+    assert_eq!(run(|done| fs.write_file(ino, fh, 1, b"b", done)), Ok(1));
+
+    drive.set_flush_delay(Some(Duration::from_secs(1)));
+    let started = Instant::now();
+    let report = shutdown.shutdown(Duration::from_millis(100));
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert_eq!(
+        report,
+        ShutdownReport {
+            timed_out: true,
+            dirty_files: vec![a.clone()],
+        }
+    );
+    assert!(!report.is_clean());
+
+    // The upload carries on, and further modifications are refused.
+    assert!(eventually(|| drive.content(&a) == Some(b"ab".to_vec())));
+    assert_eq!(run(|done| fs.write_file(ino, fh, 2, b"c", done)), Err(EROFS));
+}
+
+#[test]
+fn drive_facade_uploads_conflicted_copy_if_remote_changed() {
+    let drive = MemoryDrive::new();
+    let id = drive.add_file(text_file("shared.txt", None), b"original");
+    let local = LocalDrive::start(&drive, "conflict");
+    let mut df = DriveFacade::new(&local.config);
+
+    assert_eq!(
+        df.read(&id, None, 0, 100).map(|data| data.to_vec()).ok(),
+        Some(b"original".to_vec())
+    );
+    drive.update_content(&id, b"theirs").unwrap();
+
+    df.truncate(&id, 0).unwrap();
+    df.write(id.clone(), 0, b"ours").unwrap();
+    df.flush(&id).unwrap();
+    assert_eq!(drive.content(&id), Some(b"theirs".to_vec()));
+
+    let copies: Vec<drive3::File> = drive
+        .clone()
+        .get_all_files(None, Some(false))
+        .unwrap()
+        .into_iter()
+        .filter(|f| {
+            f.name
+                .as_ref()
+                .map(|name| name.starts_with("shared.txt (conflicted copy "))
+                == Some(true)
+        })
+        .collect();
+    assert_eq!(copies.len(), 1);
+    assert_eq!(
+        drive.content(copies[0].id.as_ref().unwrap()),
+        Some(b"ours".to_vec())
+    );
+
+    // Without remote changes in between, the original is updated.
+    df.write(id.clone(), 0, b"mine").unwrap();
+    df.flush(&id).unwrap();
+    df.write(id.clone(), 4, b"!").unwrap();
+    df.flush(&id).unwrap();
+    assert_eq!(drive.content(&id), Some(b"mine!s".to_vec()));
+}
+
+#[test]
+fn reads_see_the_writes_which_have_not_been_uploaded_yet() {
+    let drive = MemoryDrive::new();
+    let id = drive.add_file(text_file("a.txt", None), b"old content");
+    let local = LocalDrive::start(&drive, "read-after-close");
+    let fs = GCSF::with_drive_backend(&local.config, DriveFacade::new(&local.config));
+    let read = |ino| {
+        let (sender, receiver) = mpsc::channel();
+        fs.read_file(ino, 0, 100, move |data| {
+            sender.send(data.map(|data| data.to_vec())).unwrap()
+        });
+        receiver.recv_timeout(Duration::from_secs(30)).unwrap()
+    };
+
+    let ino = run(|done| fs.lookup_file(1, "a.txt".to_string(), done)).unwrap().ino;
+    assert_eq!(read(ino), Ok(b"old content".to_vec()));
+
+    let fh = run(|done| fs.open_file(ino, (O_WRONLY | O_TRUNC) as u32, done)).unwrap();
+    assert_eq!(run(|done| fs.write_file(ino, fh, 0, b"new", done)), Ok(3));
+    assert_eq!(read(ino), Ok(b"new".to_vec()));
+
+    // close() returns before the upload which release() starts has finished.
+    let (released, uploaded) = mpsc::channel();
+    fs.release_file(ino, fh, move |result| released.send(result).unwrap());
+    assert_eq!(read(ino), Ok(b"new".to_vec()));
+    assert_eq!(uploaded.recv_timeout(Duration::from_secs(30)).unwrap(), Ok(()));
+    assert_eq!(drive.content(&id).unwrap(), b"new".to_vec());
+    assert_eq!(read(ino), Ok(b"new".to_vec()));
+}
+
+#[test]
+fn setattr_truncates_and_chmods_files_on_drive() {
+    let drive = MemoryDrive::new();
+    let id = drive.add_file(text_file("a.txt", None), b"hello world");
+    let fs = GCSF::with_drive_backend(&config_with(""), drive.clone());
+    let ino = run(|done| fs.lookup_file(1, "a.txt".to_string(), done)).unwrap().ino;
+    let set = |changes| run(|done| fs.set_attributes(ino, changes, done));
+
+    // Without a writer, the new size is uploaded right away.
+    let attr = set(AttrChanges {
+        size: Some(5),
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(attr.size, 5);
+    assert_eq!(drive.content(&id), Some(b"hello".to_vec()));
+
+    let attr = set(AttrChanges {
+        size: Some(7),
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(attr.size, 7);
+    assert_eq!(drive.content(&id), Some(b"hello\0\0".to_vec()));
+
+    let attr = set(AttrChanges {
+        mode: Some(0o100600),
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(attr.perm, 0o600);
+    let properties = drive.file(&id).unwrap().app_properties.unwrap();
+    for (key, value) in File::attr_properties(Some(0o600), None, None) {
+        assert_eq!(properties.get(&key), Some(&value));
+    }
+
+    assert_eq!(set(AttrChanges::default()).map(|attr| attr.size), Ok(7));
+    assert_eq!(
+        run(|done| fs.set_attributes(999, AttrChanges::default(), done)).map(|attr| attr.ino),
+        Err(ENOENT)
+    );
+}
+
+#[test]
+fn opening_with_o_trunc_empties_files_on_drive() {
+    let drive = MemoryDrive::new();
+    let id = drive.add_file(text_file("a.txt", None), b"old content");
+    let fs = GCSF::with_drive_backend(&config_with(""), drive.clone());
+    let ino = run(|done| fs.lookup_file(1, "a.txt".to_string(), done)).unwrap().ino;
+
+    let fh = run(|done| fs.open_file(ino, (O_WRONLY | O_TRUNC) as u32, done)).unwrap();
+    assert_eq!(run(|done| fs.lookup_file(1, "a.txt".to_string(), done)).unwrap().size, 0);
+    assert_eq!(run(|done| fs.write_file(ino, fh, 0, b"new", done)), Ok(3));
+    assert_eq!(run(|done| fs.release_file(ino, fh, done)), Ok(()));
+    assert_eq!(drive.content(&id), Some(b"new".to_vec()));
+
+    // Even without any writes.
+    let fh = run(|done| fs.open_file(ino, (O_RDWR | O_TRUNC) as u32, done)).unwrap();
+    assert_eq!(run(|done| fs.release_file(ino, fh, done)), Ok(()));
+    assert_eq!(drive.content(&id), Some(Vec::new()));
+
+    // Read-only handles do not truncate.
+    drive.update_content(&id, b"kept").unwrap();
+    let fh = run(|done| fs.open_file(ino, (O_RDONLY | O_TRUNC) as u32, done)).unwrap();
+    assert_eq!(run(|done| fs.release_file(ino, fh, done)), Ok(()));
+    assert_eq!(drive.content(&id), Some(b"kept".to_vec()));
+}
+
+#[test]
+fn created_files_are_added_once_drive_has_created_them() {
+    let drive = MemoryDrive::new();
+    let fs = GCSF::with_drive_backend(&config_with(""), drive.clone());
+    let create = |name: &str| {
+        let name = name.to_string();
+        run(|done| fs.create_file(1, name, 0o100644, O_WRONLY as u32, done))
+    };
+
+    let (attr, fh) = create("new.txt").unwrap();
+    assert_eq!(
+        run(|done| fs.lookup_file(1, "new.txt".to_string(), done)).map(|attr| attr.ino),
+        Ok(attr.ino)
+    );
+    assert_eq!(run(|done| fs.write_file(attr.ino, fh, 0, b"new", done)), Ok(3));
+    assert_eq!(run(|done| fs.release_file(attr.ino, fh, done)), Ok(()));
+
+    let created: Vec<drive3::File> = drive
+        .clone()
+        .get_all_files(None, Some(false))
+        .unwrap()
+        .into_iter()
+        .filter(|f| f.name == Some("new.txt".to_string()))
+        .collect();
+    assert_eq!(created.len(), 1);
+    let id = created[0].id.clone().unwrap();
+    assert_eq!(drive.content(&id), Some(b"new".to_vec()));
+
+    assert_eq!(create("new.txt").map(|_| ()), Err(EEXIST));
+
+    // Nothing is added if Drive does not create the file.
+    drive.set_offline(true);
+    assert!(create("offline.txt").is_err());
+    drive.set_offline(false);
+    assert_eq!(
+        run(|done| fs.lookup_file(1, "offline.txt".to_string(), done)).map(|attr| attr.ino),
+        Err(ENOENT)
+    );
+}
+
+#[test]
+fn xattrs_are_set_on_drive_from_a_worker() {
+    let drive = MemoryDrive::new();
+    let id = drive.add_file(text_file("a.txt", None), b"a");
+    let fs = GCSF::with_drive_backend(&config_with(""), drive.clone());
+    let ino = run(|done| fs.lookup_file(1, "a.txt".to_string(), done)).unwrap().ino;
+    let set = |name: &str, value: Option<&[u8]>| {
+        run(|done| fs.set_xattr_on_worker(ino, OsStr::new(name), value, done))
+    };
+
+    assert_eq!(set("user.gcsf.starred", Some(b"true")), Ok(()));
+    assert_eq!(set("user.prop.status", Some(b"done")), Ok(()));
+    let remote = drive.file(&id).unwrap();
+    assert_eq!(remote.starred, Some(true));
+    assert_eq!(xattr::get(&remote, "user.prop.status"), Some("done".to_string()));
+
+    assert_eq!(set("user.prop.status", None), Ok(()));
+    assert_eq!(xattr::get(&drive.file(&id).unwrap(), "user.prop.status"), None);
+
+    assert_eq!(set("user.other.key", Some(b"x")), Err(ENOTSUP));
+    assert_eq!(set("user.gcsf.starred", Some(b"maybe")), Err(EINVAL));
+    assert_eq!(drive.file(&id).unwrap().starred, Some(true));
+}
+
+#[test]
+fn drive_facade_recovers_pending_writes_after_crash() {
+    let drive = MemoryDrive::new();
+    let kept = drive.add_file(text_file("kept.txt", None), b"hello world");
+    let removed = drive.add_file(text_file("removed.txt", None), b"old");
+    let partial = drive.add_file(text_file("partial.txt", None), b"old content");
+    let local = LocalDrive::start(&drive, "recovery");
+    let config = &local.config;
+
+    {
+        let mut df = DriveFacade::new(config);
+        df.write(kept.clone(), 6, b"drive").unwrap();
+        df.truncate(&removed, 0).unwrap();
+        df.write(removed.clone(), 0, b"new").unwrap();
+        df.write(partial.clone(), 0, b"new").unwrap();
+        // Dropped without flushing, as if GCSF had crashed.
+    }
+    drive.remove_file(&removed);
+    drive.remove_file(&partial);
+
+    DriveFacade::new(config);
+    assert_eq!(drive.content(&kept), Some(b"hello drive".to_vec()));
+
+    // A file which is gone is created again if the pending writes replace all of its content.
+    let recreated: Vec<drive3::File> = drive
+        .clone()
+        .get_all_files(None, Some(false))
+        .unwrap()
+        .into_iter()
+        .filter(|f| f.name == Some("removed.txt".to_string()))
+        .collect();
+    assert_eq!(recreated.len(), 1);
+    assert_eq!(
+        drive.content(recreated[0].id.as_ref().unwrap()),
+        Some(b"new".to_vec())
+    );
+
+    // Otherwise, the pending writes are moved to the recovery directory.
+    let recovered = config
+        .recovery_path()
+        .join(format!("{} partial.txt", partial));
+    assert_eq!(&fs::read(recovered).unwrap()[..3], b"new");
+    assert_eq!(fs::read_dir(config.spool_path()).unwrap().count(), 0);
+}
+
+#[test]
+fn drive_facade_keeps_pending_writes_left_behind_while_offline() {
+    let drive = MemoryDrive::new();
+    let id = drive.add_file(text_file("a.txt", None), b"hello world");
+    let local = LocalDrive::start(&drive, "offline-recovery");
+    {
+        let mut df = DriveFacade::new(&local.config);
+        df.write(id.clone(), 0, b"HELLO").unwrap();
+    }
+
+    // Nothing listens on port 1, so Drive is unreachable.
+    let mut offline = local.config.clone();
+    offline.api_root = Some("http://127.0.0.1:1/drive/v3/".to_string());
+    offline.upload_root = Some("http://127.0.0.1:1/".to_string());
+    let mut df = DriveFacade::new(&offline);
+    assert_eq!(df.dirty_files(), vec![id.clone()]);
+
+    // Writing again adds to the pending writes instead of starting over. Extending them is only
+    // synced to disk once flushing fails.
+    df.write(id.clone(), 6, b"WORLD").unwrap();
+    df.write(id.clone(), 11, b"!").unwrap();
+    assert!(df.flush(&id).is_err());
+    drop(df);
+
+    DriveFacade::new(&local.config);
+    assert_eq!(drive.content(&id), Some(b"HELLO WORLD!".to_vec()));
+}
+
+#[test]
+fn drive_facade_retries_rate_limited_requests() {
+    let drive = MemoryDrive::new();
+    let id = drive.add_file(text_file("a.txt", None), b"hello world");
+    let local = LocalDrive::start(&drive, "retry");
+    let mut df = DriveFacade::new(&local.config);
+
+    local.server.fail_next_requests(local.config.retry_max_attempts() as usize - 1);
+    assert_eq!(
+        df.read(&id, None, 0, 5).map(|data| data.to_vec()).ok(),
+        Some(b"hello".to_vec())
+    );
+
+    // Requests which keep failing are eventually given up on.
+    local.server.fail_next_requests(local.config.retry_max_attempts() as usize);
+    let e = df.size_and_capacity().unwrap_err();
+    assert_eq!(FsError::errno_of(&e), EAGAIN);
+
+    // A wait requested by Drive is respected, although drive3 drops the response it came with.
+    local.server.fail_next_requests_asking_to_wait(1, 1);
+    let started = Instant::now();
+    assert!(df.size_and_capacity().is_ok());
+    assert!(started.elapsed() >= Duration::from_secs(1));
+
+    // Waits longer than configured are not, and the request fails as rate limited instead.
+    let too_long = local.config.retry_max_wait().as_secs() as usize + 1;
+    local.server.fail_next_requests_asking_to_wait(1, too_long);
+    let started = Instant::now();
+    let e = df.size_and_capacity().unwrap_err();
+    assert_eq!(FsError::errno_of(&e), EAGAIN);
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn failures_map_to_errno() {
+    let drive = MemoryDrive::new();
+    let dir = drive.add_dir("dir", None);
+    drive.add_file(text_file("inner.txt", Some(&dir)), b"inner");
+    let locked = drive.add_file(
+        drive3::File {
+            capabilities: Some(drive3::FileCapabilities {
+                can_rename: Some(false),
+                ..Default::default()
+            }),
+            ..text_file("locked.txt", None)
+        },
+        b"locked",
+    );
+    let mut manager = manager_for(&drive);
+    let errno = |result: Result<(), Error>| FsError::errno_of(&result.unwrap_err());
+
+    assert_eq!(errno(manager.check_new_file(1, "dir")), EEXIST);
+    assert_eq!(errno(manager.check_new_file(1, "a/b")), EINVAL);
+    assert_eq!(errno(manager.check_new_file(12345, "new.txt")), ENOENT);
+    assert!(manager.check_new_file(1, "new.txt").is_ok());
+
+    assert_eq!(
+        errno(manager.rename(&child(1, "locked.txt"), 1, "unlocked.txt".to_string())),
+        EACCES
+    );
+    assert_eq!(
+        drive.file(&locked).unwrap().name,
+        Some("locked.txt".to_string())
+    );
+
+    assert_eq!(
+        errno(manager.remove_dir_locally(&child(1, "dir"))),
+        ENOTEMPTY
+    );
+    assert!(manager.contains(&child(1, "dir")));
+
+    drive.set_capacity(Some(10));
+    manager
+        .write(FileId::DriveId(locked.clone()), 0, b"more than ten bytes")
+        .unwrap();
+    assert_eq!(errno(manager.flush(&FileId::DriveId(locked))), ENOSPC);
+}
+
+#[test]
+fn offline_mutations_are_replayed_on_reconnect() {
+    let journal_dir = env::temp_dir().join("gcsf-test-offline-journal");
+    let _ = fs::remove_dir_all(&journal_dir);
+
+    let drive = MemoryDrive::new();
+    let id = drive.add_file(text_file("a.txt", None), b"hello world");
+    let mut manager = FileManager::with_drive_backend(
+        Duration::from_secs(0),
+        OfflineDrive::new(drive.clone(), &journal_dir, &journal_dir.join("recovered")),
+    );
+
+    drive.set_offline(true);
+    manager
+        .rename(&child(1, "a.txt"), 1, "b.txt".to_string())
+        .unwrap();
+    manager.write(FileId::DriveId(id.clone()), 6, b"drive").unwrap();
+    manager.flush(&FileId::DriveId(id.clone())).unwrap();
+
+    let file = File::from_drive_file(
+        manager.next_available_inode(),
+        text_file("new.txt", Some("memory-root")),
+        AttrDefaults::default(),
+    );
+    manager.create_file(file, Some(FileId::Inode(1))).unwrap();
+    let temp_id = manager.get_drive_id(&child(1, "new.txt")).unwrap();
+    manager.write(FileId::DriveId(temp_id.clone()), 0, b"ne").unwrap();
+    manager.write(FileId::DriveId(temp_id.clone()), 2, b"w").unwrap();
+    manager.flush(&FileId::DriveId(temp_id.clone())).unwrap();
+
+    // The content of a file which only exists in the journal is served from there. Consecutive
+    // writes to a file share one data file.
+    assert_eq!(
+        manager.df.lock().unwrap().read(&temp_id, None, 1, 10).map(|data| data.to_vec()).ok(),
+        Some(b"ew".to_vec())
+    );
+    let data_files = fs::read_dir(&journal_dir)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("data".as_ref()))
+        .count();
+    assert_eq!(data_files, 2);
+
+    // The local tree reflects the mutations, but none of them has reached Drive yet.
+    assert!(manager.contains(&child(1, "b.txt")));
+    assert_eq!(drive.file(&id).unwrap().name, Some("a.txt".to_string()));
+    assert_eq!(drive.content(&id).unwrap(), b"hello world".to_vec());
+    assert!(manager.sync().is_err());
+
+    drive.set_offline(false);
+    manager.sync().unwrap();
+    assert_eq!(drive.file(&id).unwrap().name, Some("b.txt".to_string()));
+    assert_eq!(drive.content(&id).unwrap(), b"hello drive".to_vec());
+
+    let real_id = manager.get_drive_id(&child(1, "new.txt")).unwrap();
+    assert_ne!(real_id, temp_id);
+    assert_eq!(drive.content(&real_id).unwrap(), b"new".to_vec());
+}
+
+#[test]
+fn offline_writes_which_cannot_be_replayed_are_kept() {
+    let journal_dir = env::temp_dir().join("gcsf-test-offline-recovery");
+    let recovery_dir = journal_dir.join("recovered");
+    let _ = fs::remove_dir_all(&journal_dir);
+
+    let drive = MemoryDrive::new();
+    let dir = drive.add_dir("drafts", None);
+    let mut manager = FileManager::with_drive_backend(
+        Duration::from_secs(0),
+        OfflineDrive::new(drive.clone(), &journal_dir, &recovery_dir),
+    );
+
+    drive.set_offline(true);
+    let file = File::from_drive_file(
+        manager.next_available_inode(),
+        text_file("new.txt", Some(&dir)),
+        AttrDefaults::default(),
+    );
+    let parent = manager.get_inode(&FileId::DriveId(dir.clone())).unwrap();
+    manager.create_file(file, Some(FileId::Inode(parent))).unwrap();
+    let temp_id = manager.get_drive_id(&child(parent, "new.txt")).unwrap();
+    manager.write(FileId::DriveId(temp_id.clone()), 0, b"draft").unwrap();
+    manager.flush(&FileId::DriveId(temp_id.clone())).unwrap();
+
+    // The directory is removed remotely in the meantime, so the file cannot be created.
+    drive.remove_file(&dir);
+    drive.set_offline(false);
+    manager.sync().unwrap();
+
+    let kept = recovery_dir.join(format!("{} new.txt", temp_id));
+    assert_eq!(fs::read(&kept).unwrap(), b"draft".to_vec());
+    assert_eq!(OfflineDrive::<MemoryDrive>::queued_mutations(&journal_dir), 0);
+}